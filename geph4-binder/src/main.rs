@@ -18,6 +18,17 @@ struct Opt {
     /// HTTP listening port
     #[structopt(default_value = "127.0.0.1:18080", long)]
     listen_http: SocketAddr,
+    /// Largest datagram known to reach an exit intact, given as HOSTNAME=MTU. Handed out to clients, which pad their packets to fit. May be given once per exit.
+    #[structopt(long = "mtu-hint", parse(try_from_str = parse_mtu_hint))]
+    mtu_hints: Vec<(String, u16)>,
+}
+
+fn parse_mtu_hint(s: &str) -> anyhow::Result<(String, u16)> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(hostname), Some(mtu)) => Ok((hostname.to_string(), mtu.parse()?)),
+        _ => anyhow::bail!("expected HOSTNAME=MTU"),
+    }
 }
 
 fn main() {
@@ -47,5 +58,17 @@ fn main() {
     // create server
     let http_serv = binder_transport::HttpServer::new(opt.listen_http, master_secret);
     println!("HTTP listening on {}", opt.listen_http);
-    responder::handle_requests(http_serv, &binder_core)
+    let exit_hints = opt
+        .mtu_hints
+        .into_iter()
+        .map(|(hostname, mtu)| {
+            (
+                hostname,
+                binder_transport::ExitHints {
+                    mtu_hint: Some(mtu),
+                },
+            )
+        })
+        .collect();
+    responder::handle_requests(http_serv, &binder_core, &exit_hints)
 }
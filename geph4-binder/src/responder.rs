@@ -1,7 +1,7 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use crate::bindercore::BinderCore;
-use binder_transport::{BinderError, BinderRequestData, BinderResponse, BinderServer};
+use binder_transport::{BinderError, BinderRequestData, BinderResponse, BinderServer, ExitHints};
 /// Retry an action indefinitely when the database errors out
 fn db_retry<T>(action: impl Fn() -> Result<T, BinderError>) -> Result<T, BinderError> {
     for _ in 1.. {
@@ -15,12 +15,16 @@ fn db_retry<T>(action: impl Fn() -> Result<T, BinderError>) -> Result<T, BinderE
     unreachable!()
 }
 
-/// Respond to requests coming from the given BinderServer, using the given BinderCore.
-pub fn handle_requests(serv: impl BinderServer, core: &BinderCore) {
+/// Respond to requests coming from the given BinderServer, using the given BinderCore. Exits are handed out with the given hints, or none if they have no entry.
+pub fn handle_requests(
+    serv: impl BinderServer,
+    core: &BinderCore,
+    exit_hints: &HashMap<String, ExitHints>,
+) {
     easy_parallel::Parallel::new()
         .each(0..64, |worker_id| loop {
             if let Err(e) = {
-                let v = handle_request_once(&serv, core);
+                let v = handle_request_once(&serv, core, exit_hints);
                 log::debug!("handling request on {}", worker_id);
                 v
             } {
@@ -30,7 +34,11 @@ pub fn handle_requests(serv: impl BinderServer, core: &BinderCore) {
         .run();
 }
 
-fn handle_request_once(serv: &impl BinderServer, core: &BinderCore) -> anyhow::Result<()> {
+fn handle_request_once(
+    serv: &impl BinderServer,
+    core: &BinderCore,
+    exit_hints: &HashMap<String, ExitHints>,
+) -> anyhow::Result<()> {
     let req = serv.next_request()?;
     let res = match &req.request_data {
         // password change request
@@ -119,6 +127,17 @@ fn handle_request_once(serv: &impl BinderServer, core: &BinderCore) -> anyhow::R
             let response = core.get_exits()?;
             Ok(BinderResponse::GetExitsResp(response))
         }),
+        BinderRequestData::GetExitsV2 => db_retry(|| {
+            let response = core
+                .get_exits()?
+                .into_iter()
+                .map(|exit| {
+                    let hints = exit_hints.get(&exit.hostname).cloned().unwrap_or_default();
+                    (exit, hints)
+                })
+                .collect();
+            Ok(BinderResponse::GetExitsV2Resp(response))
+        }),
         // get bridges
         BinderRequestData::GetBridges {
             level,
@@ -1,6 +1,10 @@
-use crate::{persist::KVDatabase, AuthOpt, CommonOpt};
+use crate::{
+    persist::{KVDatabase, KVTransaction},
+    AuthOpt, CommonOpt,
+};
 use binder_transport::{
     BinderClient, BinderError, BinderRequestData, BinderResponse, BridgeDescriptor, ExitDescriptor,
+    ExitHints,
 };
 use parking_lot::Mutex;
 use rand::prelude::*;
@@ -9,7 +13,12 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sha2::Sha256;
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
-use std::{sync::Arc, time::Duration, time::SystemTime};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+    time::SystemTime,
+};
 
 /// An cached client
 pub struct ClientCache {
@@ -19,11 +28,34 @@ pub struct ClientCache {
     free_pk: mizaru::PublicKey,
     plus_pk: mizaru::PublicKey,
     database: Arc<Mutex<KVDatabase>>,
+    history: HistoryStore,
+    /// Keys of the cached items being refreshed in the background.
+    refreshing: Arc<Mutex<HashSet<String>>>,
     pub force_sync: bool,
+    /// How long the list of exits is reused before asking the binder again.
+    pub exit_ttl: Duration,
+    /// How long the bridges of an exit are reused before asking the binder again.
+    pub bridge_ttl: Duration,
 }
 
 static TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Version of the format of cached items. Bumping it makes clients ignore what older ones cached.
+const CACHE_VERSION: u32 = 2;
+
+/// Fetch time of a cached value that was invalidated, so that it's fetched again before being used.
+const INVALIDATED: u64 = 0;
+/// Fetch time of a cached value that couldn't be fetched again after being invalidated, so that it's used while being refreshed in the background, like any expired value.
+const EXPIRED: u64 = 1;
+
+/// How long an auth token is reused before a new one is fetched.
+const AUTH_TOKEN_TTL: Duration = Duration::from_secs(86400);
+
+/// How long a connection outcome is remembered.
+const HISTORY_MAX_AGE: Duration = Duration::from_secs(7 * 86400);
+/// How many connection outcomes are remembered per endpoint.
+const HISTORY_MAX_LEN: usize = 32;
+
 impl ClientCache {
     /// Create a new ClientCache that saves to the given database.
     pub fn new(
@@ -40,11 +72,20 @@ impl ClientCache {
             binder_client,
             free_pk,
             plus_pk,
+            history: HistoryStore::new(database.clone()),
             database,
+            refreshing: Default::default(),
             force_sync: false,
+            exit_ttl: Duration::from_secs(3600),
+            bridge_ttl: Duration::from_secs(60),
         }
     }
 
+    /// Gets the store of per-endpoint connection history.
+    pub fn history(&self) -> &HistoryStore {
+        &self.history
+    }
+
     /// Create from options
     pub fn from_opts(common: &CommonOpt, auth: &AuthOpt) -> anyhow::Result<Self> {
         let binder_client = common.to_binder_client();
@@ -68,53 +109,131 @@ impl ClientCache {
         fallback: impl Future<Output = anyhow::Result<T>>,
         ttl: Duration,
     ) -> anyhow::Result<T> {
-        let key = format!("{}-{}", key, self.username);
+        let key = self.cache_key(key);
         let existing: Option<(T, u64)> = self.database.lock().transaction().get(&key);
         if !self.force_sync {
             if let Some((existing, timeout)) = existing {
-                if SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs()
-                    < timeout + ttl.as_secs()
-                {
+                if unix_secs() < timeout + ttl.as_secs() {
                     return Ok(existing);
                 }
             }
         }
-        let deadline: SystemTime = SystemTime::now();
-        let deadline = deadline
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        let deadline = unix_secs();
         let fresh = fallback.await?;
         log::trace!("fallback resolved for {}! ({:?})", key, fresh);
-        let mut database = self.database.lock();
-        log::trace!("database locked for {}!", key);
-        let mut db = database.transaction();
-        // save to disk
-        db.insert(&key, (fresh.clone(), deadline));
-        db.commit();
+        store(&self.database, &key, (fresh.clone(), deadline));
         log::trace!("about to return for {}!", key);
         Ok(fresh)
     }
 
+    /// Like get_cached, except that an expired value is returned right away while a fresh one is fetched in the background. This way, a freshly started client can connect without waiting for the binder, and keeps connecting while the binder is unreachable. Invalidated values are the exception: they're only returned if fetching a fresh one fails.
+    async fn get_cached_or_stale<T>(
+        &self,
+        key: &str,
+        fallback: impl Future<Output = anyhow::Result<T>> + Send + 'static,
+        ttl: Duration,
+    ) -> anyhow::Result<T>
+    where
+        T: Serialize + DeserializeOwned + Clone + std::fmt::Debug + Send + 'static,
+    {
+        if !self.force_sync {
+            let key = self.cache_key(key);
+            let existing: Option<(T, u64)> = self.database.lock().transaction().get(&key);
+            if let Some((existing, timeout)) = existing {
+                if timeout == INVALIDATED {
+                    let deadline = unix_secs();
+                    return match fallback.await {
+                        Ok(fresh) => {
+                            store(&self.database, &key, (fresh.clone(), deadline));
+                            Ok(fresh)
+                        }
+                        Err(err) => {
+                            log::warn!("couldn't refetch {}, using it as is: {}", key, err);
+                            store(&self.database, &key, (existing.clone(), EXPIRED));
+                            Ok(existing)
+                        }
+                    };
+                }
+                if unix_secs() >= timeout + ttl.as_secs()
+                    && self.refreshing.lock().insert(key.clone())
+                {
+                    log::debug!("refreshing {} in the background", key);
+                    let database = self.database.clone();
+                    let refreshing = self.refreshing.clone();
+                    smolscale::spawn(async move {
+                        let deadline = unix_secs();
+                        match fallback.await {
+                            Ok(fresh) => store(&database, &key, (fresh, deadline)),
+                            Err(err) => log::warn!("couldn't refresh {}: {}", key, err),
+                        }
+                        refreshing.lock().remove(&key);
+                    })
+                    .detach();
+                }
+                return Ok(existing);
+            }
+        }
+        self.get_cached(key, fallback, ttl).await
+    }
+
+    /// The database key of a cached item. Keys carry the version of the cache format, so that whatever an older client left behind is ignored.
+    fn cache_key(&self, key: &str) -> String {
+        format!("{}.v{}-{}", key, CACHE_VERSION, self.username)
+    }
+
     /// Obtains a new token.
     pub async fn get_auth_token(&self) -> anyhow::Result<Token> {
-        self.get_cached(
-            "cache.auth_token",
-            self.get_token_fresh(),
-            Duration::from_secs(86400),
-        )
-        .await
+        self.get_cached("cache.auth_token", self.get_token_fresh(), AUTH_TOKEN_TTL)
+            .await
+    }
+
+    /// How long until the cached token is due to be replaced, or None if there's no token yet.
+    pub fn auth_token_expires_in(&self) -> Option<Duration> {
+        let key = self.cache_key("cache.auth_token");
+        let (_, fetched): (Token, u64) = self.database.lock().transaction().get(&key)?;
+        Some(Duration::from_secs(
+            (fetched + AUTH_TOKEN_TTL.as_secs()).saturating_sub(unix_secs()),
+        ))
+    }
+
+    /// Fetches a new token right away, replacing the cached one.
+    pub async fn refresh_auth_token(&self) -> anyhow::Result<Token> {
+        let fetched = unix_secs();
+        let fresh = self.get_token_fresh().await?;
+        store(
+            &self.database,
+            &self.cache_key("cache.auth_token"),
+            (fresh.clone(), fetched),
+        );
+        Ok(fresh)
     }
 
     /// Gets a list of exits.
     pub async fn get_exits(&self) -> anyhow::Result<Vec<ExitDescriptor>> {
-        self.get_cached(
+        Ok(self
+            .get_hinted_exits()
+            .await?
+            .into_iter()
+            .map(|(exit, _)| exit)
+            .collect())
+    }
+
+    /// Gets the hints about reaching the given exit. They're empty if the binder doesn't know any, or is too old to give them out.
+    pub async fn get_exit_hints(&self, exit_hostname: &str) -> anyhow::Result<ExitHints> {
+        Ok(self
+            .get_hinted_exits()
+            .await?
+            .into_iter()
+            .find(|(exit, _)| exit.hostname == exit_hostname)
+            .map(|(_, hints)| hints)
+            .unwrap_or_default())
+    }
+
+    async fn get_hinted_exits(&self) -> anyhow::Result<Vec<(ExitDescriptor, ExitHints)>> {
+        self.get_cached_or_stale(
             "cache.exits",
-            self.get_exits_fresh(),
-            Duration::from_secs(3600),
+            get_exits_fresh(self.binder_client.clone()),
+            self.exit_ttl,
         )
         .await
     }
@@ -124,9 +243,9 @@ impl ClientCache {
         let tok = self.get_auth_token().await?;
         let binder_client = self.binder_client.clone();
         let exit_hostname = exit_hostname.to_string();
-        self.get_cached(
+        self.get_cached_or_stale(
             &format!("cache.bridges.{}", exit_hostname),
-            async {
+            async move {
                 let res = timeout(smol::unblock(move || {
                     binder_client.request(
                         BinderRequestData::GetBridges {
@@ -145,11 +264,29 @@ impl ClientCache {
                     anyhow::bail!("invalid response")
                 }
             },
-            Duration::from_secs(60),
+            self.bridge_ttl,
         )
         .await
     }
 
+    /// Marks the cached exits and bridges as invalid, so that they're fetched again the next time they're needed. They're still used if that fails.
+    pub fn invalidate_directory(&self) {
+        let mut database = self.database.lock();
+        let mut db = database.transaction();
+        let suffix = format!("-{}", self.username);
+        for key in db.keys_with_prefix("cache.exits") {
+            if key.ends_with(&suffix) {
+                invalidate::<Vec<(ExitDescriptor, ExitHints)>>(&mut db, &key);
+            }
+        }
+        for key in db.keys_with_prefix("cache.bridges.") {
+            if key.ends_with(&suffix) {
+                invalidate::<Vec<BridgeDescriptor>>(&mut db, &key);
+            }
+        }
+        db.commit();
+    }
+
     async fn get_token_fresh(&self) -> anyhow::Result<Token> {
         let digest: [u8; 32] = rand::thread_rng().gen();
         for level in &["plus", "free"] {
@@ -217,18 +354,202 @@ impl ClientCache {
         }
         anyhow::bail!("neither plus nor free worked");
     }
+}
+
+async fn get_exits_fresh(
+    binder_client: Arc<dyn BinderClient>,
+) -> anyhow::Result<Vec<(ExitDescriptor, ExitHints)>> {
+    let res = smol::unblock(move || {
+        match binder_client.request(BinderRequestData::GetExitsV2, Duration::from_secs(30)) {
+            Ok(BinderResponse::GetExitsV2Resp(exits)) => {
+                return Ok(BinderResponse::GetExitsV2Resp(exits))
+            }
+            Ok(other) => log::debug!("unexpected response {:?} to GetExitsV2", other),
+            Err(err) => log::debug!("GetExitsV2 failed ({}), falling back to GetExits", err),
+        }
+        binder_client.request(BinderRequestData::GetExits, Duration::from_secs(30))
+    })
+    .await?;
+    match res {
+        BinderResponse::GetExitsV2Resp(exits) => Ok(exits),
+        BinderResponse::GetExitsResp(exits) => Ok(exits
+            .into_iter()
+            .map(|exit| (exit, ExitHints::default()))
+            .collect()),
+        other => anyhow::bail!("unexpected response {:?}", other),
+    }
+}
 
-    async fn get_exits_fresh(&self) -> anyhow::Result<Vec<ExitDescriptor>> {
-        let binder_client = self.binder_client.clone();
-        let res = smol::unblock(move || {
-            binder_client.request(BinderRequestData::GetExits, Duration::from_secs(30))
+/// Persistent record of how connecting to every bridge or exit has gone, used to prefer endpoints that worked well before.
+#[derive(Clone)]
+pub struct HistoryStore {
+    database: Arc<Mutex<KVDatabase>>,
+}
+
+impl HistoryStore {
+    /// Creates a history store that saves to the given database.
+    pub fn new(database: Arc<Mutex<KVDatabase>>) -> Self {
+        HistoryStore { database }
+    }
+
+    /// Records the outcome of connecting to an endpoint. The latency is None if the connection failed.
+    pub fn record(&self, endpoint: &str, latency: Option<Duration>) {
+        let now = unix_secs();
+        let key = format!("history.{}", endpoint);
+        let mut database = self.database.lock();
+        let mut db = database.transaction();
+        let mut history: EndpointHistory = db.get(&key).unwrap_or_default();
+        history.record(now, latency);
+        db.insert(&key, history);
+        db.commit();
+    }
+
+    /// Like [HistoryStore::record], but writes to the database on a blocking thread, so that async tasks never wait on it. Also works from destructors.
+    pub fn record_in_background(&self, endpoint: String, latency: Option<Duration>) {
+        let this = self.clone();
+        smol::unblock(move || this.record(&endpoint, latency)).detach();
+    }
+
+    /// Gets the history of an endpoint, without any stale entries.
+    pub fn get(&self, endpoint: &str) -> EndpointHistory {
+        let mut history: EndpointHistory = self
+            .database
+            .lock()
+            .transaction()
+            .get(&format!("history.{}", endpoint))
+            .unwrap_or_default();
+        history.expire(unix_secs());
+        history
+    }
+
+    /// Gets the latency of every endpoint with any history, fastest first. Endpoints that never connected successfully come last.
+    pub fn latencies(&self) -> Vec<EndpointLatency> {
+        let now = unix_secs();
+        let mut database = self.database.lock();
+        let db = database.transaction();
+        let mut latencies: Vec<EndpointLatency> = db
+            .keys_with_prefix("history.")
+            .into_iter()
+            .filter_map(|key| {
+                let mut history: EndpointHistory = db.get(&key)?;
+                history.expire(now);
+                let (measured_at, _) = *history.outcomes.back()?;
+                let endpoint = key["history.".len()..].to_string();
+                Some(EndpointLatency {
+                    is_bridge: endpoint.parse::<std::net::SocketAddr>().is_ok(),
+                    latency_ms: history.mean_latency().map(|l| l.as_millis() as u64),
+                    success_rate: history.success_rate(),
+                    measured_at,
+                    endpoint,
+                })
+            })
+            .collect();
+        latencies.sort_by_key(|l| (l.latency_ms.is_none(), l.latency_ms));
+        latencies
+    }
+
+    /// Sorts the given items so that the ones whose endpoints have the best history come first. Endpoints without any history are ranked as if they succeed half the time. This reads the database, so async code should call it through [smol::unblock].
+    pub fn rank<T>(&self, items: &mut [T], endpoint: impl Fn(&T) -> String) {
+        items.sort_by_cached_key(|item| {
+            let history = self.get(&endpoint(item));
+            (
+                std::cmp::Reverse((history.success_rate() * 1e6) as u64),
+                history
+                    .mean_latency()
+                    .unwrap_or_else(|| Duration::from_secs(u64::MAX)),
+            )
         })
-        .await?;
-        match res {
-            binder_transport::BinderResponse::GetExitsResp(exits) => Ok(exits),
-            other => anyhow::bail!("unexpected response {:?}", other),
+    }
+}
+
+/// Summary of how fast connecting to an endpoint has been.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndpointLatency {
+    /// The exit hostname, or the bridge address.
+    pub endpoint: String,
+    pub is_bridge: bool,
+    /// Mean latency of the successful connections, in milliseconds, or None if none succeeded.
+    pub latency_ms: Option<u64>,
+    pub success_rate: f64,
+    /// UNIX time of the latest connection attempt.
+    pub measured_at: u64,
+}
+
+/// Rolling history of connection outcomes for a single endpoint.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EndpointHistory {
+    /// Outcomes from oldest to newest, as the UNIX time and the latency in milliseconds if successful.
+    outcomes: VecDeque<(u64, Option<u64>)>,
+}
+
+impl EndpointHistory {
+    fn record(&mut self, now: u64, latency: Option<Duration>) {
+        self.outcomes
+            .push_back((now, latency.map(|l| l.as_millis() as u64)));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: u64) {
+        while self.outcomes.len() > HISTORY_MAX_LEN {
+            self.outcomes.pop_front();
+        }
+        while let Some((time, _)) = self.outcomes.front() {
+            if time + HISTORY_MAX_AGE.as_secs() >= now {
+                break;
+            }
+            self.outcomes.pop_front();
         }
     }
+
+    /// Number of remembered outcomes.
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    /// Whether nothing is remembered.
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    /// Fraction of successful connections, smoothed so that a short history doesn't count for too much.
+    pub fn success_rate(&self) -> f64 {
+        let successes = self.outcomes.iter().filter(|(_, l)| l.is_some()).count();
+        (successes as f64 + 1.0) / (self.outcomes.len() as f64 + 2.0)
+    }
+
+    /// Mean latency of the successful connections.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let latencies: Vec<u64> = self.outcomes.iter().filter_map(|(_, l)| *l).collect();
+        if latencies.is_empty() {
+            None
+        } else {
+            Some(Duration::from_millis(
+                latencies.iter().sum::<u64>() / latencies.len() as u64,
+            ))
+        }
+    }
+}
+
+/// Saves something to the database.
+fn store<T: Serialize>(database: &Mutex<KVDatabase>, key: &str, value: T) {
+    let mut database = database.lock();
+    let mut db = database.transaction();
+    db.insert(key, value);
+    db.commit();
+}
+
+/// Marks a cached value as invalidated, keeping the value itself.
+fn invalidate<T: Serialize + DeserializeOwned>(db: &mut KVTransaction<'_>, key: &str) {
+    if let Some((value, _)) = db.get::<(T, u64)>(key) {
+        db.insert(key, (value, INVALIDATED));
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -245,3 +566,249 @@ async fn timeout<T, F: Future<Output = T>>(fut: F) -> anyhow::Result<T> {
         .await
         .ok_or_else(|| anyhow::anyhow!("timeout"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_influences_ranking() {
+        let path = std::env::temp_dir().join(format!(
+            "geph4-history-test-{}.db",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let history = HistoryStore::new(Arc::new(Mutex::new(KVDatabase::open(&path).unwrap())));
+        let mut endpoints = vec!["flaky", "unknown", "slow", "fast"];
+        history.rank(&mut endpoints, |e| e.to_string());
+        // nothing is known yet, so the order is left alone
+        assert_eq!(endpoints, vec!["flaky", "unknown", "slow", "fast"]);
+        for _ in 0..5 {
+            history.record("flaky", None);
+            history.record("slow", Some(Duration::from_millis(900)));
+            history.record("fast", Some(Duration::from_millis(100)));
+        }
+        history.record("flaky", Some(Duration::from_millis(50)));
+        assert_eq!(history.get("flaky").len(), 6);
+        assert_eq!(
+            history.get("fast").mean_latency(),
+            Some(Duration::from_millis(100))
+        );
+        history.rank(&mut endpoints, |e| e.to_string());
+        assert_eq!(endpoints, vec!["fast", "slow", "unknown", "flaky"]);
+        drop(history);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn latencies_are_sorted() {
+        let path = std::env::temp_dir().join(format!(
+            "geph4-latencies-test-{}.db",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let history = HistoryStore::new(Arc::new(Mutex::new(KVDatabase::open(&path).unwrap())));
+        history.record("slow.exits.geph.io", Some(Duration::from_millis(900)));
+        history.record("down.exits.geph.io", None);
+        history.record("1.2.3.4:19831", Some(Duration::from_millis(50)));
+        history.record("fast.exits.geph.io", Some(Duration::from_millis(100)));
+        history.record("fast.exits.geph.io", Some(Duration::from_millis(200)));
+        let latencies = history.latencies();
+        let endpoints: Vec<_> = latencies.iter().map(|l| l.endpoint.as_str()).collect();
+        assert_eq!(
+            endpoints,
+            vec![
+                "1.2.3.4:19831",
+                "fast.exits.geph.io",
+                "slow.exits.geph.io",
+                "down.exits.geph.io"
+            ]
+        );
+        assert!(latencies[0].is_bridge);
+        assert!(!latencies[1].is_bridge);
+        assert_eq!(latencies[1].latency_ms, Some(150));
+        assert_eq!(latencies[3].latency_ms, None);
+        assert!(latencies.iter().all(|l| l.measured_at + 60 > unix_secs()));
+        drop(history);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Serves a single exit until it's told to go down.
+    struct FlakyBinder {
+        exit: ExitDescriptor,
+        /// Hints given out for the exit, or None to act like a binder that predates them.
+        hints: Option<ExitHints>,
+        up: std::sync::atomic::AtomicBool,
+        requests: std::sync::atomic::AtomicUsize,
+    }
+
+    impl BinderClient for FlakyBinder {
+        fn request(
+            &self,
+            request: BinderRequestData,
+            _timeout: Duration,
+        ) -> binder_transport::BinderResult<BinderResponse> {
+            self.requests
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if !self.up.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(BinderError::DatabaseFailed);
+            }
+            match (request, &self.hints) {
+                (BinderRequestData::GetExitsV2, Some(hints)) => Ok(BinderResponse::GetExitsV2Resp(
+                    vec![(self.exit.clone(), hints.clone())],
+                )),
+                (BinderRequestData::GetExits, _) => {
+                    Ok(BinderResponse::GetExitsResp(vec![self.exit.clone()]))
+                }
+                _ => Err(BinderError::Other("undecodable request".into())),
+            }
+        }
+    }
+
+    fn flaky_binder() -> Arc<FlakyBinder> {
+        Arc::new(FlakyBinder {
+            exit: ExitDescriptor {
+                hostname: "us-hio-01.exits.geph.io".into(),
+                signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+                country_code: "us".into(),
+                city_code: "hio".into(),
+                sosistab_key: x25519_dalek::PublicKey::from([0u8; 32]),
+            },
+            hints: Some(ExitHints {
+                mtu_hint: Some(1400),
+            }),
+            up: std::sync::atomic::AtomicBool::new(true),
+            requests: Default::default(),
+        })
+    }
+
+    #[test]
+    fn exits_are_reused_when_the_binder_is_down() {
+        let path = std::env::temp_dir().join(format!(
+            "geph4-directory-test-{}.db",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let binder = flaky_binder();
+        let requests = || binder.requests.load(std::sync::atomic::Ordering::SeqCst);
+        let mut ccache = ClientCache::new(
+            "user",
+            "pass",
+            mizaru::PublicKey([0; 32]),
+            mizaru::PublicKey([0; 32]),
+            binder.clone(),
+            Arc::new(Mutex::new(KVDatabase::open(&path).unwrap())),
+        );
+        smol::block_on(async {
+            assert_eq!(ccache.get_exits().await.unwrap().len(), 1);
+            assert_eq!(ccache.get_exits().await.unwrap().len(), 1);
+            assert_eq!(requests(), 1);
+            // invalidated exits are fetched again before being used
+            ccache.invalidate_directory();
+            assert_eq!(ccache.get_exits().await.unwrap().len(), 1);
+            assert_eq!(requests(), 2);
+            // but a refetch while the binder is down falls back to the old list
+            binder.up.store(false, std::sync::atomic::Ordering::SeqCst);
+            ccache.invalidate_directory();
+            assert_eq!(ccache.get_exits().await.unwrap().len(), 1);
+            let refetched = requests();
+            assert!(refetched > 2);
+            // which is then refreshed in the background, like any expired list
+            assert_eq!(ccache.get_exits().await.unwrap().len(), 1);
+            while requests() == refetched {
+                smol::Timer::after(Duration::from_millis(10)).await;
+            }
+        });
+        // unless fresh data is demanded
+        ccache.force_sync = true;
+        assert!(smol::block_on(ccache.get_exits()).is_err());
+        drop(ccache);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn hints_fall_back_for_old_binders() {
+        let path = std::env::temp_dir().join(format!(
+            "geph4-hints-test-{}.db",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let database = Arc::new(Mutex::new(KVDatabase::open(&path).unwrap()));
+        let new_ccache = |binder: Arc<FlakyBinder>| {
+            let mut ccache = ClientCache::new(
+                "user",
+                "pass",
+                mizaru::PublicKey([0; 32]),
+                mizaru::PublicKey([0; 32]),
+                binder,
+                database.clone(),
+            );
+            ccache.force_sync = true;
+            ccache
+        };
+        smol::block_on(async {
+            let binder = flaky_binder();
+            let hostname = binder.exit.hostname.clone();
+            let ccache = new_ccache(binder.clone());
+            assert_eq!(
+                ccache.get_exit_hints(&hostname).await.unwrap().mtu_hint,
+                Some(1400)
+            );
+            assert_eq!(binder.requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+            // a binder that can't decode GetExitsV2 still hands out its exits
+            let mut old_binder = flaky_binder();
+            Arc::get_mut(&mut old_binder).unwrap().hints = None;
+            let ccache = new_ccache(old_binder);
+            assert_eq!(ccache.get_exits().await.unwrap().len(), 1);
+            assert_eq!(
+                ccache.get_exit_hints(&hostname).await.unwrap(),
+                ExitHints::default()
+            );
+        });
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn cold_start_uses_what_is_on_disk() {
+        let path = std::env::temp_dir().join(format!(
+            "geph4-cold-start-test-{}.db",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let binder = flaky_binder();
+        let database = Arc::new(Mutex::new(KVDatabase::open(&path).unwrap()));
+        let new_ccache = || {
+            let mut ccache = ClientCache::new(
+                "user",
+                "pass",
+                mizaru::PublicKey([0; 32]),
+                mizaru::PublicKey([0; 32]),
+                binder.clone(),
+                database.clone(),
+            );
+            ccache.exit_ttl = Duration::from_secs(0);
+            ccache
+        };
+        smol::block_on(async {
+            assert_eq!(new_ccache().get_exits().await.unwrap().len(), 1);
+            // a restarted client connects with the old list, even though it expired and the binder is down
+            binder.up.store(false, std::sync::atomic::Ordering::SeqCst);
+            let ccache = new_ccache();
+            assert_eq!(ccache.get_exits().await.unwrap().len(), 1);
+            // whatever can't be read, say because an older version wrote it, is fetched again
+            store(&database, &ccache.cache_key("cache.exits"), "garbage");
+            assert!(ccache.get_exits().await.is_err());
+            binder.up.store(true, std::sync::atomic::Ordering::SeqCst);
+            assert_eq!(ccache.get_exits().await.unwrap().len(), 1);
+        });
+        drop(database);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn history_ages_out() {
+        let mut history = EndpointHistory::default();
+        for i in 0..100 {
+            history.record(1000 + i, Some(Duration::from_millis(10)));
+        }
+        assert_eq!(history.len(), HISTORY_MAX_LEN);
+        history.expire(1100 + HISTORY_MAX_AGE.as_secs());
+        assert!(history.is_empty());
+        assert_eq!(history.mean_latency(), None);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::kalive::Keepalive;
+use parking_lot::Mutex;
+use smol::channel::{Receiver, Sender};
+use smol_timeout::TimeoutExt;
+use std::{
+    collections::HashMap,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// Most responses kept at once.
+const MAX_ENTRIES: usize = 4096;
+
+/// Record type of the EDNS pseudo-record, whose "TTL" field holds flags instead.
+const TYPE_OPT: u16 = 41;
+
+/// An in-memory cache of DNS responses, keyed by the name and type they answer, that honors the TTLs in the answers.
+#[derive(Default)]
+pub struct DnsCache {
+    entries: Mutex<HashMap<(String, u16), CachedResponse>>,
+}
+
+struct CachedResponse {
+    response: Vec<u8>,
+    /// Where the TTL field of every record is, so that it can be counted down.
+    ttl_offsets: Vec<usize>,
+    stored: Instant,
+    expires: Instant,
+}
+
+impl DnsCache {
+    /// Answers a query from the cache, if a fresh response is there. The response carries the ID of the query, with its TTLs reduced by however long it has been cached.
+    pub fn get(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (key, _) = parse_question(query)?;
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        let entry = entries.get(&key)?;
+        if entry.expires <= now {
+            entries.remove(&key);
+            return None;
+        }
+        let age = now.saturating_duration_since(entry.stored).as_secs() as u32;
+        let mut response = entry.response.clone();
+        response[..2].copy_from_slice(&query[..2]);
+        for &offset in entry.ttl_offsets.iter() {
+            let ttl = read_u32(&response, offset)?.saturating_sub(age);
+            response[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        Some(response)
+    }
+
+    /// Caches a response. Only successful responses with at least one answer are cached, for as long as the shortest TTL among the answers.
+    pub fn insert(&self, response: &[u8]) {
+        let (key, min_ttl, ttl_offsets) = match parse_response(response) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        if min_ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                let victim = entries.keys().next().cloned();
+                if let Some(victim) = victim {
+                    entries.remove(&victim);
+                }
+            }
+        }
+        entries.insert(
+            key,
+            CachedResponse {
+                response: response.to_vec(),
+                ttl_offsets,
+                stored: now,
+                expires: now + Duration::from_secs(min_ttl as u64),
+            },
+        );
+    }
+}
+
+/// Answers the DNS queries arriving on a socket, from the cache if possible and otherwise with the given resolver, which returns the response to a query. At most `max_inflight` queries are resolved at once; queries beyond that are dropped rather than queued, since clients retry them anyway.
+pub async fn serve_dns<F, Fut>(
+    socket: &smol::net::UdpSocket,
+    cache: &DnsCache,
+    max_inflight: usize,
+    resolve: F,
+) -> anyhow::Result<()>
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: Future<Output = Option<Vec<u8>>> + Send,
+{
+    let inflight = async_lock::Semaphore::new(max_inflight);
+    let scope = smol::Executor::new();
+    let mut buf = [0; 2048];
+    scope
+        .run(async {
+            loop {
+                let (n, c_addr) = socket.recv_from(&mut buf).await?;
+                let query = buf[..n].to_vec();
+                if let Some(cached) = cache.get(&query) {
+                    log::debug!("DNS request answered from cache");
+                    drop(socket.send_to(&cached, c_addr).await);
+                    continue;
+                }
+                let permit = match inflight.try_acquire() {
+                    Some(permit) => permit,
+                    None => {
+                        log::debug!("too many DNS requests in flight, dropping one");
+                        continue;
+                    }
+                };
+                let response = resolve(query);
+                scope
+                    .spawn(async move {
+                        if let Some(response) = response.await {
+                            cache.insert(&response);
+                            drop(socket.send_to(&response, c_addr).await);
+                        }
+                        drop(permit);
+                    })
+                    .detach();
+            }
+        })
+        .await
+}
+
+/// How long opening a stream to the DNS-over-HTTPS resolver and doing the TLS handshake over it may take. That's several round trips through the tunnel, so it gets much longer than a single query, and the connection is kept for the queries after.
+const DOH_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type DohConn =
+    async_dup::Arc<async_dup::Mutex<async_tls::client::TlsStream<sosistab::mux::RelConn>>>;
+
+/// A DNS-over-HTTPS client, as described in RFC 8484, that reaches its resolver through the tunnel. Idle connections are kept around for reuse.
+pub struct DohClient {
+    url: http_types::Url,
+    send_idle: Sender<DohConn>,
+    recv_idle: Receiver<DohConn>,
+}
+
+impl DohClient {
+    /// Creates a client for the given endpoint, such as https://cloudflare-dns.com/dns-query.
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let url = http_types::Url::parse(url)?;
+        if url.scheme() != "https" {
+            anyhow::bail!(
+                "DNS-over-HTTPS endpoint must be https, not {}",
+                url.scheme()
+            )
+        }
+        if url.host_str().is_none() {
+            anyhow::bail!("DNS-over-HTTPS endpoint has no host")
+        }
+        let (send_idle, recv_idle) = smol::channel::unbounded();
+        Ok(DohClient {
+            url,
+            send_idle,
+            recv_idle,
+        })
+    }
+
+    /// Resolves a query in DNS wire format, returning the response in wire format. The timeout only covers the query itself, not connecting to the resolver when there's no idle connection.
+    pub async fn query(
+        &self,
+        keepalive: &Keepalive,
+        query: &[u8],
+        timeout: Duration,
+    ) -> anyhow::Result<Vec<u8>> {
+        let conn = match self.recv_idle.try_recv() {
+            Ok(conn) => conn,
+            Err(_) => self.connect(keepalive).await?,
+        };
+        let mut req = http_types::Request::post(self.url.clone());
+        req.insert_header("Content-Type", "application/dns-message");
+        req.insert_header("Accept", "application/dns-message");
+        req.set_body(query.to_vec());
+        let response = async {
+            let mut res = async_h1::connect(conn.clone(), req)
+                .await
+                .map_err(|err| err.into_inner())?;
+            if !res.status().is_success() {
+                anyhow::bail!("DNS-over-HTTPS resolver answered {}", res.status())
+            }
+            res.body_bytes().await.map_err(|err| err.into_inner())
+        }
+        .timeout(timeout)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("DNS-over-HTTPS query timed out"))??;
+        // the response was read to the end, so the connection can take another query
+        drop(self.send_idle.try_send(conn));
+        Ok(response)
+    }
+
+    async fn connect(&self, keepalive: &Keepalive) -> anyhow::Result<DohConn> {
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(443);
+        async {
+            let conn = keepalive.connect(&format!("{}:{}", host, port)).await?;
+            let tls = async_tls::TlsConnector::default()
+                .connect(host, conn)
+                .await?;
+            Ok::<_, anyhow::Error>(async_dup::Arc::new(async_dup::Mutex::new(tls)))
+        }
+        .timeout(DOH_CONNECT_TIMEOUT)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("connecting to DNS-over-HTTPS resolver timed out"))?
+    }
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Option<u32> {
+    let bytes = msg.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Parses the one and only question of a message, returning its lowercased name and type, and where the question ends.
+fn parse_question(msg: &[u8]) -> Option<((String, u16), usize)> {
+    if read_u16(msg, 4)? != 1 {
+        return None;
+    }
+    let mut pos = 12;
+    let mut labels = Vec::new();
+    loop {
+        let len = *msg.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // compression never points into the question itself
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(msg.get(pos..pos + len)?).to_lowercase());
+        pos += len;
+    }
+    let qtype = read_u16(msg, pos)?;
+    // the class is skipped, since everything is IN anyway
+    read_u16(msg, pos + 2)?;
+    Some(((labels.join("."), qtype), pos + 4))
+}
+
+/// Skips over a possibly compressed name, returning where it ends.
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            // a pointer ends the name
+            msg.get(pos + 1)?;
+            return Some(pos + 2);
+        }
+        pos += 1 + len;
+    }
+}
+
+/// Parses a response, returning the question it answers, the shortest TTL among its answers, and where every TTL field is.
+fn parse_response(msg: &[u8]) -> Option<((String, u16), u32, Vec<usize>)> {
+    let flags = read_u16(msg, 2)?;
+    // must be a response, not truncated, and with no error
+    if flags & 0x8000 == 0 || flags & 0x0200 != 0 || flags & 0x000f != 0 {
+        return None;
+    }
+    let answers = read_u16(msg, 6)? as usize;
+    if answers == 0 {
+        return None;
+    }
+    let records = answers + read_u16(msg, 8)? as usize + read_u16(msg, 10)? as usize;
+    let (key, mut pos) = parse_question(msg)?;
+    let mut min_ttl = u32::MAX;
+    let mut ttl_offsets = Vec::with_capacity(records);
+    for i in 0..records {
+        pos = skip_name(msg, pos)?;
+        let rtype = read_u16(msg, pos)?;
+        let ttl = read_u32(msg, pos + 4)?;
+        let rdlength = read_u16(msg, pos + 8)? as usize;
+        if rtype != TYPE_OPT {
+            ttl_offsets.push(pos + 4);
+        }
+        if i < answers {
+            min_ttl = min_ttl.min(ttl);
+        }
+        pos += 10 + rdlength;
+        if pos > msg.len() {
+            return None;
+        }
+    }
+    Some((key, min_ttl, ttl_offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::prelude::*;
+
+    fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut msg = id.to_be_bytes().to_vec();
+        msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&[0, 1]);
+        msg
+    }
+
+    /// A response to the query, with one A record per TTL, all pointing back at the question name.
+    fn response(query: &[u8], ttls: &[u32]) -> Vec<u8> {
+        let mut msg = query.to_vec();
+        msg[2] = 0x81;
+        msg[3] = 0x80;
+        msg[7] = ttls.len() as u8;
+        for &ttl in ttls {
+            msg.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+            msg.extend_from_slice(&ttl.to_be_bytes());
+            msg.extend_from_slice(&[0, 4, 93, 184, 216, 34]);
+        }
+        msg
+    }
+
+    #[test]
+    fn answers_from_cache() {
+        let cache = DnsCache::default();
+        let first = query(1, "Example.com", 1);
+        assert_eq!(cache.get(&first), None);
+        cache.insert(&response(&first, &[300, 60]));
+        // a later query with a different ID and case gets the cached response, with its own ID
+        let second = query(2, "example.COM", 1);
+        let cached = cache.get(&second).unwrap();
+        assert_eq!(&cached[..2], &[0, 2]);
+        assert_eq!(&cached[2..], &response(&first, &[300, 60])[2..]);
+        // other types are separate
+        assert_eq!(cache.get(&query(3, "example.com", 28)), None);
+    }
+
+    #[test]
+    fn honors_ttl() {
+        let cache = DnsCache::default();
+        let q = query(1, "example.com", 1);
+        cache.insert(&response(&q, &[0, 300]));
+        assert_eq!(cache.get(&q), None);
+        cache.insert(&response(&q, &[300]));
+        // pretend the response has been cached for a while
+        {
+            let mut entries = cache.entries.lock();
+            let entry = entries.values_mut().next().unwrap();
+            entry.stored -= Duration::from_secs(100);
+            entry.expires -= Duration::from_secs(100);
+        }
+        let cached = cache.get(&q).unwrap();
+        assert_eq!(read_u32(&cached, cached.len() - 10), Some(200));
+        cache.entries.lock().values_mut().next().unwrap().expires = Instant::now();
+        assert_eq!(cache.get(&q), None);
+    }
+
+    #[test]
+    fn excess_queries_are_dropped() {
+        smol::block_on(async {
+            let server = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let client = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(server.local_addr().unwrap()).await.unwrap();
+            let cache = DnsCache::default();
+            let (send_release, recv_release) = smol::channel::unbounded::<()>();
+            let started = std::sync::atomic::AtomicUsize::new(0);
+            let started = &started;
+            let recv_release = &recv_release;
+            let serve = serve_dns(&server, &cache, 3, |query| async move {
+                started.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                recv_release.recv().await.ok()?;
+                Some(response(&query, &[0]))
+            });
+            let test = async {
+                for id in 0..10 {
+                    client.send(&query(id, "example.com", 1)).await.unwrap();
+                }
+                smol::Timer::after(Duration::from_millis(100)).await;
+                // only as many as the cap are being resolved, and the rest are gone
+                assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 3);
+                for _ in 0..10 {
+                    send_release.send(()).await.unwrap();
+                }
+                let mut buf = [0; 512];
+                for _ in 0..3 {
+                    client.recv(&mut buf).await.unwrap();
+                }
+                assert!(client
+                    .recv(&mut buf)
+                    .timeout(Duration::from_millis(100))
+                    .await
+                    .is_none());
+                // with the earlier queries done, new ones get through again
+                client.send(&query(10, "example.com", 1)).await.unwrap();
+                client.recv(&mut buf).await.unwrap();
+                assert_eq!(started.load(std::sync::atomic::Ordering::SeqCst), 4);
+            };
+            serve
+                .or(async {
+                    test.await;
+                    Ok(())
+                })
+                .await
+                .unwrap();
+        })
+    }
+
+    #[test]
+    fn doh_endpoints_are_checked() {
+        assert!(DohClient::new("https://cloudflare-dns.com/dns-query").is_ok());
+        assert!(DohClient::new("http://cloudflare-dns.com/dns-query").is_err());
+        assert!(DohClient::new("cloudflare-dns.com").is_err());
+    }
+
+    #[test]
+    fn skips_uncacheable_responses() {
+        let cache = DnsCache::default();
+        let q = query(1, "example.com", 1);
+        // no answers
+        cache.insert(&response(&q, &[]));
+        // NXDOMAIN
+        let mut nxdomain = response(&q, &[300]);
+        nxdomain[3] |= 3;
+        cache.insert(&nxdomain);
+        // truncated in the middle of a record
+        let full = response(&q, &[300]);
+        cache.insert(&full[..full.len() - 2]);
+        assert_eq!(cache.get(&q), None);
+    }
+}
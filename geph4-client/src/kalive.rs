@@ -1,9 +1,14 @@
 use crate::cache::ClientCache;
-use crate::stats::StatCollector;
+use crate::stats::{
+    facade, ConnectError, ConnectionPhase, FailureKind, KeepaliveEvent, StatCollector,
+};
 use anyhow::Context;
+use async_trait::async_trait;
+use parking_lot::{Mutex, RwLock};
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
+use std::collections::HashMap;
 use std::time::Duration;
 use std::{sync::Arc, time::Instant};
 
@@ -11,33 +16,141 @@ use std::{sync::Arc, time::Instant};
 pub struct Keepalive {
     open_socks5_conn: Sender<(String, Sender<sosistab::mux::RelConn>)>,
     get_stats: Sender<Sender<sosistab::SessionStats>>,
+    udp: Arc<UdpRouter>,
+    stats: Arc<StatCollector>,
+    recv_fatal: Receiver<anyhow::Error>,
+    send_shutdown: Sender<()>,
+    recv_stopped: Receiver<()>,
+    use_bridges: bool,
+    transport: Arc<Mutex<Transport>>,
+    send_reconnect: Sender<()>,
     _task: smol::Task<anyhow::Result<()>>,
 }
 
+/// Routes the datagrams of UDP associations to and from the unreliable channel of whatever session is current, with one flow per association. Every datagram is a bincode-encoded (address, payload) pair, where the address is the destination on the way out and the source on the way back.
+struct UdpRouter {
+    associations: Mutex<HashMap<u64, Sender<(String, Vec<u8>)>>>,
+    send_outgoing: Sender<(u64, Vec<u8>)>,
+    recv_outgoing: Receiver<(u64, Vec<u8>)>,
+}
+
+impl UdpRouter {
+    fn new() -> Self {
+        let (send_outgoing, recv_outgoing) = smol::channel::bounded(100);
+        UdpRouter {
+            associations: Mutex::new(HashMap::new()),
+            send_outgoing,
+            recv_outgoing,
+        }
+    }
+
+    /// Hands an incoming datagram to its association, if it's still around.
+    fn dispatch(&self, assoc_id: u64, msg: &[u8]) {
+        if let Ok((source, payload)) = bincode::deserialize::<(String, Vec<u8>)>(msg) {
+            if let Some(send) = self.associations.lock().get(&assoc_id) {
+                // like UDP itself, drop rather than wait
+                let _ = send.try_send((source, payload));
+            }
+        }
+    }
+}
+
+/// A UDP association tunneled through the exit. Just like plain UDP, datagrams may be lost.
+pub struct UdpAssociation {
+    id: u64,
+    router: Arc<UdpRouter>,
+    incoming: Receiver<(String, Vec<u8>)>,
+}
+
+impl UdpAssociation {
+    /// Sends a datagram to the given remote address, which is resolved by the exit.
+    pub fn send_to(&self, remote: &str, payload: &[u8]) {
+        let msg = bincode::serialize(&(remote, payload)).unwrap();
+        let _ = self.router.send_outgoing.try_send((self.id, msg));
+    }
+
+    /// Waits for the next datagram, along with the address it came from.
+    pub async fn recv_from(&self) -> anyhow::Result<(String, Vec<u8>)> {
+        Ok(self.incoming.recv().await?)
+    }
+}
+
+impl Drop for UdpAssociation {
+    fn drop(&mut self) {
+        self.router.associations.lock().remove(&self.id);
+    }
+}
+
 impl Keepalive {
-    /// Creates a new keepalive.
+    /// Creates a new keepalive. In one-shot mode, the session is never restarted, so the first failure is final.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         stats: Arc<StatCollector>,
-        exit_host: &str,
+        selection: ExitSelection,
         use_bridges: bool,
+        ranking: BridgeRanking,
+        transport: Transport,
+        one_shot: bool,
+        backoff: ReconnectBackoff,
+        pin_exit: Option<Duration>,
+        bond: usize,
+        standby: bool,
         ccache: Arc<ClientCache>,
+        authenticator: Arc<dyn Authenticator>,
     ) -> Self {
         let (send, recv) = smol::channel::unbounded();
         let (send_stats, recv_stats) = smol::channel::unbounded();
+        let (send_fatal, recv_fatal) = smol::channel::bounded(1);
+        let (send_shutdown, recv_shutdown) = smol::channel::bounded(1);
+        // never sent to, but closed once the actor is gone
+        let (send_stopped, recv_stopped) = smol::channel::bounded(1);
+        let (send_reconnect, recv_reconnect) = smol::channel::bounded(1);
+        let transport = Arc::new(Mutex::new(transport));
+        let udp = Arc::new(UdpRouter::new());
         Keepalive {
             open_socks5_conn: send,
             get_stats: send_stats,
+            udp: udp.clone(),
+            stats: stats.clone(),
+            recv_fatal,
+            send_shutdown,
+            recv_stopped,
+            use_bridges,
+            transport: transport.clone(),
+            send_reconnect,
             _task: smolscale::spawn(keepalive_actor(
                 stats,
-                exit_host.to_string(),
+                selection,
                 use_bridges,
+                ranking,
+                transport,
+                recv_reconnect,
+                one_shot,
+                backoff,
+                pin_exit,
+                bond,
+                standby,
                 ccache,
+                authenticator,
                 recv,
                 recv_stats,
+                udp,
+                send_fatal,
+                recv_shutdown,
+                send_stopped,
             )),
         }
     }
 
+    /// Waits for the session to fail for good, returning why. This only ever happens in one-shot mode.
+    pub async fn wait_fatal(&self) -> ConnectError {
+        self.recv_fatal
+            .recv()
+            .await
+            .unwrap_or_else(|_| anyhow::anyhow!("keepalive stopped"))
+            .into()
+    }
+
     /// Opens a connection
     pub async fn connect(&self, remote: &str) -> anyhow::Result<sosistab::mux::RelConn> {
         let (send, recv) = smol::channel::bounded(1);
@@ -47,6 +160,53 @@ impl Keepalive {
         Ok(recv.recv().await?)
     }
 
+    /// Shuts down the tunnel. New connections are refused right away, while those already open get some time to be closed. Resolves once the session is gone.
+    pub async fn shutdown(&self) {
+        let _ = self.send_shutdown.try_send(());
+        let _ = self.recv_stopped.recv().await;
+    }
+
+    /// Whether sessions go through bridges.
+    pub fn use_bridges(&self) -> bool {
+        self.use_bridges
+    }
+
+    /// Gets the transport sessions are made over.
+    pub fn transport(&self) -> Transport {
+        *self.transport.lock()
+    }
+
+    /// Switches to another transport, replacing the current session with one over the new transport. Open streams don't survive the switch.
+    pub fn set_transport(&self, transport: Transport) {
+        let old = std::mem::replace(&mut *self.transport.lock(), transport);
+        if old != transport {
+            log::info!("switching transport from {} to {}", old, transport);
+            let _ = self.send_reconnect.try_send(());
+        }
+    }
+
+    /// Subscribes to events about the session: connecting, being connected, reconnecting, and switching exits.
+    pub fn events(&self) -> Receiver<KeepaliveEvent> {
+        self.stats.subscribe()
+    }
+
+    /// Gets how far along establishing the current session is.
+    pub fn connection_phase(&self) -> ConnectionPhase {
+        self.stats.connection_phase()
+    }
+
+    /// Starts a new UDP association
+    pub fn udp_associate(&self) -> UdpAssociation {
+        let id = rand::random();
+        let (send, recv) = smol::channel::bounded(100);
+        self.udp.associations.lock().insert(id, send);
+        UdpAssociation {
+            id,
+            router: self.udp.clone(),
+            incoming: recv,
+        }
+    }
+
     /// Gets session statistics
     pub async fn get_stats(&self) -> anyhow::Result<sosistab::SessionStats> {
         let (send, recv) = smol::channel::bounded(1);
@@ -55,184 +215,463 @@ impl Keepalive {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn keepalive_actor(
     stats: Arc<StatCollector>,
-    exit_host: String,
+    selection: ExitSelection,
     use_bridges: bool,
+    ranking: BridgeRanking,
+    transport: Arc<Mutex<Transport>>,
+    recv_reconnect: Receiver<()>,
+    one_shot: bool,
+    backoff: ReconnectBackoff,
+    pin_exit: Option<Duration>,
+    bond: usize,
+    standby: bool,
     ccache: Arc<ClientCache>,
+    authenticator: Arc<dyn Authenticator>,
     recv_socks5_conn: Receiver<(String, Sender<sosistab::mux::RelConn>)>,
     recv_get_stats: Receiver<Sender<sosistab::SessionStats>>,
+    udp: Arc<UdpRouter>,
+    send_fatal: Sender<anyhow::Error>,
+    recv_shutdown: Receiver<()>,
+    _send_stopped: Sender<()>,
 ) -> anyhow::Result<()> {
+    // failures in a row, not counting those after sessions that stayed up for a while
+    let mut failures = 0u32;
+    let mut pin = pin_exit.map(ExitPin::new);
+    // a session to another exit, kept up to take over when the primary one fails
+    let spare = Mutex::new(None);
+    let mut warm = None;
     loop {
-        if let Err(err) = keepalive_actor_once(
+        let selection = match &pin {
+            Some(pin) => pin.selection(&selection),
+            None => selection.clone(),
+        };
+        let (exit, ended) = keepalive_actor_once(
             stats.clone(),
-            exit_host.clone(),
+            selection,
             use_bridges,
+            ranking,
+            *transport.lock(),
+            bond,
+            standby,
+            &spare,
+            warm.take(),
             ccache.clone(),
+            authenticator.clone(),
             recv_socks5_conn.clone(),
             recv_get_stats.clone(),
+            udp.clone(),
+            recv_shutdown.clone(),
+            recv_reconnect.clone(),
         )
-        .await
-        {
-            log::warn!("keepalive_actor restarting: {}", err);
-            smol::Timer::after(Duration::from_secs(1)).await;
+        .await;
+        if let Some(pin) = pin.as_mut() {
+            match exit {
+                Some(exit) => pin.connected(exit),
+                None if ended.is_err() => pin.failed_at(Instant::now()),
+                None => (),
+            }
+        }
+        match ended {
+            Ok(SessionEnd::Shutdown) => {
+                log::info!("keepalive_actor shut down");
+                return Ok(());
+            }
+            Ok(SessionEnd::Reconnect) => {
+                log::info!("keepalive_actor reconnecting over {}", transport.lock());
+                stats.emit(KeepaliveEvent::Reconnecting {
+                    reason: "reconnect requested".into(),
+                });
+                // the standby uses the old transport too
+                *spare.lock() = None;
+            }
+            Err(err) => {
+                if one_shot {
+                    log::error!("keepalive_actor giving up in one-shot mode: {}", err);
+                    drop(send_fatal.send(err).await);
+                    return Ok(());
+                }
+                if let Some(spare) = spare.lock().take() {
+                    log::warn!(
+                        "keepalive_actor failing over to {}: {}",
+                        spare.1.hostname,
+                        err
+                    );
+                    stats.incr_failovers();
+                    stats.emit(KeepaliveEvent::Reconnecting {
+                        reason: err.to_string(),
+                    });
+                    warm = Some(spare);
+                    continue;
+                }
+                if stats
+                    .connected_for()
+                    .map(|up| up >= backoff.stable_after)
+                    .unwrap_or(false)
+                {
+                    failures = 0;
+                }
+                failures = failures.saturating_add(1);
+                let delay = sosistab::runtime::jitter(backoff.delay(failures), backoff.jitter);
+                log::warn!("keepalive_actor restarting in {:?}: {}", delay, err);
+                stats.emit(KeepaliveEvent::Reconnecting {
+                    reason: err.to_string(),
+                });
+                stats.set_reconnect_at(Some(Instant::now() + delay));
+                let shutdown = async {
+                    let _ = recv_shutdown.recv().await;
+                    true
+                };
+                let retry = async {
+                    smol::Timer::after(delay).await;
+                    false
+                };
+                let shutting_down = shutdown.or(retry).await;
+                stats.set_reconnect_at(None);
+                if shutting_down {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// How long the keepalive waits before replacing a failed session. The delay doubles with every failure in a row, up to a cap, and is jittered so that many clients don't all retry at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectBackoff {
+    /// Delay after the first failure.
+    pub base: Duration,
+    /// Longest delay, however many failures there have been.
+    pub max: Duration,
+    /// Fraction by which every delay is randomly lengthened or shortened.
+    pub jitter: f64,
+    /// How long a session has to stay up for the next failure to count as the first again.
+    pub stable_after: Duration,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        ReconnectBackoff {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            jitter: 0.2,
+            stable_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    /// The delay after the given number of failures in a row, counting from one, before jitter.
+    fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.base
+            .checked_mul(1 << doublings)
+            .unwrap_or(self.max)
+            .min(self.max)
+    }
+}
+
+/// How long a shutdown waits for open streams to be closed.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long opening a stream through the exit may take before the session is considered broken.
+const CONN_OPEN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long a session may go without hearing anything from the exit before the watchdog gives up on it.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Whether the exit behind the given session has been heard from recently. A session that has been quiet for a while is nudged by opening a stream, which exits that predate heartbeats answer too.
+async fn check_alive(mux: &sosistab::mux::Multiplex) -> bool {
+    if mux.last_pong_age() > WATCHDOG_TIMEOUT / 2 {
+        drop(mux.open_conn_with_timeout(None, CONN_OPEN_TIMEOUT).await);
+    }
+    mux.last_pong_age() <= WATCHDOG_TIMEOUT
+}
+
+/// Waits for every stream of the given multiplexes to be gone, or for the drain timeout.
+async fn drain(muxes: &[sosistab::mux::Multiplex]) {
+    let drained = async {
+        loop {
+            let mut open_streams = 0;
+            for mux in muxes {
+                open_streams += mux.stats().await.map(|s| s.open_streams).unwrap_or(0);
+            }
+            if open_streams == 0 {
+                break;
+            }
+            smol::Timer::after(Duration::from_millis(100)).await;
         }
+    };
+    if drained.timeout(DRAIN_TIMEOUT).await.is_none() {
+        log::warn!(
+            "streams still open after {:?}, shutting down anyway",
+            DRAIN_TIMEOUT
+        );
     }
 }
 
+/// Why a session ended without failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SessionEnd {
+    /// A shutdown was asked for.
+    Shutdown,
+    /// The session is to be replaced right away, for example because the transport changed.
+    Reconnect,
+}
+
+/// Runs a single session until it fails, or until it's asked to end. Returns the exit the session went to, if it got as far as connecting, along with how it ended.
+#[allow(clippy::too_many_arguments)]
 async fn keepalive_actor_once(
     stats: Arc<StatCollector>,
-    exit_host: String,
+    selection: ExitSelection,
     use_bridges: bool,
+    ranking: BridgeRanking,
+    transport: Transport,
+    bond: usize,
+    standby: bool,
+    spare: &Mutex<Option<Spare>>,
+    warm: Option<Spare>,
     ccache: Arc<ClientCache>,
+    authenticator: Arc<dyn Authenticator>,
     recv_socks5_conn: Receiver<(String, Sender<sosistab::mux::RelConn>)>,
     recv_get_stats: Receiver<Sender<sosistab::SessionStats>>,
-) -> anyhow::Result<()> {
+    udp: Arc<UdpRouter>,
+    recv_shutdown: Receiver<()>,
+    recv_reconnect: Receiver<()>,
+) -> (
+    Option<binder_transport::ExitDescriptor>,
+    anyhow::Result<SessionEnd>,
+) {
     stats.set_exit_descriptor(None);
-
-    // find the exit
-    let mut exits = ccache.get_exits().await.context("can't get exits")?;
-    if exits.is_empty() {
-        anyhow::bail!("no exits found")
+    if warm.is_none() {
+        stats.emit(KeepaliveEvent::Connecting);
     }
-    exits.sort_by(|a, b| {
-        strsim::damerau_levenshtein(&a.hostname, &exit_host)
-            .cmp(&strsim::damerau_levenshtein(&b.hostname, &exit_host))
-    });
-    let exit_host = exits[0].hostname.clone();
-
-    let bridge_sess_async = async {
-        let bridges = ccache
-            .get_bridges(&exit_host)
-            .await
-            .context("can't get bridges")?;
-        log::debug!("got {} bridges", bridges.len());
-        if bridges.is_empty() {
-            anyhow::bail!("absolutely no bridges found")
+    let connecting = async {
+        match warm {
+            Some(warm) => Ok(Ok(warm)),
+            None => Ok(connect_authed(
+                &selection,
+                use_bridges,
+                ranking,
+                transport,
+                &ccache,
+                authenticator.as_ref(),
+                &|phase| stats.set_connection_phase(phase),
+            )
+            .await),
         }
-        // spawn a task for *every* bridge
-        let (send, recv) = smol::channel::unbounded();
-        let _tasks: Vec<_> = bridges
-            .into_iter()
-            .map(|desc| {
-                let send = send.clone();
-                smolscale::spawn(async move {
-                    log::debug!("connecting through {}...", desc.endpoint);
-                    drop(
-                        send.send((
-                            desc.endpoint,
-                            sosistab::connect(desc.endpoint, desc.sosistab_key).await,
-                        ))
-                        .await,
-                    )
-                })
+    };
+    let shutdown = async {
+        let _ = recv_shutdown.recv().await;
+        Err(SessionEnd::Shutdown)
+    };
+    let reconnect = async {
+        let _ = recv_reconnect.recv().await;
+        Err(SessionEnd::Reconnect)
+    };
+    let (mux, exit_info) = match connecting.or(shutdown).or(reconnect).await {
+        Err(end) => return (None, Ok(end)),
+        Ok(Ok(v)) => v,
+        Ok(Err(err)) => {
+            stats.record_failure(FailureKind::of(&err));
+            return (None, Err(err));
+        }
+    };
+    let (send_stop, recv_stop) = smol::channel::unbounded();
+    // bonded sessions go to the same exit, over the same transport
+    let same_exit = ExitSelection::Pinned(Box::new(exit_info.clone()));
+    let primary = exit_info.clone();
+    let no_phase = |_: ConnectionPhase| ();
+    let scope = smol::Executor::new();
+    let mut muxes = vec![mux];
+    if bond > 1 {
+        let extras: Vec<_> = (1..bond)
+            .map(|_| {
+                scope.spawn(connect_authed(
+                    &same_exit,
+                    use_bridges,
+                    ranking,
+                    transport,
+                    &ccache,
+                    authenticator.as_ref(),
+                    &no_phase,
+                ))
             })
             .collect();
-        // wait for a successful result
-        loop {
-            let (saddr, res) = recv.recv().await.context("ran out of bridges")?;
-            if let Ok(res) = res {
-                log::info!("{} is our fastest bridge", saddr);
-                break Ok(res);
+        for extra in extras {
+            match scope.run(extra).await {
+                Ok((mux, _)) => muxes.push(mux),
+                Err(err) => log::warn!("couldn't establish a bonded session: {:?}", err),
             }
         }
+        log::info!("bonded {} of {} sessions", muxes.len(), bond);
+    }
+    let muxes = muxes;
+    // indices of the bonded sessions still alive, since one dying only takes itself out of the bond
+    let live = RwLock::new((0..muxes.len()).collect::<Vec<_>>());
+    let live_muxes = || {
+        live.read()
+            .iter()
+            .map(|&i| (i, muxes[i].clone()))
+            .collect::<Vec<_>>()
     };
-    let exit_info = exits.iter().find(|v| v.hostname == exit_host).unwrap();
-    let connected_sess_async = async {
-        if use_bridges {
-            bridge_sess_async.await
+    let drop_member = |i: usize| {
+        let mut live = live.write();
+        if !live.contains(&i) {
+            return;
+        }
+        live.retain(|&j| j != i);
+        if live.is_empty() {
+            let _ = send_stop.try_send(anyhow::anyhow!("every bonded session died"));
         } else {
-            async {
-                Ok(infal(
-                    sosistab::connect(
-                        smol::net::resolve(format!("{}:19831", exit_info.hostname))
-                            .await
-                            .context("can't resolve hostname of exit")?[0],
-                        exit_info.sosistab_key,
-                    )
-                    .await,
-                )
-                .await)
-            }
-            .or(async {
-                smol::Timer::after(Duration::from_secs(5)).await;
-                log::warn!("turning on bridges because we couldn't get a direct connection");
-                bridge_sess_async.await
-            })
-            .await
+            log::warn!("dropped bonded session {}, {} left", i, live.len());
         }
     };
-    let session: anyhow::Result<sosistab::Session> = connected_sess_async
-        .or(async {
-            smol::Timer::after(Duration::from_secs(10)).await;
-            anyhow::bail!("initial connection timeout after 10");
-        })
-        .await;
-    let session = session?;
-    let mux = sosistab::mux::Multiplex::new(session);
-    let (send_stop, recv_stop) = smol::channel::unbounded();
-    let scope = smol::Executor::new();
-    // now let's authenticate
-    let token = ccache.get_auth_token().await?;
-    authenticate_session(&mux, &token)
-        .timeout(Duration::from_secs(5))
-        .await
-        .ok_or_else(|| anyhow::anyhow!("authentication timed out"))??;
-    // TODO actually authenticate
     log::info!(
-        "KEEPALIVE MAIN LOOP for exit_host={}, use_bridges={}",
-        exit_host,
-        use_bridges
+        "KEEPALIVE MAIN LOOP for exit_host={}, use_bridges={}, transport={}",
+        exit_info.hostname,
+        use_bridges,
+        transport
     );
-    stats.set_exit_descriptor(Some(exits[0].clone()));
+    stats.set_exit_descriptor(Some(exit_info));
+    stats.set_connection_phase(ConnectionPhase::Connected);
     scope
         .spawn(async {
             loop {
-                smol::Timer::after(Duration::from_secs(200)).await;
-                if mux
-                    .open_conn(None)
-                    .timeout(Duration::from_secs(15))
-                    .await
-                    .is_none()
-                {
-                    let _ = send_stop.send(anyhow::anyhow!("watchdog timed out")).await;
+                smol::Timer::after(sosistab::runtime::jitter(Duration::from_secs(10), 0.1)).await;
+                // every session is checked at once, so that one quiet session doesn't hold up the rest
+                let checks: Vec<_> = live_muxes()
+                    .into_iter()
+                    .map(|(i, mux)| (i, smolscale::spawn(async move { check_alive(&mux).await })))
+                    .collect();
+                for (i, check) in checks {
+                    if !check.await {
+                        log::warn!("watchdog timed out on bonded session {}", i);
+                        drop_member(i);
+                    }
+                }
+                let alive: Vec<_> = live_muxes().into_iter().map(|(_, mux)| mux).collect();
+                if alive.is_empty() {
                     return;
                 }
+                stats.observe_session(&bonded_stats(&alive).await);
             }
         })
         .detach();
     scope
+        .spawn(async {
+            while let Some(left) = authenticator.expires_in() {
+                smol::Timer::after(left.saturating_sub(REAUTH_MARGIN)).await;
+                let mut unsupported = false;
+                let renewed: anyhow::Result<()> = async {
+                    authenticator.refresh().await?;
+                    for (_, mux) in live_muxes() {
+                        if let Err(err) = reauthenticate_session(&mux, authenticator.as_ref()).await
+                        {
+                            unsupported = reauth_unsupported(&mux, &err).await;
+                            return Err(err);
+                        }
+                    }
+                    Ok(())
+                }
+                .await;
+                match renewed {
+                    Ok(()) => log::info!("renewed the credentials of the session"),
+                    Err(_) if unsupported => {
+                        log::info!("the exit can't renew credentials; keeping the session as is");
+                        return;
+                    }
+                    Err(err) => {
+                        log::warn!("couldn't renew the credentials of the session: {:?}", err);
+                        stats.emit(KeepaliveEvent::AuthRefreshFailed {
+                            reason: err.to_string(),
+                        });
+                        smol::Timer::after(REAUTH_RETRY).await;
+                    }
+                }
+            }
+        })
+        .detach();
+    if standby {
+        scope
+            .spawn(async {
+                loop {
+                    let spare_mux = spare.lock().as_ref().map(|(mux, _)| mux.clone());
+                    let stale = match spare_mux {
+                        Some(mux) => Some(!check_alive(&mux).await),
+                        None => None,
+                    };
+                    match stale {
+                        Some(false) => (),
+                        Some(true) => {
+                            log::warn!("standby session went stale");
+                            *spare.lock() = None;
+                        }
+                        None => match connect_standby(
+                            &primary,
+                            use_bridges,
+                            ranking,
+                            transport,
+                            &ccache,
+                            authenticator.as_ref(),
+                        )
+                        .await
+                        {
+                            Ok(standby) => {
+                                log::info!("standby session to {} is up", standby.1.hostname);
+                                *spare.lock() = Some(standby);
+                            }
+                            Err(err) => log::warn!("couldn't set up a standby session: {:?}", err),
+                        },
+                    }
+                    smol::Timer::after(sosistab::runtime::jitter(Duration::from_secs(30), 0.1))
+                        .await;
+                }
+            })
+            .detach();
+    }
+    let ended = scope
         .run(
             async {
+                // new streams take turns between the bonded sessions
+                let mut next_mux = 0;
                 loop {
                     let (conn_host, conn_reply) = recv_socks5_conn
                         .recv()
                         .await
                         .context("cannot get socks5 connect request")?;
-                    let mux = &mux;
+                    let alive = live_muxes();
+                    if alive.is_empty() {
+                        continue;
+                    }
+                    let (i, mux) = alive[next_mux % alive.len()].clone();
+                    next_mux += 1;
                     let stats = stats.clone();
-                    let send_stop = send_stop.clone();
+                    let drop_member = &drop_member;
                     scope
                         .spawn(async move {
                             let start = Instant::now();
-                            let remote = (&mux)
-                                .open_conn(Some(conn_host))
-                                .timeout(Duration::from_secs(15))
+                            let remote = mux
+                                .open_conn_with_timeout(Some(conn_host), CONN_OPEN_TIMEOUT)
                                 .await;
-                            if let Some(remote) = remote {
-                                let remote = remote.ok()?;
-                                log::debug!(
-                                    "opened connection in {} ms",
-                                    start.elapsed().as_millis()
-                                );
-                                stats.set_latency(start.elapsed().as_secs_f64() * 1000.0);
-                                conn_reply.send(remote).await.ok()?;
-                                Some(())
-                            } else {
-                                send_stop
-                                    .try_send(anyhow::anyhow!("normal connection timed out"))
-                                    .unwrap();
-                                Some(())
+                            match remote {
+                                Ok(remote) => {
+                                    log::debug!(
+                                        "opened connection in {} ms",
+                                        start.elapsed().as_millis()
+                                    );
+                                    stats.set_latency(start.elapsed().as_secs_f64() * 1000.0);
+                                    conn_reply.send(remote).await.ok()?;
+                                    Some(())
+                                }
+                                Err(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                                    log::warn!("normal connection timed out");
+                                    drop_member(i);
+                                    Some(())
+                                }
+                                Err(_) => None,
                             }
                         })
                         .detach();
@@ -242,12 +681,713 @@ async fn keepalive_actor_once(
             .or(async {
                 loop {
                     let stat_send = recv_get_stats.recv().await?;
-                    let stats = mux.get_session().get_stats().await;
+                    let alive: Vec<_> = live_muxes().into_iter().map(|(_, mux)| mux).collect();
+                    let stats = bonded_stats(&alive).await;
                     stat_send.send(stats).await?;
                 }
+            })
+            .or(async {
+                // unreliable messages stick to the first live session, so that replies come back the way requests went
+                loop {
+                    let (assoc_id, msg) = udp.recv_outgoing.recv().await?;
+                    if let Some((i, mux)) = live_muxes().into_iter().next() {
+                        if mux.send_urel_to(assoc_id, msg.into()).await.is_err() {
+                            drop_member(i);
+                        }
+                    }
+                }
+            })
+            .or(async {
+                loop {
+                    let (i, mux) = match live_muxes().into_iter().next() {
+                        Some(first) => first,
+                        None => smol::future::pending().await,
+                    };
+                    // looks again every second, in case the first session was dropped in the meantime
+                    let received = async { Some(mux.recv_urel_flow().await) }
+                        .or(async {
+                            smol::Timer::after(Duration::from_secs(1)).await;
+                            None
+                        })
+                        .await;
+                    match received {
+                        Some(Ok((assoc_id, msg))) => udp.dispatch(assoc_id, &msg),
+                        Some(Err(_)) => drop_member(i),
+                        None => (),
+                    }
+                }
+            })
+            .or(async {
+                recv_shutdown.recv().await?;
+                log::info!("shutting down, waiting for streams to close");
+                for mux in muxes.iter() {
+                    mux.set_accepting(false);
+                }
+                drain(&muxes).await;
+                stats.set_exit_descriptor(None);
+                Ok(SessionEnd::Shutdown)
+            })
+            .or(async {
+                recv_reconnect.recv().await?;
+                Ok(SessionEnd::Reconnect)
             }),
         )
+        .await;
+    drop(scope);
+    if ended.is_err() && spare.lock().is_some() {
+        // the standby takes over, while streams on the failed sessions get a chance to finish
+        smolscale::spawn(async move {
+            for mux in muxes.iter() {
+                mux.set_accepting(false);
+            }
+            drain(&muxes).await;
+        })
+        .detach();
+    }
+    // whatever comes next, we aren't connected anymore
+    stats.set_exit_descriptor(None);
+    (Some(primary), ended)
+}
+
+/// A session kept up to take over from the primary one.
+type Spare = (sosistab::mux::Multiplex, binder_transport::ExitDescriptor);
+
+/// Connects a standby session to the fastest exit other than the primary one, preferring exits in the same country.
+async fn connect_standby(
+    primary: &binder_transport::ExitDescriptor,
+    use_bridges: bool,
+    ranking: BridgeRanking,
+    transport: Transport,
+    ccache: &ClientCache,
+    authenticator: &dyn Authenticator,
+) -> anyhow::Result<Spare> {
+    let exits = ccache
+        .get_exits()
         .await
+        .context(FailureKind::DirectoryUnreachable)?;
+    let exits = standby_candidates(exits, primary);
+    if exits.is_empty() {
+        anyhow::bail!(anyhow::anyhow!(FailureKind::NoExits).context("no exit besides the primary"))
+    }
+    let hostname = fastest_exit(&exits, use_bridges, transport, ccache)
+        .await
+        .unwrap_or_else(|| exits[0].hostname.clone());
+    let exit = exits
+        .into_iter()
+        .find(|exit| exit.hostname == hostname)
+        .context("probed exit is gone")?;
+    connect_authed(
+        &ExitSelection::Pinned(Box::new(exit)),
+        use_bridges,
+        ranking,
+        transport,
+        ccache,
+        authenticator,
+        &|_: ConnectionPhase| (),
+    )
+    .await
+}
+
+/// Exits that can host the standby session: all but the primary one, narrowed down to the primary's country if there are any there.
+fn standby_candidates(
+    mut exits: Vec<binder_transport::ExitDescriptor>,
+    primary: &binder_transport::ExitDescriptor,
+) -> Vec<binder_transport::ExitDescriptor> {
+    exits.retain(|exit| exit.hostname != primary.hostname);
+    if exits
+        .iter()
+        .any(|exit| exit.country_code == primary.country_code)
+    {
+        exits.retain(|exit| exit.country_code == primary.country_code);
+    }
+    exits
+}
+
+/// Gets the stats of all bonded sessions, combined as if they were one.
+async fn bonded_stats(muxes: &[sosistab::mux::Multiplex]) -> sosistab::SessionStats {
+    let mut all = Vec::with_capacity(muxes.len());
+    for mux in muxes {
+        all.push(mux.get_session().get_stats().await);
+    }
+    combine_stats(all)
+}
+
+/// Combines the stats of several sessions. Totals and rates add up, loss, jitter and reordering are weighted by how much each session received, and the round-trip time is averaged over the sessions that have one. The probed datagram size is the smallest one, and the seqno trace is that of the first session.
+fn combine_stats(all: Vec<sosistab::SessionStats>) -> sosistab::SessionStats {
+    let mut all = all.into_iter();
+    let mut combined = all.next().expect("no sessions to combine");
+    let weight = |stats: &sosistab::SessionStats| stats.down_total.max(1) as f64;
+    let mut total_weight = weight(&combined);
+    combined.down_loss *= total_weight;
+    combined.down_recovered_loss *= total_weight;
+    combined.down_redundant *= total_weight;
+    let mut jitter = combined.down_jitter.as_secs_f64() * total_weight;
+    combined.down_reorder_rate *= total_weight;
+    let mut rtts = vec![combined.rtt];
+    for stats in all {
+        let w = weight(&stats);
+        total_weight += w;
+        combined.down_total += stats.down_total;
+        combined.down_loss += stats.down_loss * w;
+        combined.down_recovered_loss += stats.down_recovered_loss * w;
+        combined.down_redundant += stats.down_redundant * w;
+        jitter += stats.down_jitter.as_secs_f64() * w;
+        combined.down_reorder_rate += stats.down_reorder_rate * w;
+        combined.live_decoders += stats.live_decoders;
+        combined.buffered_shard_bytes += stats.buffered_shard_bytes;
+        combined.down_duplicates += stats.down_duplicates;
+        combined.down_too_old += stats.down_too_old;
+        combined.up_frames_per_sec += stats.up_frames_per_sec;
+        combined.up_bytes_per_sec += stats.up_bytes_per_sec;
+        combined.probed_datagram_size =
+            match (combined.probed_datagram_size, stats.probed_datagram_size) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        rtts.push(stats.rtt);
+    }
+    combined.down_loss /= total_weight;
+    combined.down_recovered_loss /= total_weight;
+    combined.down_redundant /= total_weight;
+    combined.down_jitter = Duration::from_secs_f64(jitter / total_weight);
+    combined.down_reorder_rate /= total_weight;
+    rtts.retain(|rtt| *rtt > Duration::from_secs(0));
+    combined.rtt = if rtts.is_empty() {
+        Duration::from_secs(0)
+    } else {
+        rtts.iter().sum::<Duration>() / rtts.len() as u32
+    };
+    combined
+}
+
+/// How the exit to connect to is picked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitSelection {
+    /// The exit whose hostname is most similar to this one.
+    Hostname(String),
+    /// The exit that completes a handshake the quickest, directly or through its bridges, among those in the given country if there is one.
+    Fastest { country: Option<String> },
+    /// Exactly this exit, even if the binder no longer lists it, or lists it differently.
+    Pinned(Box<binder_transport::ExitDescriptor>),
+}
+
+impl ExitSelection {
+    /// Picks the fastest exit in a region, given as a country code, or in any region if it's "auto".
+    pub fn fastest_in(region: &str) -> Self {
+        if region.eq_ignore_ascii_case("auto") {
+            ExitSelection::Fastest { country: None }
+        } else {
+            ExitSelection::Fastest {
+                country: Some(region.to_string()),
+            }
+        }
+    }
+}
+
+/// Keeps a client on the first exit it connects to, so that its IP address doesn't change behind the user's back. Another exit is only picked once the pinned one has been unreachable for a while.
+struct ExitPin {
+    fallback_after: Duration,
+    exit: Option<binder_transport::ExitDescriptor>,
+    unreachable_since: Option<Instant>,
+}
+
+impl ExitPin {
+    fn new(fallback_after: Duration) -> Self {
+        ExitPin {
+            fallback_after,
+            exit: None,
+            unreachable_since: None,
+        }
+    }
+
+    /// How to pick the exit for the next session: the pinned one if there is one, otherwise as usual.
+    fn selection(&self, unpinned: &ExitSelection) -> ExitSelection {
+        match &self.exit {
+            Some(exit) => ExitSelection::Pinned(Box::new(exit.clone())),
+            None => unpinned.clone(),
+        }
+    }
+
+    /// Notes a session with the given exit, pinning it if nothing is pinned yet.
+    fn connected(&mut self, exit: binder_transport::ExitDescriptor) {
+        if self.exit.is_none() {
+            log::info!("pinning exit {}", exit.hostname);
+            self.exit = Some(exit);
+        }
+        self.unreachable_since = None;
+    }
+
+    /// Notes a failure to connect, unpinning the exit if it has been unreachable for too long.
+    fn failed_at(&mut self, now: Instant) {
+        let exit = match &self.exit {
+            Some(exit) => exit,
+            None => return,
+        };
+        let since = *self.unreachable_since.get_or_insert(now);
+        if now.saturating_duration_since(since) >= self.fallback_after {
+            log::warn!(
+                "pinned exit {} unreachable for {:?}, picking another",
+                exit.hostname,
+                self.fallback_after
+            );
+            self.exit = None;
+            self.unreachable_since = None;
+        }
+    }
+}
+
+/// What sessions with the exit are carried over. Bridges only ever forward UDP, so this only matters for direct connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Plain sosistab over UDP.
+    Udp,
+    /// Sosistab datagrams framed over TCP, for networks that block or throttle UDP.
+    Tcp,
+    /// UDP, falling back to TCP for every session whose UDP handshake goes unanswered.
+    Auto,
+}
+
+impl std::str::FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            "auto" => Ok(Transport::Auto),
+            other => anyhow::bail!("unknown transport {:?}, expected udp, tcp or auto", other),
+        }
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Udp => write!(f, "udp"),
+            Transport::Tcp => write!(f, "tcp"),
+            Transport::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// Most ClientHellos sent over UDP before [Transport::Auto] gives up on it, so that blocked UDP doesn't hold up the fallback for the whole handshake.
+const AUTO_UDP_TRIES: u32 = 3;
+
+static SOCKET_BINDING: RwLock<Option<sosistab::runtime::SocketBinding>> =
+    parking_lot::const_rwlock(None);
+
+/// Sets up the UDP sockets of every session from now on, tying them to an interface or firewall mark so that they don't get routed back into a tunnel the client itself sits behind, or marking their packets for QoS. Calling this again replaces the earlier binding.
+pub fn set_socket_binding(binding: sosistab::runtime::SocketBinding) {
+    *SOCKET_BINDING.write() = Some(binding)
+}
+
+/// Connects a session to the given address over a transport.
+async fn connect_over(
+    transport: Transport,
+    addr: std::net::SocketAddr,
+    key: x25519_dalek::PublicKey,
+    mut opts: sosistab::ConnectOptions,
+) -> std::io::Result<sosistab::Session> {
+    if let Some(binding) = SOCKET_BINDING.read().as_ref() {
+        opts.binding = binding.clone();
+    }
+    // TCP connections run their tasks wherever the session does
+    let executor = opts.executor.clone();
+    let tcp_backhaul = move || Ok(sosistab::TcpBackhaul::new_on(executor.clone()));
+    match transport {
+        Transport::Udp => sosistab::connect_with_options(addr, key, opts).await,
+        Transport::Tcp => sosistab::connect_backhaul(addr, key, tcp_backhaul, opts).await,
+        Transport::Auto => {
+            let udp_opts = sosistab::ConnectOptions {
+                max_tries: opts.max_tries.min(AUTO_UDP_TRIES),
+                ..opts.clone()
+            };
+            match sosistab::connect_with_options(addr, key, udp_opts).await {
+                Ok(session) => Ok(session),
+                Err(err) => {
+                    log::info!("UDP to {} failed ({}), falling back to TCP", addr, err);
+                    sosistab::connect_backhaul(addr, key, tcp_backhaul, opts).await
+                }
+            }
+        }
+    }
+}
+
+/// How long an exit or bridge gets to answer the handshake of a latency probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Probes every exit, or its bridges if bridges are used, returning the hostname of the exit that completed a handshake the quickest. Every probe is recorded in the connection history.
+async fn fastest_exit(
+    exits: &[binder_transport::ExitDescriptor],
+    use_bridges: bool,
+    transport: Transport,
+    ccache: &ClientCache,
+) -> Option<String> {
+    // the bridges or addresses of every exit are looked up at once
+    let scope = smol::Executor::new();
+    let lookups: Vec<_> = exits
+        .iter()
+        .map(|exit| {
+            scope.spawn(async move {
+                let mut candidates = Vec::new();
+                if use_bridges {
+                    // the bridges of other exits are fetched while probing, so failures just rule the exit out
+                    let bridges = match ccache.get_bridges(&exit.hostname).await {
+                        Ok(bridges) => bridges,
+                        Err(err) => {
+                            log::debug!("no bridges to probe for {}: {}", exit.hostname, err);
+                            return candidates;
+                        }
+                    };
+                    for bridge in bridges {
+                        candidates.push((
+                            exit.hostname.clone(),
+                            bridge.endpoint.to_string(),
+                            Transport::Udp,
+                            bridge.endpoint,
+                            bridge.sosistab_key,
+                        ));
+                    }
+                } else if let Ok(addrs) =
+                    smol::net::resolve(format!("{}:19831", exit.hostname)).await
+                {
+                    if let Some(&addr) = addrs.first() {
+                        candidates.push((
+                            exit.hostname.clone(),
+                            exit.hostname.clone(),
+                            transport,
+                            addr,
+                            exit.sosistab_key,
+                        ));
+                    }
+                }
+                candidates
+            })
+        })
+        .collect();
+    let mut candidates = Vec::new();
+    for lookup in lookups {
+        candidates.extend(scope.run(lookup).await);
+    }
+    let history = ccache.history();
+    let results = probe_all(candidates).await;
+    for (_, endpoint, latency) in results.iter() {
+        history.record_in_background(endpoint.clone(), *latency);
+    }
+    let (hostname, _, latency) = results
+        .into_iter()
+        .filter(|(_, _, latency)| latency.is_some())
+        .min_by_key(|(_, _, latency)| *latency)?;
+    log::info!(
+        "{} is the fastest exit, at {:?}",
+        hostname,
+        latency.unwrap()
+    );
+    Some(hostname)
+}
+
+/// Tries a handshake with every candidate at once, given as the exit it leads to, the name it's recorded under in the history, the transport to reach it over, its address and its key. Returns how long each took, or None for those that failed. The sessions are closed right away.
+async fn probe_all(
+    candidates: Vec<(
+        String,
+        String,
+        Transport,
+        std::net::SocketAddr,
+        x25519_dalek::PublicKey,
+    )>,
+) -> Vec<(String, String, Option<Duration>)> {
+    let (send, recv) = smol::channel::unbounded();
+    let _tasks: Vec<_> = candidates
+        .into_iter()
+        .map(|(hostname, endpoint, transport, addr, key)| {
+            let send = send.clone();
+            smolscale::spawn(async move {
+                let start = Instant::now();
+                let res = connect_over(
+                    transport,
+                    addr,
+                    key,
+                    sosistab::ConnectOptions {
+                        max_tries: 1,
+                        max_timeout: PROBE_TIMEOUT,
+                        ..Default::default()
+                    },
+                )
+                .await;
+                let latency = res.as_ref().ok().map(|_| start.elapsed());
+                // say goodbye, so that the other side doesn't keep the session around until it times out
+                if let Ok(session) = res {
+                    smolscale::spawn(session.close()).detach();
+                }
+                drop(send.send((hostname, endpoint, latency)).await)
+            })
+        })
+        .collect();
+    drop(send);
+    let mut results = Vec::new();
+    while let Ok(result) = recv.recv().await {
+        results.push(result);
+    }
+    results
+}
+
+/// How the bridge a session goes through is picked among all those that connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BridgeRanking {
+    /// How long to keep waiting for other bridges after the first one connects. It should be longer than the stagger, so that the bridges dialed right after the first one still get to show whether their handshakes are quicker; otherwise the best-ranked bridge keeps winning just because it was dialed first, and the others never get a record that could move them up.
+    pub grace: Duration,
+    /// How long each bridge waits to be dialed after the one ranked just above it, bridges with the best history going first. Staggering keeps a client from hitting every bridge at once, while a short stagger keeps a dead top-ranked bridge from costing much time. Zero dials every bridge at once.
+    pub stagger: Duration,
+    /// How many connected bridges are enough to stop waiting early. One means the first bridge to connect always wins.
+    pub candidates: usize,
+}
+
+impl Default for BridgeRanking {
+    fn default() -> Self {
+        BridgeRanking {
+            grace: Duration::from_millis(250),
+            stagger: Duration::from_millis(100),
+            candidates: 3,
+        }
+    }
+}
+
+/// Connects and authenticates to the selected exit, reporting every phase as it's entered. Errors carry a [FailureKind] where one can be told apart.
+async fn connect_authed(
+    selection: &ExitSelection,
+    use_bridges: bool,
+    ranking: BridgeRanking,
+    transport: Transport,
+    ccache: &ClientCache,
+    authenticator: &dyn Authenticator,
+    on_phase: &(dyn Fn(ConnectionPhase) + Sync),
+) -> anyhow::Result<(sosistab::mux::Multiplex, binder_transport::ExitDescriptor)> {
+    // find the exit
+    on_phase(ConnectionPhase::FetchingExits);
+    let mut exits = ccache
+        .get_exits()
+        .await
+        .context(FailureKind::DirectoryUnreachable)?;
+    if exits.is_empty() {
+        anyhow::bail!(FailureKind::NoExits)
+    }
+    let exit_host = match selection {
+        ExitSelection::Hostname(exit_host) => {
+            exits.sort_by(|a, b| {
+                strsim::damerau_levenshtein(&a.hostname, &exit_host)
+                    .cmp(&strsim::damerau_levenshtein(&b.hostname, &exit_host))
+            });
+            exits[0].hostname.clone()
+        }
+        ExitSelection::Fastest { country } => {
+            if let Some(country) = country {
+                exits.retain(|exit| exit.country_code.eq_ignore_ascii_case(country));
+                if exits.is_empty() {
+                    anyhow::bail!(anyhow::anyhow!(FailureKind::NoExits)
+                        .context(format!("no exits in {}", country)))
+                }
+            }
+            match fastest_exit(&exits, use_bridges, transport, ccache).await {
+                Some(hostname) => hostname,
+                None => {
+                    // nothing answered, so the usual connection attempt gets to fail properly
+                    log::warn!("no exit answered a probe");
+                    exits[0].hostname.clone()
+                }
+            }
+        }
+        ExitSelection::Pinned(exit) => {
+            // the pinned descriptor wins over whatever the binder says now
+            exits.retain(|other| other.hostname != exit.hostname);
+            exits.push((**exit).clone());
+            exit.hostname.clone()
+        }
+    };
+
+    let bridge_sess_async = async {
+        let mut bridges = ccache
+            .get_bridges(&exit_host)
+            .await
+            .context(FailureKind::DirectoryUnreachable)?;
+        log::debug!("got {} bridges", bridges.len());
+        if bridges.is_empty() {
+            anyhow::bail!(FailureKind::NoBridges)
+        }
+        // bridges that worked well before are dialed first
+        let history = ccache.history().clone();
+        let bridges = smol::unblock(move || {
+            history.rank(&mut bridges, |desc| desc.endpoint.to_string());
+            bridges
+        })
+        .await;
+        // spawn a task for *every* bridge, dialing them one stagger apart
+        on_phase(ConnectionPhase::Handshaking);
+        let (send, recv) = smol::channel::unbounded();
+        let _tasks: Vec<_> = bridges
+            .into_iter()
+            .enumerate()
+            .map(|(rank, desc)| {
+                let send = send.clone();
+                let history = ccache.history().clone();
+                smolscale::spawn(async move {
+                    smol::Timer::after(ranking.stagger * rank as u32).await;
+                    log::debug!("connecting through {}...", desc.endpoint);
+                    let start = Instant::now();
+                    // a bridge still handshaking when another one wins isn't recorded at all, since it may well have been about to connect
+                    let res = connect_over(
+                        Transport::Udp,
+                        desc.endpoint,
+                        desc.sosistab_key,
+                        Default::default(),
+                    )
+                    .await;
+                    let elapsed = start.elapsed();
+                    history.record_in_background(
+                        desc.endpoint.to_string(),
+                        res.as_ref().ok().map(|_| elapsed),
+                    );
+                    if res.is_ok() {
+                        facade::histogram("geph_handshake_seconds", elapsed);
+                    }
+                    drop(send.send((desc.endpoint, elapsed, res)).await)
+                })
+            })
+            .collect();
+        drop(send);
+        pick_bridge(recv, ranking).await
+    };
+    let exit_info = exits.iter().find(|v| v.hostname == exit_host).unwrap();
+    let connected_sess_async = async {
+        if use_bridges {
+            bridge_sess_async.await
+        } else {
+            async {
+                let start = Instant::now();
+                on_phase(ConnectionPhase::Resolving);
+                let exit_addr = smol::net::resolve(format!("{}:19831", exit_info.hostname))
+                    .await
+                    .context("can't resolve hostname of exit")?[0];
+                let hints = ccache
+                    .get_exit_hints(&exit_info.hostname)
+                    .await
+                    .unwrap_or_default();
+                on_phase(ConnectionPhase::Handshaking);
+                let res = connect_over(
+                    transport,
+                    exit_addr,
+                    exit_info.sosistab_key,
+                    exit_connect_options(&hints),
+                )
+                .await;
+                ccache.history().record_in_background(
+                    exit_info.hostname.clone(),
+                    res.as_ref().ok().map(|_| start.elapsed()),
+                );
+                if res.is_ok() {
+                    facade::histogram("geph_handshake_seconds", start.elapsed());
+                }
+                Ok(infal(res).await)
+            }
+            .or(async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                log::warn!("turning on bridges because we couldn't get a direct connection");
+                bridge_sess_async.await
+            })
+            .await
+        }
+    };
+    let session: anyhow::Result<sosistab::Session> = connected_sess_async
+        .or(async {
+            smol::Timer::after(Duration::from_secs(10)).await;
+            Err(anyhow::anyhow!(FailureKind::Timeout)
+                .context("initial connection timeout after 10"))
+        })
+        .await;
+    let mux = sosistab::mux::Multiplex::new(session?);
+    on_phase(ConnectionPhase::Authenticating);
+    authenticate_session(&mux, authenticator).await?;
+    Ok((mux, exit_info.clone()))
+}
+
+/// Room left in every datagram for the IP and UDP headers, encryption and framing.
+const PADDING_HEADROOM: usize = 128;
+
+/// Options for connecting directly to an exit. If the binder gave out an MTU hint for the exit, data frames are padded to fit it rather than to the default target. Bridges lie on a different path, so they don't use the hint.
+fn exit_connect_options(hints: &binder_transport::ExitHints) -> sosistab::ConnectOptions {
+    let mut opts = sosistab::ConnectOptions::default();
+    if let Some(mtu) = hints.mtu_hint {
+        let max = (mtu as usize).saturating_sub(PADDING_HEADROOM).max(256);
+        log::debug!("padding up to {} bytes for MTU hint {}", max, mtu);
+        opts.padding = sosistab::PaddingPolicy::Random { min: 0, max };
+    }
+    opts
+}
+
+/// Waits for bridges to connect, giving the others the ranking's grace period after the first one does, and returns the session through the one whose handshake was the quickest. With no grace period, that's simply the first bridge to connect.
+async fn pick_bridge<T>(
+    recv: Receiver<(std::net::SocketAddr, Duration, std::io::Result<T>)>,
+    ranking: BridgeRanking,
+) -> anyhow::Result<T> {
+    // collect successful results until the grace period after the first one is over
+    let mut last_err = None;
+    let mut connected = Vec::new();
+    let mut deadline = None;
+    loop {
+        let next = async { recv.recv().await.ok() };
+        let next = match deadline {
+            Some(deadline) => {
+                next.or(async {
+                    smol::Timer::at(deadline).await;
+                    None
+                })
+                .await
+            }
+            None => next.await,
+        };
+        let (saddr, elapsed, res) = match next {
+            Some(v) => v,
+            None => break,
+        };
+        match res {
+            Ok(res) => {
+                log::debug!("{} connected in {:?}", saddr, elapsed);
+                connected.push((elapsed, saddr, res));
+                if connected.len() >= ranking.candidates {
+                    break;
+                }
+                deadline.get_or_insert_with(|| Instant::now() + ranking.grace);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    // the slower sessions, and the bridges still trying, are dropped here
+    match connected.into_iter().min_by_key(|(elapsed, _, _)| *elapsed) {
+        Some((elapsed, saddr, res)) => {
+            log::info!("{} is our fastest bridge, at {:?}", saddr, elapsed);
+            Ok(res)
+        }
+        None => match last_err {
+            // the last failure is kept around as the cause
+            Some(err) => {
+                log::warn!(
+                    "every bridge failed, the last one with {:?}",
+                    connect_failure(&err)
+                );
+                Err(anyhow::Error::from(err).context(FailureKind::AllBridgesFailed))
+            }
+            None => Err(anyhow::anyhow!(FailureKind::NoBridges).context("ran out of bridges")),
+        },
+    }
+}
+
+/// Categorizes an error from a sosistab handshake. A server with a different key than we expect can't even read our hellos, so that looks like a timeout; only a key the server does present but we reject is a [FailureKind::PubkeyMismatch].
+fn connect_failure(err: &std::io::Error) -> FailureKind {
+    match sosistab::HandshakeError::of(err) {
+        Some(sosistab::HandshakeError::UntrustedKey) => FailureKind::PubkeyMismatch,
+        Some(sosistab::HandshakeError::VersionMismatch { .. }) => FailureKind::VersionMismatch,
+        None if err.kind() == std::io::ErrorKind::TimedOut => FailureKind::Timeout,
+        None => FailureKind::Network,
+    }
 }
 
 async fn infal<T, E>(v: Result<T, E>) -> T {
@@ -258,22 +1398,1000 @@ async fn infal<T, E>(v: Result<T, E>) -> T {
     }
 }
 
+/// How long the whole authentication step may take, including fetching credentials.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long before the credentials of a session expire that they're renewed.
+const REAUTH_MARGIN: Duration = Duration::from_secs(3600);
+
+/// How long to wait before trying again after renewing credentials failed.
+const REAUTH_RETRY: Duration = Duration::from_secs(300);
+
+/// What streams that authenticate an established session again are opened with, instead of a destination. It can never be mistaken for a host:port.
+const REAUTH_LABEL: &str = "!reauth";
+
+/// A way of proving to the exit that we're allowed to use it. The keepalive runs it over the first stream of every session before anything else goes through.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Authenticates over the given stream, failing if the exit turns us down.
+    async fn authenticate(&self, conn: &mut sosistab::mux::RelConn) -> anyhow::Result<()>;
+
+    /// How long until the credentials presented by [Authenticator::authenticate] expire. None means they never do, so sessions are never authenticated again.
+    fn expires_in(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Gets fresh credentials, ahead of the current ones expiring.
+    async fn refresh(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// The standard authenticator, which presents a blind-signed token from the binder.
+pub struct TokenAuthenticator {
+    ccache: Arc<ClientCache>,
+}
+
+impl TokenAuthenticator {
+    /// Creates a token authenticator that gets its tokens from the given cache.
+    pub fn new(ccache: Arc<ClientCache>) -> Self {
+        TokenAuthenticator { ccache }
+    }
+}
+
+#[async_trait]
+impl Authenticator for TokenAuthenticator {
+    async fn authenticate(&self, conn: &mut sosistab::mux::RelConn) -> anyhow::Result<()> {
+        let token = self.ccache.get_auth_token().await?;
+        log::debug!("sending auth info...");
+        aioutils::write_pascalish(
+            conn,
+            &(
+                &token.unblinded_digest,
+                &token.unblinded_signature,
+                &token.level,
+            ),
+        )
+        .await?;
+        let reply: u8 = aioutils::read_pascalish(conn).await?;
+        match reply {
+            1 => Ok(()),
+            0 => anyhow::bail!("the exit rejected our credentials"),
+            _ => anyhow::bail!("the exit couldn't check our credentials"),
+        }
+    }
+
+    fn expires_in(&self) -> Option<Duration> {
+        self.ccache.auth_token_expires_in()
+    }
+
+    async fn refresh(&self) -> anyhow::Result<()> {
+        self.ccache.refresh_auth_token().await?;
+        Ok(())
+    }
+}
+
 /// authenticates a muxed session
 async fn authenticate_session(
     session: &sosistab::mux::Multiplex,
-    token: &crate::cache::Token,
+    authenticator: &dyn Authenticator,
 ) -> anyhow::Result<()> {
-    let mut auth_conn = session.open_conn(None).await?;
-    log::debug!("sending auth info...");
-    aioutils::write_pascalish(
-        &mut auth_conn,
-        &(
-            &token.unblinded_digest,
-            &token.unblinded_signature,
-            &token.level,
-        ),
-    )
-    .await?;
-    let _: u8 = aioutils::read_pascalish(&mut auth_conn).await?;
-    Ok(())
+    authenticate_over(session, authenticator, None).await
+}
+
+/// Authenticates an established session again, so that it keeps working past the expiry of the credentials it was first authenticated with.
+async fn reauthenticate_session(
+    session: &sosistab::mux::Multiplex,
+    authenticator: &dyn Authenticator,
+) -> anyhow::Result<()> {
+    authenticate_over(session, authenticator, Some(REAUTH_LABEL.into())).await
+}
+
+/// Whether authenticating a session again failed because the exit closed the stream without answering, while the session itself is still fine. Exits that predate renewing credentials do that with any stream labelled [REAUTH_LABEL], while those that do renew them always answer, even if only to turn the credentials down.
+async fn reauth_unsupported(session: &sosistab::mux::Multiplex, err: &anyhow::Error) -> bool {
+    let unanswered = err.chain().any(|err| {
+        err.downcast_ref::<std::io::Error>()
+            .map(|err| {
+                matches!(
+                    err.kind(),
+                    std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset
+                )
+            })
+            .unwrap_or(false)
+    });
+    // a session that died takes its streams down with it, which looks just the same
+    unanswered
+        && session
+            .open_conn_with_timeout(None, CONN_OPEN_TIMEOUT)
+            .await
+            .is_ok()
+}
+
+async fn authenticate_over(
+    session: &sosistab::mux::Multiplex,
+    authenticator: &dyn Authenticator,
+    label: Option<String>,
+) -> anyhow::Result<()> {
+    async {
+        let mut auth_conn = session.open_conn(label).await?;
+        authenticator.authenticate(&mut auth_conn).await
+    }
+    .timeout(AUTH_TIMEOUT)
+    .await
+    .ok_or_else(|| anyhow::anyhow!("authentication timed out"))
+    .and_then(|res| res)
+    .context(FailureKind::AuthFailure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::prelude::*;
+
+    /// Answers the exit's challenge by adding one to it.
+    struct IncrementAuthenticator;
+
+    #[async_trait]
+    impl Authenticator for IncrementAuthenticator {
+        async fn authenticate(&self, conn: &mut sosistab::mux::RelConn) -> anyhow::Result<()> {
+            let challenge: u64 = aioutils::read_pascalish(conn).await?;
+            aioutils::write_pascalish(conn, &(challenge + 1)).await?;
+            let accepted: bool = aioutils::read_pascalish(conn).await?;
+            if !accepted {
+                anyhow::bail!("exit rejected our answer")
+            }
+            Ok(())
+        }
+    }
+
+    /// A binder that only knows about a single exit.
+    struct OneExitBinder(binder_transport::ExitDescriptor);
+
+    impl binder_transport::BinderClient for OneExitBinder {
+        fn request(
+            &self,
+            request: binder_transport::BinderRequestData,
+            _timeout: Duration,
+        ) -> binder_transport::BinderResult<binder_transport::BinderResponse> {
+            match request {
+                binder_transport::BinderRequestData::GetExits => {
+                    Ok(binder_transport::BinderResponse::GetExitsResp(vec![self
+                        .0
+                        .clone()]))
+                }
+                _ => Err(binder_transport::BinderError::DatabaseFailed),
+            }
+        }
+    }
+
+    /// Lets everyone in without asking anything.
+    struct NoopAuthenticator;
+
+    #[async_trait]
+    impl Authenticator for NoopAuthenticator {
+        async fn authenticate(&self, _conn: &mut sosistab::mux::RelConn) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn mtu_hint_sets_pad_target() {
+        let mut hints = binder_transport::ExitHints::default();
+        assert_eq!(
+            exit_connect_options(&hints).padding,
+            sosistab::ConnectOptions::default().padding
+        );
+        hints.mtu_hint = Some(1400);
+        assert_eq!(
+            exit_connect_options(&hints).padding,
+            sosistab::PaddingPolicy::Random {
+                min: 0,
+                max: 1400 - PADDING_HEADROOM
+            }
+        );
+        // absurdly small hints still leave room for a frame
+        hints.mtu_hint = Some(100);
+        assert_eq!(
+            exit_connect_options(&hints).padding,
+            sosistab::PaddingPolicy::Random { min: 0, max: 256 }
+        );
+    }
+
+    /// Held by tests that run an exit, since exits are always reached on the same port.
+    static EXIT_PORT: once_cell::sync::Lazy<Mutex<()>> =
+        once_cell::sync::Lazy::new(|| Mutex::new(()));
+
+    #[test]
+    fn connect_reports_phases_in_order() {
+        let _port = EXIT_PORT.lock();
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let exit = binder_transport::ExitDescriptor {
+                hostname: "127.0.0.1".into(),
+                signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+                country_code: "XX".into(),
+                city_code: "xxx".into(),
+                sosistab_key: (&server_sk).into(),
+            };
+            // exits are always reached on the standard port
+            let _listener = sosistab::Listener::listen("127.0.0.1:19831", server_sk).await;
+            let path =
+                std::env::temp_dir().join(format!("geph4-phase-test-{}.db", rand::random::<u64>()));
+            let ccache = ClientCache::new(
+                "user",
+                "pass",
+                mizaru::PublicKey([0; 32]),
+                mizaru::PublicKey([0; 32]),
+                Arc::new(OneExitBinder(exit)),
+                Arc::new(Mutex::new(crate::persist::KVDatabase::open(&path).unwrap())),
+            );
+            let phases = Mutex::new(Vec::new());
+            connect_authed(
+                &ExitSelection::Hostname("127.0.0.1".into()),
+                false,
+                BridgeRanking::default(),
+                Transport::Udp,
+                &ccache,
+                &NoopAuthenticator,
+                &|phase| phases.lock().push(phase),
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                *phases.lock(),
+                vec![
+                    ConnectionPhase::FetchingExits,
+                    ConnectionPhase::Resolving,
+                    ConnectionPhase::Handshaking,
+                    ConnectionPhase::Authenticating,
+                ]
+            );
+            drop(ccache);
+            let _ = std::fs::remove_file(&path);
+        })
+    }
+
+    #[test]
+    fn bonded_streams_take_turns() {
+        let _port = EXIT_PORT.lock();
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let exit = binder_transport::ExitDescriptor {
+                hostname: "127.0.0.1".into(),
+                signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+                country_code: "XX".into(),
+                city_code: "xxx".into(),
+                sosistab_key: (&server_sk).into(),
+            };
+            let listener = sosistab::Listener::listen("127.0.0.1:19831", server_sk).await;
+            let _exit = smol::spawn(async move {
+                // every stream is told which session it arrived on
+                let mut sessions = Vec::new();
+                for index in 0..2u8 {
+                    let mux =
+                        sosistab::mux::Multiplex::new(listener.accept_session().await.unwrap());
+                    sessions.push(smol::spawn(async move {
+                        // the stream used for authentication
+                        drop(mux.accept_conn().await.unwrap());
+                        let mut conns = Vec::new();
+                        loop {
+                            let mut conn = mux.accept_conn().await.unwrap();
+                            conn.write_all(&[index]).await.unwrap();
+                            conns.push(conn);
+                        }
+                    }));
+                }
+                smol::future::pending::<()>().await;
+            });
+            let path =
+                std::env::temp_dir().join(format!("geph4-bond-test-{}.db", rand::random::<u64>()));
+            let ccache = Arc::new(ClientCache::new(
+                "user",
+                "pass",
+                mizaru::PublicKey([0; 32]),
+                mizaru::PublicKey([0; 32]),
+                Arc::new(OneExitBinder(exit)),
+                Arc::new(Mutex::new(crate::persist::KVDatabase::open(&path).unwrap())),
+            ));
+            let keepalive = Keepalive::new(
+                Arc::new(StatCollector::default()),
+                ExitSelection::Hostname("127.0.0.1".into()),
+                false,
+                BridgeRanking::default(),
+                Transport::Udp,
+                false,
+                ReconnectBackoff::default(),
+                None,
+                2,
+                false,
+                ccache,
+                Arc::new(NoopAuthenticator),
+            );
+            let mut seen = [0; 2];
+            let mut conns = Vec::new();
+            for _ in 0..4 {
+                let mut conn = keepalive.connect("example.com:80").await.unwrap();
+                let mut index = [0u8];
+                conn.read_exact(&mut index).await.unwrap();
+                seen[index[0] as usize] += 1;
+                conns.push(conn);
+            }
+            assert_eq!(seen, [2, 2]);
+            drop(conns);
+            keepalive.shutdown().await;
+            let _ = std::fs::remove_file(&path);
+        })
+    }
+
+    #[test]
+    fn standby_prefers_the_same_country() {
+        let exit = |hostname: &str, country: &str| binder_transport::ExitDescriptor {
+            hostname: hostname.into(),
+            signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+            country_code: country.into(),
+            city_code: "xxx".into(),
+            sosistab_key: x25519_dalek::PublicKey::from([0; 32]),
+        };
+        let primary = exit("us-1", "us");
+        let exits = vec![primary.clone(), exit("de-1", "de"), exit("us-2", "us")];
+        let hostnames = |exits: Vec<binder_transport::ExitDescriptor>| {
+            exits
+                .into_iter()
+                .map(|exit| exit.hostname)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            hostnames(standby_candidates(exits.clone(), &primary)),
+            vec!["us-2"]
+        );
+        // elsewhere is better than nowhere
+        assert_eq!(
+            hostnames(standby_candidates(exits[..2].to_vec(), &primary)),
+            vec!["de-1"]
+        );
+        assert!(standby_candidates(vec![primary.clone()], &primary).is_empty());
+    }
+
+    #[test]
+    fn bonded_stats_add_up() {
+        let session = |down_total, down_loss, rtt, up_bytes_per_sec, probed_datagram_size| {
+            sosistab::SessionStats {
+                down_total,
+                down_loss,
+                down_recovered_loss: 0.0,
+                down_redundant: 0.0,
+                recent_seqnos: Default::default(),
+                rtt,
+                live_decoders: 0,
+                buffered_shard_bytes: 0,
+                down_duplicates: 0,
+                down_too_old: 0,
+                up_frames_per_sec: 0.0,
+                up_bytes_per_sec,
+                down_jitter: Duration::from_secs(0),
+                down_reorder_rate: 0.0,
+                probed_datagram_size,
+            }
+        };
+        let first = session(300, 0.1, Duration::from_millis(100), 1000.0, Some(1400));
+        let second = session(100, 0.5, Duration::from_secs(0), 500.0, Some(1200));
+        let combined = combine_stats(vec![first, second]);
+        assert_eq!(combined.down_total, 400);
+        assert!((combined.down_loss - 0.2).abs() < 1e-9);
+        assert!((combined.up_bytes_per_sec - 1500.0).abs() < 1e-9);
+        // the second session has no round-trip time yet
+        assert_eq!(combined.rtt, Duration::from_millis(100));
+        assert_eq!(combined.probed_datagram_size, Some(1200));
+    }
+
+    #[test]
+    fn shutdown_waits_for_streams() {
+        let _port = EXIT_PORT.lock();
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let exit = binder_transport::ExitDescriptor {
+                hostname: "127.0.0.1".into(),
+                signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+                country_code: "XX".into(),
+                city_code: "xxx".into(),
+                sosistab_key: (&server_sk).into(),
+            };
+            let listener = sosistab::Listener::listen("127.0.0.1:19831", server_sk).await;
+            let _exit = smol::spawn(async move {
+                let mux = sosistab::mux::Multiplex::new(listener.accept_session().await.unwrap());
+                // the stream used for authentication
+                drop(mux.accept_conn().await.unwrap());
+                // echo until the client closes, then close too
+                let mut conn = mux.accept_conn().await.unwrap();
+                let mut buf = [0u8; 1024];
+                loop {
+                    let n = conn.read(&mut buf).await.unwrap_or(0);
+                    if n == 0 {
+                        break;
+                    }
+                    conn.write_all(&buf[..n]).await.unwrap();
+                }
+                conn.shutdown().await;
+                smol::future::pending::<()>().await;
+            });
+            let path = std::env::temp_dir()
+                .join(format!("geph4-shutdown-test-{}.db", rand::random::<u64>()));
+            let ccache = Arc::new(ClientCache::new(
+                "user",
+                "pass",
+                mizaru::PublicKey([0; 32]),
+                mizaru::PublicKey([0; 32]),
+                Arc::new(OneExitBinder(exit)),
+                Arc::new(Mutex::new(crate::persist::KVDatabase::open(&path).unwrap())),
+            ));
+            let keepalive = Keepalive::new(
+                Arc::new(StatCollector::default()),
+                ExitSelection::Hostname("127.0.0.1".into()),
+                false,
+                BridgeRanking::default(),
+                Transport::Udp,
+                false,
+                ReconnectBackoff::default(),
+                None,
+                1,
+                false,
+                ccache,
+                Arc::new(NoopAuthenticator),
+            );
+            let mut conn = keepalive.connect("example.com:80").await.unwrap();
+            conn.write_all(b"hello").await.unwrap();
+            let mut echoed = [0u8; 5];
+            conn.read_exact(&mut echoed).await.unwrap();
+            let closed = std::sync::atomic::AtomicBool::new(false);
+            let start = Instant::now();
+            let shutdown = async {
+                keepalive.shutdown().await;
+                assert!(closed.load(std::sync::atomic::Ordering::SeqCst));
+            };
+            let close = async {
+                smol::Timer::after(Duration::from_millis(500)).await;
+                conn.shutdown().await;
+                let mut rest = Vec::new();
+                conn.read_to_end(&mut rest).await.unwrap();
+                closed.store(true, std::sync::atomic::Ordering::SeqCst);
+                drop(conn);
+            };
+            smol::future::zip(shutdown, close).await;
+            // the stream closing ended the shutdown, not the drain timeout
+            assert!(start.elapsed() < DRAIN_TIMEOUT);
+            // and nothing new gets through
+            assert!(keepalive
+                .connect("example.com:80")
+                .timeout(Duration::from_secs(1))
+                .await
+                .map(|res| res.is_err())
+                .unwrap_or(true));
+            drop(keepalive);
+            let _ = std::fs::remove_file(&path);
+        })
+    }
+
+    #[test]
+    fn switching_transport_reconnects() {
+        let _port = EXIT_PORT.lock();
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let exit = binder_transport::ExitDescriptor {
+                hostname: "127.0.0.1".into(),
+                signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+                country_code: "XX".into(),
+                city_code: "xxx".into(),
+                sosistab_key: (&server_sk).into(),
+            };
+            // the exit takes sessions over both transports, and tells every stream which one it came over
+            let serve = |listener: sosistab::Listener, marker: &'static [u8]| {
+                smol::spawn(async move {
+                    loop {
+                        let mux =
+                            sosistab::mux::Multiplex::new(listener.accept_session().await.unwrap());
+                        smol::spawn(async move {
+                            // the stream used for authentication
+                            let _auth = mux.accept_conn().await;
+                            let mut conns = Vec::new();
+                            while let Ok(mut conn) = mux.accept_conn().await {
+                                let _ = conn.write_all(marker).await;
+                                conns.push(conn);
+                            }
+                        })
+                        .detach();
+                    }
+                })
+            };
+            let addr: std::net::SocketAddr = "127.0.0.1:19831".parse().unwrap();
+            let _udp_exit = serve(
+                sosistab::Listener::listen(addr, server_sk.clone()).await,
+                b"udp",
+            );
+            let _tcp_exit = serve(
+                sosistab::Listener::listen_backhaul(
+                    sosistab::TcpBackhaul::listen(addr).await.unwrap(),
+                    addr,
+                    server_sk,
+                    sosistab::ListenerOptions::default(),
+                ),
+                b"tcp",
+            );
+            let path = std::env::temp_dir()
+                .join(format!("geph4-transport-test-{}.db", rand::random::<u64>()));
+            let ccache = Arc::new(ClientCache::new(
+                "user",
+                "pass",
+                mizaru::PublicKey([0; 32]),
+                mizaru::PublicKey([0; 32]),
+                Arc::new(OneExitBinder(exit)),
+                Arc::new(Mutex::new(crate::persist::KVDatabase::open(&path).unwrap())),
+            ));
+            let keepalive = Keepalive::new(
+                Arc::new(StatCollector::default()),
+                ExitSelection::Hostname("127.0.0.1".into()),
+                false,
+                BridgeRanking::default(),
+                Transport::Udp,
+                false,
+                ReconnectBackoff::default(),
+                None,
+                1,
+                false,
+                ccache,
+                Arc::new(NoopAuthenticator),
+            );
+            let kalive = &keepalive;
+            let transport_used = || async move {
+                let mut conn = kalive.connect("example.com:80").await.ok()?;
+                let mut marker = [0u8; 3];
+                conn.read_exact(&mut marker)
+                    .timeout(Duration::from_secs(1))
+                    .await?
+                    .ok()?;
+                Some(marker)
+            };
+            assert_eq!(transport_used().await, Some(*b"udp"));
+            keepalive.set_transport(Transport::Tcp);
+            assert_eq!(keepalive.transport(), Transport::Tcp);
+            // streams may still land on the old session until the switch has gone through
+            let mut marker = None;
+            for _ in 0..20 {
+                marker = transport_used().await;
+                if marker == Some(*b"tcp") {
+                    break;
+                }
+                smol::Timer::after(Duration::from_millis(100)).await;
+            }
+            assert_eq!(marker, Some(*b"tcp"));
+            drop(keepalive);
+            let _ = std::fs::remove_file(&path);
+        })
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_cap() {
+        let backoff = ReconnectBackoff {
+            base: Duration::from_millis(500),
+            max: Duration::from_secs(10),
+            ..Default::default()
+        };
+        let delays: Vec<_> = (1..=7).map(|failures| backoff.delay(failures)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(500),
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(4),
+                Duration::from_secs(8),
+                Duration::from_secs(10),
+                Duration::from_secs(10),
+            ]
+        );
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn pinned_exit_survives_short_outages() {
+        let exit = |hostname: &str| binder_transport::ExitDescriptor {
+            hostname: hostname.into(),
+            signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+            country_code: "XX".into(),
+            city_code: "xxx".into(),
+            sosistab_key: x25519_dalek::PublicKey::from([0u8; 32]),
+        };
+        let unpinned = ExitSelection::Fastest { country: None };
+        let mut pin = ExitPin::new(Duration::from_secs(60));
+        // nothing to lose before the first session
+        pin.failed_at(Instant::now());
+        assert_eq!(pin.selection(&unpinned), unpinned);
+        let first = exit("first");
+        pin.connected(first.clone());
+        let pinned = ExitSelection::Pinned(Box::new(first.clone()));
+        assert_eq!(pin.selection(&unpinned), pinned);
+        // later sessions don't move the pin
+        pin.connected(exit("second"));
+        assert_eq!(pin.selection(&unpinned), pinned);
+        // an outage shorter than the fallback period keeps it, and reconnecting starts the clock over
+        let start = Instant::now();
+        pin.failed_at(start);
+        pin.failed_at(start + Duration::from_secs(30));
+        assert_eq!(pin.selection(&unpinned), pinned);
+        pin.connected(first);
+        pin.failed_at(start + Duration::from_secs(40));
+        pin.failed_at(start + Duration::from_secs(90));
+        assert_eq!(pin.selection(&unpinned), pinned);
+        // but a long one gives it up
+        pin.failed_at(start + Duration::from_secs(100));
+        assert_eq!(pin.selection(&unpinned), unpinned);
+    }
+
+    #[test]
+    fn transport_parses() {
+        assert_eq!("UDP".parse::<Transport>().unwrap(), Transport::Udp);
+        assert_eq!("tcp".parse::<Transport>().unwrap(), Transport::Tcp);
+        assert!("quic".parse::<Transport>().is_err());
+        assert_eq!(Transport::Tcp.to_string(), "tcp");
+        assert_eq!("auto".parse::<Transport>().unwrap(), Transport::Auto);
+    }
+
+    #[test]
+    fn auto_falls_back_to_tcp() {
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let server_pk = x25519_dalek::PublicKey::from(&server_sk);
+            // only TCP gets through
+            let backhaul = sosistab::TcpBackhaul::listen("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = backhaul.local_addr().unwrap();
+            let listener = sosistab::Listener::listen_backhaul(
+                backhaul,
+                addr,
+                server_sk,
+                sosistab::ListenerOptions::default(),
+            );
+            let _accept =
+                smol::spawn(async move { while listener.accept_session().await.is_some() {} });
+            let opts = sosistab::ConnectOptions {
+                max_tries: 2,
+                max_timeout: Duration::from_millis(500),
+                ..Default::default()
+            };
+            assert!(connect_over(Transport::Udp, addr, server_pk, opts.clone())
+                .await
+                .is_err());
+            connect_over(Transport::Auto, addr, server_pk, opts)
+                .await
+                .unwrap();
+        })
+    }
+
+    #[test]
+    fn one_shot_gives_up() {
+        smol::block_on(async {
+            // nothing listens for this exit, and the binder can't hand out bridges either
+            let exit = binder_transport::ExitDescriptor {
+                hostname: "127.0.0.1".into(),
+                signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+                country_code: "XX".into(),
+                city_code: "xxx".into(),
+                sosistab_key: x25519_dalek::PublicKey::from([0u8; 32]),
+            };
+            let path = std::env::temp_dir()
+                .join(format!("geph4-oneshot-test-{}.db", rand::random::<u64>()));
+            let ccache = Arc::new(ClientCache::new(
+                "user",
+                "pass",
+                mizaru::PublicKey([0; 32]),
+                mizaru::PublicKey([0; 32]),
+                Arc::new(OneExitBinder(exit)),
+                Arc::new(Mutex::new(crate::persist::KVDatabase::open(&path).unwrap())),
+            ));
+            let stats = Arc::new(StatCollector::default());
+            let keepalive = Keepalive::new(
+                stats.clone(),
+                ExitSelection::Hostname("127.0.0.1".into()),
+                true,
+                BridgeRanking::default(),
+                Transport::Udp,
+                true,
+                ReconnectBackoff::default(),
+                None,
+                1,
+                false,
+                ccache,
+                Arc::new(NoopAuthenticator),
+            );
+            let err = keepalive
+                .wait_fatal()
+                .timeout(Duration::from_secs(10))
+                .await
+                .expect("one-shot mode never gave up");
+            let kind = err.kind;
+            assert_eq!(kind, FailureKind::DirectoryUnreachable);
+            assert_ne!(kind.exit_code(), 0);
+            // no second attempt is ever made
+            smol::Timer::after(Duration::from_secs(2)).await;
+            assert_eq!(stats.failure_count(kind), 1);
+            drop(keepalive);
+            let _ = std::fs::remove_file(&path);
+        })
+    }
+
+    #[test]
+    fn probes_find_fastest() {
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let listener = sosistab::Listener::listen("127.0.0.1:0", server_sk.clone()).await;
+            let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let candidates = vec![
+                (
+                    "dead".to_string(),
+                    "dead".to_string(),
+                    Transport::Udp,
+                    silent.local_addr().unwrap(),
+                    (&server_sk).into(),
+                ),
+                (
+                    "alive".to_string(),
+                    "alive".to_string(),
+                    Transport::Udp,
+                    listener.local_addr(),
+                    (&server_sk).into(),
+                ),
+            ];
+            let mut results = probe_all(candidates).await;
+            results.sort();
+            assert_eq!(results[0].0, "alive");
+            assert!(results[0].2.is_some());
+            assert_eq!(results[1].0, "dead");
+            assert_eq!(results[1].2, None);
+        });
+        assert_eq!(
+            ExitSelection::fastest_in("AUTO"),
+            ExitSelection::Fastest { country: None }
+        );
+        assert_eq!(
+            ExitSelection::fastest_in("us"),
+            ExitSelection::Fastest {
+                country: Some("us".into())
+            }
+        );
+    }
+
+    #[test]
+    fn fastest_bridge_in_grace_wins() {
+        smol::block_on(async {
+            let ranking = BridgeRanking {
+                grace: Duration::from_millis(200),
+                candidates: 3,
+                ..Default::default()
+            };
+            let addr = |port| std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            let ms = Duration::from_millis;
+            // a slow bridge answers first, but a faster one shows up within the grace period
+            let (send, recv) = smol::channel::unbounded();
+            let _sender = smolscale::spawn(async move {
+                send.send((addr(1), ms(900), Ok(1))).await.unwrap();
+                smol::Timer::after(ms(50)).await;
+                send.send((addr(2), ms(0), Err(std::io::ErrorKind::TimedOut.into())))
+                    .await
+                    .unwrap();
+                send.send((addr(3), ms(100), Ok(3))).await.unwrap();
+                smol::Timer::after(ms(500)).await;
+                // too late to count
+                drop(send.send((addr(4), ms(10), Ok(4))).await);
+                smol::future::pending::<()>().await
+            });
+            assert_eq!(pick_bridge(recv, ranking).await.unwrap(), 3);
+            // enough candidates end the wait early
+            let (send, recv) = smol::channel::unbounded();
+            send.send((addr(1), ms(300), Ok(1))).await.unwrap();
+            send.send((addr(2), ms(200), Ok(2))).await.unwrap();
+            let start = Instant::now();
+            let picked = pick_bridge(
+                recv,
+                BridgeRanking {
+                    candidates: 2,
+                    ..ranking
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(picked, 2);
+            assert!(start.elapsed() < ranking.grace);
+            // nothing connecting is an error, keeping why the last one failed
+            let (send, recv) = smol::channel::unbounded::<(_, _, std::io::Result<()>)>();
+            send.send((addr(1), ms(0), Err(std::io::ErrorKind::TimedOut.into())))
+                .await
+                .unwrap();
+            drop(send);
+            let err = pick_bridge(recv, ranking).await.unwrap_err();
+            assert_eq!(FailureKind::of(&err), FailureKind::AllBridgesFailed);
+            assert_eq!(
+                err.root_cause()
+                    .downcast_ref::<std::io::Error>()
+                    .map(|err| err.kind()),
+                Some(std::io::ErrorKind::TimedOut)
+            );
+            // with no bridges at all, none could fail
+            let (send, recv) = smol::channel::unbounded::<(_, _, std::io::Result<()>)>();
+            drop(send);
+            let err = pick_bridge(recv, ranking).await.unwrap_err();
+            assert_eq!(FailureKind::of(&err), FailureKind::NoBridges);
+        })
+    }
+
+    #[test]
+    fn reauthentication_is_labelled() {
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let server_pk = (&server_sk).into();
+            let listener = sosistab::Listener::listen("127.0.0.1:0", server_sk).await;
+            let exit_addr = listener.local_addr();
+            let exit = smol::spawn(async move {
+                let mux = sosistab::mux::Multiplex::new(listener.accept_session().await.unwrap());
+                let mut labels = Vec::new();
+                for challenge in &[1u64, 2] {
+                    let mut conn = mux.accept_conn().await.unwrap();
+                    labels.push(conn.additional_info().map(|s| s.to_string()));
+                    aioutils::write_pascalish(&mut conn, challenge)
+                        .await
+                        .unwrap();
+                    let _: u64 = aioutils::read_pascalish(&mut conn).await.unwrap();
+                    aioutils::write_pascalish(&mut conn, &true).await.unwrap();
+                    conn.flush().await.unwrap();
+                }
+                labels
+            });
+            let session = sosistab::connect(exit_addr, server_pk).await.unwrap();
+            let mux = sosistab::mux::Multiplex::new(session);
+            authenticate_session(&mux, &IncrementAuthenticator)
+                .await
+                .unwrap();
+            reauthenticate_session(&mux, &IncrementAuthenticator)
+                .await
+                .unwrap();
+            assert_eq!(exit.await, vec![None, Some(REAUTH_LABEL.to_string())]);
+        })
+    }
+
+    #[test]
+    fn old_exits_refuse_reauthentication() {
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let server_pk = (&server_sk).into();
+            let listener = sosistab::Listener::listen("127.0.0.1:0", server_sk).await;
+            let exit_addr = listener.local_addr();
+            let _exit = smol::spawn(async move {
+                let mux = sosistab::mux::Multiplex::new(listener.accept_session().await.unwrap());
+                let mut conn = mux.accept_conn().await.unwrap();
+                aioutils::write_pascalish(&mut conn, &1u64).await.unwrap();
+                let _: u64 = aioutils::read_pascalish(&mut conn).await.unwrap();
+                aioutils::write_pascalish(&mut conn, &true).await.unwrap();
+                conn.flush().await.unwrap();
+                // "!reauth" is no host anyone can connect to
+                while let Ok(conn) = mux.accept_conn().await {
+                    drop(conn)
+                }
+            });
+            let session = sosistab::connect(exit_addr, server_pk).await.unwrap();
+            let mux = sosistab::mux::Multiplex::new(session);
+            authenticate_session(&mux, &IncrementAuthenticator)
+                .await
+                .unwrap();
+            let err = reauthenticate_session(&mux, &IncrementAuthenticator)
+                .await
+                .unwrap_err();
+            assert!(reauth_unsupported(&mux, &err).await, "{:?}", err);
+            assert!(!reauth_unsupported(&mux, &anyhow::anyhow!("bad token")).await);
+        })
+    }
+
+    #[test]
+    fn custom_authenticator_runs() {
+        smol::block_on(async {
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let server_pk = (&server_sk).into();
+            let listener = sosistab::Listener::listen("127.0.0.1:0", server_sk).await;
+            let exit_addr = listener.local_addr();
+            let exit = smol::spawn(async move {
+                let mux = sosistab::mux::Multiplex::new(listener.accept_session().await.unwrap());
+                for challenge in &[41u64, 99] {
+                    let mut conn = mux.accept_conn().await.unwrap();
+                    aioutils::write_pascalish(&mut conn, challenge)
+                        .await
+                        .unwrap();
+                    let answer: u64 = aioutils::read_pascalish(&mut conn).await.unwrap();
+                    // the second time around, the exit turns everyone down
+                    let accepted = answer == challenge + 1 && *challenge == 41;
+                    aioutils::write_pascalish(&mut conn, &accepted)
+                        .await
+                        .unwrap();
+                    conn.flush().await.unwrap();
+                }
+                mux
+            });
+            let session = sosistab::connect(exit_addr, server_pk).await.unwrap();
+            let mux = sosistab::mux::Multiplex::new(session);
+            authenticate_session(&mux, &IncrementAuthenticator)
+                .await
+                .unwrap();
+            let err = authenticate_session(&mux, &IncrementAuthenticator)
+                .await
+                .unwrap_err();
+            assert_eq!(FailureKind::of(&err), FailureKind::AuthFailure);
+            drop(exit.await);
+        })
+    }
+
+    #[test]
+    fn failures_are_categorized() {
+        let stats = StatCollector::default();
+        let record = |err: anyhow::Error| stats.record_failure(FailureKind::of(&err));
+        smol::block_on(async {
+            // a server whose key we refuse
+            let server_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let wrong_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let listener = sosistab::Listener::listen("127.0.0.1:0", server_sk.clone()).await;
+            let err = sosistab::connect_with_options(
+                listener.local_addr(),
+                (&server_sk).into(),
+                sosistab::ConnectOptions {
+                    verify_server: Some(Arc::new(|_: &x25519_dalek::PublicKey| false)),
+                    ..Default::default()
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(connect_failure(&err), FailureKind::PubkeyMismatch);
+            record(anyhow::anyhow!(connect_failure(&err)).context("ran out of bridges"));
+            // a server with a different key than we expect never answers
+            let err = sosistab::connect_with_options(
+                listener.local_addr(),
+                (&wrong_sk).into(),
+                sosistab::ConnectOptions {
+                    max_tries: 1,
+                    max_timeout: Duration::from_millis(100),
+                    ..Default::default()
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(connect_failure(&err), FailureKind::Timeout);
+            record(anyhow::anyhow!(connect_failure(&err)).context("ran out of bridges"));
+        });
+        assert_eq!(stats.last_failure(), Some(FailureKind::Timeout));
+        record(anyhow::anyhow!(FailureKind::NoExits));
+        record(anyhow::anyhow!(FailureKind::NoBridges));
+        let auth: anyhow::Result<()> = Err(anyhow::anyhow!("bad token"));
+        record(auth.context(FailureKind::AuthFailure).unwrap_err());
+        record(anyhow::anyhow!("connection reset"));
+        assert_eq!(stats.last_failure(), Some(FailureKind::Network));
+        let exits: anyhow::Result<()> = Err(anyhow::anyhow!("binder timed out"));
+        record(
+            exits
+                .context(FailureKind::DirectoryUnreachable)
+                .unwrap_err(),
+        );
+        for kind in &[
+            FailureKind::Timeout,
+            FailureKind::PubkeyMismatch,
+            FailureKind::NoExits,
+            FailureKind::AuthFailure,
+            FailureKind::NoBridges,
+            FailureKind::Network,
+            FailureKind::DirectoryUnreachable,
+        ] {
+            assert_eq!(stats.failure_count(*kind), 1, "{:?}", kind);
+        }
+        stats.record_failure(FailureKind::AuthFailure);
+        assert_eq!(stats.failure_count(FailureKind::AuthFailure), 2);
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["last_failure"], "auth_failure");
+        assert_eq!(json["failure_counts"]["auth_failure"], 2);
+    }
 }
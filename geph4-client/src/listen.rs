@@ -0,0 +1,213 @@
+use std::{
+    fmt::Display,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use smol::io::{AsyncRead, AsyncWrite};
+
+/// Where a local listener (SOCKS5, HTTP or stats) is bound: either a TCP address, or, given as `unix:/path`, a Unix domain socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                if path.is_empty() {
+                    anyhow::bail!("{:?} has no socket path", s)
+                }
+                return Ok(ListenAddr::Unix(path.into()));
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = path;
+                anyhow::bail!("Unix sockets aren't supported on this platform")
+            }
+        }
+        Ok(ListenAddr::Tcp(s.parse()?))
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A bound local listener.
+pub enum LocalListener {
+    Tcp(smol::net::TcpListener),
+    #[cfg(unix)]
+    Unix(smol::net::unix::UnixListener),
+}
+
+impl LocalListener {
+    /// Binds to the given address. A socket file left behind at a Unix path, say by a client that crashed, is removed first, but one that something still listens on is left alone and binding fails.
+    pub async fn bind(addr: &ListenAddr) -> anyhow::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(LocalListener::Tcp(
+                sosistab::runtime::new_tcp_listener_bind(*addr).await?,
+            )),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+                if let Ok(meta) = std::fs::symlink_metadata(path) {
+                    if meta.file_type().is_socket() {
+                        match smol::net::unix::UnixStream::connect(path).await {
+                            Ok(_) => anyhow::bail!("{} is already in use", path.display()),
+                            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                                std::fs::remove_file(path)?
+                            }
+                            Err(err) => return Err(err.into()),
+                        }
+                    }
+                }
+                Ok(LocalListener::Unix(smol::net::unix::UnixListener::bind(
+                    path,
+                )?))
+            }
+        }
+    }
+
+    /// Accepts the next connection.
+    pub async fn accept(&self) -> io::Result<LocalStream> {
+        match self {
+            LocalListener::Tcp(listener) => Ok(LocalStream::Tcp(listener.accept().await?.0)),
+            #[cfg(unix)]
+            LocalListener::Unix(listener) => Ok(LocalStream::Unix(listener.accept().await?.0)),
+        }
+    }
+}
+
+impl Drop for LocalListener {
+    fn drop(&mut self) {
+        // a Unix socket file would outlive the listener otherwise
+        #[cfg(unix)]
+        if let LocalListener::Unix(listener) = self {
+            if let Some(path) = listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.to_owned()))
+            {
+                drop(std::fs::remove_file(path));
+            }
+        }
+    }
+}
+
+/// A connection accepted by a [LocalListener].
+#[derive(Clone)]
+pub enum LocalStream {
+    Tcp(smol::net::TcpStream),
+    #[cfg(unix)]
+    Unix(smol::net::unix::UnixStream),
+}
+
+impl AsyncRead for LocalStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            LocalStream::Tcp(conn) => Pin::new(conn).poll_read(cx, buf),
+            #[cfg(unix)]
+            LocalStream::Unix(conn) => Pin::new(conn).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for LocalStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            LocalStream::Tcp(conn) => Pin::new(conn).poll_write(cx, buf),
+            #[cfg(unix)]
+            LocalStream::Unix(conn) => Pin::new(conn).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LocalStream::Tcp(conn) => Pin::new(conn).poll_flush(cx),
+            #[cfg(unix)]
+            LocalStream::Unix(conn) => Pin::new(conn).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            LocalStream::Tcp(conn) => Pin::new(conn).poll_close(cx),
+            #[cfg(unix)]
+            LocalStream::Unix(conn) => Pin::new(conn).poll_close(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::prelude::*;
+
+    #[test]
+    fn listen_addrs_are_parsed() {
+        assert_eq!(
+            "127.0.0.1:9909".parse::<ListenAddr>().unwrap(),
+            ListenAddr::Tcp("127.0.0.1:9909".parse().unwrap())
+        );
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("localhost".parse::<ListenAddr>().is_err());
+        #[cfg(unix)]
+        {
+            let addr: ListenAddr = "unix:/run/geph4/socks5.sock".parse().unwrap();
+            assert_eq!(addr, ListenAddr::Unix("/run/geph4/socks5.sock".into()));
+            assert_eq!(addr.to_string(), "unix:/run/geph4/socks5.sock");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_listener_replaces_stale_socket() {
+        smol::block_on(async {
+            let path = std::env::temp_dir().join(format!("geph4-test-{}.sock", std::process::id()));
+            let addr = ListenAddr::Unix(path.clone());
+            // a listener that went away without cleaning up
+            drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+            assert!(path.exists());
+            let listener = LocalListener::bind(&addr).await.unwrap();
+            let mut client = smol::net::unix::UnixStream::connect(&path).await.unwrap();
+            let mut accepted = listener.accept().await.unwrap();
+            client.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            // one that's still listening isn't replaced
+            assert!(LocalListener::bind(&addr).await.is_err());
+            assert!(path.exists());
+            // and it's cleaned up once we're done
+            drop(listener);
+            assert!(!path.exists());
+        })
+    }
+}
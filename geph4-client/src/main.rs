@@ -7,8 +7,11 @@ use flexi_logger::{DeferredNow, Record};
 use stats::GLOBAL_LOGGER;
 use structopt::StructOpt;
 mod cache;
+mod dns;
 mod kalive;
+mod listen;
 mod persist;
+mod route;
 use once_cell::sync::Lazy;
 use prelude::*;
 mod prelude;
@@ -81,9 +84,13 @@ fn main() -> anyhow::Result<()> {
     smol::future::block_on(smolscale::spawn(async move {
         match opt {
             Opt::Connect(opt) => loop {
-                if let Err(err) = main_connect::main_connect(opt.clone()).await {
-                    log::error!("Something SERIOUSLY wrong has happened! {:#?}", err);
-                    smol::Timer::after(Duration::from_secs(1)).await;
+                match main_connect::main_connect(opt.clone()).await {
+                    // shut down through /kill
+                    Ok(()) => break Ok(()),
+                    Err(err) => {
+                        log::error!("Something SERIOUSLY wrong has happened! {:#?}", err);
+                        smol::Timer::after(Duration::from_secs(1)).await;
+                    }
                 }
             },
             Opt::Sync(opt) => main_sync::main_sync(opt).await,
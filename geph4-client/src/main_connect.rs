@@ -1,10 +1,28 @@
 use crate::stats::GLOBAL_LOGGER;
-use crate::{cache::ClientCache, kalive::Keepalive, stats::StatCollector, AuthOpt, CommonOpt};
+use crate::{
+    cache::ClientCache,
+    dns::{serve_dns, DnsCache, DohClient},
+    kalive::{
+        set_socket_binding, BridgeRanking, ExitSelection, Keepalive, ReconnectBackoff,
+        TokenAuthenticator, Transport,
+    },
+    listen::{ListenAddr, LocalListener, LocalStream},
+    route::RoutingPolicy,
+    stats::StatCollector,
+    AuthOpt, CommonOpt,
+};
+use anyhow::Context;
 use chrono::prelude::*;
 use scopeguard::defer;
+use sha2::{Digest, Sha256};
 use smol::prelude::*;
 use smol_timeout::TimeoutExt;
-use std::{net::Ipv4Addr, net::SocketAddr, net::SocketAddrV4, sync::Arc, time::Duration};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::{
+    net::IpAddr, net::Ipv4Addr, net::Ipv6Addr, net::SocketAddr, net::SocketAddrV4, sync::Arc,
+    time::Duration,
+};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt, Clone)]
@@ -19,66 +37,315 @@ pub struct ConnectOpt {
     /// whether or not to use bridges
     use_bridges: bool,
 
+    #[structopt(long, default_value = "250")]
+    /// how long to wait for other bridges after the first one connects, in milliseconds, before going with the fastest of them. Should be longer than --bridge-stagger-ms, so that bridges dialed just after the first one get a fair chance; 0 goes with the first bridge to connect
+    bridge_grace_ms: u64,
+
+    #[structopt(long, default_value = "100")]
+    /// how long each bridge waits to be dialed after the one ranked just above it, in milliseconds. Bridges that connected well before are dialed first; 0 dials every bridge at once
+    bridge_stagger_ms: u64,
+
+    #[structopt(long, default_value = "3")]
+    /// how many connected bridges are enough to stop waiting early. 1 always goes with the first bridge to connect
+    bridge_candidates: usize,
+
+    #[structopt(long, default_value = "3600")]
+    /// how long the list of exits from the binder is reused, in seconds. After that, the old list is still used while a new one is fetched in the background, so that connecting never waits on the binder once a list is cached. /refresh has it fetched again
+    exit_cache_secs: u64,
+
+    #[structopt(long, default_value = "60")]
+    /// how long the bridges of an exit are reused, in seconds
+    bridge_cache_secs: u64,
+
+    #[structopt(long, default_value = "udp")]
+    /// what sessions with the exit are carried over: "udp", "tcp" for networks that block UDP, or "auto" to try UDP first and fall back to TCP. Can be switched later through /transport
+    transport: Transport,
+
     #[structopt(long, default_value = "127.0.0.1:9909")]
-    /// where to listen for SOCKS5 connections
-    socks5_listen: SocketAddr,
+    /// where to listen for SOCKS5 connections: an address, or unix:/path for a Unix domain socket
+    socks5_listen: ListenAddr,
 
     #[structopt(long, default_value = "127.0.0.1:9910")]
-    /// where to listen for HTTP proxy connections
-    http_listen: SocketAddr,
+    /// where to listen for HTTP proxy connections: an address, or unix:/path for a Unix domain socket
+    http_listen: ListenAddr,
 
     #[structopt(long, default_value = "127.0.0.1:9809")]
-    /// where to listen for REST-based local connections
-    stats_listen: SocketAddr,
+    /// where to listen for REST-based local connections: an address, or unix:/path for a Unix domain socket
+    stats_listen: ListenAddr,
 
     #[structopt(long)]
     /// where to listen for proxied DNS requests. Optional.
     dns_listen: Option<SocketAddr>,
 
+    #[structopt(long, default_value = "0")]
+    /// how many more times to try binding --dns-listen, a few seconds apart, if the first try fails, for example because another resolver still holds the port
+    dns_bind_retries: u32,
+
+    #[structopt(long, default_value = "128")]
+    /// most DNS queries to --dns-listen worked on at once, at least 1. Queries beyond that are dropped, so that a flood of them can't overwhelm the tunnel.
+    dns_max_inflight: usize,
+
     #[structopt(long, default_value = "sg-sgp-test-01.exits.geph.io")]
     /// which exit server to connect to. If there isn't an exact match, the exit server with the most similar hostname is picked.
     exit_server: String,
 
+    #[structopt(long)]
+    /// pick the exit that answers the quickest instead of going by --exit-server: "auto" for any exit, or a country code like "us" for exits in that country
+    fastest_exit: Option<String>,
+
+    #[structopt(long)]
+    /// try connecting just once, and exit with a nonzero status if that fails or the session later dies, instead of reconnecting forever. The status tells why: 2 for a timeout, 3 for a key mismatch, 4 for no exits, 5 for failed authentication, 6 for no bridges, 7 for any other network failure, 8 for an unreachable directory, 9 for a protocol version mismatch, and 10 when every bridge failed.
+    one_shot: bool,
+
+    #[structopt(long)]
+    /// stay on the first exit connected to for as long as the client runs, so that the IP address sites see doesn't change when reconnecting
+    pin_exit: bool,
+
+    #[structopt(long, default_value = "300")]
+    /// with --pin-exit, how long the pinned exit may stay unreachable, in seconds, before another one is picked
+    pin_exit_fallback_secs: u64,
+
+    #[structopt(long, default_value = "1")]
+    /// number of sessions to keep open to the exit at once, with new connections spread across them for throughput
+    bond: usize,
+
+    #[structopt(long, conflicts_with = "pin_exit")]
+    /// keep a standby session to the next best exit, and switch to it right away if the current one fails
+    standby: bool,
+
+    #[structopt(long, default_value = "1000")]
+    /// how long to wait before reconnecting after a session fails, in milliseconds. The wait doubles with every failure in a row
+    reconnect_base_ms: u64,
+
+    #[structopt(long, default_value = "60")]
+    /// longest wait before reconnecting, in seconds, however many failures there have been
+    reconnect_max_secs: u64,
+
     #[structopt(long)]
     /// whether or not to collect detailed profiling statistics
     pprof: bool,
+
+    #[structopt(long)]
+    /// TCP_USER_TIMEOUT, in milliseconds, for local SOCKS5 and HTTP connections, so that writes to a dead local peer fail quickly. Only supported on Linux.
+    tcp_user_timeout: Option<u64>,
+
+    #[structopt(long)]
+    /// DNS resolver, as reached from the exit, that queries to --dns-listen go to. Can be given several times, in which case queries are spread across the resolvers, and go to the next one when one times out. Defaults to ordns.he.net:53.
+    dns_upstream: Vec<String>,
+
+    #[structopt(long)]
+    /// DNS-over-HTTPS endpoint, such as https://cloudflare-dns.com/dns-query. When given, DNS queries go there, encrypted all the way, instead of to --dns-upstream.
+    dns_doh: Option<String>,
+
+    #[structopt(long)]
+    /// HTTP proxy, as reached from the exit, that HTTP proxy requests are passed on to instead of being handled by the client itself. For example, 127.0.0.1:3128 for a squid running on the exit. Exits only let clients reach local addresses that they were started with as their own --http-upstream.
+    http_upstream: Option<String>,
+
+    #[structopt(long, use_delimiter = true)]
+    /// comma-separated domains that SOCKS5 and HTTP connections go to directly instead of through the tunnel. *.example.com matches every subdomain of example.com.
+    direct_domains: Vec<String>,
+
+    #[structopt(long, use_delimiter = true)]
+    /// comma-separated CIDRs, like 10.0.0.0/8, that SOCKS5 and HTTP connections go to directly instead of through the tunnel. Only destinations given as IP addresses are matched.
+    direct_cidrs: Vec<String>,
+
+    #[structopt(long)]
+    /// username that SOCKS5 clients must authenticate with. Without it, SOCKS5 clients don't need to authenticate at all.
+    socks5_username: Option<String>,
+
+    #[structopt(long)]
+    /// password that SOCKS5 clients must authenticate with, along with the username
+    socks5_password: Option<String>,
+
+    #[structopt(long)]
+    /// set SO_REUSEADDR on bound sockets, so that a restarted client can rebind its ports immediately
+    reuse_addr: bool,
+
+    #[structopt(long)]
+    /// set SO_REUSEPORT on bound sockets where supported, so that several processes can share the listeners
+    reuse_port: bool,
+
+    #[structopt(long)]
+    /// receive buffer size of the UDP sockets carrying sessions, in KiB. Raising it helps against drops on fast, long-distance links, within what the OS allows
+    udp_recv_buffer_kb: Option<usize>,
+
+    #[structopt(long)]
+    /// send buffer size of the UDP sockets carrying sessions, in KiB
+    udp_send_buffer_kb: Option<usize>,
+
+    #[structopt(long)]
+    /// Linux only: set this firewall mark (SO_MARK) on the UDP sockets carrying sessions, so that policy routing can keep them out of a tunnel the client runs behind
+    socket_mark: Option<u32>,
+
+    #[structopt(long)]
+    /// Linux only: send the UDP sockets carrying sessions out through this interface (SO_BINDTODEVICE), whatever the routing table says
+    bind_device: Option<String>,
+
+    #[structopt(long)]
+    /// mark the UDP packets carrying sessions with this DSCP class, from 0 to 63, so that QoS along the way can prioritize them. 46 is for interactive traffic
+    dscp: Option<u8>,
 }
 
 pub async fn main_connect(opt: ConnectOpt) -> anyhow::Result<()> {
     log::info!("connect mode started");
+    sosistab::runtime::set_socket_reuse(sosistab::runtime::SocketReuse {
+        reuse_addr: opt.reuse_addr,
+        reuse_port: opt.reuse_port,
+    });
+    if opt.udp_recv_buffer_kb.is_some() || opt.udp_send_buffer_kb.is_some() {
+        // whichever isn't given stays at what debuffer uses for TCP
+        sosistab::runtime::set_udp_buffer_sizes(
+            opt.udp_recv_buffer_kb.unwrap_or(160) * 1024,
+            opt.udp_send_buffer_kb.unwrap_or(160) * 1024,
+        );
+    }
+    if (opt.socket_mark.is_some() || opt.bind_device.is_some()) && !cfg!(target_os = "linux") {
+        anyhow::bail!("--socket-mark and --bind-device are only supported on Linux")
+    }
+    if opt.dscp.map(|dscp| dscp > 63).unwrap_or(false) {
+        anyhow::bail!("--dscp must be from 0 to 63")
+    }
+    if opt.dns_max_inflight == 0 {
+        anyhow::bail!("--dns-max-inflight must be at least 1")
+    }
+    if opt.socket_mark.is_some() || opt.bind_device.is_some() || opt.dscp.is_some() {
+        set_socket_binding(sosistab::runtime::SocketBinding {
+            mark: opt.socket_mark,
+            device: opt.bind_device.clone(),
+            dscp: opt.dscp,
+        });
+    }
+    let socks5_credentials = match (opt.socks5_username, opt.socks5_password) {
+        (Some(username), Some(password)) => Some((username, password)),
+        (None, None) => None,
+        _ => anyhow::bail!("--socks5-username and --socks5-password must be given together"),
+    };
+    if let Some(upstream) = &opt.http_upstream {
+        check_host_port(upstream).context("invalid --http-upstream")?;
+    }
+    let http_upstream = opt.http_upstream.clone();
+    for upstream in opt.dns_upstream.iter() {
+        check_host_port(upstream).context("invalid --dns-upstream")?;
+    }
+    let dns_doh = match &opt.dns_doh {
+        Some(url) => Some(DohClient::new(url).context("invalid --dns-doh")?),
+        None => None,
+    };
+    let dns_upstreams = if opt.dns_upstream.is_empty() {
+        vec![DEFAULT_DNS_UPSTREAM.to_string()]
+    } else {
+        opt.dns_upstream.clone()
+    };
+    let routing = RoutingPolicy::new(&opt.direct_domains, &opt.direct_cidrs)?;
+    // a PAC file can only point browsers at a TCP proxy
+    let pac = match &opt.http_listen {
+        ListenAddr::Tcp(addr) => Some(routing.to_pac(&format!("127.0.0.1:{}", addr.port()))),
+        #[cfg(unix)]
+        ListenAddr::Unix(_) => None,
+    };
     let stat_collector = Arc::new(StatCollector::default());
     // create a db directory if doesn't exist
-    let client_cache = ClientCache::from_opts(&opt.common, &opt.auth)?;
+    let mut client_cache = ClientCache::from_opts(&opt.common, &opt.auth)?;
+    client_cache.exit_ttl = Duration::from_secs(opt.exit_cache_secs);
+    client_cache.bridge_ttl = Duration::from_secs(opt.bridge_cache_secs);
+    let client_cache = Arc::new(client_cache);
+    let ccache = client_cache.clone();
+    let exit_selection = match &opt.fastest_exit {
+        Some(region) => ExitSelection::fastest_in(region),
+        None => ExitSelection::Hostname(opt.exit_server.clone()),
+    };
     // create a kalive
     let keepalive = Keepalive::new(
         stat_collector.clone(),
-        &opt.exit_server,
+        exit_selection,
         opt.use_bridges,
-        Arc::new(client_cache),
+        BridgeRanking {
+            grace: Duration::from_millis(opt.bridge_grace_ms),
+            stagger: Duration::from_millis(opt.bridge_stagger_ms),
+            candidates: opt.bridge_candidates.max(1),
+        },
+        opt.transport,
+        opt.one_shot,
+        ReconnectBackoff {
+            base: Duration::from_millis(opt.reconnect_base_ms),
+            max: Duration::from_secs(opt.reconnect_max_secs),
+            ..Default::default()
+        },
+        if opt.pin_exit {
+            Some(Duration::from_secs(opt.pin_exit_fallback_secs))
+        } else {
+            None
+        },
+        opt.bond.max(1),
+        opt.standby,
+        client_cache.clone(),
+        Arc::new(TokenAuthenticator::new(client_cache)),
     );
     // enter the socks5 loop
-    let socks5_listener = smol::net::TcpListener::bind(opt.socks5_listen).await?;
-    let stat_listener = smol::net::TcpListener::bind(opt.stats_listen).await?;
-    let http_listener = smol::net::TcpListener::bind(opt.http_listen).await?;
+    let socks5_listener = LocalListener::bind(&opt.socks5_listen).await?;
+    let stat_listener = LocalListener::bind(&opt.stats_listen).await?;
+    let http_listener = LocalListener::bind(&opt.http_listen).await?;
     let scollect = stat_collector.clone();
+    let user_timeout = opt.tcp_user_timeout.map(Duration::from_millis);
+    // asked for through /kill
+    let (send_shutdown, recv_shutdown) = smol::channel::bounded(1);
     // scope
     let scope = smol::Executor::new();
+    if opt.one_shot {
+        let keepalive = &keepalive;
+        scope
+            .spawn(async move {
+                let err = keepalive.wait_fatal().await;
+                log::error!("giving up after one try ({}): {:?}", err.kind, err.error);
+                std::process::exit(err.kind.exit_code())
+            })
+            .detach();
+    }
     if let Some(dns_listen) = opt.dns_listen {
-        scope.spawn(dns_loop(dns_listen, &keepalive)).detach();
+        let stat_collector = &stat_collector;
+        let keepalive = &keepalive;
+        let dns_upstreams = &dns_upstreams;
+        let dns_doh = dns_doh.as_ref();
+        let retries = opt.dns_bind_retries;
+        let max_inflight = opt.dns_max_inflight;
+        scope
+            .spawn(async move {
+                let socket = match bind_dns_socket(dns_listen, retries, stat_collector).await {
+                    Some(socket) => socket,
+                    None => return,
+                };
+                if let Err(err) =
+                    dns_loop(socket, keepalive, dns_upstreams, dns_doh, max_inflight).await
+                {
+                    log::error!("DNS proxy on {} stopped: {}", dns_listen, err);
+                    stat_collector.set_dns_error(Some(err.to_string()));
+                }
+            })
+            .detach();
     }
     let _stat: smol::Task<anyhow::Result<()>> = scope.spawn(async {
         let my_scope = smol::Executor::new();
         my_scope
             .run(async {
                 loop {
-                    let (stat_client, _) = stat_listener.accept().await?;
+                    let stat_client = stat_listener.accept().await?;
                     let scollect = scollect.clone();
                     let keepalive = &keepalive;
+                    let pac = pac.as_deref();
+                    let ccache = &ccache;
+                    let send_shutdown = &send_shutdown;
                     my_scope
                         .spawn(async move {
                             drop(
                                 async_h1::accept(stat_client, |req| {
-                                    handle_stats(scollect.clone(), keepalive, req)
+                                    handle_stats(
+                                        scollect.clone(),
+                                        keepalive,
+                                        ccache,
+                                        pac,
+                                        send_shutdown,
+                                        req,
+                                    )
                                 })
                                 .await,
                             );
@@ -93,22 +360,49 @@ pub async fn main_connect(opt: ConnectOpt) -> anyhow::Result<()> {
         my_scope
             .run(async {
                 loop {
-                    let (http_client, _) = http_listener.accept().await?;
+                    let http_client = http_listener.accept().await?;
                     my_scope
-                        .spawn(handle_http(stat_collector.clone(), http_client, &keepalive))
+                        .spawn(handle_http(
+                            stat_collector.clone(),
+                            http_client,
+                            &keepalive,
+                            user_timeout,
+                            http_upstream.as_deref(),
+                            &routing,
+                        ))
                         .detach();
                 }
             })
             .await
     });
+    // returning drops the accept loops, and then the keepalive
     scope
         .run(async {
-            loop {
-                let (s5client, _) = socks5_listener.accept().await?;
-                scope
-                    .spawn(handle_socks5(stat_collector.clone(), s5client, &keepalive))
-                    .detach()
-            }
+            let accept = async {
+                loop {
+                    let s5client = socks5_listener.accept().await?;
+                    scope
+                        .spawn(handle_socks5(
+                            stat_collector.clone(),
+                            s5client,
+                            &keepalive,
+                            user_timeout,
+                            socks5_credentials.as_ref(),
+                            &routing,
+                        ))
+                        .detach()
+                }
+            };
+            let shutdown = async {
+                drop(recv_shutdown.recv().await);
+                // give the /kill response time to get out
+                smol::Timer::after(SHUTDOWN_GRACE).await;
+                log::info!("shutting down");
+                // open connections keep being served while the tunnel drains
+                keepalive.shutdown().await;
+                Ok(())
+            };
+            accept.or(shutdown).await
         })
         .await
 }
@@ -119,6 +413,9 @@ use std::io::prelude::*;
 async fn handle_stats(
     stats: Arc<StatCollector>,
     kalive: &Keepalive,
+    ccache: &ClientCache,
+    pac: Option<&str>,
+    shutdown: &smol::channel::Sender<()>,
     _req: http_types::Request,
 ) -> http_types::Result<http_types::Response> {
     let mut res = http_types::Response::new(http_types::StatusCode::Ok);
@@ -140,7 +437,7 @@ async fn handle_stats(
                     writeln!(
                         sosistab_buf,
                         "{},{}",
-                        time.saturating_duration_since(*first_time).as_secs_f64(),
+                        time.saturating_duration_since(first_time).as_secs_f64(),
                         seqno
                     )?;
                 }
@@ -160,6 +457,16 @@ async fn handle_stats(
             logs_header.set_mode(0o666);
             logs_header.set_size(logs_buffer.len() as u64);
             tar_build.append_data(&mut logs_header, "logs.txt", logs_buffer.as_slice())?;
+            let mut meta = debug_meta(&stats, kalive.use_bridges())?;
+            meta.push_str(&format!(
+                "down jitter: {} ms\ndown reorder rate: {:.4}\n",
+                detail.down_jitter.as_secs_f64() * 1000.0,
+                detail.down_reorder_rate
+            ));
+            let mut meta_header = tar::Header::new_gnu();
+            meta_header.set_mode(0o666);
+            meta_header.set_size(meta.len() as u64);
+            tar_build.append_data(&mut meta_header, "meta.txt", meta.as_bytes())?;
             let result = tar_build.into_inner()?;
             res.insert_header("content-type", "application/tar");
             res.insert_header(
@@ -172,13 +479,98 @@ async fn handle_stats(
             res.set_body(result);
             Ok(res)
         }
-        "/proxy.pac" => {
-            res.set_body("function FindProxyForURL(url, host){return 'PROXY 127.0.0.1:9910';}");
+        "/proxy.pac" => match pac {
+            Some(pac) => {
+                res.set_body(pac);
+                res.insert_header("Content-Type", "application/x-ns-proxy-autoconfig");
+                Ok(res)
+            }
+            None => {
+                res.set_status(http_types::StatusCode::NotFound);
+                res.set_body("no PAC file, since the HTTP proxy listens on a Unix socket");
+                Ok(res)
+            }
+        },
+        "/conns" => {
+            res.set_body(serde_json::to_string(&stats.conns())?);
+            res.insert_header("Content-Type", "application/json");
+            Ok(res)
+        }
+        "/latencies" => {
+            res.set_body(serde_json::to_string(&ccache.history().latencies())?);
+            res.insert_header("Content-Type", "application/json");
+            Ok(res)
+        }
+        "/healthz" => {
+            if let Some(err) = stats.dns_error() {
+                res.set_status(http_types::StatusCode::ServiceUnavailable);
+                res.set_body(format!("DNS proxy down: {}", err));
+            } else if stats.is_connected() {
+                res.set_body("connected");
+            } else {
+                res.set_status(http_types::StatusCode::ServiceUnavailable);
+                let status = match stats.last_failure() {
+                    Some(kind) => format!("not connected: {}", kind),
+                    None => "connecting".to_string(),
+                };
+                match stats.reconnect_in() {
+                    Some(wait) => res.set_body(format!(
+                        "{}; reconnecting in {}s",
+                        status,
+                        wait.as_secs_f64().ceil()
+                    )),
+                    None => res.set_body(status),
+                }
+            }
+            Ok(res)
+        }
+        "/metrics" => {
+            let session = if stats.is_connected() {
+                kalive
+                    .get_stats()
+                    .timeout(Duration::from_secs(1))
+                    .await
+                    .and_then(|stats| stats.ok())
+            } else {
+                None
+            };
+            res.set_body(stats.to_prometheus(session.as_ref()));
+            res.insert_header("Content-Type", "text/plain; version=0.0.4");
+            Ok(res)
+        }
+        "/transport" => {
+            // switching goes through a query string, so that it's as easy to do as /kill
+            if let Some((_, set)) = _req.url().query_pairs().find(|(k, _)| k == "set") {
+                match set.parse::<Transport>() {
+                    Ok(transport) => kalive.set_transport(transport),
+                    Err(err) => {
+                        res.set_status(http_types::StatusCode::BadRequest);
+                        res.set_body(err.to_string());
+                        return Ok(res);
+                    }
+                }
+            }
+            res.set_body(kalive.transport().to_string());
+            Ok(res)
+        }
+        "/refresh" => {
+            // the next session gets a fresh list, while the current one carries on
+            ccache.invalidate_directory();
+            res.set_body("exits and bridges will be fetched again");
+            Ok(res)
+        }
+        "/kill" => {
+            drop(shutdown.try_send(()));
+            res.set_body("shutting down");
             Ok(res)
         }
-        "/kill" => std::process::exit(0),
         _ => {
-            let jstats = serde_json::to_string(&stats)?;
+            let mut jstats = serde_json::to_value(&*stats)?;
+            jstats["reconnect_in_secs"] =
+                serde_json::json!(stats.reconnect_in().map(|wait| wait.as_secs_f64()));
+            jstats["rx_bps"] = serde_json::json!(stats.rx_bps());
+            jstats["tx_bps"] = serde_json::json!(stats.tx_bps());
+            let jstats = serde_json::to_string(&jstats)?;
             res.set_body(jstats);
             res.insert_header("Content-Type", "application/json");
             Ok(res)
@@ -186,81 +578,190 @@ async fn handle_stats(
     }
 }
 
-/// Handle DNS requests from localhost
-async fn dns_loop(addr: SocketAddr, keepalive: &Keepalive) -> anyhow::Result<()> {
-    let socket = smol::net::UdpSocket::bind(addr).await?;
-    let mut buf = [0; 2048];
-    let (send_conn, recv_conn) = smol::channel::unbounded();
-    let scope = smol::Executor::new();
+/// Describes the client and its configuration, so that bug reports come with it.
+fn debug_meta(stats: &StatCollector, use_bridges: bool) -> anyhow::Result<String> {
+    let mut meta = String::new();
+    use std::fmt::Write;
+    writeln!(meta, "version: {}", env!("CARGO_PKG_VERSION"))?;
+    writeln!(
+        meta,
+        "platform: {}-{}",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    )?;
+    writeln!(meta, "use_bridges: {}", use_bridges)?;
+    match stats.exit_descriptor() {
+        Some(exit) => {
+            writeln!(meta, "exit: {}", exit.hostname)?;
+            let digest = Sha256::digest(exit.sosistab_key.as_bytes());
+            writeln!(meta, "exit key fingerprint: {}", hex::encode(&digest[..8]))?;
+        }
+        None => writeln!(meta, "exit: not connected")?,
+    }
+    writeln!(meta, "stats: {}", serde_json::to_string(stats)?)?;
+    Ok(meta)
+}
+
+/// How long /kill waits before tearing everything down.
+const SHUTDOWN_GRACE: Duration = Duration::from_millis(200);
+
+const DEFAULT_DNS_UPSTREAM: &str = "ordns.he.net:53";
+
+const DNS_BIND_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Binds the socket that the DNS proxy listens on, trying again up to the given number of times. Every failure is logged, and if the last try fails too, it is recorded in the stats, so that the DNS proxy being down shows up in /healthz.
+async fn bind_dns_socket(
+    addr: SocketAddr,
+    retries: u32,
+    stats: &StatCollector,
+) -> Option<smol::net::UdpSocket> {
+    for i in 0..=retries {
+        if i > 0 {
+            smol::Timer::after(DNS_BIND_RETRY_INTERVAL).await;
+        }
+        match smol::net::UdpSocket::bind(addr).await {
+            Ok(socket) => {
+                stats.set_dns_error(None);
+                return Some(socket);
+            }
+            Err(err) => {
+                log::error!(
+                    "could not bind DNS proxy to {} (try {} of {}): {}",
+                    addr,
+                    i + 1,
+                    retries + 1,
+                    err
+                );
+                stats.set_dns_error(Some(format!("could not bind to {}: {}", addr, err)));
+            }
+        }
+    }
+    None
+}
+
+/// Handle DNS requests from localhost, spreading them across the upstream resolvers, or sending them all to the DNS-over-HTTPS resolver if there is one
+async fn dns_loop(
+    socket: smol::net::UdpSocket,
+    keepalive: &Keepalive,
+    upstreams: &[String],
+    doh: Option<&DohClient>,
+    max_inflight: usize,
+) -> anyhow::Result<()> {
+    // idle connections to every upstream, for reuse
+    let pools: Vec<_> = upstreams
+        .iter()
+        .map(|_| smol::channel::unbounded())
+        .collect();
+    let next_upstream = AtomicUsize::new(0);
     let dns_timeout = Duration::from_secs(1);
-    scope
-        .run(async {
-            loop {
-                let (n, c_addr) = socket.recv_from(&mut buf).await?;
-                let buff = buf[..n].to_vec();
-                let socket = &socket;
-                let recv_conn = &recv_conn;
-                let send_conn = &send_conn;
-                scope
-                    .spawn(async move {
-                        let fut = || async {
-                            let mut conn = {
-                                let lala = recv_conn.try_recv();
-                                match lala {
-                                    Ok(v) => v,
-                                    _ => keepalive
-                                        .connect("ordns.he.net:53")
-                                        .timeout(dns_timeout)
-                                        .await?
-                                        .ok()?,
-                                }
-                            };
-                            conn.write_all(&(buff.len() as u16).to_be_bytes())
-                                .timeout(dns_timeout)
-                                .await?
-                                .ok()?;
-                            conn.write_all(&buff).timeout(dns_timeout).await?.ok()?;
-                            conn.flush().timeout(dns_timeout).await?.ok()?;
-                            let mut n_buf = [0; 2];
-                            conn.read_exact(&mut n_buf)
-                                .timeout(dns_timeout)
-                                .await?
-                                .ok()?;
-                            let mut true_buf = vec![0u8; u16::from_be_bytes(n_buf) as usize];
-                            conn.read_exact(&mut true_buf)
-                                .timeout(dns_timeout)
-                                .await?
-                                .ok()?;
-                            socket.send_to(&true_buf, c_addr).await.ok()?;
-                            send_conn.send(conn).await.ok()?;
-                            Some(())
-                        };
-                        for i in 0u32..5 {
-                            if fut().await.is_some() {
-                                log::debug!("DNS request succeeded on try {}", i);
-                                return;
-                            }
-                        }
-                    })
-                    .detach();
+    let cache = DnsCache::default();
+    let pools = &pools;
+    let next_upstream = &next_upstream;
+    serve_dns(&socket, &cache, max_inflight, |buff| async move {
+        if let Some(doh) = doh {
+            for i in 0..5 {
+                match doh.query(keepalive, &buff, dns_timeout).await {
+                    Ok(response) => {
+                        log::debug!("DoH request succeeded on try {}", i);
+                        return Some(response);
+                    }
+                    Err(err) => log::debug!("DoH request failed: {}", err),
+                }
             }
-        })
-        .await
+            return None;
+        }
+        let first_upstream = next_upstream.fetch_add(1, Ordering::Relaxed);
+        let buff = &buff;
+        let fut = |i: usize| async move {
+            let (send_conn, recv_conn) = &pools[i];
+            let mut conn = {
+                let lala = recv_conn.try_recv();
+                match lala {
+                    Ok(v) => v,
+                    _ => {
+                        let mut conn = keepalive
+                            .connect(&upstreams[i])
+                            .timeout(dns_timeout)
+                            .await?
+                            .ok()?;
+                        conn.set_read_timeout(dns_timeout);
+                        conn.set_write_timeout(dns_timeout);
+                        conn
+                    }
+                }
+            };
+            conn.write_all(&(buff.len() as u16).to_be_bytes())
+                .await
+                .ok()?;
+            conn.write_all(&buff).await.ok()?;
+            conn.flush().await.ok()?;
+            let mut n_buf = [0; 2];
+            conn.read_exact(&mut n_buf).await.ok()?;
+            let mut true_buf = vec![0u8; u16::from_be_bytes(n_buf) as usize];
+            conn.read_exact(&mut true_buf).await.ok()?;
+            send_conn.send(conn).await.ok()?;
+            Some(true_buf)
+        };
+        for i in 0..5 {
+            // round-robin, and on to the next upstream whenever one fails
+            let upstream = (first_upstream + i) % upstreams.len();
+            if let Some(response) = fut(upstream).await {
+                log::debug!(
+                    "DNS request succeeded on try {} through {}",
+                    i,
+                    upstreams[upstream]
+                );
+                return Some(response);
+            }
+        }
+        None
+    })
+    .await
 }
 
 /// Handle a socks5 client from localhost.
 async fn handle_socks5(
     stats: Arc<StatCollector>,
-    s5client: smol::net::TcpStream,
+    s5client: LocalStream,
     keepalive: &Keepalive,
+    user_timeout: Option<Duration>,
+    credentials: Option<&(String, String)>,
+    routing: &RoutingPolicy,
 ) -> anyhow::Result<()> {
-    let s5client = debuffer(s5client);
+    let s5client = debuffer_local(s5client, user_timeout);
     stats.incr_open_conns();
     defer!(stats.decr_open_conns());
+    let conn_id = stats.add_conn();
+    defer!(stats.remove_conn(conn_id));
     use socksv5::v5::*;
-    let _handshake = read_handshake(s5client.clone()).await?;
-    write_auth_method(s5client.clone(), SocksV5AuthMethod::Noauth).await?;
+    let handshake = read_handshake(s5client.clone()).await?;
+    if let Some((username, password)) = credentials {
+        if !handshake
+            .methods
+            .iter()
+            .any(|m| matches!(m, SocksV5AuthMethod::UsernamePassword))
+        {
+            write_auth_method(s5client.clone(), SocksV5AuthMethod::NoAcceptableMethod).await?;
+            anyhow::bail!("SOCKS5 client doesn't support password authentication")
+        }
+        write_auth_method(s5client.clone(), SocksV5AuthMethod::UsernamePassword).await?;
+        let (given_username, given_password) = read_userpass(s5client.clone()).await?;
+        let accepted = &given_username == username && &given_password == password;
+        // version 1 of the subnegotiation, then 0 for success
+        let mut s5client = s5client.clone();
+        s5client
+            .write_all(&[1, if accepted { 0 } else { 1 }])
+            .await?;
+        if !accepted {
+            anyhow::bail!("SOCKS5 client gave wrong credentials")
+        }
+    } else {
+        write_auth_method(s5client.clone(), SocksV5AuthMethod::Noauth).await?;
+    }
     let request = read_request(s5client.clone()).await?;
+    if let SocksV5Command::UdpAssociate = request.command {
+        return handle_udp_associate(&stats, s5client, keepalive).await;
+    }
     let port = request.port;
     let addr: String = match &request.host {
         SocksV5Host::Domain(dom) => format!("{}:{}", String::from_utf8_lossy(&dom), request.port),
@@ -278,45 +779,635 @@ async fn handle_socks5(
         port,
     )
     .await?;
+    let direct = routing.is_direct(&addr);
+    stats.set_conn_destination(conn_id, &addr, direct);
+    if direct {
+        log::debug!("connecting to {} directly", addr);
+        let conn = connect_direct(&addr).await?;
+        smol::future::race(
+            aioutils::copy_with_stats(conn.clone(), s5client.clone(), |n| {
+                stats.incr_conn_rx(conn_id, n as u64)
+            }),
+            aioutils::copy_with_stats(s5client, conn, |n| stats.incr_conn_tx(conn_id, n as u64)),
+        )
+        .await?;
+        return Ok(());
+    }
     let conn = keepalive.connect(&addr).await?;
-    smol::future::race(
+    let abort_handle = conn.clone();
+    let res = smol::future::race(
         aioutils::copy_with_stats(conn.clone(), s5client.clone(), |n| {
-            stats.incr_total_rx(n as u64)
+            stats.incr_total_rx(n as u64);
+            stats.incr_conn_rx(conn_id, n as u64)
+        }),
+        aioutils::copy_with_stats(s5client, conn, |n| {
+            stats.incr_total_tx(n as u64);
+            stats.incr_conn_tx(conn_id, n as u64)
         }),
-        aioutils::copy_with_stats(s5client, conn, |n| stats.incr_total_tx(n as u64)),
     )
-    .await?;
+    .await;
+    if res.is_err() {
+        // tear down the remote end right away instead of leaving it to time out
+        abort_handle.reset();
+    }
+    res?;
     Ok(())
 }
 
+/// Reads the username and password a SOCKS5 client authenticates with, as described in RFC 1929.
+async fn read_userpass(mut reader: impl AsyncRead + Unpin) -> anyhow::Result<(String, String)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    if header[0] != 1 {
+        anyhow::bail!(
+            "unknown SOCKS5 password subnegotiation version {}",
+            header[0]
+        )
+    }
+    let mut username = vec![0u8; header[1] as usize];
+    reader.read_exact(&mut username).await?;
+    let mut password_len = [0u8; 1];
+    reader.read_exact(&mut password_len).await?;
+    let mut password = vec![0u8; password_len[0] as usize];
+    reader.read_exact(&mut password).await?;
+    Ok((String::from_utf8(username)?, String::from_utf8(password)?))
+}
+
+/// Handle a SOCKS5 UDP ASSOCIATE request, relaying datagrams through the exit until the control connection closes.
+async fn handle_udp_associate(
+    stats: &StatCollector,
+    s5client: LocalStream,
+    keepalive: &Keepalive,
+) -> anyhow::Result<()> {
+    use socksv5::v5::*;
+    let tcp_client = match &s5client {
+        LocalStream::Tcp(conn) => conn,
+        #[cfg(unix)]
+        LocalStream::Unix(_) => {
+            write_request_status(
+                s5client.clone(),
+                SocksV5RequestStatus::CommandNotSupported,
+                SocksV5Host::Ipv4([0, 0, 0, 0]),
+                0,
+            )
+            .await?;
+            anyhow::bail!("UDP ASSOCIATE needs a TCP control connection")
+        }
+    };
+    let relay =
+        smol::net::UdpSocket::bind(SocketAddr::new(tcp_client.local_addr()?.ip(), 0)).await?;
+    let relay_addr = relay.local_addr()?;
+    let relay_host = match relay_addr.ip() {
+        IpAddr::V4(v4) => SocksV5Host::Ipv4(v4.octets()),
+        IpAddr::V6(v6) => SocksV5Host::Ipv6(v6.octets()),
+    };
+    write_request_status(
+        s5client.clone(),
+        SocksV5RequestStatus::Success,
+        relay_host,
+        relay_addr.port(),
+    )
+    .await?;
+    let assoc = keepalive.udp_associate();
+    // only whoever is on the other end of the control connection may use the relay
+    let client_ip = tcp_client.peer_addr()?.ip();
+    let client_addr = parking_lot::Mutex::new(None);
+    let upload = async {
+        let mut buf = [0u8; 65536];
+        loop {
+            let (n, from) = relay.recv_from(&mut buf).await?;
+            if from.ip() != client_ip {
+                continue;
+            }
+            *client_addr.lock() = Some(from);
+            if let Some((remote, payload)) = parse_udp_request(&buf[..n]) {
+                stats.incr_total_tx(payload.len() as u64);
+                assoc.send_to(&remote, payload);
+            }
+        }
+    };
+    let download = async {
+        loop {
+            let (source, payload) = assoc.recv_from().await?;
+            let client = *client_addr.lock();
+            if let (Some(client), Ok(source)) = (client, source.parse()) {
+                stats.incr_total_rx(payload.len() as u64);
+                let mut datagram = udp_header(source);
+                datagram.extend_from_slice(&payload);
+                relay.send_to(&datagram, client).await?;
+            }
+        }
+    };
+    // the association lasts exactly as long as the control connection
+    let control = async {
+        let mut s5client = s5client;
+        let mut buf = [0u8; 128];
+        while s5client.read(&mut buf).await? > 0 {}
+        Ok(())
+    };
+    upload.or(download).or(control).await
+}
+
+/// Parses the header in front of every datagram sent to a SOCKS5 UDP relay, returning the destination and the payload. Fragmented datagrams aren't supported, so they're dropped.
+fn parse_udp_request(buf: &[u8]) -> Option<(String, &[u8])> {
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+    let (host, rest) = match buf[3] {
+        1 => {
+            let v4: [u8; 4] = buf.get(4..8)?.try_into().ok()?;
+            (Ipv4Addr::from(v4).to_string(), &buf[8..])
+        }
+        3 => {
+            let len = *buf.get(4)? as usize;
+            let domain = buf.get(5..5 + len)?;
+            (String::from_utf8_lossy(domain).to_string(), &buf[5 + len..])
+        }
+        4 => {
+            let v6: [u8; 16] = buf.get(4..20)?.try_into().ok()?;
+            (format!("[{}]", Ipv6Addr::from(v6)), &buf[20..])
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?);
+    Some((format!("{}:{}", host, port), &rest[2..]))
+}
+
+/// Builds the header in front of every datagram a SOCKS5 UDP relay sends back, given where the datagram came from.
+fn udp_header(source: SocketAddr) -> Vec<u8> {
+    let mut header = vec![0, 0, 0];
+    match source.ip() {
+        IpAddr::V4(v4) => {
+            header.push(1);
+            header.extend_from_slice(&v4.octets());
+        }
+        IpAddr::V6(v6) => {
+            header.push(4);
+            header.extend_from_slice(&v6.octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header
+}
+
 /// Handle a HTTP client from localhost.
 async fn handle_http(
     stats: Arc<StatCollector>,
-    hclient: smol::net::TcpStream,
+    hclient: LocalStream,
     keepalive: &Keepalive,
+    user_timeout: Option<Duration>,
+    upstream: Option<&str>,
+    routing: &RoutingPolicy,
 ) -> anyhow::Result<()> {
-    let hclient = debuffer(hclient);
+    let hclient = debuffer_local(hclient, user_timeout);
     stats.incr_open_conns();
     defer!(stats.decr_open_conns());
-    // Rely on "squid" remotely
-    let conn = keepalive.connect("127.0.0.1:3128").await?;
+    let conn_id = stats.add_conn();
+    defer!(stats.remove_conn(conn_id));
+    if let Some(upstream) = upstream {
+        stats.set_conn_destination(conn_id, upstream, false);
+        return relay_http_upstream(&stats, conn_id, hclient, keepalive, upstream).await;
+    }
+    // buffered, since a CONNECT client may send its first bytes right behind the request
+    let mut reader = smol::io::BufReader::new(hclient.clone());
+    let head = read_http_head(&mut reader).await?;
+    let request = match parse_http_request(&head) {
+        Ok(request) => request,
+        Err(err) => {
+            hclient
+                .clone()
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Err(err);
+        }
+    };
+    let direct = routing.is_direct(&request.host);
+    stats.set_conn_destination(conn_id, &request.host, direct);
+    if direct {
+        log::debug!("connecting to {} directly", request.host);
+        let conn = connect_direct(&request.host).await;
+        return forward_http(&stats, conn_id, true, hclient, reader, conn, &request).await;
+    }
+    let conn = keepalive.connect(&request.host).await;
+    forward_http(&stats, conn_id, false, hclient, reader, conn, &request).await
+}
+
+/// Carries out a parsed HTTP proxy request over a connection to its destination. Traffic of direct connections is counted only for the connection itself, and not in the tunnel totals.
+async fn forward_http<C: AsyncRead + AsyncWrite + Clone + Unpin>(
+    stats: &StatCollector,
+    conn_id: u64,
+    direct: bool,
+    hclient: LocalStream,
+    reader: impl AsyncRead + Unpin,
+    conn: anyhow::Result<C>,
+    request: &HttpRequest,
+) -> anyhow::Result<()> {
+    let mut conn = match conn {
+        Ok(conn) => conn,
+        Err(err) => {
+            hclient
+                .clone()
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Err(err);
+        }
+    };
+    match &request.forward {
+        Some(forward) => conn.write_all(forward).await?,
+        None => {
+            hclient
+                .clone()
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?
+        }
+    }
+    smol::future::race(
+        aioutils::copy_with_stats(conn.clone(), hclient, |n| {
+            if !direct {
+                stats.incr_total_rx(n as u64)
+            }
+            stats.incr_conn_rx(conn_id, n as u64)
+        }),
+        aioutils::copy_with_stats(reader, conn, |n| {
+            if !direct {
+                stats.incr_total_tx(n as u64)
+            }
+            stats.incr_conn_tx(conn_id, n as u64)
+        }),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Connects to a destination directly, bypassing the tunnel.
+async fn connect_direct(addr: &str) -> anyhow::Result<smol::net::TcpStream> {
+    smol::net::TcpStream::connect(addr)
+        .timeout(Duration::from_secs(10))
+        .await
+        .ok_or_else(|| anyhow::anyhow!("timed out connecting to {} directly", addr))?
+        .with_context(|| format!("cannot connect to {} directly", addr))
+}
+
+/// Whether an HTTP proxy request has been passed on to the upstream yet.
+static UPSTREAM_TRIED: AtomicBool = AtomicBool::new(false);
+
+/// Passes an HTTP proxy connection on, untouched, to an upstream HTTP proxy.
+async fn relay_http_upstream(
+    stats: &StatCollector,
+    conn_id: u64,
+    hclient: LocalStream,
+    keepalive: &Keepalive,
+    upstream: &str,
+) -> anyhow::Result<()> {
+    let first = !UPSTREAM_TRIED.swap(true, Ordering::SeqCst);
+    let conn = match keepalive.connect(upstream).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            if first {
+                log::error!(
+                    "HTTP upstream {} is unreachable from the exit ({}); HTTP proxy requests will fail until it is up",
+                    upstream,
+                    err
+                );
+            }
+            return Err(err);
+        }
+    };
     smol::future::race(
         aioutils::copy_with_stats(conn.clone(), hclient.clone(), |n| {
-            stats.incr_total_rx(n as u64)
+            stats.incr_total_rx(n as u64);
+            stats.incr_conn_rx(conn_id, n as u64)
+        }),
+        aioutils::copy_with_stats(hclient, conn, |n| {
+            stats.incr_total_tx(n as u64);
+            stats.incr_conn_tx(conn_id, n as u64)
         }),
-        aioutils::copy_with_stats(hclient, conn, |n| stats.incr_total_tx(n as u64)),
     )
     .await?;
     Ok(())
 }
 
-/// Smallify the buffers for a TCP connection
-fn debuffer(conn: async_net::TcpStream) -> async_net::TcpStream {
+/// Checks that an address has the host:port form.
+fn check_host_port(addr: &str) -> anyhow::Result<()> {
+    let colon = addr
+        .rfind(':')
+        .ok_or_else(|| anyhow::anyhow!("{:?} has no port", addr))?;
+    if colon == 0 {
+        anyhow::bail!("{:?} has no host", addr)
+    }
+    addr[colon + 1..]
+        .parse::<u16>()
+        .with_context(|| format!("{:?} has an invalid port", addr))?;
+    Ok(())
+}
+
+/// Longest request head we accept from an HTTP proxy client.
+const MAX_HTTP_HEAD: usize = 16384;
+
+/// Reads an HTTP request line and headers, up to and including the empty line.
+async fn read_http_head(reader: &mut (impl AsyncBufRead + Unpin)) -> anyhow::Result<String> {
+    let mut head = String::new();
+    loop {
+        let n = reader.read_line(&mut head).await?;
+        if n == 0 {
+            anyhow::bail!("HTTP client hung up in the middle of a request")
+        }
+        if head.len() > MAX_HTTP_HEAD {
+            anyhow::bail!("HTTP request head too long")
+        }
+        if head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+            return Ok(head);
+        }
+    }
+}
+
+/// What an HTTP proxy client is asking for.
+#[derive(Debug, PartialEq, Eq)]
+struct HttpRequest {
+    /// The host:port to connect to.
+    host: String,
+    /// For plain requests, the rewritten request head to send to the origin server. None for CONNECT.
+    forward: Option<Vec<u8>>,
+}
+
+/// Parses a proxy request head. CONNECT requests tunnel to their target, while requests with an absolute URI are rewritten into the origin form. Only one plain request is forwarded per connection, so `Connection: close` is forced.
+fn parse_http_request(head: &str) -> anyhow::Result<HttpRequest> {
+    let mut lines = head.lines();
+    let request_line = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("empty HTTP request"))?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) => (method, target, version),
+        _ => anyhow::bail!("malformed HTTP request line {:?}", request_line),
+    };
+    if method.eq_ignore_ascii_case("CONNECT") {
+        if !target.contains(':') {
+            anyhow::bail!("CONNECT target {:?} has no port", target)
+        }
+        return Ok(HttpRequest {
+            host: target.to_string(),
+            forward: None,
+        });
+    }
+    let rest = target
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow::anyhow!("unsupported HTTP proxy target {:?}", target))?;
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        anyhow::bail!("HTTP proxy target {:?} has no host", target)
+    }
+    let host = if authority.rsplit(':').next().unwrap().parse::<u16>().is_ok() {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    let mut forward = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if name.eq_ignore_ascii_case("proxy-connection")
+            || name.eq_ignore_ascii_case("connection")
+            || name.eq_ignore_ascii_case("keep-alive")
+        {
+            continue;
+        }
+        forward.push_str(line);
+        forward.push_str("\r\n");
+    }
+    forward.push_str("Connection: close\r\n\r\n");
+    Ok(HttpRequest {
+        host,
+        forward: Some(forward.into_bytes()),
+    })
+}
+
+/// Debuffers a local connection if it's over TCP; Unix sockets have nothing to tune.
+fn debuffer_local(conn: LocalStream, user_timeout: Option<Duration>) -> LocalStream {
+    match conn {
+        LocalStream::Tcp(conn) => LocalStream::Tcp(debuffer(conn, user_timeout)),
+        #[cfg(unix)]
+        other => other,
+    }
+}
+
+/// Smallify the buffers for a TCP connection, and optionally bound how long unacknowledged data may linger
+fn debuffer(conn: async_net::TcpStream, user_timeout: Option<Duration>) -> async_net::TcpStream {
     let conn: Arc<smol::Async<std::net::TcpStream>> = conn.into();
     let conn: std::net::TcpStream = conn.get_ref().try_clone().unwrap();
     let conn: socket2::Socket = conn.into();
     conn.set_nodelay(true).unwrap();
     conn.set_recv_buffer_size(163840).unwrap();
     conn.set_send_buffer_size(163840).unwrap();
+    if let Some(user_timeout) = user_timeout {
+        if let Err(err) = set_tcp_user_timeout(&conn, user_timeout) {
+            log::warn!("could not set TCP_USER_TIMEOUT: {}", err);
+        }
+    }
     smol::Async::new(conn.into_tcp_stream()).unwrap().into()
 }
+
+#[cfg(target_os = "linux")]
+fn set_tcp_user_timeout(conn: &socket2::Socket, timeout: Duration) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let millis = timeout.as_millis().min(libc::c_uint::MAX as u128) as libc::c_uint;
+    let ret = unsafe {
+        libc::setsockopt(
+            conn.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+            &millis as *const libc::c_uint as *const libc::c_void,
+            std::mem::size_of::<libc::c_uint>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_tcp_user_timeout(_conn: &socket2::Socket, _timeout: Duration) -> std::io::Result<()> {
+    // this runs for every connection, so just say it once
+    static WARNED: std::sync::Once = std::sync::Once::new();
+    WARNED.call_once(|| log::warn!("--tcp-user-timeout is only supported on Linux, ignoring it"));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_meta_describes_client() {
+        let stats = StatCollector::default();
+        let meta = debug_meta(&stats, true).unwrap();
+        assert!(meta.contains(&format!("version: {}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(meta.contains("use_bridges: true\n"));
+        assert!(meta.contains("exit: not connected\n"));
+        stats.set_exit_descriptor(Some(binder_transport::ExitDescriptor {
+            hostname: "us-hio-01.exits.geph.io".into(),
+            signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+            country_code: "us".into(),
+            city_code: "hio".into(),
+            sosistab_key: x25519_dalek::PublicKey::from([0u8; 32]),
+        }));
+        let meta = debug_meta(&stats, false).unwrap();
+        assert!(meta.contains("exit: us-hio-01.exits.geph.io\n"));
+        assert!(meta.contains("exit key fingerprint: "));
+        assert!(meta.contains("\"hostname\":\"us-hio-01.exits.geph.io\""));
+    }
+
+    #[test]
+    fn dns_bind_failure_is_reported() {
+        smol::block_on(async {
+            let taken = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let addr = taken.local_addr().unwrap();
+            let stats = StatCollector::default();
+            assert!(bind_dns_socket(addr, 0, &stats).await.is_none());
+            let err = stats.dns_error().unwrap();
+            assert!(err.contains(&addr.to_string()));
+            // once the port is free, binding works and the error is cleared
+            drop(taken);
+            assert!(bind_dns_socket(addr, 0, &stats).await.is_some());
+            assert_eq!(stats.dns_error(), None);
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn debuffer_sets_user_timeout() {
+        use std::os::unix::io::AsRawFd;
+        smol::block_on(async {
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let _client = smol::net::TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let accepted = debuffer(accepted, Some(Duration::from_millis(5000)));
+            let accepted: Arc<smol::Async<std::net::TcpStream>> = accepted.into();
+            let mut millis: libc::c_uint = 0;
+            let mut len = std::mem::size_of::<libc::c_uint>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(
+                    accepted.get_ref().as_raw_fd(),
+                    libc::IPPROTO_TCP,
+                    libc::TCP_USER_TIMEOUT,
+                    &mut millis as *mut libc::c_uint as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            assert_eq!(ret, 0);
+            assert_eq!(millis, 5000);
+        })
+    }
+
+    #[test]
+    fn userpass_is_parsed() {
+        smol::block_on(async {
+            let mut request = vec![1, 5];
+            request.extend_from_slice(b"alice");
+            request.push(6);
+            request.extend_from_slice(b"hunter");
+            let (username, password) = read_userpass(smol::io::Cursor::new(request)).await.unwrap();
+            assert_eq!(username, "alice");
+            assert_eq!(password, "hunter");
+            // unknown versions and truncated requests are refused
+            assert!(read_userpass(smol::io::Cursor::new(vec![5, 0, 0]))
+                .await
+                .is_err());
+            assert!(read_userpass(smol::io::Cursor::new(vec![1, 5, b'a']))
+                .await
+                .is_err());
+        })
+    }
+
+    #[test]
+    fn http_requests_are_parsed() {
+        assert_eq!(
+            parse_http_request("CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+                .unwrap(),
+            HttpRequest {
+                host: "example.com:443".into(),
+                forward: None
+            }
+        );
+        let request = parse_http_request(
+            "GET http://example.com/index.html?q=1 HTTP/1.1\r\nHost: example.com\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(request.host, "example.com:80");
+        assert_eq!(
+            String::from_utf8(request.forward.unwrap()).unwrap(),
+            "GET /index.html?q=1 HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+        let request = parse_http_request("POST http://[::1]:8080 HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(request.host, "[::1]:8080");
+        assert_eq!(
+            request.forward.unwrap(),
+            b"POST / HTTP/1.0\r\nConnection: close\r\n\r\n"
+        );
+        assert_eq!(
+            parse_http_request("GET http://[::1] HTTP/1.1\r\n\r\n")
+                .unwrap()
+                .host,
+            "[::1]:80"
+        );
+        // origin-form and TLS targets are not proxy requests we can handle
+        assert!(parse_http_request("GET / HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_http_request("GET https://example.com/ HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_http_request("CONNECT example.com HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn upstream_addresses_are_checked() {
+        assert!(check_host_port("127.0.0.1:3128").is_ok());
+        assert!(check_host_port("squid.internal:8080").is_ok());
+        assert!(check_host_port("[::1]:3128").is_ok());
+        assert!(check_host_port("127.0.0.1").is_err());
+        assert!(check_host_port(":3128").is_err());
+        assert!(check_host_port("127.0.0.1:squid").is_err());
+        assert!(check_host_port("127.0.0.1:70000").is_err());
+    }
+
+    #[test]
+    fn http_head_stops_at_empty_line() {
+        smol::block_on(async {
+            let mut reader = smol::io::BufReader::new(smol::io::Cursor::new(
+                b"CONNECT a:1 HTTP/1.1\r\nHost: a:1\r\n\r\n\x16\x03\x01".to_vec(),
+            ));
+            let head = read_http_head(&mut reader).await.unwrap();
+            assert_eq!(head, "CONNECT a:1 HTTP/1.1\r\nHost: a:1\r\n\r\n");
+            // whatever follows the head stays in the reader
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).await.unwrap();
+            assert_eq!(rest, b"\x16\x03\x01");
+        })
+    }
+
+    #[test]
+    fn udp_headers_round_trip() {
+        for source in &["1.2.3.4:53", "[2001:db8::1]:443"] {
+            let source: SocketAddr = source.parse().unwrap();
+            let mut datagram = udp_header(source);
+            datagram.extend_from_slice(b"payload");
+            let (dest, payload) = parse_udp_request(&datagram).unwrap();
+            assert_eq!(dest.parse::<SocketAddr>().unwrap(), source);
+            assert_eq!(payload, b"payload");
+        }
+        let mut domain = vec![0, 0, 0, 3, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&[0, 53]);
+        domain.extend_from_slice(b"query");
+        assert_eq!(
+            parse_udp_request(&domain),
+            Some(("example.com:53".to_string(), &b"query"[..]))
+        );
+        // fragments and truncated headers are dropped
+        domain[2] = 1;
+        assert_eq!(parse_udp_request(&domain), None);
+        assert_eq!(parse_udp_request(&[0, 0, 0, 1, 127, 0]), None);
+    }
+}
@@ -37,7 +37,7 @@ pub struct KVTransaction<'a> {
 }
 
 impl<'a> KVTransaction<'a> {
-    /// Read something
+    /// Read something. Values that can't be read as the given type are treated as missing.
     pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
         let val: Option<Vec<u8>> = self
             .txn
@@ -49,12 +49,46 @@ impl<'a> KVTransaction<'a> {
             .optional()
             .unwrap();
 
-        match val {
-            Some(val) => Some(bincode::deserialize(&val).expect("deserialization failed?!")),
-            None => None,
+        // something written in another format, for example by an older version, counts as missing
+        match bincode::deserialize(&val?) {
+            Ok(val) => Some(val),
+            Err(err) => {
+                log::warn!("ignoring unreadable value of {}: {}", key, err);
+                None
+            }
         }
     }
 
+    /// List every key starting with the given prefix
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        // keys with the prefix are exactly those from the prefix up to, but not including, its successor, a range the primary key index can look up
+        let keys: Vec<Vec<u8>> = match prefix_successor(prefix.as_bytes()) {
+            Some(end) => {
+                let mut stmt = self
+                    .txn
+                    .prepare("select key from kvv where key >= $1 and key < $2")
+                    .expect("sqlite prepare failed");
+                let rows = stmt
+                    .query_map(&[&prefix.as_bytes(), &end.as_slice()], |row| row.get(0))
+                    .expect("sqlite read failed");
+                rows.filter_map(Result::ok).collect()
+            }
+            None => {
+                let mut stmt = self
+                    .txn
+                    .prepare("select key from kvv where key >= $1")
+                    .expect("sqlite prepare failed");
+                let rows = stmt
+                    .query_map(&[&prefix.as_bytes()], |row| row.get(0))
+                    .expect("sqlite read failed");
+                rows.filter_map(Result::ok).collect()
+            }
+        };
+        keys.into_iter()
+            .filter_map(|key| String::from_utf8(key).ok())
+            .collect()
+    }
+
     /// Write something
     pub fn insert<T: Serialize>(&mut self, key: &str, value: T) {
         self.txn
@@ -70,3 +104,54 @@ impl<'a> KVTransaction<'a> {
         self.txn.commit().expect("sqlite commit failed")
     }
 }
+
+/// The smallest byte string greater than every one starting with the given prefix, or None if there's no such thing because the prefix is all 0xff bytes.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn prefix_successor_bounds_the_prefix() {
+        assert_eq!(prefix_successor(b"cache.a"), Some(b"cache.b".to_vec()));
+        assert_eq!(prefix_successor(b"a\xff\xff"), Some(b"b".to_vec()));
+        assert_eq!(prefix_successor(b"\xff"), None);
+        assert_eq!(prefix_successor(b""), None);
+    }
+
+    #[test]
+    fn keys_with_prefix_are_exact() {
+        let path = std::env::temp_dir().join(format!(
+            "geph4-persist-test-{}.db",
+            rand::thread_rng().gen::<u64>()
+        ));
+        let mut database = KVDatabase::open(&path).unwrap();
+        let mut txn = database.transaction();
+        for key in &[
+            "cache.bridges.a",
+            "cache.bridges.b",
+            "cache_bridges.c",
+            "CACHE.bridges.d",
+            "cache.bridges",
+        ] {
+            txn.insert(key, 0u8);
+        }
+        let mut keys = txn.keys_with_prefix("cache.bridges.");
+        keys.sort();
+        assert_eq!(keys, vec!["cache.bridges.a", "cache.bridges.b"]);
+        drop(txn);
+        drop(database);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,201 @@
+use anyhow::Context;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Decides which destinations are connected to directly, bypassing the tunnel. Anything that doesn't clearly match a rule goes through the tunnel, and domains are never resolved locally to check them against the CIDRs, since that would leak the lookup.
+#[derive(Debug, Default)]
+pub struct RoutingPolicy {
+    domains: Vec<DomainRule>,
+    cidrs: Vec<(IpAddr, u8)>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DomainRule {
+    /// Just this domain.
+    Exact(String),
+    /// Any subdomain of this domain, written as *.example.com.
+    Suffix(String),
+}
+
+impl RoutingPolicy {
+    /// Creates a policy from domains, such as example.com or *.example.com, and CIDRs, such as 10.0.0.0/8 or a lone address.
+    pub fn new(domains: &[String], cidrs: &[String]) -> anyhow::Result<Self> {
+        let domains = domains
+            .iter()
+            .map(|domain| {
+                let domain = normalize_domain(domain);
+                if let Some(suffix) = domain.strip_prefix("*.") {
+                    DomainRule::Suffix(suffix.to_string())
+                } else {
+                    DomainRule::Exact(domain)
+                }
+            })
+            .collect();
+        let cidrs = cidrs
+            .iter()
+            .map(|cidr| parse_cidr(cidr).with_context(|| format!("invalid CIDR {:?}", cidr)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(RoutingPolicy { domains, cidrs })
+    }
+
+    /// Whether a host:port destination should be connected to directly.
+    pub fn is_direct(&self, dest: &str) -> bool {
+        let host = match dest.rfind(':') {
+            Some(idx) => &dest[..idx],
+            None => dest,
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self
+                .cidrs
+                .iter()
+                .any(|&(network, prefix)| in_network(ip, network, prefix));
+        }
+        let host = normalize_domain(host);
+        self.domains.iter().any(|rule| match rule {
+            DomainRule::Exact(domain) => &host == domain,
+            DomainRule::Suffix(suffix) => host
+                .strip_suffix(suffix.as_str())
+                .map(|rest| rest.ends_with('.'))
+                .unwrap_or(false),
+        })
+    }
+
+    /// Renders the policy as a proxy auto-config script that sends the same destinations directly, and everything else to the given HTTP proxy.
+    pub fn to_pac(&self, proxy: &str) -> String {
+        let mut conditions = Vec::new();
+        for rule in self.domains.iter() {
+            conditions.push(match rule {
+                DomainRule::Exact(domain) => format!("host == {}", js_string(domain)),
+                DomainRule::Suffix(suffix) => {
+                    format!("dnsDomainIs(host, {})", js_string(&format!(".{}", suffix)))
+                }
+            });
+        }
+        for &(network, prefix) in self.cidrs.iter() {
+            // isInNet would resolve domains, so only IP literals are checked, just like in is_direct
+            conditions.push(match network {
+                IpAddr::V4(network) => {
+                    let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                    format!(
+                        "(isV4 && isInNet(host, {}, {}))",
+                        js_string(&network.to_string()),
+                        js_string(&Ipv4Addr::from(mask).to_string())
+                    )
+                }
+                IpAddr::V6(network) => format!(
+                    "(isV6 && typeof isInNetEx == \"function\" && isInNetEx(host, {}))",
+                    js_string(&format!("{}/{}", network, prefix))
+                ),
+            });
+        }
+        let mut pac = String::from("function FindProxyForURL(url, host) {\n");
+        pac.push_str("  host = host.toLowerCase().replace(/\\.$/, \"\");\n");
+        pac.push_str("  var isV4 = /^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host);\n");
+        pac.push_str("  var isV6 = host.indexOf(\":\") >= 0;\n");
+        for condition in conditions {
+            pac.push_str(&format!("  if ({}) return \"DIRECT\";\n", condition));
+        }
+        pac.push_str(&format!(
+            "  return {};\n}}\n",
+            js_string(&format!("PROXY {}", proxy))
+        ));
+        pac
+    }
+}
+
+fn js_string(s: &str) -> String {
+    serde_json::to_string(s).expect("strings always serialize")
+}
+
+fn normalize_domain(domain: &str) -> String {
+    domain.trim().trim_end_matches('.').to_lowercase()
+}
+
+fn parse_cidr(cidr: &str) -> anyhow::Result<(IpAddr, u8)> {
+    let (addr, prefix) = match cidr.find('/') {
+        Some(idx) => (&cidr[..idx], Some(cidr[idx + 1..].parse::<u8>()?)),
+        None => (cidr, None),
+    };
+    let addr: IpAddr = addr.trim().parse()?;
+    let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = prefix.unwrap_or(max_prefix);
+    if prefix > max_prefix {
+        anyhow::bail!("prefix length {} is too long", prefix)
+    }
+    Ok((addr, prefix))
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(domains: &[&str], cidrs: &[&str]) -> RoutingPolicy {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        RoutingPolicy::new(&strings(domains), &strings(cidrs)).unwrap()
+    }
+
+    #[test]
+    fn domains_match_exactly_or_by_suffix() {
+        let policy = policy(&["intranet.corp", "*.Example.com."], &[]);
+        assert!(policy.is_direct("intranet.corp:443"));
+        assert!(policy.is_direct("INTRANET.corp.:80"));
+        assert!(!policy.is_direct("www.intranet.corp:443"));
+        assert!(policy.is_direct("www.example.com:443"));
+        assert!(policy.is_direct("a.b.example.com:443"));
+        // the wildcard only covers subdomains, and not lookalikes
+        assert!(!policy.is_direct("example.com:443"));
+        assert!(!policy.is_direct("badexample.com:443"));
+    }
+
+    #[test]
+    fn addresses_match_cidrs() {
+        let policy = policy(&[], &["10.0.0.0/8", "192.168.1.1", "fd00::/8", "0.0.0.0/0"]);
+        assert!(policy.is_direct("10.20.30.40:22"));
+        assert!(policy.is_direct("192.168.1.1:80"));
+        assert!(policy.is_direct("[fd12::1]:443"));
+        assert!(!policy.is_direct("[2001:db8::1]:443"));
+        // a /0 covers every IPv4 address, but domains are never resolved to check them
+        assert!(policy.is_direct("1.1.1.1:53"));
+        assert!(!policy.is_direct("example.com:443"));
+    }
+
+    #[test]
+    fn pac_follows_policy() {
+        let pac = policy(
+            &["intranet.corp", "*.example.com"],
+            &["10.0.0.0/8", "fd00::/8"],
+        )
+        .to_pac("127.0.0.1:9910");
+        assert!(pac.contains(r#"if (host == "intranet.corp") return "DIRECT";"#));
+        assert!(pac.contains(r#"if (dnsDomainIs(host, ".example.com")) return "DIRECT";"#));
+        assert!(pac.contains(r#"isInNet(host, "10.0.0.0", "255.0.0.0")"#));
+        assert!(pac.contains(r#"isInNetEx(host, "fd00::/8")"#));
+        assert!(pac.contains(r#"return "PROXY 127.0.0.1:9910";"#));
+        // with no rules, everything goes through the proxy
+        let pac = RoutingPolicy::default().to_pac("127.0.0.1:1234");
+        assert!(!pac.contains("DIRECT"));
+        assert!(pac.contains(r#"return "PROXY 127.0.0.1:1234";"#));
+    }
+
+    #[test]
+    fn bad_cidrs_are_rejected() {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(RoutingPolicy::new(&[], &strings(&["10.0.0.0/33"])).is_err());
+        assert!(RoutingPolicy::new(&[], &strings(&["example.com/8"])).is_err());
+        assert!(RoutingPolicy::new(&[], &strings(&["fd00::/129"])).is_err());
+    }
+}
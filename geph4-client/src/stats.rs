@@ -1,8 +1,11 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant};
 
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
+use smol::channel::{Receiver, Sender, TrySendError};
 
 #[derive(Default, Serialize, Deserialize)]
 pub struct StatCollector {
@@ -12,21 +15,306 @@ pub struct StatCollector {
     open_conns: Mutex<u64>,
     open_latency: Mutex<f64>,
     exit_info: Mutex<Option<binder_transport::ExitDescriptor>>,
+
+    last_failure: Mutex<Option<FailureKind>>,
+    failure_counts: Mutex<BTreeMap<FailureKind, u64>>,
+    connection_phase: Mutex<ConnectionPhase>,
+    dns_error: Mutex<Option<String>>,
+    sessions_established: Mutex<u64>,
+    failovers: Mutex<u64>,
+    last_event: Mutex<Option<KeepaliveEvent>>,
+
+    #[serde(skip)]
+    subscribers: Mutex<Vec<Sender<KeepaliveEvent>>>,
+    #[serde(skip)]
+    last_exit: Mutex<Option<String>>,
+    #[serde(skip)]
+    conns: Mutex<BTreeMap<u64, LiveConn>>,
+    #[serde(skip)]
+    next_conn_id: Mutex<u64>,
+    #[serde(skip)]
+    connected_since: Mutex<Option<Instant>>,
+    #[serde(skip)]
+    reconnect_at: Mutex<Option<Instant>>,
+    #[serde(skip)]
+    recent_rx: Mutex<RateWindow>,
+    #[serde(skip)]
+    recent_tx: Mutex<RateWindow>,
+}
+
+/// Span over which transfer rates are worked out.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Transfers closer together than this are added up into one sample, so that the window never holds more than a handful.
+const RATE_GRANULARITY: Duration = Duration::from_millis(100);
+
+/// Bytes transferred within the last rate window, as (time, bytes) samples, oldest first.
+#[derive(Default)]
+struct RateWindow {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateWindow {
+    fn record(&mut self, now: Instant, bytes: u64) {
+        match self.samples.back_mut() {
+            Some((at, total)) if now.saturating_duration_since(*at) < RATE_GRANULARITY => {
+                *total += bytes
+            }
+            _ => self.samples.push_back((now, bytes)),
+        }
+        self.expire(now)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, _)) = self.samples.front() {
+            if now.saturating_duration_since(*at) < RATE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second over the window ending now.
+    fn rate(&mut self, now: Instant) -> f64 {
+        self.expire(now);
+        let bytes: u64 = self.samples.iter().map(|(_, bytes)| bytes).sum();
+        bytes as f64 / RATE_WINDOW.as_secs_f64()
+    }
+}
+
+/// A proxied connection that is still open.
+struct LiveConn {
+    destination: Option<String>,
+    direct: bool,
+    rx: u64,
+    tx: u64,
+    opened: Instant,
+}
+
+/// What the stats API shows about an open proxied connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnSummary {
+    pub id: u64,
+    /// Where the connection goes, as host:port, or None if that isn't known yet.
+    pub destination: Option<String>,
+    /// Whether the connection bypasses the tunnel.
+    pub direct: bool,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub age_secs: f64,
+}
+
+/// How far along establishing a session is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionPhase {
+    /// Nothing has been attempted yet.
+    Idle,
+    /// Getting the list of exits, and bridges if needed, from the binder.
+    FetchingExits,
+    /// Resolving the hostname of the exit.
+    Resolving,
+    /// Doing the sosistab handshake with the exit or its bridges.
+    Handshaking,
+    /// Proving to the exit that we're allowed to use it.
+    Authenticating,
+    /// Ready to carry traffic.
+    Connected,
+}
+
+impl Default for ConnectionPhase {
+    fn default() -> Self {
+        ConnectionPhase::Idle
+    }
+}
+
+/// Something that happened to the tunnel, pushed to everyone who subscribed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum KeepaliveEvent {
+    /// A new session is being set up.
+    Connecting,
+    /// A session with the given exit is ready to carry traffic.
+    Connected { exit: String },
+    /// The session ended and is about to be replaced.
+    Reconnecting { reason: String },
+    /// The new session goes to another exit than the last one did. Comes right before the Connected event of that session.
+    ExitSwitched { from: String, to: String },
+    /// Credentials about to expire couldn't be renewed, so the session will stop working once they do. Logging in again may help.
+    AuthRefreshFailed { reason: String },
+}
+
+/// How many events a subscriber can fall behind by before it misses some.
+const EVENT_BACKLOG: usize = 100;
+
+/// Why an attempt to establish a session failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    Timeout,
+    PubkeyMismatch,
+    NoExits,
+    AuthFailure,
+    NoBridges,
+    Network,
+    /// The binder couldn't be asked for exits or bridges, and nothing usable was cached.
+    DirectoryUnreachable,
+    /// The server speaks none of the protocol versions this client does.
+    VersionMismatch,
+    /// There were bridges to go through, but none of them connected.
+    AllBridgesFailed,
+}
+
+impl FailureKind {
+    /// Finds the category attached to an error, treating anything uncategorized as a network problem.
+    pub fn of(err: &anyhow::Error) -> Self {
+        err.downcast_ref::<FailureKind>()
+            .copied()
+            .unwrap_or(FailureKind::Network)
+    }
+
+    /// The status a one-shot client exits with when connecting fails this way. Every kind gets its own nonzero code, so that scripts can tell them apart.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::Timeout => 2,
+            FailureKind::PubkeyMismatch => 3,
+            FailureKind::NoExits => 4,
+            FailureKind::AuthFailure => 5,
+            FailureKind::NoBridges => 6,
+            FailureKind::Network => 7,
+            FailureKind::DirectoryUnreachable => 8,
+            FailureKind::VersionMismatch => 9,
+            FailureKind::AllBridgesFailed => 10,
+        }
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::Timeout => "connection timed out; the network may be blocking Geph",
+            FailureKind::PubkeyMismatch => {
+                "server key mismatch; the exit list may be stale or the connection tampered with"
+            }
+            FailureKind::NoExits => "no exit servers available",
+            FailureKind::AuthFailure => "authentication failed; check your credentials",
+            FailureKind::NoBridges => "no bridges available",
+            FailureKind::Network => "network unreachable",
+            FailureKind::DirectoryUnreachable => "can't get exits or bridges from the directory",
+            FailureKind::VersionMismatch => {
+                "the server speaks another protocol version; Geph may need updating"
+            }
+            FailureKind::AllBridgesFailed => {
+                "no bridge could be reached; the network may be blocking them"
+            }
+        })
+    }
+}
+
+/// Why a session couldn't be established, as handed out by [Keepalive::wait_fatal](crate::kalive::Keepalive::wait_fatal). Frontends can act on the kind, say by logging in again after an [FailureKind::AuthFailure], and show the error itself for details.
+#[derive(Debug)]
+pub struct ConnectError {
+    pub kind: FailureKind,
+    pub error: anyhow::Error,
+}
+
+impl From<anyhow::Error> for ConnectError {
+    fn from(error: anyhow::Error) -> Self {
+        ConnectError {
+            kind: FailureKind::of(&error),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {:#}", self.kind, self.error)
+    }
 }
 
+impl std::error::Error for ConnectError {}
+
 impl StatCollector {
     pub fn incr_total_rx(&self, bytes: u64) {
-        *self.total_rx.lock() += bytes
+        *self.total_rx.lock() += bytes;
+        self.recent_rx.lock().record(Instant::now(), bytes);
+        facade::counter("geph_rx_bytes_total", bytes)
     }
     pub fn incr_total_tx(&self, bytes: u64) {
-        *self.total_tx.lock() += bytes
+        *self.total_tx.lock() += bytes;
+        self.recent_tx.lock().record(Instant::now(), bytes);
+        facade::counter("geph_tx_bytes_total", bytes)
+    }
+    /// Bytes per second received over the last second.
+    pub fn rx_bps(&self) -> f64 {
+        self.recent_rx.lock().rate(Instant::now())
+    }
+    /// Bytes per second sent over the last second.
+    pub fn tx_bps(&self) -> f64 {
+        self.recent_tx.lock().rate(Instant::now())
     }
 
     pub fn incr_open_conns(&self) {
-        *self.open_conns.lock() += 1
+        let mut open_conns = self.open_conns.lock();
+        *open_conns += 1;
+        facade::gauge("geph_open_connections", *open_conns as f64)
     }
     pub fn decr_open_conns(&self) {
-        *self.open_conns.lock() -= 1
+        let mut open_conns = self.open_conns.lock();
+        *open_conns -= 1;
+        facade::gauge("geph_open_connections", *open_conns as f64)
+    }
+
+    /// Starts tracking a newly opened connection, returning its ID.
+    pub fn add_conn(&self) -> u64 {
+        let mut next_id = self.next_conn_id.lock();
+        let id = *next_id;
+        *next_id += 1;
+        self.conns.lock().insert(
+            id,
+            LiveConn {
+                destination: None,
+                direct: false,
+                rx: 0,
+                tx: 0,
+                opened: Instant::now(),
+            },
+        );
+        id
+    }
+    pub fn remove_conn(&self, id: u64) {
+        self.conns.lock().remove(&id);
+    }
+    pub fn set_conn_destination(&self, id: u64, destination: &str, direct: bool) {
+        if let Some(conn) = self.conns.lock().get_mut(&id) {
+            conn.destination = Some(destination.to_string());
+            conn.direct = direct;
+        }
+    }
+    pub fn incr_conn_rx(&self, id: u64, bytes: u64) {
+        if let Some(conn) = self.conns.lock().get_mut(&id) {
+            conn.rx += bytes
+        }
+    }
+    pub fn incr_conn_tx(&self, id: u64, bytes: u64) {
+        if let Some(conn) = self.conns.lock().get_mut(&id) {
+            conn.tx += bytes
+        }
+    }
+    /// Lists the connections that are still open, oldest first.
+    pub fn conns(&self) -> Vec<ConnSummary> {
+        self.conns
+            .lock()
+            .iter()
+            .map(|(&id, conn)| ConnSummary {
+                id,
+                destination: conn.destination.clone(),
+                direct: conn.direct,
+                rx_bytes: conn.rx,
+                tx_bytes: conn.tx,
+                age_secs: conn.opened.elapsed().as_secs_f64(),
+            })
+            .collect()
     }
 
     pub fn set_latency(&self, ms: f64) {
@@ -41,14 +329,452 @@ impl StatCollector {
             *old = ms
         }
     }
-    // pub fn get_latency(&self) -> f64 {
-    //     *self.open_latency.lock()
-    // }
+    pub fn get_latency(&self) -> f64 {
+        *self.open_latency.lock()
+    }
 
     pub fn set_exit_descriptor(&self, desc: Option<binder_transport::ExitDescriptor>) {
+        if desc.is_some() {
+            let mut established = self.sessions_established.lock();
+            *established += 1;
+            if *established > 1 {
+                facade::counter("geph_reconnects_total", 1)
+            }
+        }
+        facade::gauge("geph_connected", if desc.is_some() { 1.0 } else { 0.0 });
+        if let Some(desc) = &desc {
+            let to = desc.hostname.clone();
+            let from = self.last_exit.lock().replace(to.clone());
+            if let Some(from) = from.filter(|from| from != &to) {
+                self.emit(KeepaliveEvent::ExitSwitched {
+                    from,
+                    to: to.clone(),
+                });
+            }
+            self.emit(KeepaliveEvent::Connected { exit: to });
+        }
         *self.exit_info.lock() = desc
     }
+    pub fn exit_descriptor(&self) -> Option<binder_transport::ExitDescriptor> {
+        self.exit_info.lock().clone()
+    }
+    pub fn is_connected(&self) -> bool {
+        self.exit_info.lock().is_some()
+    }
+
+    /// Counts a switch to the standby session after the primary one failed.
+    pub fn incr_failovers(&self) {
+        *self.failovers.lock() += 1;
+        facade::counter("geph_failovers_total", 1)
+    }
+    pub fn failovers(&self) -> u64 {
+        *self.failovers.lock()
+    }
+
+    pub fn record_failure(&self, kind: FailureKind) {
+        *self.last_failure.lock() = Some(kind);
+        *self.failure_counts.lock().entry(kind).or_default() += 1
+    }
+    pub fn last_failure(&self) -> Option<FailureKind> {
+        *self.last_failure.lock()
+    }
+    pub fn failure_count(&self, kind: FailureKind) -> u64 {
+        self.failure_counts.lock().get(&kind).copied().unwrap_or(0)
+    }
+
+    pub fn set_connection_phase(&self, phase: ConnectionPhase) {
+        let mut current = self.connection_phase.lock();
+        if phase != ConnectionPhase::Connected {
+            *self.connected_since.lock() = None;
+        } else if *current != ConnectionPhase::Connected {
+            *self.connected_since.lock() = Some(Instant::now());
+        }
+        *current = phase
+    }
+    pub fn connection_phase(&self) -> ConnectionPhase {
+        *self.connection_phase.lock()
+    }
+
+    /// How long the current session has been up, if there is one.
+    pub fn connected_for(&self) -> Option<Duration> {
+        self.connected_since.lock().map(|since| since.elapsed())
+    }
+
+    /// Notes when the next attempt to connect is due, or None once it's underway.
+    pub fn set_reconnect_at(&self, at: Option<Instant>) {
+        *self.reconnect_at.lock() = at
+    }
+    /// How long until the next attempt to connect, while a failed session is being backed off from.
+    pub fn reconnect_in(&self) -> Option<Duration> {
+        self.reconnect_at
+            .lock()
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Subscribes to events from now on. Events are dropped for subscribers that fall too far behind.
+    pub fn subscribe(&self) -> Receiver<KeepaliveEvent> {
+        let (send, recv) = smol::channel::bounded(EVENT_BACKLOG);
+        self.subscribers.lock().push(send);
+        recv
+    }
+    /// Tells every subscriber about an event, forgetting those that are gone.
+    pub fn emit(&self, event: KeepaliveEvent) {
+        log::debug!("keepalive event: {:?}", event);
+        self.subscribers
+            .lock()
+            .retain(|send| !matches!(send.try_send(event.clone()), Err(TrySendError::Closed(_))));
+        *self.last_event.lock() = Some(event)
+    }
+    pub fn last_event(&self) -> Option<KeepaliveEvent> {
+        self.last_event.lock().clone()
+    }
+
+    pub fn set_dns_error(&self, err: Option<String>) {
+        *self.dns_error.lock() = err
+    }
+    pub fn dns_error(&self) -> Option<String> {
+        self.dns_error.lock().clone()
+    }
+
+    /// Passes on the stats of the current session to the metrics facade.
+    pub fn observe_session(&self, session: &sosistab::SessionStats) {
+        facade::gauge("geph_session_down_loss_ratio", session.down_loss);
+        facade::gauge(
+            "geph_session_down_recovered_loss_ratio",
+            session.down_recovered_loss,
+        );
+        facade::gauge("geph_session_rtt_seconds", session.rtt.as_secs_f64());
+    }
+
+    /// Renders the stats, along with those of the current session if there is one, in the Prometheus text format.
+    pub fn to_prometheus(&self, session: Option<&sosistab::SessionStats>) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        metric(
+            "geph_rx_bytes_total",
+            "counter",
+            "Bytes received through the tunnel.",
+            *self.total_rx.lock() as f64,
+        );
+        metric(
+            "geph_tx_bytes_total",
+            "counter",
+            "Bytes sent through the tunnel.",
+            *self.total_tx.lock() as f64,
+        );
+        metric(
+            "geph_open_connections",
+            "gauge",
+            "Proxied connections currently open.",
+            *self.open_conns.lock() as f64,
+        );
+        metric(
+            "geph_open_latency_milliseconds",
+            "gauge",
+            "Smoothed time taken to open a connection through the tunnel.",
+            self.get_latency(),
+        );
+        metric(
+            "geph_connected",
+            "gauge",
+            "Whether a session to the exit is up.",
+            if self.is_connected() { 1.0 } else { 0.0 },
+        );
+        metric(
+            "geph_reconnects_total",
+            "counter",
+            "Sessions established after the first one.",
+            self.sessions_established.lock().saturating_sub(1) as f64,
+        );
+        metric(
+            "geph_failovers_total",
+            "counter",
+            "Switches to the standby session after the primary one failed.",
+            self.failovers() as f64,
+        );
+        if let Some(session) = session {
+            metric(
+                "geph_session_down_loss_ratio",
+                "gauge",
+                "Fraction of downstream frames lost, before error correction.",
+                session.down_loss,
+            );
+            metric(
+                "geph_session_down_recovered_loss_ratio",
+                "gauge",
+                "Fraction of downstream frames lost, after error correction.",
+                session.down_recovered_loss,
+            );
+            metric(
+                "geph_session_rtt_seconds",
+                "gauge",
+                "Smoothed round-trip time of the session.",
+                session.rtt.as_secs_f64(),
+            );
+            metric(
+                "geph_session_up_frames_per_second",
+                "gauge",
+                "Frames sent per second over the session.",
+                session.up_frames_per_sec,
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP geph_failures_total Failed attempts to establish a session, by cause."
+        );
+        let _ = writeln!(out, "# TYPE geph_failures_total counter");
+        for (kind, count) in self.failure_counts.lock().iter() {
+            let label = serde_json::to_string(kind).unwrap_or_default();
+            let _ = writeln!(out, "geph_failures_total{{kind={}}} {}", label, count);
+        }
+        out
+    }
 }
 
 pub static GLOBAL_LOGGER: Lazy<RwLock<VecDeque<String>>> =
     Lazy::new(|| RwLock::new(VecDeque::new()));
+
+/// Emits metrics through the `metrics` crate, so that whatever exporter the embedding application installs picks them up. Without the `metrics` feature, this does nothing. Names match those of the /metrics endpoint.
+pub mod facade {
+    use std::time::Duration;
+
+    #[cfg(feature = "metrics")]
+    pub fn counter(name: &'static str, value: u64) {
+        metrics::counter!(name, value)
+    }
+    #[cfg(feature = "metrics")]
+    pub fn gauge(name: &'static str, value: f64) {
+        metrics::gauge!(name, value)
+    }
+    #[cfg(feature = "metrics")]
+    pub fn histogram(name: &'static str, value: Duration) {
+        metrics::histogram!(name, value.as_secs_f64())
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    pub fn counter(_name: &'static str, _value: u64) {}
+    #[cfg(not(feature = "metrics"))]
+    pub fn gauge(_name: &'static str, _value: f64) {}
+    #[cfg(not(feature = "metrics"))]
+    pub fn histogram(_name: &'static str, _value: Duration) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics_reach_the_facade() {
+        use metrics::{GaugeValue, Key, Recorder, Unit};
+
+        /// Notes down the names of all metrics it sees.
+        #[derive(Default)]
+        struct Capture(Mutex<std::collections::BTreeSet<String>>);
+
+        impl Recorder for Capture {
+            fn register_counter(&self, _: &Key, _: Option<Unit>, _: Option<&'static str>) {}
+            fn register_gauge(&self, _: &Key, _: Option<Unit>, _: Option<&'static str>) {}
+            fn register_histogram(&self, _: &Key, _: Option<Unit>, _: Option<&'static str>) {}
+            fn increment_counter(&self, key: &Key, _: u64) {
+                self.0.lock().insert(key.name().to_string());
+            }
+            fn update_gauge(&self, key: &Key, _: GaugeValue) {
+                self.0.lock().insert(key.name().to_string());
+            }
+            fn record_histogram(&self, key: &Key, _: f64) {
+                self.0.lock().insert(key.name().to_string());
+            }
+        }
+
+        static CAPTURE: Lazy<Capture> = Lazy::new(Capture::default);
+        metrics::set_recorder(&*CAPTURE).unwrap();
+        let stats = StatCollector::default();
+        stats.incr_total_rx(100);
+        stats.incr_total_tx(100);
+        stats.incr_open_conns();
+        stats.decr_open_conns();
+        stats.set_exit_descriptor(Some(test_exit()));
+        stats.set_exit_descriptor(Some(test_exit()));
+        stats.observe_session(&test_session());
+        facade::histogram("geph_handshake_seconds", Duration::from_millis(100));
+        let seen = CAPTURE.0.lock().clone();
+        for name in &[
+            "geph_rx_bytes_total",
+            "geph_tx_bytes_total",
+            "geph_open_connections",
+            "geph_connected",
+            "geph_reconnects_total",
+            "geph_session_down_loss_ratio",
+            "geph_session_rtt_seconds",
+            "geph_handshake_seconds",
+        ] {
+            assert!(seen.contains(*name), "{} never emitted", name);
+        }
+    }
+
+    #[test]
+    fn backoff_state_is_tracked() {
+        let stats = StatCollector::default();
+        assert_eq!(stats.connected_for(), None);
+        stats.set_connection_phase(ConnectionPhase::Connected);
+        std::thread::sleep(Duration::from_millis(20));
+        let up = stats.connected_for().unwrap();
+        assert!(up >= Duration::from_millis(20));
+        // staying connected doesn't restart the clock
+        stats.set_connection_phase(ConnectionPhase::Connected);
+        assert!(stats.connected_for().unwrap() >= up);
+        stats.set_connection_phase(ConnectionPhase::FetchingExits);
+        assert_eq!(stats.connected_for(), None);
+        assert_eq!(stats.reconnect_in(), None);
+        stats.set_reconnect_at(Some(Instant::now() + Duration::from_secs(10)));
+        let wait = stats.reconnect_in().unwrap();
+        assert!(wait > Duration::from_secs(9) && wait <= Duration::from_secs(10));
+        stats.set_reconnect_at(Some(Instant::now() - Duration::from_secs(1)));
+        assert_eq!(stats.reconnect_in(), Some(Duration::from_secs(0)));
+        stats.set_reconnect_at(None);
+        assert_eq!(stats.reconnect_in(), None);
+    }
+
+    #[test]
+    fn events_reach_subscribers() {
+        let stats = StatCollector::default();
+        let events = stats.subscribe();
+        let gone = stats.subscribe();
+        drop(gone);
+        stats.emit(KeepaliveEvent::Connecting);
+        stats.set_exit_descriptor(Some(test_exit()));
+        stats.set_exit_descriptor(None);
+        stats.emit(KeepaliveEvent::Reconnecting {
+            reason: "watchdog timed out".into(),
+        });
+        let mut other = test_exit();
+        other.hostname = "de-fra-01.exits.geph.io".into();
+        stats.set_exit_descriptor(Some(other));
+        let seen: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(
+            seen,
+            vec![
+                KeepaliveEvent::Connecting,
+                KeepaliveEvent::Connected {
+                    exit: "us-hio-01.exits.geph.io".into()
+                },
+                KeepaliveEvent::Reconnecting {
+                    reason: "watchdog timed out".into()
+                },
+                KeepaliveEvent::ExitSwitched {
+                    from: "us-hio-01.exits.geph.io".into(),
+                    to: "de-fra-01.exits.geph.io".into()
+                },
+                KeepaliveEvent::Connected {
+                    exit: "de-fra-01.exits.geph.io".into()
+                },
+            ]
+        );
+        assert_eq!(stats.subscribers.lock().len(), 1);
+        assert_eq!(stats.last_event(), seen.last().cloned());
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["last_event"]["event"], "connected");
+    }
+
+    #[test]
+    fn rates_cover_the_last_second() {
+        let start = Instant::now();
+        let mut window = RateWindow::default();
+        for i in 0..100u32 {
+            window.record(start + Duration::from_millis(10) * i, 1000);
+        }
+        // nearby transfers share samples
+        assert!(window.samples.len() <= 11);
+        let rate = window.rate(start + Duration::from_millis(990));
+        assert!((rate - 100_000.0).abs() < 1.0);
+        // only the samples from after 500ms are still in the window
+        let rate = window.rate(start + Duration::from_millis(1500));
+        assert!((rate - 40_000.0).abs() < 1.0);
+        assert!(window.rate(start + Duration::from_secs(3)).abs() < 1e-9);
+        assert!(window.samples.is_empty());
+        let stats = StatCollector::default();
+        stats.incr_total_rx(5000);
+        assert!((stats.rx_bps() - 5000.0).abs() < 1.0);
+        assert!(stats.tx_bps().abs() < 1e-9);
+    }
+
+    #[test]
+    fn live_conns_are_listed() {
+        let stats = StatCollector::default();
+        let first = stats.add_conn();
+        let second = stats.add_conn();
+        stats.set_conn_destination(first, "example.com:443", false);
+        stats.set_conn_destination(second, "10.0.0.1:22", true);
+        stats.incr_conn_rx(first, 100);
+        stats.incr_conn_tx(first, 10);
+        stats.incr_conn_rx(first, 50);
+        let conns = stats.conns();
+        assert_eq!(conns.len(), 2);
+        assert_eq!(conns[0].destination.as_deref(), Some("example.com:443"));
+        assert!(!conns[0].direct);
+        assert_eq!((conns[0].rx_bytes, conns[0].tx_bytes), (150, 10));
+        assert!(conns[1].direct);
+        stats.remove_conn(first);
+        let conns = stats.conns();
+        assert_eq!(conns.len(), 1);
+        assert_eq!(conns[0].id, second);
+    }
+
+    fn test_exit() -> binder_transport::ExitDescriptor {
+        binder_transport::ExitDescriptor {
+            hostname: "us-hio-01.exits.geph.io".into(),
+            signing_key: ed25519_dalek::Keypair::generate(&mut rand::thread_rng()).public,
+            country_code: "us".into(),
+            city_code: "hio".into(),
+            sosistab_key: x25519_dalek::PublicKey::from([0u8; 32]),
+        }
+    }
+
+    fn test_session() -> sosistab::SessionStats {
+        sosistab::SessionStats {
+            down_total: 100,
+            down_loss: 0.25,
+            down_recovered_loss: 0.0,
+            down_redundant: 0.0,
+            recent_seqnos: Default::default(),
+            rtt: std::time::Duration::from_millis(50),
+            live_decoders: 0,
+            buffered_shard_bytes: 0,
+            down_duplicates: 0,
+            down_too_old: 0,
+            up_frames_per_sec: 0.0,
+            up_bytes_per_sec: 0.0,
+            down_jitter: std::time::Duration::from_millis(0),
+            down_reorder_rate: 0.0,
+            probed_datagram_size: None,
+        }
+    }
+
+    #[test]
+    fn metrics_are_prometheus_text() {
+        let exit = test_exit();
+        let stats = StatCollector::default();
+        stats.incr_total_rx(1000);
+        stats.incr_open_conns();
+        stats.record_failure(FailureKind::Timeout);
+        stats.record_failure(FailureKind::Timeout);
+        stats.set_exit_descriptor(Some(exit.clone()));
+        stats.set_exit_descriptor(None);
+        stats.set_exit_descriptor(Some(exit));
+        stats.incr_failovers();
+        let text = stats.to_prometheus(None);
+        assert!(text.contains("# TYPE geph_rx_bytes_total counter\ngeph_rx_bytes_total 1000\n"));
+        assert!(text.contains("\ngeph_open_connections 1\n"));
+        assert!(text.contains("\ngeph_reconnects_total 1\n"));
+        assert!(text.contains("\ngeph_failovers_total 1\n"));
+        assert!(text.contains("\ngeph_failures_total{kind=\"timeout\"} 2\n"));
+        assert!(!text.contains("geph_session_down_loss_ratio"));
+        let text = stats.to_prometheus(Some(&test_session()));
+        assert!(text.contains("\ngeph_session_down_loss_ratio 0.25\n"));
+    }
+}
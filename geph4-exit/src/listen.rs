@@ -1,8 +1,9 @@
 use std::{
-    net::SocketAddr,
-    sync::{atomic::AtomicUsize, Arc},
+    collections::{HashMap, VecDeque},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{atomic::AtomicUsize, Arc, Mutex},
     time::Duration,
-    time::SystemTime,
+    time::{Instant, SystemTime},
 };
 
 use anyhow::Context;
@@ -20,6 +21,7 @@ pub async fn main_loop<'a>(
     bridge_secret: &'a str,
     signing_sk: ed25519_dalek::Keypair,
     sosistab_sk: x25519_dalek::StaticSecret,
+    http_upstream: SocketAddr,
 ) -> anyhow::Result<()> {
     let session_count = AtomicUsize::new(0);
     let scope = smol::Executor::new();
@@ -50,7 +52,7 @@ pub async fn main_loop<'a>(
     };
     // future that governs the "self bridge"
     let self_bridge_fut = async {
-        let sosis_listener = sosistab::Listener::listen("[::0]:19831", sosistab_sk).await;
+        let sosis_listener = sosistab::Listener::listen("[::0]:19831", sosistab_sk.clone()).await;
         log::info!("sosis_listener initialized");
         loop {
             let sess = sosis_listener
@@ -63,6 +65,42 @@ pub async fn main_loop<'a>(
                     &stat_client,
                     exit_hostname,
                     binder_client.clone(),
+                    http_upstream,
+                    sess,
+                ))
+                .detach();
+        }
+    };
+    // future that takes sessions over TCP, for clients whose UDP is blocked
+    let tcp_fut = async {
+        let addr: SocketAddr = "[::0]:19831".parse().unwrap();
+        let backhaul = match sosistab::TcpBackhaul::listen(addr).await {
+            Ok(backhaul) => backhaul,
+            Err(err) => {
+                // clients with working UDP can still be served
+                log::warn!("can't take sessions over TCP on {}: {}", addr, err);
+                return smol::future::pending().await;
+            }
+        };
+        let tcp_listener = sosistab::Listener::listen_backhaul(
+            backhaul,
+            addr,
+            sosistab_sk.clone(),
+            sosistab::ListenerOptions::default(),
+        );
+        log::info!("TCP sosis_listener initialized");
+        loop {
+            let sess = tcp_listener
+                .accept_session()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("can't accept from TCP sosistab"))?;
+            scope
+                .spawn(handle_session(
+                    &session_count,
+                    &stat_client,
+                    exit_hostname,
+                    binder_client.clone(),
+                    http_upstream,
                     sess,
                 ))
                 .detach();
@@ -79,7 +117,11 @@ pub async fn main_loop<'a>(
     };
     // race
     scope
-        .run(smol::future::race(control_prot_fut, self_bridge_fut).or(gauge_fut))
+        .run(
+            smol::future::race(control_prot_fut, self_bridge_fut)
+                .or(tcp_fut)
+                .or(gauge_fut),
+        )
         .await
 }
 
@@ -153,6 +195,7 @@ async fn handle_control<'a>(
                                             &stat_client,
                                             exit_hostname,
                                             binder_client.clone(),
+                                            http_upstream,
                                             sess,
                                         ))
                                         .detach();
@@ -204,6 +247,7 @@ async fn handle_session<'a>(
     stat_client: &'a statsd::Client,
     exit_hostname: &'a str,
     binder_client: Arc<dyn BinderClient>,
+    http_upstream: SocketAddr,
     sess: sosistab::Session,
 ) -> anyhow::Result<()> {
     log::info!("authentication started...");
@@ -219,31 +263,110 @@ async fn handle_session<'a>(
         scopeguard::defer!({
             session_count.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
         });
-        loop {
-            let stream = sess
-                .accept_conn()
-                .timeout(Duration::from_secs(600))
-                .await
-                .ok_or_else(|| anyhow::anyhow!("accept timeout"))??;
-            scope
-                .spawn(handle_proxy_stream(stat_client, exit_hostname, stream))
-                .detach();
-        }
+        // credentials that fail to be renewed end the session
+        let (send_revoked, recv_revoked) = smol::channel::bounded(1);
+        let accept_streams = async {
+            loop {
+                let mut stream = sess
+                    .accept_conn()
+                    .timeout(Duration::from_secs(600))
+                    .await
+                    .ok_or_else(|| anyhow::anyhow!("accept timeout"))??;
+                if stream.additional_info() == Some(REAUTH_LABEL) {
+                    let binder_client = binder_client.clone();
+                    let send_revoked = send_revoked.clone();
+                    scope
+                        .spawn(async move {
+                            match authenticate_stream(binder_client, &mut stream).await {
+                                Ok(true) => log::debug!("authenticated a session again"),
+                                Ok(false) => drop(
+                                    send_revoked
+                                        .try_send(anyhow::anyhow!("invalid authentication token")),
+                                ),
+                                // the session keeps its old credentials until they can be checked
+                                Err(err) => {
+                                    log::warn!("couldn't authenticate a session again: {:?}", err)
+                                }
+                            }
+                        })
+                        .detach();
+                    continue;
+                }
+                scope
+                    .spawn(handle_proxy_stream(
+                        stat_client,
+                        exit_hostname,
+                        http_upstream,
+                        stream,
+                    ))
+                    .detach();
+            }
+        };
+        accept_streams
+            .or(handle_udp(stat_client, exit_hostname, &sess))
+            .or(async {
+                let err: anyhow::Error = recv_revoked.recv().await?;
+                Err(err.context("session failed to authenticate again"))
+            })
+            .await
     };
     scope.run(handle_streams).await
 }
 
+/// What clients open streams that authenticate their session again with, instead of a destination. It can never be mistaken for a host:port.
+const REAUTH_LABEL: &str = "!reauth";
+
 async fn authenticate_sess(
     binder_client: Arc<dyn BinderClient>,
     sess: &sosistab::mux::Multiplex,
 ) -> anyhow::Result<()> {
     let mut stream = sess.accept_conn().await?;
     log::debug!("authenticating session...");
+    if !authenticate_stream(binder_client, &mut stream).await? {
+        anyhow::bail!("invalid authentication token")
+    }
+    Ok(())
+}
+
+/// Reply to a token that was found valid.
+const AUTH_ACCEPTED: u8 = 1;
+
+/// Reply to a token that was found invalid.
+const AUTH_REJECTED: u8 = 0;
+
+/// Reply to a token that couldn't be checked, say because the binder is unreachable. Clients should try again later.
+const AUTH_UNCHECKED: u8 = 2;
+
+/// Checks the token sent over a stream and replies with the verdict, which is also returned. Errors mean that the token couldn't be checked, not that it's invalid.
+async fn authenticate_stream(
+    binder_client: Arc<dyn BinderClient>,
+    stream: &mut sosistab::mux::RelConn,
+) -> anyhow::Result<bool> {
     // wait for a message containing a blinded signature
     let (auth_tok, auth_sig, level): (Vec<u8>, mizaru::UnblindedSignature, String) =
-        aioutils::read_pascalish(&mut stream).await?;
+        aioutils::read_pascalish(stream).await?;
+    let verdict = check_token(binder_client, auth_tok, auth_sig, level).await;
+    let reply = match verdict {
+        Ok(true) => AUTH_ACCEPTED,
+        Ok(false) => AUTH_REJECTED,
+        Err(_) => AUTH_UNCHECKED,
+    };
+    // send response
+    aioutils::write_pascalish(stream, &reply).await?;
+    stream.flush().await?;
+    verdict
+}
+
+/// Whether a token is valid, as far as the binder is concerned.
+async fn check_token(
+    binder_client: Arc<dyn BinderClient>,
+    auth_tok: Vec<u8>,
+    auth_sig: mizaru::UnblindedSignature,
+    level: String,
+) -> anyhow::Result<bool> {
     if (auth_sig.epoch as i32 - mizaru::time_to_epoch(SystemTime::now()) as i32).abs() > 2 {
-        anyhow::bail!("outdated authentication token")
+        log::debug!("outdated authentication token");
+        return Ok(false);
     }
     // validate it through the binder
     let res = smol::unblock(move || {
@@ -257,17 +380,173 @@ async fn authenticate_sess(
         )
     })
     .await?;
-    if res != BinderResponse::ValidateResp(true) {
-        anyhow::bail!("unexpected authentication response from binder: {:?}", res)
+    match res {
+        BinderResponse::ValidateResp(valid) => Ok(valid),
+        res => anyhow::bail!("unexpected authentication response from binder: {:?}", res),
+    }
+}
+
+/// Most UDP associations a session may have at once. Beyond that, the oldest ones are closed.
+const MAX_UDP_ASSOCIATIONS: usize = 64;
+
+/// Most destinations an association remembers the addresses of. Beyond that, it forgets them all and starts over.
+const MAX_RESOLVED_PER_ASSOCIATION: usize = 256;
+
+/// Most datagrams waiting to be sent out by an association. Beyond that, new ones are dropped.
+const UDP_QUEUE_LEN: usize = 128;
+
+/// How long an association can go without a datagram in either direction before it's closed.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A SOCKS5 UDP association, relayed by its own task.
+struct UdpAssociation {
+    send_outgoing: smol::channel::Sender<(String, Vec<u8>)>,
+    _task: smol::Task<()>,
+}
+
+/// Relays the datagrams of SOCKS5 UDP associations over the unreliable channel, with one flow per association. Every datagram is a bincode-encoded (address, payload) pair, where the address is the destination on the way out and the source on the way back. Each association gets its own task and UDP sockets, so that looking up one's destinations never holds up the others.
+async fn handle_udp<'a>(
+    stat_client: &'a statsd::Client,
+    exit_hostname: &'a str,
+    sess: &sosistab::mux::Multiplex,
+) -> anyhow::Result<()> {
+    let key = format!("exit_usage.{}", exit_hostname.replace(".", "-"));
+    let mut associations: HashMap<u64, UdpAssociation> = HashMap::new();
+    let mut association_order = VecDeque::new();
+    loop {
+        let (assoc_id, msg) = sess.recv_urel_flow().await?;
+        let (to_prox, payload): (String, Vec<u8>) = match bincode::deserialize(&msg) {
+            Ok(v) => v,
+            Err(_) => continue,
+        };
+        let expired = associations
+            .get(&assoc_id)
+            .map(|assoc| assoc.send_outgoing.is_closed())
+            .unwrap_or(true);
+        if expired {
+            associations.retain(|_, assoc| !assoc.send_outgoing.is_closed());
+            association_order.retain(|id| associations.contains_key(id));
+            let (send_outgoing, recv_outgoing) = smol::channel::bounded(UDP_QUEUE_LEN);
+            let task = smol::spawn(relay_udp_association(sess.clone(), assoc_id, recv_outgoing));
+            log::debug!("new UDP association {}", assoc_id);
+            associations.insert(
+                assoc_id,
+                UdpAssociation {
+                    send_outgoing,
+                    _task: task,
+                },
+            );
+            association_order.push_back(assoc_id);
+            if association_order.len() > MAX_UDP_ASSOCIATIONS {
+                if let Some(oldest) = association_order.pop_front() {
+                    associations.remove(&oldest);
+                }
+            }
+        }
+        let assoc = associations
+            .get(&assoc_id)
+            .expect("association was just created");
+        let len = payload.len();
+        if assoc.send_outgoing.try_send((to_prox, payload)).is_ok() {
+            stat_client.sampled_count(&key, len as f64, 0.5);
+        }
+    }
+}
+
+/// Sends out the datagrams of one association and relays the replies back, until it's been idle for [UDP_IDLE_TIMEOUT]. IPv4 and IPv6 destinations each get a socket of their own, bound on first use.
+async fn relay_udp_association(
+    sess: sosistab::mux::Multiplex,
+    assoc_id: u64,
+    recv_outgoing: smol::channel::Receiver<(String, Vec<u8>)>,
+) {
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    // destinations already looked up, so that only the first datagram to each one waits for DNS
+    let mut resolved: HashMap<String, SocketAddr> = HashMap::new();
+    // sockets by whether they're IPv6, along with the tasks relaying their replies
+    let mut sockets: HashMap<bool, (Arc<smol::net::UdpSocket>, smol::Task<()>)> = HashMap::new();
+    loop {
+        let idle_left = UDP_IDLE_TIMEOUT.saturating_sub(last_active.lock().unwrap().elapsed());
+        let (to_prox, payload) = match recv_outgoing.recv().timeout(idle_left).await {
+            Some(Ok(v)) => v,
+            Some(Err(_)) => return,
+            None => {
+                if last_active.lock().unwrap().elapsed() >= UDP_IDLE_TIMEOUT {
+                    log::debug!("UDP association {} expired", assoc_id);
+                    return;
+                }
+                continue;
+            }
+        };
+        *last_active.lock().unwrap() = Instant::now();
+        let dest = match resolved.get(&to_prox) {
+            Some(dest) => *dest,
+            None => {
+                let dest = match smol::net::resolve(&to_prox)
+                    .await
+                    .ok()
+                    .and_then(|v| v.first().copied())
+                {
+                    Some(dest) => dest,
+                    None => continue,
+                };
+                if resolved.len() >= MAX_RESOLVED_PER_ASSOCIATION {
+                    resolved.clear();
+                }
+                resolved.insert(to_prox, dest);
+                dest
+            }
+        };
+        if dest.ip().is_loopback() || dest.ip().is_multicast() || dest.ip().is_unspecified() {
+            log::warn!("dropping UDP datagram to non-global address {}", dest);
+            continue;
+        }
+        let socket = match sockets.get(&dest.is_ipv6()) {
+            Some((socket, _)) => socket.clone(),
+            None => {
+                let bind_addr: SocketAddr = if dest.is_ipv6() {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                };
+                let socket = match smol::net::UdpSocket::bind(bind_addr).await {
+                    Ok(socket) => Arc::new(socket),
+                    Err(err) => {
+                        log::warn!(
+                            "couldn't bind a UDP socket for association {}: {:?}",
+                            assoc_id,
+                            err
+                        );
+                        continue;
+                    }
+                };
+                let task = {
+                    let socket = socket.clone();
+                    let sess = sess.clone();
+                    let last_active = last_active.clone();
+                    smol::spawn(async move {
+                        let mut buf = [0u8; 65536];
+                        while let Ok((n, source)) = socket.recv_from(&mut buf).await {
+                            *last_active.lock().unwrap() = Instant::now();
+                            let reply =
+                                bincode::serialize(&(source.to_string(), &buf[..n])).unwrap();
+                            if sess.send_urel_to(assoc_id, reply.into()).await.is_err() {
+                                return;
+                            }
+                        }
+                    })
+                };
+                sockets.insert(dest.is_ipv6(), (socket.clone(), task));
+                socket
+            }
+        };
+        drop(socket.send_to(&payload, dest).await);
     }
-    // send response
-    aioutils::write_pascalish(&mut stream, &1u8).await?;
-    Ok(())
 }
 
 async fn handle_proxy_stream<'a>(
     stat_client: &'a statsd::Client,
     exit_hostname: &'a str,
+    http_upstream: SocketAddr,
     mut client: sosistab::mux::RelConn,
 ) -> anyhow::Result<()> {
     // read proxy request
@@ -285,12 +564,12 @@ async fn handle_proxy_stream<'a>(
             ))
         })
         .await?;
-    // this is fine because just connecting to a local service is not a security problem
-    if &to_prox != "127.0.0.1:3128" {
-        if let Ok(peer_addr) = remote.peer_addr() {
-            if peer_addr.ip().is_loopback() || peer_addr.ip().is_multicast() {
-                anyhow::bail!("attempted a connection to a non-global IP address")
-            }
+    // this is fine because just connecting to the local HTTP proxy is not a security problem
+    if let Ok(peer_addr) = remote.peer_addr() {
+        if peer_addr != http_upstream
+            && (peer_addr.ip().is_loopback() || peer_addr.ip().is_multicast())
+        {
+            anyhow::bail!("attempted a connection to a non-global IP address")
         }
     }
     let key = format!("exit_usage.{}", exit_hostname.replace(".", "-"));
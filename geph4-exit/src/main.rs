@@ -35,6 +35,10 @@ struct Opt {
     /// Hostname of this exit.
     #[structopt(long)]
     exit_hostname: String,
+
+    /// Address of the local HTTP proxy that clients pass their HTTP requests to. It's the only local address clients may connect to.
+    #[structopt(long, default_value = "127.0.0.1:3128")]
+    http_upstream: SocketAddr,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -106,6 +110,7 @@ fn main() -> anyhow::Result<()> {
             &opt.bridge_secret,
             signing_sk,
             sosistab_sk,
+            opt.http_upstream,
         )
         .await?;
         Ok(())
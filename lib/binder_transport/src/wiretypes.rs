@@ -72,6 +72,9 @@ pub enum BinderRequestData {
         unblinded_signature: mizaru::UnblindedSignature,
         exit_hostname: String,
     },
+
+    /// Get all exits, along with hints about reaching them. Binders that predate this request can't decode it, so clients fall back to GetExits when it fails. New variants go last, since bincode encodes variants by position.
+    GetExitsV2,
 }
 
 impl BinderRequestData {
@@ -105,6 +108,7 @@ impl BinderRequestData {
             BinderRequestData::GetEpochKey { .. } => true,
             BinderRequestData::GetCaptcha { .. } => true,
             BinderRequestData::GetExits { .. } => true,
+            BinderRequestData::GetExitsV2 { .. } => true,
             BinderRequestData::GetBridges { .. } => true,
             // BinderRequestData::Authenticate { .. } => true,
             // BinderRequestData::Validate { .. } => true,
@@ -163,6 +167,8 @@ pub enum BinderResponse {
     GetExitsResp(Vec<ExitDescriptor>),
     /// Response to request for bridges
     GetBridgesResp(Vec<BridgeDescriptor>),
+    /// Response to request for all exits with hints
+    GetExitsV2Resp(Vec<(ExitDescriptor, ExitHints)>),
 }
 
 /// Exit descriptor
@@ -175,6 +181,13 @@ pub struct ExitDescriptor {
     pub sosistab_key: x25519_dalek::PublicKey,
 }
 
+/// Hints about reaching an exit, kept out of the exit descriptor so that its encoding stays the same.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ExitHints {
+    /// Largest datagram known to make it to the exit intact, if known.
+    pub mtu_hint: Option<u16>,
+}
+
 /// Bridge descriptor
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 pub struct BridgeDescriptor {
@@ -256,3 +269,38 @@ impl From<std::io::Error> for BinderError {
         BinderError::Other(value.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Signer;
+
+    /// The position bincode encodes a variant under.
+    fn variant_index<T: Serialize>(value: &T) -> u32 {
+        let encoded = bincode::serialize(value).unwrap();
+        u32::from_le_bytes([encoded[0], encoded[1], encoded[2], encoded[3]])
+    }
+
+    #[test]
+    fn variant_indices_are_pinned() {
+        // binders, bridges and clients of different versions must agree on these
+        assert_eq!(variant_index(&BinderRequestData::GetCaptcha), 3);
+        assert_eq!(variant_index(&BinderRequestData::GetExits), 7);
+        let keypair = ed25519_dalek::Keypair::generate(&mut rand::thread_rng());
+        let add_route = BinderRequestData::AddBridgeRoute {
+            sosistab_pubkey: x25519_dalek::PublicKey::from([0u8; 32]),
+            bridge_address: "127.0.0.1:1".parse().unwrap(),
+            bridge_group: "default".into(),
+            exit_hostname: "exit".into(),
+            route_unixtime: 0,
+            exit_signature: keypair.sign(b""),
+        };
+        assert_eq!(variant_index(&add_route), 8);
+        // GetBridges sits at 9, between the two
+        assert_eq!(variant_index(&BinderRequestData::GetExitsV2), 10);
+        assert_eq!(variant_index(&BinderResponse::Okay), 0);
+        assert_eq!(variant_index(&BinderResponse::GetExitsResp(vec![])), 5);
+        assert_eq!(variant_index(&BinderResponse::GetBridgesResp(vec![])), 6);
+        assert_eq!(variant_index(&BinderResponse::GetExitsV2Resp(vec![])), 7);
+    }
+}
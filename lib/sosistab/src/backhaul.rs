@@ -1,6 +1,19 @@
-use std::{io, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Weak},
+    time::Duration,
+};
 
 use bytes::{Bytes, BytesMut};
+use parking_lot::Mutex;
+use smol::channel::{Receiver, Sender};
+use smol::net::{TcpListener, TcpStream};
+use smol::prelude::*;
+
+use crate::runtime;
 
 /// A trait that represents a datagram backhaul. This presents an interface similar to that of "PacketConn" in Go, and it is used to abstract over different kinds of datagram transports.
 #[async_trait::async_trait]
@@ -9,6 +22,19 @@ pub trait Backhaul: Send + Sync + Clone {
     async fn recv_from(&self) -> io::Result<(Bytes, SocketAddr)>;
     /// Sends a datagram
     async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> io::Result<()>;
+
+    /// Waits for the next datagram, returning it along with any others that have already arrived. By default, only one datagram is ever returned.
+    async fn recv_many(&self) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        Ok(vec![self.recv_from().await?])
+    }
+
+    /// Sends several datagrams to the same destination. Transports that can hand over a whole batch at once override this; by default, the datagrams are sent one by one.
+    async fn send_many(&self, to_send: Vec<Bytes>, dest: SocketAddr) -> io::Result<()> {
+        for to_send in to_send {
+            self.send_to(to_send, dest).await?;
+        }
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -26,4 +52,560 @@ impl Backhaul for smol::net::UdpSocket {
         self.send_to(&to_send, dest).await?;
         Ok(())
     }
+
+    #[cfg(target_os = "linux")]
+    async fn recv_many(&self) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        use std::os::unix::io::AsRawFd;
+        let socket: std::sync::Arc<smol::Async<std::net::UdpSocket>> = self.clone().into();
+        socket
+            .read_with(|sock| mmsg::recv_batch(sock.as_raw_fd()))
+            .await
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn send_many(&self, to_send: Vec<Bytes>, dest: SocketAddr) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        let socket: std::sync::Arc<smol::Async<std::net::UdpSocket>> = self.clone().into();
+        let mut sent = 0;
+        while sent < to_send.len() {
+            sent += socket
+                .write_with(|sock| mmsg::send_batch(sock.as_raw_fd(), &to_send[sent..], dest))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// A backhaul that carries datagrams over TCP, for networks where UDP is blocked or throttled. Datagrams are framed with a two-byte length and sent over a connection to their destination, opened in the background the first time anything is sent there; datagrams sent in the meantime wait for it. Connections that carry nothing in for five minutes are closed, and a listening backhaul keeps only so many at once. A backhaul created with [TcpBackhaul::listen] instead takes connections, answering every peer over its own connection and never connecting out: datagrams to peers without a live connection are dropped.
+#[derive(Clone)]
+pub struct TcpBackhaul {
+    inner: Arc<TcpInner>,
+}
+
+type TcpConnTable = Arc<Mutex<HashMap<SocketAddr, TcpConn>>>;
+
+struct TcpInner {
+    conns: TcpConnTable,
+    send_incoming: Sender<(Bytes, SocketAddr)>,
+    recv_incoming: Receiver<(Bytes, SocketAddr)>,
+    local_addr: Option<SocketAddr>,
+    executor: Option<runtime::ExecutorHandle>,
+    _accept_task: Option<smol::Task<()>>,
+}
+
+/// One TCP connection of a [TcpBackhaul], with a task that reads and writes frames. Once either side is done, the connection removes itself from the table, so that a listening backhaul forgets the peer and any other one reconnects on the next send.
+struct TcpConn {
+    id: u64,
+    send_outgoing: Sender<Bytes>,
+    _task: smol::Task<()>,
+}
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(0);
+
+/// How long connecting to a destination may take before the datagrams waiting for it are given up on.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection may go without carrying anything in before it's closed.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Interval of the TCP keepalives sent on every connection, so that peers that vanish without closing are noticed.
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Most connections a listening backhaul keeps at once. Beyond that, new ones are refused.
+const MAX_TCP_CONNS: usize = 4096;
+
+/// Connects to a destination, giving up after [TCP_CONNECT_TIMEOUT].
+async fn tcp_connect(dest: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(dest)
+        .or(async {
+            smol::Timer::after(TCP_CONNECT_TIMEOUT).await;
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out connecting",
+            ))
+        })
+        .await?;
+    tcp_configure(&stream)?;
+    Ok(stream)
+}
+
+/// Sets the options every connection of a [TcpBackhaul] has.
+fn tcp_configure(stream: &TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let stream: Arc<smol::Async<std::net::TcpStream>> = stream.clone().into();
+    // options set through a duplicate apply to the socket itself
+    socket2::Socket::from(stream.get_ref().try_clone()?).set_keepalive(Some(TCP_KEEPALIVE))
+}
+
+impl TcpConn {
+    fn new(
+        connect: impl Future<Output = io::Result<TcpStream>> + Send + 'static,
+        peer: SocketAddr,
+        send_incoming: Sender<(Bytes, SocketAddr)>,
+        conns: Weak<Mutex<HashMap<SocketAddr, TcpConn>>>,
+        executor: Option<&runtime::ExecutorHandle>,
+    ) -> Self {
+        let id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
+        let (send_outgoing, recv_outgoing) = smol::channel::bounded::<Bytes>(1000);
+        TcpConn {
+            id,
+            send_outgoing,
+            _task: runtime::spawn_on(executor, async move {
+                match connect.await {
+                    Ok(stream) => Self::relay(stream, peer, send_incoming, recv_outgoing).await,
+                    Err(err) => log::debug!("TCP backhaul couldn't connect to {}: {}", peer, err),
+                }
+                if let Some(conns) = conns.upgrade() {
+                    let mut conns = conns.lock();
+                    // a newer connection from the same peer may have taken our place already
+                    if conns.get(&peer).map(|conn| conn.id) == Some(id) {
+                        // this cancels the task itself, but only once it returns
+                        conns.remove(&peer);
+                    }
+                }
+            }),
+        }
+    }
+
+    /// Reads and writes frames until either side is done, or nothing comes in for [TCP_IDLE_TIMEOUT].
+    async fn relay(
+        stream: TcpStream,
+        peer: SocketAddr,
+        send_incoming: Sender<(Bytes, SocketAddr)>,
+        recv_outgoing: Receiver<Bytes>,
+    ) {
+        let mut reader = stream.clone();
+        let mut writer = stream;
+        let reader_loop = async move {
+            let mut len = [0u8; 2];
+            loop {
+                let read_len = reader.read_exact(&mut len).or(async {
+                    smol::Timer::after(TCP_IDLE_TIMEOUT).await;
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "idle connection"))
+                });
+                if read_len.await.is_err() {
+                    break;
+                }
+                let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+                if reader.read_exact(&mut buf).await.is_err()
+                    || send_incoming.send((buf.into(), peer)).await.is_err()
+                {
+                    break;
+                }
+            }
+        };
+        let writer_loop = async move {
+            while let Ok(datagram) = recv_outgoing.recv().await {
+                let mut frame = Vec::with_capacity(datagram.len() + 2);
+                frame.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+                frame.extend_from_slice(&datagram);
+                if writer.write_all(&frame).await.is_err() {
+                    break;
+                }
+            }
+        };
+        reader_loop.race(writer_loop).await
+    }
+}
+
+impl TcpBackhaul {
+    /// Creates a backhaul that only makes connections of its own.
+    pub fn new() -> Self {
+        Self::new_on(None)
+    }
+
+    /// Like [TcpBackhaul::new], but runs the tasks of its connections on the given executor rather than the global one of [runtime](crate::runtime).
+    pub fn new_on(executor: Option<runtime::ExecutorHandle>) -> Self {
+        let (send_incoming, recv_incoming) = smol::channel::bounded(1000);
+        TcpBackhaul {
+            inner: Arc::new(TcpInner {
+                conns: Default::default(),
+                send_incoming,
+                recv_incoming,
+                local_addr: None,
+                executor,
+                _accept_task: None,
+            }),
+        }
+    }
+
+    /// Creates a backhaul that also takes connections on the given address.
+    pub async fn listen(addr: SocketAddr) -> io::Result<Self> {
+        Self::listen_on(addr, None).await
+    }
+
+    /// Like [TcpBackhaul::listen], but runs the accepting task and those of its connections on the given executor rather than the global one of [runtime](crate::runtime).
+    pub async fn listen_on(
+        addr: SocketAddr,
+        executor: Option<runtime::ExecutorHandle>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let conns: TcpConnTable = Default::default();
+        let (send_incoming, recv_incoming) = smol::channel::bounded(1000);
+        let accept_task = {
+            let conns = conns.clone();
+            let send_incoming = send_incoming.clone();
+            let conn_executor = executor.clone();
+            runtime::spawn_on(executor.as_ref(), async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, peer)) => {
+                            if conns.lock().len() >= MAX_TCP_CONNS {
+                                log::warn!("TCP backhaul refusing {}: too many connections", peer);
+                                continue;
+                            }
+                            let _ = tcp_configure(&stream);
+                            let conn = TcpConn::new(
+                                smol::future::ready(Ok(stream)),
+                                peer,
+                                send_incoming.clone(),
+                                Arc::downgrade(&conns),
+                                conn_executor.as_ref(),
+                            );
+                            conns.lock().insert(peer, conn);
+                        }
+                        Err(err) => {
+                            log::warn!("TCP backhaul can't accept: {}", err);
+                            smol::Timer::after(std::time::Duration::from_secs(1)).await;
+                        }
+                    }
+                }
+            })
+        };
+        Ok(TcpBackhaul {
+            inner: Arc::new(TcpInner {
+                conns,
+                send_incoming,
+                recv_incoming,
+                local_addr: Some(local_addr),
+                executor,
+                _accept_task: Some(accept_task),
+            }),
+        })
+    }
+
+    /// The address connections are taken on, if the backhaul listens.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.inner.local_addr
+    }
+}
+
+impl Default for TcpBackhaul {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait::async_trait]
+impl Backhaul for TcpBackhaul {
+    async fn recv_from(&self) -> io::Result<(Bytes, SocketAddr)> {
+        // the backhaul holds a sender itself, so this never fails
+        Ok(self.inner.recv_incoming.recv().await.unwrap())
+    }
+
+    async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> io::Result<()> {
+        if to_send.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram too long to frame",
+            ));
+        }
+        let send_outgoing = {
+            let mut conns = self.inner.conns.lock();
+            match conns.get(&dest) {
+                Some(conn) => conn.send_outgoing.clone(),
+                // connecting back out would let anyone who spoofs a packet make the listener connect anywhere
+                None if self.inner.local_addr.is_some() => {
+                    log::trace!("TCP backhaul dropping datagram to unconnected {}", dest);
+                    return Ok(());
+                }
+                // the connection goes in the table before it's made, so that concurrent sends share it
+                None => {
+                    let conn = TcpConn::new(
+                        tcp_connect(dest),
+                        dest,
+                        self.inner.send_incoming.clone(),
+                        Arc::downgrade(&self.inner.conns),
+                        self.inner.executor.as_ref(),
+                    );
+                    let send = conn.send_outgoing.clone();
+                    conns.insert(dest, conn);
+                    send
+                }
+            }
+        };
+        // just like UDP, drop rather than wait when the connection can't keep up
+        let _ = send_outgoing.try_send(to_send);
+        Ok(())
+    }
+}
+
+/// Batched UDP I/O with sendmmsg and recvmmsg, so that a whole batch of datagrams costs one syscall.
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use bytes::Bytes;
+    use std::{
+        io,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6},
+        os::unix::io::RawFd,
+    };
+
+    /// Most datagrams handed to or taken from the kernel in one call.
+    const MAX_BATCH: usize = 32;
+
+    /// Sends as many of the datagrams as the kernel takes in one go, returning how many were sent.
+    pub(super) fn send_batch(fd: RawFd, batch: &[Bytes], dest: SocketAddr) -> io::Result<usize> {
+        let batch = &batch[..batch.len().min(MAX_BATCH)];
+        let dest = socket2::SockAddr::from(dest);
+        let mut iovecs: Vec<libc::iovec> = batch
+            .iter()
+            .map(|datagram| libc::iovec {
+                iov_base: datagram.as_ptr() as *mut libc::c_void,
+                iov_len: datagram.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iovec| {
+                let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+                hdr.msg_name = dest.as_ptr() as *mut libc::c_void;
+                hdr.msg_namelen = dest.len();
+                hdr.msg_iov = iovec;
+                hdr.msg_iovlen = 1;
+                libc::mmsghdr {
+                    msg_hdr: hdr,
+                    msg_len: 0,
+                }
+            })
+            .collect();
+        let n = unsafe {
+            libc::sendmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+
+    /// Takes every datagram that is already waiting, up to a limit. Fails with WouldBlock if there are none.
+    pub(super) fn recv_batch(fd: RawFd) -> io::Result<Vec<(Bytes, SocketAddr)>> {
+        let mut bufs = vec![[0u8; 2048]; MAX_BATCH];
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { std::mem::zeroed() }; MAX_BATCH];
+        let mut iovecs: Vec<libc::iovec> = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .zip(addrs.iter_mut())
+            .map(|(iovec, addr)| {
+                let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+                hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+                hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                hdr.msg_iov = iovec;
+                hdr.msg_iovlen = 1;
+                libc::mmsghdr {
+                    msg_hdr: hdr,
+                    msg_len: 0,
+                }
+            })
+            .collect();
+        let n = unsafe {
+            libc::recvmmsg(
+                fd,
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let n = n as usize;
+        let mut received = Vec::with_capacity(n);
+        for (i, msg) in msgs.iter().take(n).enumerate() {
+            let len = msg.msg_len as usize;
+            received.push((
+                Bytes::copy_from_slice(&bufs[i][..len]),
+                to_socket_addr(&addrs[i])?,
+            ));
+        }
+        Ok(received)
+    }
+
+    fn to_socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddr::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into(),
+                    u16::from_be(addr.sin_port),
+                ))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+                Ok(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )
+                .into())
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown address family {}", family),
+            )),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::Backhaul;
+        use std::os::unix::io::AsRawFd;
+
+        #[test]
+        fn batch_goes_out_in_one_call() {
+            smol::block_on(async {
+                let sender = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let receiver = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let batch: Vec<Bytes> = (0..8u8).map(|i| Bytes::from(vec![i; 100])).collect();
+                let sent =
+                    send_batch(sender.as_raw_fd(), &batch, receiver.local_addr().unwrap()).unwrap();
+                assert_eq!(sent, 8);
+                let mut received = Vec::new();
+                while received.len() < 8 {
+                    received.extend(receiver.recv_many().await.unwrap());
+                }
+                let sender_addr = sender.local_addr().unwrap();
+                assert!(received.iter().all(|(_, addr)| *addr == sender_addr));
+                let received: Vec<Bytes> = received.into_iter().map(|(bts, _)| bts).collect();
+                assert_eq!(received, batch);
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_backhaul_carries_datagrams() {
+        smol::block_on(async {
+            let server = TcpBackhaul::listen("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let server_addr = server.local_addr().unwrap();
+            let client = TcpBackhaul::new();
+            for i in 0..10u8 {
+                client
+                    .send_to(Bytes::from(vec![i; 1000]), server_addr)
+                    .await
+                    .unwrap();
+            }
+            let mut client_addr = None;
+            for i in 0..10u8 {
+                let (datagram, from) = server.recv_from().await.unwrap();
+                assert_eq!(datagram, vec![i; 1000]);
+                client_addr = Some(from);
+            }
+            // replies go back over the same connection
+            server
+                .send_to(Bytes::from_static(b"hello"), client_addr.unwrap())
+                .await
+                .unwrap();
+            let (datagram, from) = client.recv_from().await.unwrap();
+            assert_eq!(datagram, Bytes::from_static(b"hello"));
+            assert_eq!(from, server_addr);
+        })
+    }
+
+    #[test]
+    fn tcp_first_sends_share_a_connection() {
+        smol::block_on(async {
+            let server = TcpBackhaul::listen("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let server_addr = server.local_addr().unwrap();
+            let client = TcpBackhaul::new();
+            let (a, b) = client
+                .send_to(Bytes::from_static(b"a"), server_addr)
+                .zip(client.send_to(Bytes::from_static(b"b"), server_addr))
+                .await;
+            a.unwrap();
+            b.unwrap();
+            let (_, from_a) = server.recv_from().await.unwrap();
+            let (_, from_b) = server.recv_from().await.unwrap();
+            assert_eq!(from_a, from_b);
+            assert_eq!(server.inner.conns.lock().len(), 1);
+        })
+    }
+
+    #[test]
+    fn tcp_failed_connects_are_forgotten() {
+        smol::block_on(async {
+            let nobody = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let nobody_addr = nobody.local_addr().unwrap();
+            drop(nobody);
+            let client = TcpBackhaul::new();
+            // sending never waits for the connection
+            client
+                .send_to(Bytes::from_static(b"hello"), nobody_addr)
+                .await
+                .unwrap();
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+            while !client.inner.conns.lock().is_empty() {
+                assert!(
+                    std::time::Instant::now() < deadline,
+                    "failed connection kept"
+                );
+                smol::Timer::after(std::time::Duration::from_millis(10)).await;
+            }
+        })
+    }
+
+    #[test]
+    fn tcp_listener_never_connects_out() {
+        smol::block_on(async {
+            let server = TcpBackhaul::listen("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let server_addr = server.local_addr().unwrap();
+            // nothing listens here, yet the datagram is dropped right away rather than failing a connect
+            let nobody = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let nobody_addr = nobody.local_addr().unwrap();
+            drop(nobody);
+            server
+                .send_to(Bytes::from_static(b"hello"), nobody_addr)
+                .await
+                .unwrap();
+            assert!(server.inner.conns.lock().is_empty());
+            // connections that go away are forgotten
+            let client = TcpBackhaul::new();
+            client
+                .send_to(Bytes::from_static(b"hello"), server_addr)
+                .await
+                .unwrap();
+            server.recv_from().await.unwrap();
+            assert_eq!(server.inner.conns.lock().len(), 1);
+            drop(client);
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+            while !server.inner.conns.lock().is_empty() {
+                assert!(std::time::Instant::now() < deadline, "dead connection kept");
+                smol::Timer::after(std::time::Duration::from_millis(10)).await;
+            }
+        })
+    }
 }
@@ -13,41 +13,360 @@ pub async fn connect(
     server_addr: SocketAddr,
     pubkey: x25519_dalek::PublicKey,
 ) -> std::io::Result<Session> {
-    connect_custom(server_addr, pubkey, || {
-        let val = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
-        Ok(val)
-    })
+    connect_with_options(server_addr, pubkey, ConnectOptions::default()).await
+}
+
+/// Connects to a remote server, with the given options.
+pub async fn connect_with_options(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    opts: ConnectOptions,
+) -> std::io::Result<Session> {
+    connect_custom(
+        server_addr,
+        pubkey,
+        move || Ok(any_local_addr(server_addr)),
+        opts,
+    )
     .await
 }
 
-/// Connects to a remote server, given a closure that generates socket addresses.
+/// Why a handshake turned the server down. Connecting then fails with an io::Error that carries one of these, which [HandshakeError::of] gets back out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    /// The server presented a long-term key that isn't trusted. The error kind is ConnectionRefused.
+    UntrustedKey,
+    /// The server speaks none of the protocol versions we do, but the given range instead. The error kind is InvalidData.
+    VersionMismatch { min_version: u64, max_version: u64 },
+}
+
+impl HandshakeError {
+    /// Finds the handshake error an io::Error carries, if any.
+    pub fn of(err: &std::io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref::<HandshakeError>().copied()
+    }
+
+    fn into_io(self) -> std::io::Error {
+        let kind = match self {
+            HandshakeError::UntrustedKey => std::io::ErrorKind::ConnectionRefused,
+            HandshakeError::VersionMismatch { .. } => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, self)
+    }
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::UntrustedKey => f.write_str("bad pubkey"),
+            HandshakeError::VersionMismatch {
+                min_version,
+                max_version,
+            } => write!(
+                f,
+                "server speaks versions {}..={}, but we speak {}..={}",
+                min_version,
+                max_version,
+                msg::MIN_VERSION,
+                msg::MAX_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
+
+/// Picks an unspecified local address of the same family as the server address, so that the socket can actually reach it.
+fn any_local_addr(server_addr: SocketAddr) -> SocketAddr {
+    match server_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    }
+}
+
+/// Decides whether the long-term public key a server presents in its ServerHello is acceptable. See [ConnectOptions::verify_server] for what this can and can't do.
+pub type ServerKeyVerifier = Arc<dyn Fn(&x25519_dalek::PublicKey) -> bool + Send + Sync>;
+
+/// Options controlling how a client handshake is carried out.
+#[derive(Clone)]
+pub struct ConnectOptions {
+    /// How many ClientHellos to send before giving up with a TimedOut error.
+    pub max_tries: u32,
+    /// Upper bound on how long to wait for a reply to any one ClientHello. The wait doubles on every attempt, starting from one second.
+    pub max_timeout: Duration,
+    /// Observer notified of handshake lifecycle events.
+    pub observer: Option<Arc<dyn HandshakeObserver>>,
+    /// Extra check on the long-term public key the server presents, for example that it's still listed by a trusted authority. It only runs once the key is known to be exactly the one the connection was made with, which is always checked: handshake packets are obfuscated under the public key alone, which anyone may know, so that match is what authenticates the server. A verifier can therefore only refuse keys, never admit other ones such as a rotated key.
+    pub verify_server: Option<ServerKeyVerifier>,
+    /// How many UDP sockets the session spreads its traffic over. More shards add redundancy, fewer save data. At least one is always used.
+    pub shards: u8,
+    /// How often every shard moves to a freshly bound UDP socket. Shards take turns, spread evenly over the interval, so they never all rebind at once. None keeps the same sockets for the whole session, while a zero interval is refused.
+    pub rebind_interval: Option<Duration>,
+    /// How data frames sent to the server are padded.
+    pub padding: PaddingPolicy,
+    /// How handshake packets sent to the server are padded. Bootstrapping over expensive links can use less padding here, while data frames stay fully padded.
+    pub handshake_padding: PaddingPolicy,
+    /// Fraction by which every resume interval is randomly lengthened or shortened, so that many clients connecting to the same server don't all resend their resume tokens and rebind at the same moments. Zero keeps the intervals exact.
+    pub timer_jitter: f64,
+    /// Whether shards send and receive datagrams in batches, which saves a lot of syscalls at high packet rates. Only UDP on Linux actually batches (with sendmmsg and recvmmsg); other backhauls fall back to one datagram at a time.
+    pub udp_batching: bool,
+    /// Whether the shards of every FEC run are sent to the server in a random order rather than data first, then parity. Middleboxes that drop packets at fixed positions then hit different shards every run, instead of always the same ones.
+    pub shuffle_shards: bool,
+    /// Interface, firewall mark and DSCP class of the UDP sockets of the session, including those that shards rebind to. Other backhauls don't use it.
+    pub binding: runtime::SocketBinding,
+    /// The FEC code of the session. The server is told which one it is, and refuses codes it doesn't know; codes other than Reed-Solomon can't be used with servers that predate this.
+    pub fec_codec: Arc<dyn FecCodec>,
+    /// Percentile of recent loss samples that the session sizes its FEC for. Raising it from the default of 0.25 trades bandwidth for fewer unrecovered losses.
+    pub loss_percentile: f64,
+    /// How the session estimates the loss that its FEC is sized for. [LossEstimator::Ewma] reacts to bursts of loss much faster than the default.
+    pub loss_estimator: LossEstimator,
+    /// Largest datagram, in bytes of UDP payload, that padding may grow handshake packets and data frames to. Frames whose contents alone are bigger are still sent whole, so this keeps padding from pushing datagrams over the path MTU, but doesn't split anything. Servers are told about a limit below the default, so that their padding stays within it too, unless they predate that.
+    pub max_datagram_size: usize,
+    /// Whether the handshake first checks that datagrams of `max_datagram_size` reach the server, with probes padded to exactly that size. If those don't get through while smaller ones do, datagrams stay within 1200 bytes, which nearly every path carries. Padding on both ends then stays within whatever size got through, which shows up in [SessionStats](crate::SessionStats). Servers that predate probing never answer probes, so sessions to them always settle on 1200 bytes.
+    pub probe_mtu: bool,
+    /// Executor that the session, its shards and any multiplex built on it run their tasks on. None uses the global one of [runtime](crate::runtime).
+    pub executor: Option<runtime::ExecutorHandle>,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            max_tries: 6,
+            max_timeout: Duration::from_secs(16),
+            observer: None,
+            verify_server: None,
+            shards: 2,
+            rebind_interval: Some(DEFAULT_REBIND_INTERVAL),
+            padding: PaddingPolicy::default(),
+            handshake_padding: PaddingPolicy::default(),
+            timer_jitter: 0.1,
+            udp_batching: false,
+            shuffle_shards: false,
+            binding: runtime::SocketBinding::default(),
+            fec_codec: Arc::new(ReedSolomonCodec),
+            loss_percentile: 0.25,
+            loss_estimator: LossEstimator::Windowed,
+            max_datagram_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            probe_mtu: false,
+            executor: None,
+        }
+    }
+}
+
+/// Hooks into the lifecycle of a client handshake, for example to collect metrics. Every method does nothing by default.
+pub trait HandshakeObserver: Send + Sync {
+    /// Called once before the first ClientHello is sent.
+    fn on_start(&self, _server_addr: SocketAddr) {}
+    /// Called every time a ClientHello is sent, with attempts counted from zero.
+    fn on_attempt(&self, _server_addr: SocketAddr, _attempt: u32) {}
+    /// Called when the handshake succeeds, with the time elapsed since it started.
+    fn on_success(&self, _server_addr: SocketAddr, _elapsed: Duration) {}
+    /// Called when the handshake fails, with the error that is returned to the caller.
+    fn on_failure(&self, _server_addr: SocketAddr, _elapsed: Duration, _error: &std::io::Error) {}
+}
+
+/// Connects to a remote server, given a closure that generates socket addresses and the handshake options.
 pub async fn connect_custom(
     server_addr: SocketAddr,
     pubkey: x25519_dalek::PublicKey,
     laddr_gen: impl Fn() -> std::io::Result<SocketAddr> + Send + Sync + 'static,
+    opts: ConnectOptions,
+) -> std::io::Result<Session> {
+    let binding = opts.binding.clone();
+    connect_backhaul(
+        server_addr,
+        pubkey,
+        move || runtime::new_udp_socket(laddr_gen()?, &binding),
+        opts,
+    )
+    .await
+}
+
+/// Connects to a remote server over an arbitrary datagram transport rather than UDP, given a closure that creates a fresh [Backhaul]. One backhaul is created for the handshake, one for every shard, and one every time a shard rebinds.
+pub async fn connect_backhaul<B: Backhaul + 'static>(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    backhaul_gen: impl Fn() -> std::io::Result<B> + Send + Sync + 'static,
+    opts: ConnectOptions,
+) -> std::io::Result<Session> {
+    let start = Instant::now();
+    if let Some(observer) = &opts.observer {
+        observer.on_start(server_addr);
+    }
+    let res = handshake(server_addr, pubkey, backhaul_gen, &opts).await;
+    if let Some(observer) = &opts.observer {
+        let elapsed = start.elapsed();
+        match &res {
+            Ok(_) => observer.on_success(server_addr, elapsed),
+            Err(err) => observer.on_failure(server_addr, elapsed, err),
+        }
+    }
+    res
+}
+
+/// Connects to whichever of several addresses of the same server answers first. Handshakes are started in a staggered fashion, and the losers are cancelled once one succeeds.
+pub async fn connect_multi(
+    addrs: Vec<SocketAddr>,
+    pubkey: x25519_dalek::PublicKey,
+    laddr_gen: impl Fn() -> std::io::Result<SocketAddr> + Send + Sync + 'static,
+    opts: ConnectOptions,
+) -> std::io::Result<Session> {
+    if addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no addresses to connect to",
+        ));
+    }
+    let laddr_gen = Arc::new(laddr_gen);
+    let (send_res, recv_res) = smol::channel::unbounded();
+    let attempt_count = addrs.len();
+    // dropping these cancels the handshakes that are still running
+    let _attempts: Vec<smol::Task<()>> = addrs
+        .into_iter()
+        .enumerate()
+        .map(|(i, addr)| {
+            let laddr_gen = laddr_gen.clone();
+            let opts = opts.clone();
+            let send_res = send_res.clone();
+            runtime::spawn(async move {
+                smol::Timer::after(HAPPY_EYEBALLS_DELAY * i as u32).await;
+                let res = connect_custom(addr, pubkey, move || laddr_gen(), opts).await;
+                if let Err(err) = &res {
+                    log::debug!("connect_multi attempt to {} failed: {}", addr, err);
+                }
+                drop(send_res.send(res).await);
+            })
+        })
+        .collect();
+    let mut last_err = None;
+    for _ in 0..attempt_count {
+        match recv_res.recv().await {
+            Ok(Ok(session)) => return Ok(session),
+            Ok(Err(err)) => last_err = Some(err),
+            Err(_) => break,
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "all connection attempts failed",
+        )
+    }))
+}
+
+/// Result of probing a server.
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeResult {
+    /// Whether the server replied in time.
+    pub reachable: bool,
+    /// Round-trip time of the probe. Zero if the server is unreachable.
+    pub rtt: Duration,
+}
+
+/// Checks whether a server is alive and measures the round-trip time to it, with a single cheap exchange that doesn't open a session.
+pub async fn probe(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+) -> std::io::Result<ProbeResult> {
+    probe_with_binding(server_addr, pubkey, &runtime::SocketBinding::default()).await
+}
+
+/// Like [probe], but sends the probe from a socket set up with the given binding, just like the sessions it stands in for.
+pub async fn probe_with_binding(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    binding: &runtime::SocketBinding,
+) -> std::io::Result<ProbeResult> {
+    let udp_socket = runtime::new_udp_socket(any_local_addr(server_addr), binding)?;
+    let cookie = crypt::Cookie::new(pubkey);
+    let nonce: u64 = rand::random();
+    let probe = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap()).pad_encrypt(
+        &msg::HandshakeFrame::ClientProbe { nonce },
+        PaddingPolicy::default(),
+    );
+    let start = Instant::now();
+    udp_socket.send_to(&probe, server_addr).await?;
+    let mut buf = [0u8; 2048];
+    let wait_reply = async {
+        loop {
+            let (n, _) = udp_socket.recv_from(&mut buf).await?;
+            for possible_key in cookie.generate_s2c() {
+                let decrypter = crypt::StdAEAD::new(&possible_key);
+                let response: Option<msg::HandshakeFrame> = decrypter.pad_decrypt(&buf[..n]);
+                if let Some(msg::HandshakeFrame::ServerProbe { nonce: echoed }) = response {
+                    if echoed == nonce {
+                        return Ok::<_, std::io::Error>(Some(start.elapsed()));
+                    }
+                }
+            }
+        }
+    };
+    let rtt = wait_reply
+        .or(async {
+            smol::Timer::after(PROBE_TIMEOUT).await;
+            Ok(None)
+        })
+        .await?;
+    Ok(ProbeResult {
+        reachable: rtt.is_some(),
+        rtt: rtt.unwrap_or_default(),
+    })
+}
+
+async fn handshake<B: Backhaul + 'static>(
+    server_addr: SocketAddr,
+    pubkey: x25519_dalek::PublicKey,
+    backhaul_gen: impl Fn() -> std::io::Result<B> + Send + Sync + 'static,
+    opts: &ConnectOptions,
 ) -> std::io::Result<Session> {
-    let udp_socket = runtime::new_udp_socket_bind(laddr_gen()?).await?;
+    if opts.rebind_interval == Some(Duration::from_secs(0)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "rebind interval must not be zero",
+        ));
+    }
+    let backhaul = backhaul_gen()?;
     let my_long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
     let my_eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
-    // do the handshake
     let cookie = crypt::Cookie::new(pubkey);
-    let init_hello = msg::HandshakeFrame::ClientHello {
-        long_pk: (&my_long_sk).into(),
-        eph_pk: (&my_eph_sk).into(),
-        version: 1,
+    let probe_size = if opts.probe_mtu {
+        let size = probe_datagram_size(
+            &backhaul_gen()?,
+            server_addr,
+            &cookie,
+            opts.max_datagram_size,
+        )
+        .await?;
+        log::debug!("datagrams of {} bytes reach {}", size, server_addr);
+        Some(size)
+    } else {
+        None
     };
-    let mut buf = [0u8; 2048];
-    for timeout_factor in (0u32..).map(|x| 2u64.pow(x)) {
+    let max_datagram_size = probe_size.unwrap_or(opts.max_datagram_size);
+    // do the handshake
+    for attempt in 0..opts.max_tries {
+        let init_hello = client_hello(
+            &my_long_sk,
+            &my_eph_sk,
+            attempt,
+            opts.fec_codec.id(),
+            max_datagram_size,
+        );
+        let timeout = Duration::from_secs(2u64.saturating_pow(attempt)).min(opts.max_timeout);
+        if let Some(observer) = &opts.observer {
+            observer.on_attempt(server_addr, attempt);
+        }
+        let hello_padding = opts.handshake_padding.capped(max_datagram_size);
         // send hello
         let init_hello = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap())
-            .pad_encrypt(&init_hello, 1000);
-        udp_socket.send_to(&init_hello, server_addr).await?;
+            .pad_encrypt(&init_hello, hello_padding);
+        backhaul.send_to(init_hello, server_addr).await?;
         log::trace!("sent client hello");
         // wait for response
-        let res = udp_socket
-            .recv_from(&mut buf)
+        let res = backhaul
+            .recv_from()
             .or(async {
-                smol::Timer::after(Duration::from_secs(timeout_factor)).await;
+                smol::Timer::after(timeout).await;
                 Err(std::io::Error::new(
                     std::io::ErrorKind::TimedOut,
                     "timed out",
@@ -55,23 +374,51 @@ pub async fn connect_custom(
             })
             .await;
         match res {
-            Ok((n, _)) => {
-                let buf = &buf[..n];
+            Ok((buf, _)) => {
                 for possible_key in cookie.generate_s2c() {
                     let decrypter = crypt::StdAEAD::new(&possible_key);
-                    let response: Option<msg::HandshakeFrame> = decrypter.pad_decrypt(buf);
-                    if let Some(msg::HandshakeFrame::ServerHello {
-                        long_pk,
-                        eph_pk,
-                        resume_token,
-                    }) = response
-                    {
-                        log::trace!("obtained response from server");
-                        if long_pk.as_bytes() != pubkey.as_bytes() {
-                            return Err(std::io::Error::new(
-                                std::io::ErrorKind::ConnectionRefused,
-                                "bad pubkey",
-                            ));
+                    let response: Option<msg::HandshakeFrame> = decrypter.pad_decrypt(&buf);
+                    let server_hello = match response {
+                        Some(msg::HandshakeFrame::ServerVersionMismatch {
+                            min_version,
+                            max_version,
+                        }) => {
+                            return Err(HandshakeError::VersionMismatch {
+                                min_version,
+                                max_version,
+                            }
+                            .into_io())
+                        }
+                        Some(msg::HandshakeFrame::ServerHelloV2 {
+                            long_pk,
+                            eph_pk,
+                            resume_token,
+                            version,
+                        }) => Some((long_pk, eph_pk, resume_token, version)),
+                        // the answer of a server that predates negotiation, which only speaks version 1
+                        Some(msg::HandshakeFrame::ServerHello {
+                            long_pk,
+                            eph_pk,
+                            resume_token,
+                        }) => Some((long_pk, eph_pk, resume_token, 1)),
+                        _ => None,
+                    };
+                    if let Some((long_pk, eph_pk, resume_token, version)) = server_hello {
+                        log::trace!("obtained response from server with version {}", version);
+                        if msg::negotiate_version(version, version).is_none() {
+                            return Err(HandshakeError::VersionMismatch {
+                                min_version: version,
+                                max_version: version,
+                            }
+                            .into_io());
+                        }
+                        let trusted = long_pk.as_bytes() == pubkey.as_bytes()
+                            && opts
+                                .verify_server
+                                .as_ref()
+                                .map_or(true, |verify| verify(&long_pk));
+                        if !trusted {
+                            return Err(HandshakeError::UntrustedKey.into_io());
                         }
                         let shared_sec =
                             crypt::triple_ecdh(&my_long_sk, &my_eph_sk, &long_pk, &eph_pk);
@@ -80,7 +427,19 @@ pub async fn connect_custom(
                             resume_token,
                             shared_sec,
                             server_addr,
-                            Arc::new(laddr_gen),
+                            Arc::new(backhaul_gen),
+                            opts.shards.max(1),
+                            opts.rebind_interval,
+                            opts.padding.capped(max_datagram_size),
+                            opts.handshake_padding.capped(max_datagram_size),
+                            opts.timer_jitter,
+                            opts.udp_batching,
+                            opts.shuffle_shards,
+                            opts.fec_codec.clone(),
+                            opts.loss_percentile,
+                            opts.loss_estimator,
+                            probe_size,
+                            opts.executor.clone(),
                         )
                         .await;
                     }
@@ -89,9 +448,9 @@ pub async fn connect_custom(
             Err(err) => {
                 if err.kind() == std::io::ErrorKind::TimedOut {
                     log::trace!(
-                        "timed out to {} with {}s timeout; trying again",
+                        "timed out to {} with {:?} timeout; trying again",
                         server_addr,
-                        timeout_factor
+                        timeout
                     );
                     continue;
                 }
@@ -99,38 +458,181 @@ pub async fn connect_custom(
             }
         }
     }
-    unimplemented!()
+    Err(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        format!(
+            "no reply from {} after {} tries",
+            server_addr, opts.max_tries
+        ),
+    ))
+}
+
+/// How many ClientHelloV2s go unanswered before falling back to the hello of servers that predate negotiation.
+const V2_HELLO_TRIES: u32 = 2;
+
+/// The hello to send on the given attempt. Servers that predate ClientHelloV2 can't decode it and silently drop it, so as long as version 1 is all we speak and the server needn't be told to keep its datagrams small, the legacy ClientHello is sent instead. Otherwise the legacy hello is only tried once the ClientHelloV2s have gone unanswered. Either way, only ClientHelloV2 can name an FEC code other than Reed-Solomon, so with any other code it's all we send.
+fn client_hello(
+    long_sk: &x25519_dalek::StaticSecret,
+    eph_sk: &x25519_dalek::StaticSecret,
+    attempt: u32,
+    fec_codec: u8,
+    max_datagram_size: usize,
+) -> msg::HandshakeFrame {
+    let v2_first = msg::MAX_VERSION > 1 || max_datagram_size < DEFAULT_MAX_DATAGRAM_SIZE;
+    let legacy =
+        fec_codec == 0 && msg::MIN_VERSION <= 1 && (!v2_first || attempt >= V2_HELLO_TRIES);
+    if legacy {
+        msg::HandshakeFrame::ClientHello {
+            long_pk: long_sk.into(),
+            eph_pk: eph_sk.into(),
+            version: 1,
+        }
+    } else {
+        msg::HandshakeFrame::ClientHelloV2 {
+            long_pk: long_sk.into(),
+            eph_pk: eph_sk.into(),
+            min_version: msg::MIN_VERSION,
+            max_version: msg::MAX_VERSION,
+            fec_codec,
+            max_datagram_size: max_datagram_size as u64,
+        }
+    }
+}
+
+/// Finds out whether datagrams of the given size reach the server, with ClientProbes padded to exactly that size. Each goes out along with a small one, and rounds where the small one goes unanswered too are lost to ordinary loss, saying nothing about the size. Once the small probe alone has come back a few times, or if no round says anything at all, we fall back to a size nearly every path carries. Servers that predate probes never answer, so with them we always fall back.
+async fn probe_datagram_size(
+    backhaul: &impl Backhaul,
+    server_addr: SocketAddr,
+    cookie: &crypt::Cookie,
+    size: usize,
+) -> std::io::Result<usize> {
+    let c2s = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap());
+    let mut too_big = 0;
+    for _ in 0..MTU_PROBE_ROUNDS {
+        let (big_nonce, small_nonce): (u64, u64) = (rand::random(), rand::random());
+        let big = c2s.pad_encrypt(
+            &msg::HandshakeFrame::ClientProbe { nonce: big_nonce },
+            PaddingPolicy::Fixed(size.saturating_sub(crypt::AEAD_OVERHEAD)),
+        );
+        let small = c2s.pad_encrypt(
+            &msg::HandshakeFrame::ClientProbe { nonce: small_nonce },
+            PaddingPolicy::None,
+        );
+        backhaul.send_to(big, server_addr).await?;
+        backhaul.send_to(small, server_addr).await?;
+        let mut small_answered = false;
+        let big_answered = async {
+            loop {
+                let (buf, _) = backhaul.recv_from().await?;
+                for possible_key in cookie.generate_s2c() {
+                    let decrypter = crypt::StdAEAD::new(&possible_key);
+                    if let Some(msg::HandshakeFrame::ServerProbe { nonce }) =
+                        decrypter.pad_decrypt(&buf)
+                    {
+                        if nonce == big_nonce {
+                            return Ok::<_, std::io::Error>(true);
+                        }
+                        small_answered |= nonce == small_nonce;
+                    }
+                }
+            }
+        }
+        .or(async {
+            smol::Timer::after(MTU_PROBE_TIMEOUT).await;
+            Ok(false)
+        })
+        .await?;
+        if big_answered {
+            return Ok(size);
+        }
+        if small_answered {
+            too_big += 1;
+            if too_big >= MTU_PROBE_TRIES {
+                break;
+            }
+        }
+    }
+    Ok(size.min(MTU_PROBE_FALLBACK))
 }
 
-const SHARDS: u8 = 2;
-const RESET_MILLIS: u128 = 5000;
+const DEFAULT_REBIND_INTERVAL: Duration = Duration::from_secs(5);
+/// Shortest time a shard keeps reading its old socket after rebinding, for whatever the server sent there before it saw the new one. Longer rebind intervals drain for a whole interval, since the server may take that long to hear from the new socket.
+const MIN_REBIND_DRAIN: Duration = Duration::from_secs(5);
+const FEC_REORDER_RUNS: u64 = 10;
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_millis(250);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+const MAX_SEND_BATCH: usize = 32;
+/// How many times a full-sized probe must go unanswered while the small one next to it is answered before probing falls back.
+const MTU_PROBE_TRIES: u32 = 2;
+/// Most rounds of probes sent before probing gives up and falls back.
+const MTU_PROBE_ROUNDS: u32 = 3;
+/// How long each round of probes waits for replies.
+const MTU_PROBE_TIMEOUT: Duration = Duration::from_millis(500);
+/// Largest datagram that padding grows packets to, unless told otherwise.
+const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1400;
+/// Datagram size that probing falls back to. IPv6 requires links to carry 1280-byte packets, which leaves 1232 bytes of UDP payload.
+const MTU_PROBE_FALLBACK: usize = 1200;
 
-async fn init_session(
+async fn init_session<B: Backhaul + 'static>(
     cookie: crypt::Cookie,
     resume_token: Bytes,
     shared_sec: blake3::Hash,
     remote_addr: SocketAddr,
-    laddr_gen: Arc<impl Fn() -> std::io::Result<SocketAddr> + Send + Sync + 'static>,
+    backhaul_gen: Arc<impl Fn() -> std::io::Result<B> + Send + Sync + 'static>,
+    shards: u8,
+    rebind_interval: Option<Duration>,
+    padding: PaddingPolicy,
+    handshake_padding: PaddingPolicy,
+    timer_jitter: f64,
+    udp_batching: bool,
+    shuffle_shards: bool,
+    fec_codec: Arc<dyn FecCodec>,
+    loss_percentile: f64,
+    loss_estimator: LossEstimator,
+    probed_datagram_size: Option<usize>,
+    executor: Option<runtime::ExecutorHandle>,
 ) -> std::io::Result<Session> {
     let (send_frame_out, recv_frame_out) = smol::channel::bounded::<msg::DataFrame>(1000);
     let (send_frame_in, recv_frame_in) = smol::channel::bounded::<msg::DataFrame>(1000);
-    let backhaul_tasks: Vec<_> = (0..SHARDS)
+    let backhaul_tasks: Vec<_> = (0..shards)
         .map(|i| {
-            runtime::spawn(client_backhaul_once(
-                cookie.clone(),
-                resume_token.clone(),
-                send_frame_in.clone(),
-                recv_frame_out.clone(),
-                i,
-                remote_addr,
-                shared_sec,
-                laddr_gen.clone(),
-            ))
+            runtime::spawn_on(
+                executor.as_ref(),
+                client_backhaul_once(
+                    cookie.clone(),
+                    resume_token.clone(),
+                    send_frame_in.clone(),
+                    recv_frame_out.clone(),
+                    i,
+                    shards,
+                    remote_addr,
+                    shared_sec,
+                    backhaul_gen.clone(),
+                    rebind_interval,
+                    padding,
+                    handshake_padding,
+                    timer_jitter,
+                    udp_batching,
+                    executor.clone(),
+                ),
+            )
         })
         .collect();
     let mut session = Session::new(SessionConfig {
-        latency: std::time::Duration::from_millis(1),
+        min_latency: std::time::Duration::from_millis(1),
+        max_latency: std::time::Duration::from_millis(10),
+        max_packet_age: None,
+        idle_timeout: None,
         target_loss: 0.05,
+        loss_percentile,
+        loss_estimator,
+        min_loss_for_fec: 0.0,
+        fec_reorder_runs: FEC_REORDER_RUNS,
+        fec_eviction: EvictionPolicy::Count,
+        shuffle_shards,
+        fec_codec,
+        probed_datagram_size,
+        executor: executor.clone(),
         send_frame: send_frame_out,
         recv_frame: recv_frame_in,
     });
@@ -141,31 +643,43 @@ async fn init_session(
 }
 
 #[allow(clippy::all)]
-async fn client_backhaul_once(
+async fn client_backhaul_once<B: Backhaul + 'static>(
     cookie: crypt::Cookie,
     resume_token: Bytes,
     send_frame_in: Sender<msg::DataFrame>,
     recv_frame_out: Receiver<msg::DataFrame>,
     shard_id: u8,
+    shard_count: u8,
     remote_addr: SocketAddr,
     shared_sec: blake3::Hash,
-    laddr_gen: Arc<impl Fn() -> std::io::Result<SocketAddr> + Send + Sync + 'static>,
+    backhaul_gen: Arc<impl Fn() -> std::io::Result<B> + Send + Sync + 'static>,
+    rebind_interval: Option<Duration>,
+    padding: PaddingPolicy,
+    handshake_padding: PaddingPolicy,
+    timer_jitter: f64,
+    udp_batching: bool,
+    executor: Option<runtime::ExecutorHandle>,
 ) -> Option<()> {
     let up_key = blake3::keyed_hash(crypt::UP_KEY, shared_sec.as_bytes());
     let dn_key = blake3::keyed_hash(crypt::DN_KEY, shared_sec.as_bytes());
     let dn_crypter = Arc::new(crypt::StdAEAD::new(dn_key.as_bytes()));
     let up_crypter = Arc::new(crypt::StdAEAD::new(up_key.as_bytes()));
-    let mut buf = [0u8; 2048];
 
-    let mut last_resume = Instant::now();
     let mut updated = false;
-    let mut socket = runtime::new_udp_socket_bind(laddr_gen().ok()?).await.ok()?;
+    let mut socket = backhaul_gen().ok()?;
+    // without rebinding, the resume token is still resent periodically, which also keeps NAT mappings alive
+    let resume_interval = rebind_interval.unwrap_or(DEFAULT_REBIND_INTERVAL);
+    let rebind_drain = resume_interval.max(MIN_REBIND_DRAIN);
+    // shards are phase-shifted, so that some of them are always up while the others rebind
+    let mut next_resume = Instant::now()
+        + runtime::jitter(resume_interval, timer_jitter)
+        + rebind_offset(resume_interval, shard_id, shard_count);
     // let mut _old_cleanup: Option<smol::Task<Option<()>>> = None;
 
     #[derive(Debug)]
     enum Evt {
-        Incoming(msg::DataFrame),
-        Outgoing(Bytes),
+        Incoming(Vec<msg::DataFrame>),
+        Outgoing(Vec<Bytes>),
     };
 
     loop {
@@ -173,93 +687,850 @@ async fn client_backhaul_once(
         let down = {
             let dn_crypter = dn_crypter.clone();
             async move {
-                let (n, addr) = down_socket.recv_from(&mut buf).await.ok()?;
-                if let Some(plain) = dn_crypter.pad_decrypt::<msg::DataFrame>(&buf[..n]) {
-                    log::trace!("shard {} decrypted UDP message with len {}", shard_id, n);
-                    Some(Evt::Incoming(plain))
+                let datagrams = if udp_batching {
+                    down_socket.recv_many().await.ok()?
                 } else {
-                    log::warn!("anomalous UDP packet of len {} from {}", n, addr);
+                    vec![down_socket.recv_from().await.ok()?]
+                };
+                let mut frames = Vec::with_capacity(datagrams.len());
+                for (buf, addr) in datagrams {
+                    let n = buf.len();
+                    if let Some(plain) = dn_crypter.pad_decrypt::<msg::DataFrame>(&buf) {
+                        log::trace!("shard {} decrypted UDP message with len {}", shard_id, n);
+                        frames.push(plain);
+                    } else {
+                        log::warn!("anomalous UDP packet of len {} from {}", n, addr);
+                    }
+                }
+                if frames.is_empty() {
                     smol::future::pending().await
+                } else {
+                    Some(Evt::Incoming(frames))
                 }
             }
         };
         let up_crypter = up_crypter.clone();
         let up = async {
             let df = recv_frame_out.recv().await.ok()?;
-            let encrypted = up_crypter.pad_encrypt(df, 1000);
-            Some(Evt::Outgoing(encrypted))
+            let mut batch = vec![up_crypter.pad_encrypt(df, padding)];
+            // pick up whatever else is already queued, without waiting for more
+            while udp_batching && batch.len() < MAX_SEND_BATCH {
+                match recv_frame_out.try_recv() {
+                    Ok(df) => batch.push(up_crypter.pad_encrypt(df, padding)),
+                    Err(_) => break,
+                }
+            }
+            Some(Evt::Outgoing(batch))
         };
         match smol::future::race(down, up).await {
-            Some(Evt::Incoming(df)) => {
-                send_frame_in.send(df).await.ok()?;
+            Some(Evt::Incoming(frames)) => {
+                for df in frames {
+                    send_frame_in.send(df).await.ok()?;
+                }
             }
-            Some(Evt::Outgoing(bts)) => {
+            Some(Evt::Outgoing(batch)) => {
                 let now = Instant::now();
-                if now.saturating_duration_since(last_resume).as_millis() > RESET_MILLIS || !updated
-                {
+                let due = now >= next_resume;
+                if due || !updated {
                     updated = true;
-                    last_resume = Instant::now();
+                    // keep to the schedule rather than restarting it, so the phases don't drift together
+                    while next_resume <= now {
+                        next_resume += runtime::jitter(resume_interval, timer_jitter);
+                    }
                     let g_encrypt = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap());
-                    // also replace the UDP socket!
-                    let old_socket = socket.clone();
-                    let dn_crypter = dn_crypter.clone();
-                    let send_frame_in = send_frame_in.clone();
-                    // spawn a task to clean up the UDP socket
-                    let tata: smol::Task<Option<()>> = runtime::spawn(
-                        async move {
-                            loop {
-                                let (n, _) = old_socket.recv_from(&mut buf).await.ok()?;
-                                if let Some(plain) =
-                                    dn_crypter.pad_decrypt::<msg::DataFrame>(&buf[..n])
-                                {
-                                    log::trace!(
-                                        "shard {} decrypted UDP message with len {}",
-                                        shard_id,
-                                        n
-                                    );
-                                    drop(send_frame_in.send(plain).await)
+                    // the very first resume token just registers the fresh socket
+                    if rebind_interval.is_some() && due {
+                        // also replace the UDP socket!
+                        let old_socket = socket.clone();
+                        let dn_crypter = dn_crypter.clone();
+                        let send_frame_in = send_frame_in.clone();
+                        // spawn a task to clean up the UDP socket
+                        let tata: smol::Task<Option<()>> = runtime::spawn_on(
+                            executor.as_ref(),
+                            async move {
+                                loop {
+                                    let (buf, _) = old_socket.recv_from().await.ok()?;
+                                    if let Some(plain) =
+                                        dn_crypter.pad_decrypt::<msg::DataFrame>(&buf)
+                                    {
+                                        log::trace!(
+                                            "shard {} decrypted UDP message with len {}",
+                                            shard_id,
+                                            buf.len()
+                                        );
+                                        drop(send_frame_in.send(plain).await)
+                                    }
                                 }
                             }
-                        }
-                        .or(async {
-                            smol::Timer::after(Duration::from_secs(5)).await;
-                            None
-                        }),
-                    );
-                    tata.detach();
-                    socket = loop {
-                        match runtime::new_udp_socket_bind(laddr_gen().ok()?).await {
-                            Ok(sock) => break sock,
-                            Err(err) => {
-                                log::warn!("error rebinding: {}", err);
-                                smol::Timer::after(Duration::from_secs(1)).await;
+                            .or(async {
+                                smol::Timer::after(rebind_drain).await;
+                                None
+                            }),
+                        );
+                        tata.detach();
+                        socket = loop {
+                            match backhaul_gen() {
+                                Ok(sock) => break sock,
+                                Err(err) => {
+                                    log::warn!("error rebinding: {}", err);
+                                    smol::Timer::after(Duration::from_secs(1)).await;
+                                }
                             }
-                        }
-                    };
-                    log::trace!(
-                        "resending resume token {} to {} from {}...",
-                        shard_id,
-                        remote_addr,
-                        socket.local_addr().unwrap()
-                    );
+                        };
+                    }
+                    log::trace!("resending resume token {} to {}...", shard_id, remote_addr);
                     drop(
                         socket
                             .send_to(
-                                &g_encrypt.pad_encrypt(
+                                g_encrypt.pad_encrypt(
                                     msg::HandshakeFrame::ClientResume {
                                         resume_token: resume_token.clone(),
                                         shard_id,
                                     },
-                                    1000,
+                                    handshake_padding,
                                 ),
                                 remote_addr,
                             )
                             .await,
                     );
                 }
-                drop(socket.send_to(&bts, remote_addr).await);
+                if udp_batching {
+                    drop(socket.send_many(batch, remote_addr).await);
+                } else {
+                    for bts in batch {
+                        drop(socket.send_to(bts, remote_addr).await);
+                    }
+                }
             }
             None => return None,
         }
     }
 }
+
+/// How far into every rebind interval the given shard rebinds.
+fn rebind_offset(interval: Duration, shard_id: u8, shard_count: u8) -> Duration {
+    interval * shard_id as u32 / shard_count.max(1) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        attempts: Mutex<Vec<u32>>,
+        success: Mutex<Option<Duration>>,
+    }
+
+    impl HandshakeObserver for RecordingObserver {
+        fn on_attempt(&self, _server_addr: SocketAddr, attempt: u32) {
+            self.attempts.lock().push(attempt);
+        }
+
+        fn on_success(&self, _server_addr: SocketAddr, elapsed: Duration) {
+            *self.success.lock() = Some(elapsed);
+        }
+    }
+
+    #[test]
+    fn zero_rebind_interval_is_refused() {
+        smol::block_on(async {
+            let sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let err = connect_with_options(
+                "127.0.0.1:1".parse().unwrap(),
+                (&sk).into(),
+                ConnectOptions {
+                    rebind_interval: Some(Duration::from_secs(0)),
+                    ..ConnectOptions::default()
+                },
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        })
+    }
+
+    #[test]
+    fn observer_sees_success() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            let server_addr = listener.local_addr();
+            let _accept =
+                smol::spawn(async move { while listener.accept_session().await.is_some() {} });
+
+            let observer = Arc::new(RecordingObserver::default());
+            let before = Instant::now();
+            let _session = connect_custom(
+                server_addr,
+                pubkey,
+                || Ok("127.0.0.1:0".parse().unwrap()),
+                ConnectOptions {
+                    observer: Some(observer.clone()),
+                    ..ConnectOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            let total = before.elapsed();
+
+            let elapsed = observer.success.lock().expect("success event did not fire");
+            assert!(elapsed > Duration::from_secs(0));
+            assert!(elapsed <= total);
+            assert_eq!(observer.attempts.lock().first(), Some(&0));
+        })
+    }
+
+    #[test]
+    fn old_servers_get_hellos_they_understand() {
+        let sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+        for attempt in 0..6 {
+            let hello = client_hello(&sk, &sk, attempt, ReedSolomonCodec.id(), 1400);
+            if msg::MAX_VERSION == 1 || attempt >= V2_HELLO_TRIES {
+                assert!(matches!(
+                    hello,
+                    msg::HandshakeFrame::ClientHello { version: 1, .. }
+                ));
+            } else {
+                assert!(matches!(hello, msg::HandshakeFrame::ClientHelloV2 { .. }));
+            }
+            // but they'd never know which code to use
+            let hello = client_hello(&sk, &sk, attempt, XorParityCodec.id(), 1400);
+            assert!(matches!(
+                hello,
+                msg::HandshakeFrame::ClientHelloV2 { fec_codec: 1, .. }
+            ));
+            // nor that they should keep their datagrams small, though that's no reason not to connect
+            let hello = client_hello(&sk, &sk, attempt, ReedSolomonCodec.id(), 1200);
+            if attempt >= V2_HELLO_TRIES {
+                assert!(matches!(hello, msg::HandshakeFrame::ClientHello { .. }));
+            } else {
+                assert!(matches!(
+                    hello,
+                    msg::HandshakeFrame::ClientHelloV2 {
+                        max_datagram_size: 1200,
+                        ..
+                    }
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn server_key_goes_through_the_verifier() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            let server_addr = listener.local_addr();
+            let _accept =
+                smol::spawn(async move { while listener.accept_session().await.is_some() {} });
+
+            let connect = |verify: ServerKeyVerifier| {
+                connect_custom(
+                    server_addr,
+                    pubkey,
+                    || Ok("127.0.0.1:0".parse().unwrap()),
+                    ConnectOptions {
+                        verify_server: Some(verify),
+                        ..ConnectOptions::default()
+                    },
+                )
+            };
+            let err = connect(Arc::new(|_| false)).await.err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+            assert_eq!(HandshakeError::of(&err), Some(HandshakeError::UntrustedKey));
+            let seen = Arc::new(Mutex::new(None));
+            let seen_clo = seen.clone();
+            connect(Arc::new(move |pk| {
+                *seen_clo.lock() = Some(*pk);
+                true
+            }))
+            .await
+            .unwrap();
+            assert_eq!(seen.lock().unwrap().as_bytes(), pubkey.as_bytes());
+        })
+    }
+
+    #[test]
+    fn connect_multi_skips_dead_address() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            let server_addr = listener.local_addr();
+            let _accept =
+                smol::spawn(async move { while listener.accept_session().await.is_some() {} });
+            let silent = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+            let before = Instant::now();
+            connect_multi(
+                vec![silent.local_addr().unwrap(), server_addr],
+                pubkey,
+                || Ok("127.0.0.1:0".parse().unwrap()),
+                ConnectOptions::default(),
+            )
+            .await
+            .unwrap();
+            // the live address wins without waiting for the dead one to time out
+            assert!(before.elapsed() < Duration::from_secs(1));
+        })
+    }
+
+    #[test]
+    fn probe_loopback_server() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            let before = Instant::now();
+            let result = probe(listener.local_addr(), pubkey).await.unwrap();
+            assert!(result.reachable);
+            assert!(result.rtt > Duration::from_secs(0));
+            assert!(result.rtt <= before.elapsed());
+            assert!(result.rtt < Duration::from_secs(1));
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn probe_uses_the_binding() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            let binding = runtime::SocketBinding {
+                device: Some("geph-no-such-if".into()),
+                ..Default::default()
+            };
+            assert!(probe_with_binding(listener.local_addr(), pubkey, &binding)
+                .await
+                .is_err());
+        })
+    }
+
+    #[test]
+    fn ipv6_server_gets_ipv6_socket() {
+        smol::block_on(async {
+            let server_addr: SocketAddr = "[2001:db8::1]:12345".parse().unwrap();
+            let laddr = any_local_addr(server_addr);
+            assert!(laddr.is_ipv6());
+            let socket = runtime::new_udp_socket_bind(laddr).await.unwrap();
+            assert!(socket.local_addr().unwrap().is_ipv6());
+            let server_addr: SocketAddr = "192.0.2.1:12345".parse().unwrap();
+            assert!(any_local_addr(server_addr).is_ipv4());
+        })
+    }
+
+    #[test]
+    fn custom_shard_counts() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            for &shards in &[1u8, 4] {
+                let client = connect_with_options(
+                    listener.local_addr(),
+                    pubkey,
+                    ConnectOptions {
+                        shards,
+                        ..ConnectOptions::default()
+                    },
+                )
+                .await
+                .unwrap();
+                // the server only learns about the session once the client sends something
+                client.send_bytes(Bytes::from_static(b"hello")).await;
+                let server = listener.accept_session().await.unwrap();
+                let received = server
+                    .recv_bytes()
+                    .or(async {
+                        smol::Timer::after(Duration::from_secs(5)).await;
+                        panic!("nothing arrived with {} shards", shards)
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(received, Bytes::from_static(b"hello"));
+            }
+        })
+    }
+
+    #[test]
+    fn handshake_padding_is_separate() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            // relay everything through a socket that records the sizes of client packets
+            let relay = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let upstream = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            upstream.connect(listener.local_addr()).await.unwrap();
+            let sizes = Arc::new(Mutex::new(Vec::new()));
+            let client_addr = Arc::new(Mutex::new(None));
+            let _up = {
+                let (relay, upstream) = (relay.clone(), upstream.clone());
+                let (sizes, client_addr) = (sizes.clone(), client_addr.clone());
+                smol::spawn(async move {
+                    let mut buf = [0u8; 2048];
+                    loop {
+                        let (n, addr) = relay.recv_from(&mut buf).await.unwrap();
+                        sizes.lock().push(n);
+                        *client_addr.lock() = Some(addr);
+                        drop(upstream.send(&buf[..n]).await);
+                    }
+                })
+            };
+            let _down = {
+                let relay = relay.clone();
+                smol::spawn(async move {
+                    let mut buf = [0u8; 2048];
+                    loop {
+                        let n = upstream.recv(&mut buf).await.unwrap();
+                        let addr = *client_addr.lock();
+                        if let Some(addr) = addr {
+                            drop(relay.send_to(&buf[..n], addr).await);
+                        }
+                    }
+                })
+            };
+
+            let client = connect_custom(
+                relay.local_addr().unwrap(),
+                pubkey,
+                || Ok("127.0.0.1:0".parse().unwrap()),
+                ConnectOptions {
+                    shards: 1,
+                    rebind_interval: None,
+                    padding: PaddingPolicy::Fixed(1000),
+                    handshake_padding: PaddingPolicy::Fixed(200),
+                    ..ConnectOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            client.send_bytes(Bytes::from_static(b"hello")).await;
+            let server = listener.accept_session().await.unwrap();
+            server
+                .recv_bytes()
+                .or(async {
+                    smol::Timer::after(Duration::from_secs(5)).await;
+                    panic!("nothing arrived through the relay")
+                })
+                .await
+                .unwrap();
+
+            let sizes = sizes.lock().clone();
+            // the ClientHello and ClientResume are small, while the data frames are fully padded
+            let handshake_size = sizes[0];
+            assert!(
+                handshake_size < 300,
+                "handshake was {} bytes",
+                handshake_size
+            );
+            assert!(sizes.contains(&(handshake_size + 800)));
+            assert!(sizes
+                .iter()
+                .all(|&n| n == handshake_size || n == handshake_size + 800));
+        })
+    }
+
+    #[test]
+    fn probing_finds_a_smaller_mtu() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            // the server pads to more than the path carries, unless told otherwise
+            let listener = Listener::listen_with_options(
+                "127.0.0.1:0",
+                long_sk,
+                crate::ListenerOptions {
+                    padding: PaddingPolicy::Fixed(1300),
+                    ..Default::default()
+                },
+            )
+            .await;
+            // relay everything through a socket that drops client packets too big for the path
+            let relay = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let upstream = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            upstream.connect(listener.local_addr()).await.unwrap();
+            let sizes = Arc::new(Mutex::new(Vec::new()));
+            let client_addr = Arc::new(Mutex::new(None));
+            let _up = {
+                let (relay, upstream) = (relay.clone(), upstream.clone());
+                let (sizes, client_addr) = (sizes.clone(), client_addr.clone());
+                smol::spawn(async move {
+                    let mut buf = [0u8; 2048];
+                    loop {
+                        let (n, addr) = relay.recv_from(&mut buf).await.unwrap();
+                        *client_addr.lock() = Some(addr);
+                        if n <= 1250 {
+                            sizes.lock().push(n);
+                            drop(upstream.send(&buf[..n]).await);
+                        }
+                    }
+                })
+            };
+            let down_sizes = Arc::new(Mutex::new(Vec::new()));
+            let _down = {
+                let relay = relay.clone();
+                let down_sizes = down_sizes.clone();
+                smol::spawn(async move {
+                    let mut buf = [0u8; 2048];
+                    loop {
+                        let n = upstream.recv(&mut buf).await.unwrap();
+                        down_sizes.lock().push(n);
+                        let addr = *client_addr.lock();
+                        if let Some(addr) = addr {
+                            drop(relay.send_to(&buf[..n], addr).await);
+                        }
+                    }
+                })
+            };
+
+            let client = connect_custom(
+                relay.local_addr().unwrap(),
+                pubkey,
+                || Ok("127.0.0.1:0".parse().unwrap()),
+                ConnectOptions {
+                    shards: 1,
+                    rebind_interval: None,
+                    padding: PaddingPolicy::Fixed(1300),
+                    max_datagram_size: 1400,
+                    probe_mtu: true,
+                    ..ConnectOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!(client.get_stats().await.probed_datagram_size, Some(1200));
+            client.send_bytes(Bytes::from_static(b"hello")).await;
+            let server = listener.accept_session().await.unwrap();
+            server
+                .recv_bytes()
+                .or(async {
+                    smol::Timer::after(Duration::from_secs(5)).await;
+                    panic!("nothing arrived through the relay")
+                })
+                .await
+                .unwrap();
+            // data frames are padded to what got through, not to what was asked for
+            let sizes = sizes.lock().clone();
+            assert!(sizes.iter().all(|&n| n <= 1200), "sizes were {:?}", sizes);
+            assert!(sizes.contains(&1200));
+            // and so is everything the server sends
+            let down_sizes = down_sizes.lock().clone();
+            assert!(
+                down_sizes.iter().all(|&n| n <= 1200),
+                "sizes were {:?}",
+                down_sizes
+            );
+        })
+    }
+
+    /// An in-memory network of datagram endpoints, standing in for real sockets.
+    #[derive(Clone, Default)]
+    struct MemNetwork {
+        hosts: Arc<Mutex<std::collections::HashMap<SocketAddr, Sender<(Bytes, SocketAddr)>>>>,
+        /// Sizes of the batches passed to send_many, in order.
+        batches: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl MemNetwork {
+        fn bind(&self, addr: SocketAddr) -> MemBackhaul {
+            let (send, recv) = smol::channel::unbounded();
+            self.hosts.lock().insert(addr, send);
+            MemBackhaul {
+                addr,
+                network: self.clone(),
+                recv,
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct MemBackhaul {
+        addr: SocketAddr,
+        network: MemNetwork,
+        recv: Receiver<(Bytes, SocketAddr)>,
+    }
+
+    #[async_trait::async_trait]
+    impl Backhaul for MemBackhaul {
+        async fn recv_from(&self) -> std::io::Result<(Bytes, SocketAddr)> {
+            self.recv.recv().await.map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "network is gone")
+            })
+        }
+
+        async fn send_to(&self, to_send: Bytes, dest: SocketAddr) -> std::io::Result<()> {
+            let dest = self.network.hosts.lock().get(&dest).cloned();
+            // like UDP, datagrams to nowhere silently vanish
+            if let Some(dest) = dest {
+                drop(dest.try_send((to_send, self.addr)));
+            }
+            Ok(())
+        }
+
+        async fn send_many(&self, to_send: Vec<Bytes>, dest: SocketAddr) -> std::io::Result<()> {
+            self.network.batches.lock().push(to_send.len());
+            for to_send in to_send {
+                self.send_to(to_send, dest).await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn session_over_memory_backhaul() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let network = MemNetwork::default();
+            let server_addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+            let listener = Listener::listen_backhaul(
+                network.bind(server_addr),
+                server_addr,
+                long_sk,
+                ListenerOptions::default(),
+            );
+            let next_port = std::sync::atomic::AtomicU16::new(1);
+            let client_network = network.clone();
+            let client = connect_backhaul(
+                server_addr,
+                pubkey,
+                move || {
+                    let port = next_port.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    Ok(client_network.bind(SocketAddr::new([10, 0, 0, 2].into(), port)))
+                },
+                ConnectOptions::default(),
+            )
+            .await
+            .unwrap();
+            async {
+                client.send_bytes(Bytes::from_static(b"hello")).await;
+                let server = listener.accept_session().await.unwrap();
+                assert_eq!(
+                    server.recv_bytes().await.unwrap(),
+                    Bytes::from_static(b"hello")
+                );
+                server.send_bytes(Bytes::from_static(b"world")).await;
+                assert_eq!(
+                    client.recv_bytes().await.unwrap(),
+                    Bytes::from_static(b"world")
+                );
+            }
+            .or(async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                panic!("no traffic over the in-memory backhaul")
+            })
+            .await;
+        })
+    }
+
+    #[test]
+    fn shards_rebind_in_turn() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let network = MemNetwork::default();
+            let server_addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+            let listener = Listener::listen_backhaul(
+                network.bind(server_addr),
+                server_addr,
+                long_sk,
+                ListenerOptions::default(),
+            );
+            let next_port = std::sync::atomic::AtomicU16::new(1);
+            let binds = Arc::new(Mutex::new(Vec::new()));
+            let client_binds = binds.clone();
+            let client_network = network.clone();
+            let interval = Duration::from_millis(400);
+            let client = connect_backhaul(
+                server_addr,
+                pubkey,
+                move || {
+                    client_binds.lock().push(Instant::now());
+                    let port = next_port.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    Ok(client_network.bind(SocketAddr::new([10, 0, 0, 2].into(), port)))
+                },
+                ConnectOptions {
+                    shards: 4,
+                    rebind_interval: Some(interval),
+                    ..ConnectOptions::default()
+                },
+            )
+            .await
+            .unwrap();
+            // keep every shard busy for a few intervals
+            let start = Instant::now();
+            while start.elapsed() < interval * 3 {
+                client.send_bytes(Bytes::from_static(b"hello")).await;
+                smol::Timer::after(Duration::from_millis(2)).await;
+            }
+            // skip the handshake socket and the initial socket of every shard
+            let rebinds = binds.lock()[5..].to_vec();
+            assert!(rebinds.len() >= 4, "only {} rebinds", rebinds.len());
+            for pair in rebinds.windows(2) {
+                let gap = pair[1].saturating_duration_since(pair[0]);
+                assert!(gap > interval / 10, "shards rebound {:?} apart", gap);
+            }
+        })
+    }
+
+    #[test]
+    fn queued_frames_go_out_in_one_batch() {
+        smol::block_on(async {
+            let network = MemNetwork::default();
+            let server_addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+            let server = network.bind(server_addr);
+            let (send_frame_in, _recv_frame_in) = smol::channel::unbounded();
+            let (send_frame_out, recv_frame_out) = smol::channel::unbounded();
+            for frame_no in 0..10 {
+                send_frame_out
+                    .send(msg::DataFrame::goodbye(frame_no, 0))
+                    .await
+                    .unwrap();
+            }
+            let client_network = network.clone();
+            let _shard = smol::spawn(client_backhaul_once(
+                crypt::Cookie::new(x25519_dalek::PublicKey::from([0u8; 32])),
+                Bytes::new(),
+                send_frame_in,
+                recv_frame_out,
+                0,
+                1,
+                server_addr,
+                blake3::hash(b"shared secret"),
+                Arc::new(move || Ok(client_network.bind("10.0.0.2:1".parse().unwrap()))),
+                None,
+                PaddingPolicy::default(),
+                PaddingPolicy::default(),
+                0.0,
+                true,
+                None,
+            ));
+            // the resume token, then all of the frames
+            for _ in 0..11 {
+                server
+                    .recv_from()
+                    .or(async {
+                        smol::Timer::after(Duration::from_secs(5)).await;
+                        panic!("frames never arrived")
+                    })
+                    .await
+                    .unwrap();
+            }
+            assert_eq!(network.batches.lock().clone(), vec![10]);
+        })
+    }
+
+    #[test]
+    fn jitter_desynchronizes_sessions() {
+        smol::block_on(async {
+            let network = MemNetwork::default();
+            let server_addr: SocketAddr = "10.0.0.1:1".parse().unwrap();
+            let interval = Duration::from_millis(100);
+            let mut feeds = Vec::new();
+            let mut binds = Vec::new();
+            let mut _shards = Vec::new();
+            // two sessions that start at the same moment, with the same settings
+            for session in 0..2u8 {
+                let (send_frame_in, recv_frame_in) = smol::channel::unbounded();
+                let (send_frame_out, recv_frame_out) = smol::channel::unbounded();
+                let session_binds = Arc::new(Mutex::new(Vec::new()));
+                let client_binds = session_binds.clone();
+                let client_network = network.clone();
+                let next_port = std::sync::atomic::AtomicU16::new(1);
+                _shards.push(smol::spawn(client_backhaul_once(
+                    crypt::Cookie::new(x25519_dalek::PublicKey::from([0u8; 32])),
+                    Bytes::new(),
+                    send_frame_in,
+                    recv_frame_out,
+                    0,
+                    1,
+                    server_addr,
+                    blake3::hash(b"shared secret"),
+                    Arc::new(move || {
+                        client_binds.lock().push(Instant::now());
+                        let port = next_port.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        Ok(client_network.bind(SocketAddr::new([10, 0, 0, session].into(), port)))
+                    }),
+                    Some(interval),
+                    PaddingPolicy::default(),
+                    PaddingPolicy::default(),
+                    0.5,
+                    false,
+                    None,
+                )));
+                feeds.push((send_frame_out, recv_frame_in));
+                binds.push(session_binds);
+            }
+            let start = Instant::now();
+            let mut frame_no = 0;
+            while start.elapsed() < interval * 10 {
+                for (send_frame_out, _) in &feeds {
+                    send_frame_out
+                        .send(msg::DataFrame::goodbye(frame_no, 0))
+                        .await
+                        .unwrap();
+                }
+                frame_no += 1;
+                smol::Timer::after(Duration::from_millis(2)).await;
+            }
+            // skip the initial sockets, and compare when the sessions rebound
+            let first = binds[0].lock()[1..].to_vec();
+            let second = binds[1].lock()[1..].to_vec();
+            assert!(first.len() >= 3 && second.len() >= 3);
+            let max_gap = first
+                .iter()
+                .zip(second.iter())
+                .map(|(a, b)| {
+                    a.saturating_duration_since(*b)
+                        .max(b.saturating_duration_since(*a))
+                })
+                .max()
+                .unwrap();
+            assert!(max_gap > interval / 5, "sessions rebound in lockstep");
+        })
+    }
+
+    #[test]
+    fn rebind_offsets_spread_over_interval() {
+        let interval = Duration::from_secs(4);
+        let offsets: Vec<_> = (0..4).map(|i| rebind_offset(interval, i, 4)).collect();
+        assert_eq!(
+            offsets,
+            vec![
+                Duration::from_secs(0),
+                Duration::from_secs(1),
+                Duration::from_secs(2),
+                Duration::from_secs(3)
+            ]
+        );
+        assert_eq!(rebind_offset(interval, 0, 0), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn silent_server_times_out() {
+        smol::block_on(async {
+            let silent = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let pubkey = x25519_dalek::PublicKey::from([0u8; 32]);
+            let err = connect_custom(
+                silent.local_addr().unwrap(),
+                pubkey,
+                || Ok("127.0.0.1:0".parse().unwrap()),
+                ConnectOptions {
+                    max_tries: 3,
+                    max_timeout: Duration::from_millis(50),
+                    ..ConnectOptions::default()
+                },
+            )
+            .await
+            .err()
+            .unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        })
+    }
+}
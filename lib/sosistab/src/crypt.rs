@@ -7,6 +7,8 @@ use serde::Serialize;
 use std::time::SystemTime;
 
 pub const UP_KEY: &[u8; 32] = b"upload--------------------------";
+/// Bytes that encryption adds to every datagram: a 16-byte nonce and an 8-byte MAC.
+pub const AEAD_OVERHEAD: usize = 24;
 pub const DN_KEY: &[u8; 32] = b"download------------------------";
 /// A structure for encrypting or decrypting Chacha12/Blake3-64.
 pub struct StdAEAD {
@@ -74,14 +76,11 @@ impl StdAEAD {
     }
 
     /// Pad and encrypt.
-    pub fn pad_encrypt(&self, msg: impl Serialize, target_len: usize) -> Bytes {
-        let mut target_len = rand::thread_rng().gen_range(0, target_len + 1);
+    pub fn pad_encrypt(&self, msg: impl Serialize, padding: PaddingPolicy) -> Bytes {
         let mut plain = Vec::with_capacity(1500);
         bincode::serialize_into(&mut plain, &msg).unwrap();
         let plainlen = plain.len();
-        if plain.len() > target_len {
-            target_len = plain.len() + rand::thread_rng().gen_range(0, 16);
-        }
+        let target_len = padding.target_len(plainlen);
         plain.extend_from_slice(&vec![0; target_len - plain.len()]);
         let encrypted = self.encrypt(&plain, rand::thread_rng().gen());
         log::trace!("PAD and ENCRYPT {} => {}", plainlen, encrypted.len());
@@ -153,6 +152,54 @@ impl StdAEAD {
 //     }
 // }
 
+/// How datagrams are padded before encryption, to obscure their true length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// No padding at all.
+    None,
+    /// Pad every datagram to the same length. Longer datagrams are left as they are, which is a fingerprint of its own.
+    Fixed(usize),
+    /// Pad every datagram to a length picked uniformly between the bounds.
+    Random { min: usize, max: usize },
+}
+
+impl Default for PaddingPolicy {
+    fn default() -> Self {
+        PaddingPolicy::Random { min: 0, max: 1000 }
+    }
+}
+
+impl PaddingPolicy {
+    /// Limits the padding so that it never grows a datagram beyond the given size. Datagrams whose contents alone are bigger are still sent whole.
+    pub fn capped(self, max_datagram_size: usize) -> Self {
+        let cap = max_datagram_size.saturating_sub(AEAD_OVERHEAD);
+        match self {
+            PaddingPolicy::None => PaddingPolicy::None,
+            PaddingPolicy::Fixed(len) => PaddingPolicy::Fixed(len.min(cap)),
+            PaddingPolicy::Random { min, max } => PaddingPolicy::Random {
+                min: min.min(cap),
+                max: max.min(cap),
+            },
+        }
+    }
+
+    /// Picks the padded length of a plaintext.
+    fn target_len(self, plain_len: usize) -> usize {
+        match self {
+            PaddingPolicy::None => plain_len,
+            PaddingPolicy::Fixed(len) => len.max(plain_len),
+            PaddingPolicy::Random { min, max } => {
+                let target_len = rand::thread_rng().gen_range(min, max.max(min) + 1);
+                if plain_len > target_len {
+                    plain_len + rand::thread_rng().gen_range(0, 16)
+                } else {
+                    target_len
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 /// Cookie is a generator of temporary symmetric keys.
 pub struct Cookie(x25519_dalek::PublicKey);
@@ -217,3 +264,50 @@ pub fn triple_ecdh(
     };
     blake3::hash(&to_hash)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padding_policies() {
+        let aead = StdAEAD::new(b"helloworld");
+        let msg = vec![1u8; 100];
+        let plain_len = bincode::serialize(&msg).unwrap().len();
+        let overhead = AEAD_OVERHEAD;
+        assert_eq!(
+            aead.pad_encrypt(&msg, PaddingPolicy::None).len(),
+            plain_len + overhead
+        );
+        assert_eq!(
+            aead.pad_encrypt(&msg, PaddingPolicy::Fixed(500)).len(),
+            500 + overhead
+        );
+        assert_eq!(
+            aead.pad_encrypt(&msg, PaddingPolicy::Fixed(10)).len(),
+            plain_len + overhead
+        );
+        for _ in 0..100 {
+            let len = aead
+                .pad_encrypt(&msg, PaddingPolicy::Random { min: 200, max: 300 })
+                .len();
+            assert!(len >= 200 + overhead && len <= 300 + overhead);
+        }
+        let padded = aead.pad_encrypt(&msg, PaddingPolicy::Fixed(500));
+        assert_eq!(aead.pad_decrypt::<Vec<u8>>(&padded), Some(msg));
+    }
+
+    #[test]
+    fn capped_padding_fits_datagrams() {
+        let aead = StdAEAD::new(b"helloworld");
+        let msg = vec![1u8; 100];
+        let capped = PaddingPolicy::Fixed(1300).capped(1200);
+        assert_eq!(capped, PaddingPolicy::Fixed(1200 - AEAD_OVERHEAD));
+        assert_eq!(aead.pad_encrypt(&msg, capped).len(), 1200);
+        assert_eq!(
+            PaddingPolicy::Random { min: 0, max: 1000 }.capped(524),
+            PaddingPolicy::Random { min: 0, max: 500 }
+        );
+        assert_eq!(PaddingPolicy::None.capped(100), PaddingPolicy::None);
+    }
+}
@@ -3,6 +3,197 @@ use probability::distribution::Distribution;
 use reed_solomon_erasure::galois_8;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A forward error correction code, which turns every run of packets into data shards that carry the packets, followed by parity shards that can stand in for lost data shards. Both ends of a session must use the same code, since frames don't say which one made them, so clients name theirs by [FecCodec::id] in the handshake.
+pub trait FecCodec: Send + Sync + std::fmt::Debug {
+    /// Identifies the code on the wire. Codes that decode each other's runs may share an id. Ids below 128 are reserved for the codes built into sosistab.
+    fn id(&self) -> u8;
+
+    /// Encodes a run of packets, given the measured loss and the loss to aim for after decoding, both in 256ths. The data shards must come first, one per packet.
+    fn encode(&self, measured_loss: u8, target_loss: u8, pkts: &[Bytes]) -> Vec<Bytes>;
+
+    /// Creates a decoder for a single run with the given numbers of shards.
+    fn decoder(&self, data_shards: usize, parity_shards: usize) -> Box<dyn FecDecoder>;
+}
+
+/// A single-use decoder for one run, fed shards as they arrive.
+pub trait FecDecoder: Send + Sync {
+    /// Takes in a shard, returning whatever packets can be had because of it.
+    fn decode(&mut self, pkt: &[u8], pkt_idx: usize) -> Option<Vec<Bytes>>;
+
+    /// Number of packets that arrived or were reconstructed.
+    fn good_pkts(&self) -> usize;
+
+    /// Number of packets still missing.
+    fn lost_pkts(&self) -> usize;
+
+    /// Whether nothing more can come out of this decoder.
+    fn is_complete(&self) -> bool {
+        self.lost_pkts() == 0
+    }
+
+    /// Bytes of buffer space held for reconstructing the run.
+    fn buffered_bytes(&self) -> usize;
+}
+
+/// The default code: Reed-Solomon, with as many parity shards as it takes to bring the measured loss down to the target.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReedSolomonCodec;
+
+impl FecCodec for ReedSolomonCodec {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn encode(&self, measured_loss: u8, target_loss: u8, pkts: &[Bytes]) -> Vec<Bytes> {
+        FrameEncoder::new(target_loss).encode(measured_loss, pkts)
+    }
+
+    fn decoder(&self, data_shards: usize, parity_shards: usize) -> Box<dyn FecDecoder> {
+        Box::new(FrameDecoder::new(data_shards, parity_shards))
+    }
+}
+
+/// No FEC at all, for links where parity is pure overhead. Since runs without parity decode the same way under Reed-Solomon, this can talk to a peer using [ReedSolomonCodec].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoFecCodec;
+
+impl FecCodec for NoFecCodec {
+    fn id(&self) -> u8 {
+        // runs without parity are just Reed-Solomon runs
+        0
+    }
+
+    fn encode(&self, _measured_loss: u8, _target_loss: u8, pkts: &[Bytes]) -> Vec<Bytes> {
+        FrameEncoder::encode_data_only(pkts)
+    }
+
+    fn decoder(&self, data_shards: usize, parity_shards: usize) -> Box<dyn FecDecoder> {
+        Box::new(FrameDecoder::new(data_shards, parity_shards))
+    }
+}
+
+/// A single XOR parity shard per run whenever there's any loss at all, which recovers one lost packet per run. Much cheaper than Reed-Solomon, and enough for links with light, scattered loss.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct XorParityCodec;
+
+impl FecCodec for XorParityCodec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn encode(&self, measured_loss: u8, _target_loss: u8, pkts: &[Bytes]) -> Vec<Bytes> {
+        if measured_loss == 0 {
+            return FrameEncoder::encode_data_only(pkts);
+        }
+        let max_length = pkts.iter().map(|v| v.len()).max().unwrap();
+        let mut parity = vec![0u8; max_length + 2];
+        let mut toret: Vec<Bytes> = pkts
+            .iter()
+            .map(|p| {
+                let shard = pre_encode(p, max_length + 2);
+                xor_into(&mut parity, &shard);
+                shard.freeze()
+            })
+            .collect();
+        toret.push(parity.into());
+        toret
+    }
+
+    fn decoder(&self, data_shards: usize, parity_shards: usize) -> Box<dyn FecDecoder> {
+        if parity_shards == 0 {
+            Box::new(FrameDecoder::new(data_shards, 0))
+        } else {
+            Box::new(XorDecoder::new(data_shards))
+        }
+    }
+}
+
+/// Finds the code with the given id among the given extra one and those built in.
+pub(crate) fn codec_by_id(id: u8, extra: &Arc<dyn FecCodec>) -> Option<Arc<dyn FecCodec>> {
+    match id {
+        _ if id == extra.id() => Some(extra.clone()),
+        0 => Some(Arc::new(ReedSolomonCodec)),
+        1 => Some(Arc::new(XorParityCodec)),
+        _ => None,
+    }
+}
+
+fn xor_into(acc: &mut [u8], shard: &[u8]) {
+    for (a, b) in acc.iter_mut().zip(shard) {
+        *a ^= b
+    }
+}
+
+/// Decoder for runs encoded by [XorParityCodec].
+struct XorDecoder {
+    data_shards: usize,
+    /// XOR of every shard received so far, parity included.
+    acc: Vec<u8>,
+    present: Vec<bool>,
+    done: bool,
+}
+
+impl XorDecoder {
+    fn new(data_shards: usize) -> Self {
+        XorDecoder {
+            data_shards,
+            acc: Vec::new(),
+            present: vec![false; data_shards + 1],
+            done: false,
+        }
+    }
+}
+
+impl FecDecoder for XorDecoder {
+    fn decode(&mut self, pkt: &[u8], pkt_idx: usize) -> Option<Vec<Bytes>> {
+        if self.done || pkt_idx >= self.present.len() || self.present[pkt_idx] {
+            return None;
+        }
+        if self.acc.is_empty() {
+            self.acc = vec![0u8; pkt.len()];
+        }
+        if pkt.len() != self.acc.len() {
+            return None;
+        }
+        self.present[pkt_idx] = true;
+        xor_into(&mut self.acc, pkt);
+        let mut toret = Vec::new();
+        if pkt_idx < self.data_shards {
+            toret.push(post_decode(Bytes::copy_from_slice(pkt))?);
+        }
+        let missing: Vec<usize> = (0..self.data_shards)
+            .filter(|&idx| !self.present[idx])
+            .collect();
+        if missing.is_empty() {
+            self.done = true;
+            self.acc = Vec::new();
+        } else if missing.len() == 1 && self.present[self.data_shards] {
+            // everything but one data shard, plus the parity, XORs to the missing shard
+            self.present[missing[0]] = true;
+            self.done = true;
+            toret.extend(post_decode(std::mem::take(&mut self.acc).into()));
+        }
+        Some(toret)
+    }
+
+    fn good_pkts(&self) -> usize {
+        self.present[..self.data_shards]
+            .iter()
+            .filter(|present| **present)
+            .count()
+    }
+
+    fn lost_pkts(&self) -> usize {
+        self.data_shards - self.good_pkts()
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        self.acc.len()
+    }
+}
+
 /// A forward error correction encoder. Retains internal state for memoization, memory pooling etc.
 #[derive(Debug)]
 pub struct FrameEncoder {
@@ -58,6 +249,13 @@ impl FrameEncoder {
         toret
     }
 
+    /// Frames a slice of packets without any parity shards, for links where FEC is pure overhead.
+    pub fn encode_data_only(pkts: &[Bytes]) -> Vec<Bytes> {
+        pkts.iter()
+            .map(|p| pre_encode(p, p.len() + 2).freeze())
+            .collect()
+    }
+
     /// Calculates the number of repair blocks needed to properly reconstruct a run of packets.
     fn repair_len(&mut self, measured_loss: u8, run_len: usize) -> usize {
         let target_loss = self.target_loss;
@@ -109,6 +307,28 @@ fn new_rs_decoder(data_shards: usize, parity_shards: usize) -> &'static galois_8
     })
 }
 
+impl FecDecoder for FrameDecoder {
+    fn decode(&mut self, pkt: &[u8], pkt_idx: usize) -> Option<Vec<Bytes>> {
+        FrameDecoder::decode(self, pkt, pkt_idx)
+    }
+
+    fn good_pkts(&self) -> usize {
+        FrameDecoder::good_pkts(self)
+    }
+
+    fn lost_pkts(&self) -> usize {
+        FrameDecoder::lost_pkts(self)
+    }
+
+    fn is_complete(&self) -> bool {
+        FrameDecoder::is_complete(self)
+    }
+
+    fn buffered_bytes(&self) -> usize {
+        FrameDecoder::buffered_bytes(self)
+    }
+}
+
 impl FrameDecoder {
     pub fn new(data_shards: usize, parity_shards: usize) -> Self {
         FrameDecoder {
@@ -142,16 +362,37 @@ impl FrameDecoder {
         self.data_shards - self.good_pkts()
     }
 
+    /// Whether every data shard has either arrived or been reconstructed, so that nothing more can come out of this decoder.
+    pub fn is_complete(&self) -> bool {
+        self.good_pkts() == self.data_shards
+    }
+
+    /// Bytes of buffer space held for reconstructing the run.
+    pub fn buffered_bytes(&self) -> usize {
+        self.space.iter().map(|shard| shard.len()).sum()
+    }
+
     pub fn decode(&mut self, pkt: &[u8], pkt_idx: usize) -> Option<Vec<Bytes>> {
         // if we don't have parity shards, don't touch anything
         if self.parity_shards == 0 {
+            if pkt_idx >= self.present.len() {
+                return None;
+            }
+            // still keep track of what arrived, so that loss statistics stay accurate
+            if !self.present[pkt_idx] {
+                self.present_count += 1
+            }
+            self.present[pkt_idx] = true;
             return Some(vec![post_decode(Bytes::copy_from_slice(pkt))?]);
         }
+        if self.done {
+            return None;
+        }
         if self.space.is_empty() {
             log::trace!("decode with pad len {}", pkt.len());
             self.space = vec![vec![0u8; pkt.len()]; self.data_shards + self.parity_shards]
         }
-        if self.done || pkt_idx > self.space.len() || pkt_idx > self.present.len() {
+        if pkt_idx > self.space.len() || pkt_idx > self.present.len() {
             return None;
         }
         // decompress without allocation
@@ -162,9 +403,13 @@ impl FrameDecoder {
         self.present[pkt_idx] = true;
         // if I'm a data shard, just return it
         if pkt_idx < self.data_shards {
-            return Some(vec![post_decode(Bytes::copy_from_slice(
-                &self.space[pkt_idx],
-            ))?]);
+            let res = post_decode(Bytes::copy_from_slice(&self.space[pkt_idx]));
+            // once all the data is here, parity is useless, so stop holding on to the buffers
+            if self.is_complete() {
+                self.done = true;
+                self.space = Vec::new();
+            }
+            return Some(vec![res?]);
         }
         if self.present_count < self.data_shards {
             log::trace!("don't even attempt yet");
@@ -222,6 +467,39 @@ fn post_decode(raw: Bytes) -> Option<Bytes> {
 }
 
 // #[cfg(test)]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xor_parity_recovers_one_lost_packet() {
+        let pkts: Vec<Bytes> = vec![
+            Bytes::from_static(b"hello"),
+            Bytes::from_static(b"world!!"),
+            Bytes::from_static(b"x"),
+        ];
+        let shards = XorParityCodec.encode(10, 2, &pkts);
+        assert_eq!(shards.len(), 4);
+        let mut decoder = XorParityCodec.decoder(3, 1);
+        let mut got = Vec::new();
+        for idx in [0, 2, 3].iter() {
+            got.extend(decoder.decode(&shards[*idx], *idx).unwrap());
+        }
+        got.sort();
+        let mut expected = pkts.clone();
+        expected.sort();
+        assert_eq!(got, expected);
+        assert!(decoder.is_complete());
+        assert_eq!(decoder.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn xor_parity_is_skipped_without_loss() {
+        let pkts = vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")];
+        assert_eq!(XorParityCodec.encode(0, 2, &pkts).len(), 2);
+    }
+}
+
 // mod tests {
 //     extern crate test;
 //     use super::*;
@@ -1,6 +1,8 @@
 mod client;
 mod crypt;
+pub use crypt::PaddingPolicy;
 mod fec;
+pub use fec::{FecCodec, FecDecoder, NoFecCodec, ReedSolomonCodec, XorParityCodec};
 mod listener;
 pub use client::*;
 pub use listener::*;
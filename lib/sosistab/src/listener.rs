@@ -8,11 +8,12 @@ use serde::{Deserialize, Serialize};
 use smol::channel::{Receiver, Sender};
 use smol::net::AsyncToSocketAddrs;
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::{net::SocketAddr, time::Instant};
 use std::{sync::Arc, time::Duration};
 
 pub struct Listener {
-    accepted: Receiver<Session>,
+    accepted: Receiver<(Session, ClientInfo)>,
     local_addr: SocketAddr,
     _task: smol::Task<Option<()>>,
 }
@@ -20,23 +21,73 @@ pub struct Listener {
 impl Listener {
     /// Accepts a session. This function must be repeatedly called for the entire Listener to make any progress.
     pub async fn accept_session(&self) -> Option<Session> {
+        Some(self.accept_session_with_info().await?.0)
+    }
+
+    /// Accepts a session, along with where the client connected from and the public key it shook hands with.
+    pub async fn accept_session_with_info(&self) -> Option<(Session, ClientInfo)> {
         self.accepted.recv().await.ok()
     }
     /// Creates a new listener given the parameters.
     pub async fn listen(
         addr: impl AsyncToSocketAddrs,
         long_sk: x25519_dalek::StaticSecret,
+    ) -> Self {
+        Self::listen_with_options(addr, long_sk, ListenerOptions::default()).await
+    }
+
+    /// Creates a new listener that limits how fast handshakes from any one source subnet are processed.
+    pub async fn listen_with_limit(
+        addr: impl AsyncToSocketAddrs,
+        long_sk: x25519_dalek::StaticSecret,
+        limit: HandshakeLimit,
+    ) -> Self {
+        Self::listen_with_options(
+            addr,
+            long_sk,
+            ListenerOptions {
+                handshake_limit: Some(limit),
+                ..ListenerOptions::default()
+            },
+        )
+        .await
+    }
+
+    /// Creates a new listener with the given options.
+    pub async fn listen_with_options(
+        addr: impl AsyncToSocketAddrs,
+        long_sk: x25519_dalek::StaticSecret,
+        opts: ListenerOptions,
     ) -> Self {
         // let addr = async_net::resolve(addr).await;
         let socket = runtime::new_udp_socket_bind(addr).await.unwrap();
         let local_addr = socket.local_addr().unwrap();
+        Self::listen_backhaul(socket, local_addr, long_sk, opts)
+    }
+
+    /// Creates a new listener over an arbitrary datagram transport rather than UDP. The given local address is only reported by [Listener::local_addr].
+    pub fn listen_backhaul<B: Backhaul + 'static>(
+        backhaul: B,
+        local_addr: SocketAddr,
+        long_sk: x25519_dalek::StaticSecret,
+        opts: ListenerOptions,
+    ) -> Self {
         let cookie = crypt::Cookie::new((&long_sk).into());
         let (send, recv) = smol::channel::unbounded();
-        let task = runtime::spawn(
+        let task = runtime::spawn_on(
+            opts.executor.as_ref(),
             ListenerActor {
-                socket,
+                socket: backhaul,
                 cookie,
                 long_sk,
+                limit: opts.handshake_limit,
+                padding: opts.padding,
+                fec_codec: opts.fec_codec,
+                loss_percentile: opts.loss_percentile,
+                loss_estimator: opts.loss_estimator,
+                idle_timeout: opts.idle_timeout,
+                shuffle_shards: opts.shuffle_shards,
+                executor: opts.executor.clone(),
             }
             .run(send),
         );
@@ -53,6 +104,15 @@ impl Listener {
     }
 }
 
+/// What the listener knows about the client of an accepted session.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    /// Source address of the first shard of the session.
+    pub addr: SocketAddr,
+    /// Long-term public key from the ClientHello. Clients may generate a fresh one for every session.
+    pub long_pk: x25519_dalek::PublicKey,
+}
+
 // recently seen tracker
 struct RecentFilter {
     curr_bloom: bloomfilter::Bloom<[u8]>,
@@ -82,18 +142,151 @@ impl RecentFilter {
     }
 }
 
+/// Options for a Listener.
+#[derive(Debug, Clone)]
+pub struct ListenerOptions {
+    /// Limit on handshakes from any one source subnet. None, the default, processes every handshake; many clients may share one address behind a NAT.
+    pub handshake_limit: Option<HandshakeLimit>,
+    /// How packets sent by the listener are padded.
+    pub padding: PaddingPolicy,
+    /// An FEC code that accepted sessions may use besides the built-in ones. Every session uses the code its client names in the handshake, and is refused if that's neither this one nor a built-in one.
+    pub fec_codec: Arc<dyn FecCodec>,
+    /// Percentile of recent loss samples that accepted sessions size their FEC for.
+    pub loss_percentile: f64,
+    /// How accepted sessions estimate the loss that their FEC is sized for.
+    pub loss_estimator: LossEstimator,
+    /// How long accepted sessions wait for anything from their clients before closing themselves. None keeps them open until the application drops them.
+    pub idle_timeout: Option<Duration>,
+    /// Whether accepted sessions send the shards of every FEC run in a random order rather than data first, then parity, like [ConnectOptions::shuffle_shards] does on the client side.
+    pub shuffle_shards: bool,
+    /// Executor that the listener and every session it accepts run their tasks on. None uses the global one of [runtime](crate::runtime). A [TcpBackhaul](crate::TcpBackhaul) given to [Listener::listen_backhaul] spawns tasks of its own, so it should be created with the same executor.
+    pub executor: Option<runtime::ExecutorHandle>,
+}
+
+impl Default for ListenerOptions {
+    fn default() -> Self {
+        ListenerOptions {
+            handshake_limit: None,
+            padding: PaddingPolicy::default(),
+            fec_codec: Arc::new(ReedSolomonCodec),
+            loss_percentile: 0.25,
+            loss_estimator: LossEstimator::Windowed,
+            idle_timeout: None,
+            shuffle_shards: false,
+            executor: None,
+        }
+    }
+}
+
+/// Per-source limits on handshake processing, protecting the listener against ClientHello floods.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeLimit {
+    /// Long-run handshakes per second allowed from one subnet.
+    pub per_second: f64,
+    /// Handshakes one subnet may send in a burst.
+    pub burst: f64,
+    /// Prefix length grouping IPv4 sources into one subnet.
+    pub ipv4_prefix: u8,
+    /// Prefix length grouping IPv6 sources into one subnet.
+    pub ipv6_prefix: u8,
+}
+
+impl Default for HandshakeLimit {
+    fn default() -> Self {
+        HandshakeLimit {
+            per_second: 10.0,
+            burst: 20.0,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+        }
+    }
+}
+
+impl HandshakeLimit {
+    fn subnet(&self, ip: IpAddr) -> IpAddr {
+        match ip {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.ipv4_prefix.min(32) as u32)
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.ipv6_prefix.min(128) as u32)
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask))
+            }
+        }
+    }
+}
+
+// per-subnet token buckets for handshakes
+struct HandshakeLimiter {
+    limit: HandshakeLimit,
+    buckets: HashMap<IpAddr, (f64, Instant)>,
+    last_prune: Instant,
+}
+
+impl HandshakeLimiter {
+    fn new(limit: HandshakeLimit) -> Self {
+        HandshakeLimiter {
+            limit,
+            buckets: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    fn check(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let limit = self.limit;
+        if now.saturating_duration_since(self.last_prune).as_secs() > 60 {
+            // buckets that have refilled are no different from fresh ones
+            self.buckets.retain(|_, (tokens, last)| {
+                *tokens + now.saturating_duration_since(*last).as_secs_f64() * limit.per_second
+                    < limit.burst
+            });
+            self.last_prune = now;
+        }
+        let (tokens, last) = self
+            .buckets
+            .entry(limit.subnet(ip))
+            .or_insert((limit.burst, now));
+        *tokens = (*tokens + now.saturating_duration_since(*last).as_secs_f64() * limit.per_second)
+            .min(limit.burst);
+        *last = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
 type ShardedAddrs = IndexMap<u8, SocketAddr>;
 
-struct ListenerActor {
-    socket: smol::net::UdpSocket,
+struct ListenerActor<B: Backhaul> {
+    socket: B,
     cookie: crypt::Cookie,
     long_sk: x25519_dalek::StaticSecret,
+    limit: Option<HandshakeLimit>,
+    padding: PaddingPolicy,
+    fec_codec: Arc<dyn FecCodec>,
+    loss_percentile: f64,
+    loss_estimator: LossEstimator,
+    idle_timeout: Option<Duration>,
+    shuffle_shards: bool,
+    executor: Option<runtime::ExecutorHandle>,
 }
-impl ListenerActor {
+impl<B: Backhaul + 'static> ListenerActor<B> {
     #[allow(clippy::mutable_key_type)]
-    async fn run(self, accepted: Sender<Session>) -> Option<()> {
+    async fn run(self, accepted: Sender<(Session, ClientInfo)>) -> Option<()> {
         // replay filter for globally-encrypted stuff
         let mut curr_filter = RecentFilter::new();
+        // rate limiter for handshakes
+        let mut hs_limiter = self.limit.map(HandshakeLimiter::new);
+        let padding = self.padding;
         // session table
         let mut session_table = SessionTable::default();
         // channel for dropping sessions
@@ -107,17 +300,15 @@ impl ListenerActor {
 
         let socket = self.socket;
 
-        let mut buffer = [0u8; 2048];
-
         // two possible events
         enum Evt {
-            NewRecv((usize, SocketAddr)),
+            NewRecv((Bytes, SocketAddr)),
             DeadSess(Bytes),
         }
 
         loop {
             let event = smol::future::race(
-                async { Some(Evt::NewRecv(socket.recv_from(&mut buffer).await.ok()?)) },
+                async { Some(Evt::NewRecv(socket.recv_from().await.ok()?)) },
                 async { Some(Evt::DeadSess(recv_dead.recv().await.ok()?)) },
             );
             match event.await? {
@@ -125,8 +316,8 @@ impl ListenerActor {
                     log::trace!("removing existing session!");
                     session_table.delete(resume_token).await;
                 }
-                Evt::NewRecv((n, addr)) => {
-                    let buffer = &buffer[..n];
+                Evt::NewRecv((buffer, addr)) => {
+                    let buffer = &buffer[..];
                     // first we attempt to map this to an existing session
                     if let Some((sess, sess_crypt)) = session_table.lookup(addr) {
                         // try feeding it into the session
@@ -147,46 +338,97 @@ impl ListenerActor {
                         let crypter = crypt::StdAEAD::new(&possible_key);
                         if let Some(handshake) = crypter.pad_decrypt::<msg::HandshakeFrame>(buffer)
                         {
-                            match handshake {
-                                ClientHello {
-                                    long_pk,
-                                    eph_pk,
-                                    version,
-                                } => {
-                                    if version != 1 {
-                                        log::warn!("got packet with incorrect version {}", version);
+                            if let Some(msg::HelloInfo {
+                                long_pk,
+                                eph_pk,
+                                min_version,
+                                max_version,
+                                fec_codec,
+                                max_datagram_size,
+                            }) = handshake.client_hello()
+                            {
+                                let legacy = matches!(handshake, ClientHello { .. });
+                                // drop floods before doing any expensive crypto
+                                if !hs_limiter.as_mut().map_or(true, |l| l.check(addr.ip())) {
+                                    log::trace!("rate-limiting ClientHello from {}", addr);
+                                    break;
+                                }
+                                let version = match msg::negotiate_version(min_version, max_version)
+                                {
+                                    Some(version) => version,
+                                    None => {
+                                        log::warn!(
+                                            "no common version with {}, which speaks {}..={}",
+                                            addr,
+                                            min_version,
+                                            max_version
+                                        );
+                                        // clients that only speak version 1 wouldn't understand
+                                        if !legacy {
+                                            let reply = crypt::StdAEAD::new(&s2c_key).pad_encrypt(
+                                                &msg::HandshakeFrame::ServerVersionMismatch {
+                                                    min_version: msg::MIN_VERSION,
+                                                    max_version: msg::MAX_VERSION,
+                                                },
+                                                padding,
+                                            );
+                                            drop(socket.send_to(reply, addr).await);
+                                        }
                                         break;
                                     }
-                                    // generate session key
-                                    let my_eph_sk =
-                                        x25519_dalek::StaticSecret::new(&mut rand::rngs::OsRng {});
-                                    let token = TokenInfo {
-                                        sess_key: crypt::triple_ecdh(
-                                            &self.long_sk,
-                                            &my_eph_sk,
-                                            &long_pk,
-                                            &eph_pk,
-                                        )
-                                        .as_bytes()
-                                        .to_vec()
-                                        .into(),
-                                        init_time_ms: std::time::SystemTime::now()
-                                            .duration_since(std::time::UNIX_EPOCH)
-                                            .unwrap()
-                                            .as_millis()
-                                            as u64,
+                                };
+                                if fec::codec_by_id(fec_codec, &self.fec_codec).is_none() {
+                                    log::warn!("{} uses unknown FEC code {}", addr, fec_codec);
+                                    break;
+                                }
+                                // generate session key
+                                let my_eph_sk =
+                                    x25519_dalek::StaticSecret::new(&mut rand::rngs::OsRng {});
+                                let token = TokenInfo {
+                                    sess_key: crypt::triple_ecdh(
+                                        &self.long_sk,
+                                        &my_eph_sk,
+                                        &long_pk,
+                                        &eph_pk,
+                                    )
+                                    .as_bytes()
+                                    .to_vec()
+                                    .into(),
+                                    client_pk: long_pk,
+                                    init_time_ms: std::time::SystemTime::now()
+                                        .duration_since(std::time::UNIX_EPOCH)
+                                        .unwrap()
+                                        .as_millis()
+                                        as u64,
+                                    fec_codec,
+                                    max_datagram_size,
+                                }
+                                .encrypt(&token_key);
+                                let reply = if legacy {
+                                    msg::HandshakeFrame::ServerHello {
+                                        long_pk: (&self.long_sk).into(),
+                                        eph_pk: (&my_eph_sk).into(),
+                                        resume_token: token,
                                     }
-                                    .encrypt(&token_key);
-                                    let reply = msg::HandshakeFrame::ServerHello {
+                                } else {
+                                    msg::HandshakeFrame::ServerHelloV2 {
                                         long_pk: (&self.long_sk).into(),
                                         eph_pk: (&my_eph_sk).into(),
                                         resume_token: token,
-                                    };
-                                    let reply =
-                                        crypt::StdAEAD::new(&s2c_key).pad_encrypt(&reply, 1000);
-                                    socket.send_to(&reply, addr).await.ok()?;
-                                    log::trace!("replied to ClientHello from {}", addr);
-                                }
+                                        version,
+                                    }
+                                };
+                                let reply = crypt::StdAEAD::new(&s2c_key)
+                                    .pad_encrypt(&reply, capped(padding, max_datagram_size));
+                                socket.send_to(reply, addr).await.ok()?;
+                                log::trace!(
+                                    "replied to ClientHello from {} with version {}",
+                                    addr,
+                                    version
+                                );
+                                break;
+                            }
+                            match handshake {
                                 ClientResume {
                                     resume_token,
                                     shard_id,
@@ -198,8 +440,15 @@ impl ListenerActor {
                                         .await
                                     {
                                         log::trace!("ClientResume from {} is new!", addr);
-                                        let tokinfo = TokenInfo::decrypt(&token_key, &resume_token);
-                                        if let Some(tokinfo) = tokinfo {
+                                        let tokinfo = TokenInfo::decrypt(&token_key, &resume_token)
+                                            .and_then(|tokinfo| {
+                                                let codec = fec::codec_by_id(
+                                                    tokinfo.fec_codec,
+                                                    &self.fec_codec,
+                                                )?;
+                                                Some((tokinfo, codec))
+                                            });
+                                        if let Some((tokinfo, fec_codec)) = tokinfo {
                                             let up_key = blake3::keyed_hash(
                                                 crypt::UP_KEY,
                                                 &tokinfo.sess_key,
@@ -211,6 +460,8 @@ impl ListenerActor {
                                             let up_aead = crypt::StdAEAD::new(up_key.as_bytes());
                                             let dn_aead = crypt::StdAEAD::new(dn_key.as_bytes());
                                             let socket = socket.clone();
+                                            let padding =
+                                                capped(padding, tokinfo.max_datagram_size);
                                             let (session_input, session_input_recv) =
                                                 smol::channel::bounded(100);
                                             // create session
@@ -223,47 +474,64 @@ impl ListenerActor {
                                                 Arc::new(smol::lock::Mutex::new(locked_addrs));
                                             let output_poller = {
                                                 let locked_addrs = locked_addrs.clone();
-                                                runtime::spawn(async move {
-                                                    let mut ctr = 0u8;
-                                                    loop {
-                                                        match session_output_recv.recv().await {
-                                                            Ok(df) => {
-                                                                let enc =
-                                                                    dn_aead.pad_encrypt(&df, 1000);
-                                                                let addrs =
-                                                                    locked_addrs.lock().await;
-                                                                assert!(!addrs.is_empty());
-                                                                loop {
-                                                                    ctr = ctr.wrapping_add(1);
-                                                                    if let Some((_, remote_addr)) =
-                                                                        addrs.get_index(
+                                                runtime::spawn_on(
+                                                    self.executor.as_ref(),
+                                                    async move {
+                                                        let mut ctr = 0u8;
+                                                        loop {
+                                                            match session_output_recv.recv().await {
+                                                                Ok(df) => {
+                                                                    let enc = dn_aead
+                                                                        .pad_encrypt(&df, padding);
+                                                                    let addrs =
+                                                                        locked_addrs.lock().await;
+                                                                    assert!(!addrs.is_empty());
+                                                                    loop {
+                                                                        ctr = ctr.wrapping_add(1);
+                                                                        if let Some((
+                                                                            _,
+                                                                            remote_addr,
+                                                                        )) = addrs.get_index(
                                                                             (ctr % (addrs.len()
                                                                                 as u8))
                                                                                 as usize,
-                                                                        )
-                                                                    {
-                                                                        drop(
+                                                                        ) {
+                                                                            drop(
                                                                             socket
                                                                                 .send_to(
-                                                                                    &enc,
+                                                                                    enc.clone(),
                                                                                     *remote_addr,
                                                                                 )
                                                                                 .await,
                                                                         );
-                                                                        break;
+                                                                            break;
+                                                                        }
                                                                     }
                                                                 }
-                                                            }
-                                                            Err(_) => {
-                                                                smol::future::pending::<()>().await
+                                                                Err(_) => {
+                                                                    smol::future::pending::<()>()
+                                                                        .await
+                                                                }
                                                             }
                                                         }
-                                                    }
-                                                })
+                                                    },
+                                                )
                                             };
                                             let mut session = Session::new(SessionConfig {
-                                                latency: Duration::from_millis(5),
+                                                min_latency: Duration::from_millis(5),
+                                                max_latency: Duration::from_millis(20),
+                                                max_packet_age: None,
+                                                idle_timeout: self.idle_timeout,
                                                 target_loss: 0.005,
+                                                min_loss_for_fec: 0.0,
+                                                fec_reorder_runs: 10,
+                                                fec_eviction: EvictionPolicy::Count,
+                                                shuffle_shards: self.shuffle_shards,
+                                                loss_percentile: self.loss_percentile,
+                                                loss_estimator: self.loss_estimator,
+                                                fec_codec,
+                                                probed_datagram_size: None,
+                                                executor: self.executor.clone(),
                                                 send_frame: session_output_send,
                                                 recv_frame: session_input_recv,
                                             });
@@ -283,7 +551,11 @@ impl ListenerActor {
                                             session_table
                                                 .rebind(addr, shard_id, resume_token)
                                                 .await;
-                                            drop(accepted.send(session).await);
+                                            let info = ClientInfo {
+                                                addr,
+                                                long_pk: tokinfo.client_pk,
+                                            };
+                                            drop(accepted.send((session, info)).await);
                                         } else {
                                             log::warn!(
                                                 "ClientResume from {} can't be decrypted",
@@ -292,6 +564,20 @@ impl ListenerActor {
                                         }
                                     }
                                 }
+                                ClientProbe { nonce } => {
+                                    // cheap, but still shouldn't be an open reflector
+                                    if !hs_limiter.as_mut().map_or(true, |l| l.check(addr.ip())) {
+                                        log::trace!("rate-limiting ClientProbe from {}", addr);
+                                        break;
+                                    }
+                                    // never bigger than the probe, which is all we know got through
+                                    let reply = crypt::StdAEAD::new(&s2c_key).pad_encrypt(
+                                        &msg::HandshakeFrame::ServerProbe { nonce },
+                                        padding.capped(buffer.len()),
+                                    );
+                                    drop(socket.send_to(reply, addr).await);
+                                    log::trace!("replied to ClientProbe from {}", addr);
+                                }
                                 _ => continue,
                             }
                         }
@@ -305,7 +591,18 @@ impl ListenerActor {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct TokenInfo {
     sess_key: Bytes,
+    client_pk: x25519_dalek::PublicKey,
     init_time_ms: u64,
+    fec_codec: u8,
+    max_datagram_size: Option<usize>,
+}
+
+/// Padding that never grows datagrams beyond what the client said it takes, if it said anything.
+fn capped(padding: PaddingPolicy, max_datagram_size: Option<usize>) -> PaddingPolicy {
+    match max_datagram_size {
+        Some(size) => padding.capped(size),
+        None => padding,
+    }
 }
 
 impl TokenInfo {
@@ -378,3 +675,192 @@ impl SessionTable {
             .insert(token, (sender, aead, locked_addrs));
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::prelude::*;
+
+    #[test]
+    fn subnet_masking() {
+        let limit = HandshakeLimit {
+            ipv4_prefix: 24,
+            ipv6_prefix: 0,
+            ..HandshakeLimit::default()
+        };
+        assert_eq!(
+            limit.subnet("10.1.2.3".parse().unwrap()),
+            "10.1.2.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            limit.subnet("2001:db8::1".parse().unwrap()),
+            "::".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn clients_pick_the_fec_code() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            let server_addr = listener.local_addr();
+            let client = smol::spawn(crate::connect_with_options(
+                server_addr,
+                pubkey,
+                crate::ConnectOptions {
+                    fec_codec: Arc::new(XorParityCodec),
+                    ..Default::default()
+                },
+            ));
+            let server = listener.accept_session().await.unwrap();
+            let client = client.await.unwrap();
+            client.send_bytes(Bytes::from_static(b"hello")).await;
+            assert_eq!(&server.recv_bytes().await.unwrap()[..], b"hello");
+            // codes nobody knows are refused
+            let default: Arc<dyn FecCodec> = Arc::new(ReedSolomonCodec);
+            assert!(fec::codec_by_id(200, &default).is_none());
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn accepted_sessions_come_with_client_info() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            let server_addr = listener.local_addr();
+            let client = smol::spawn(crate::connect_custom(
+                server_addr,
+                pubkey,
+                || Ok("127.0.0.2:0".parse().unwrap()),
+                crate::ConnectOptions::default(),
+            ));
+            let (_session, info) = listener.accept_session_with_info().await.unwrap();
+            client.await.unwrap();
+            assert_eq!(info.addr.ip(), "127.0.0.2".parse::<IpAddr>().unwrap());
+            assert_ne!(info.long_pk.as_bytes(), pubkey.as_bytes());
+        })
+    }
+
+    #[test]
+    fn versions_are_negotiated() {
+        assert_eq!(msg::negotiate_version(1, 1), Some(1));
+        assert_eq!(msg::negotiate_version(0, 1000), Some(msg::MAX_VERSION));
+        assert_eq!(msg::negotiate_version(msg::MAX_VERSION + 1, 1000), None);
+        assert_eq!(msg::negotiate_version(0, 0), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn version_mismatch_is_answered() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let listener = Listener::listen("127.0.0.1:0", long_sk).await;
+            let server_addr = listener.local_addr();
+            let cookie = crypt::Cookie::new(pubkey);
+            let client = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let exchange = |hello: msg::HandshakeFrame| {
+                let cookie = cookie.clone();
+                let client = &client;
+                async move {
+                    let hello = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap())
+                        .pad_encrypt(&hello, PaddingPolicy::default());
+                    client.send_to(&hello, server_addr).await.unwrap();
+                    let mut buf = [0u8; 2048];
+                    let (n, _) = client.recv_from(&mut buf).await.unwrap();
+                    cookie.generate_s2c().find_map(|key| {
+                        crypt::StdAEAD::new(&key).pad_decrypt::<msg::HandshakeFrame>(&buf[..n])
+                    })
+                }
+            };
+            let eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let reply = exchange(ClientHelloV2 {
+                long_pk: (&eph_sk).into(),
+                eph_pk: (&eph_sk).into(),
+                min_version: msg::MAX_VERSION + 1,
+                max_version: msg::MAX_VERSION + 5,
+                fec_codec: 0,
+                max_datagram_size: 1400,
+            })
+            .await;
+            match reply {
+                Some(ServerVersionMismatch {
+                    min_version,
+                    max_version,
+                }) => {
+                    assert_eq!(min_version, msg::MIN_VERSION);
+                    assert_eq!(max_version, msg::MAX_VERSION);
+                }
+                other => panic!("unexpected reply {:?}", other),
+            }
+            // clients that predate negotiation still get the reply they understand
+            let reply = exchange(ClientHello {
+                long_pk: (&eph_sk).into(),
+                eph_pk: (&eph_sk).into(),
+                version: 1,
+            })
+            .await;
+            assert!(matches!(reply, Some(ServerHello { .. })));
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn handshake_flood_is_limited() {
+        smol::block_on(async {
+            let long_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+            let pubkey = x25519_dalek::PublicKey::from(&long_sk);
+            let limit = HandshakeLimit {
+                per_second: 0.001,
+                burst: 3.0,
+                ..HandshakeLimit::default()
+            };
+            let listener = Listener::listen_with_limit("127.0.0.1:0", long_sk, limit).await;
+            let server_addr = listener.local_addr();
+            let _accept =
+                smol::spawn(async move { while listener.accept_session().await.is_some() {} });
+
+            // flood from one source
+            let cookie = crypt::Cookie::new(pubkey);
+            let flooder = smol::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            for _ in 0..50 {
+                let eph_sk = x25519_dalek::StaticSecret::new(&mut rand::thread_rng());
+                let hello = ClientHello {
+                    long_pk: (&eph_sk).into(),
+                    eph_pk: (&eph_sk).into(),
+                    version: 1,
+                };
+                let hello = crypt::StdAEAD::new(&cookie.generate_c2s().next().unwrap())
+                    .pad_encrypt(&hello, PaddingPolicy::default());
+                flooder.send_to(&hello, server_addr).await.unwrap();
+            }
+            let mut replies = 0;
+            let mut buf = [0u8; 2048];
+            while flooder
+                .recv_from(&mut buf)
+                .or(async {
+                    smol::Timer::after(Duration::from_millis(500)).await;
+                    Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "done"))
+                })
+                .await
+                .is_ok()
+            {
+                replies += 1;
+            }
+            assert_eq!(replies, 3);
+
+            // a legitimate client elsewhere still gets through
+            crate::connect_custom(
+                server_addr,
+                pubkey,
+                || Ok("127.0.0.2:0".parse().unwrap()),
+                crate::ConnectOptions::default(),
+            )
+            .await
+            .unwrap();
+        })
+    }
+}
@@ -1,15 +1,31 @@
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+
+/// Oldest handshake protocol version this implementation speaks.
+pub const MIN_VERSION: u64 = 1;
+/// Newest handshake protocol version this implementation speaks.
+pub const MAX_VERSION: u64 = 1;
+
+/// Picks the highest version in the given range that this implementation also speaks.
+pub fn negotiate_version(min_version: u64, max_version: u64) -> Option<u64> {
+    let version = max_version.min(MAX_VERSION);
+    if version >= min_version.max(MIN_VERSION) {
+        Some(version)
+    } else {
+        None
+    }
+}
+
 /// Frame sent as a session-negotiation message. This is always encrypted with the cookie.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum HandshakeFrame {
-    /// Frame sent from client to server when opening a connection. This is always globally encrypted.
+    /// Frame sent from client to server when opening a connection, by clients that only speak version 1. This is always globally encrypted.
     ClientHello {
         long_pk: x25519_dalek::PublicKey,
         eph_pk: x25519_dalek::PublicKey,
         version: u64,
     },
-    /// Frame sent from server to client to give a cookie for finally opening a connection.
+    /// Frame sent from server to client in reply to a ClientHello, to give a cookie for finally opening a connection.
     ServerHello {
         long_pk: x25519_dalek::PublicKey,
         eph_pk: x25519_dalek::PublicKey,
@@ -24,6 +40,81 @@ pub enum HandshakeFrame {
         /// Which shard is this
         shard_id: u8,
     },
+
+    /// Frame sent from client to server to check reachability and round-trip time without opening a session. This is globally encrypted.
+    ClientProbe { nonce: u64 },
+    /// Frame sent from server to client in reply to a ClientProbe, echoing its nonce.
+    ServerProbe { nonce: u64 },
+
+    /// Frame sent from client to server when opening a connection, advertising every protocol version the client speaks. New variants only ever go at the end, so older peers just ignore them.
+    ClientHelloV2 {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        min_version: u64,
+        max_version: u64,
+        /// Id of the FEC code the client uses, which the session the server sets up must use too.
+        fec_codec: u8,
+        /// Largest datagram that the server's padding may grow its packets to.
+        max_datagram_size: u64,
+    },
+    /// Frame sent from server to client in reply to a ClientHelloV2, carrying the version the server picked.
+    ServerHelloV2 {
+        long_pk: x25519_dalek::PublicKey,
+        eph_pk: x25519_dalek::PublicKey,
+        resume_token: Bytes,
+        version: u64,
+    },
+    /// Frame sent from server to client when no version the client advertised is spoken by the server, which instead speaks the given range.
+    ServerVersionMismatch { min_version: u64, max_version: u64 },
+}
+
+/// What a ClientHello of either kind says about the client.
+#[derive(Clone, Copy, Debug)]
+pub struct HelloInfo {
+    pub long_pk: x25519_dalek::PublicKey,
+    pub eph_pk: x25519_dalek::PublicKey,
+    pub min_version: u64,
+    pub max_version: u64,
+    /// Legacy hellos come from clients that only know Reed-Solomon.
+    pub fec_codec: u8,
+    /// None for legacy hellos, whose clients take datagrams of any size.
+    pub max_datagram_size: Option<usize>,
+}
+
+impl HandshakeFrame {
+    /// For a ClientHello of either kind, what it says about the client.
+    pub fn client_hello(&self) -> Option<HelloInfo> {
+        match self {
+            HandshakeFrame::ClientHello {
+                long_pk,
+                eph_pk,
+                version,
+            } => Some(HelloInfo {
+                long_pk: *long_pk,
+                eph_pk: *eph_pk,
+                min_version: *version,
+                max_version: *version,
+                fec_codec: 0,
+                max_datagram_size: None,
+            }),
+            HandshakeFrame::ClientHelloV2 {
+                long_pk,
+                eph_pk,
+                min_version,
+                max_version,
+                fec_codec,
+                max_datagram_size,
+            } => Some(HelloInfo {
+                long_pk: *long_pk,
+                eph_pk: *eph_pk,
+                min_version: *min_version,
+                max_version: *max_version,
+                fec_codec: *fec_codec,
+                max_datagram_size: Some(*max_datagram_size as usize),
+            }),
+            _ => None,
+        }
+    }
 }
 
 /// Frame sent as an per-session message. This is always encrypted with a per-session key.
@@ -47,3 +138,24 @@ pub struct DataFrame {
     /// Body.
     pub body: Bytes,
 }
+
+impl DataFrame {
+    /// Creates a goodbye frame, which tells the other side that the session is ending. Goodbyes carry no shards at all, which no ordinary frame ever does.
+    pub fn goodbye(frame_no: u64, run_no: u64) -> Self {
+        DataFrame {
+            frame_no,
+            run_no,
+            run_idx: 0,
+            data_shards: 0,
+            parity_shards: 0,
+            high_recv_frame_no: 0,
+            total_recv_frames: 0,
+            body: Bytes::new(),
+        }
+    }
+
+    /// Checks whether this is a goodbye frame.
+    pub fn is_goodbye(&self) -> bool {
+        self.data_shards == 0 && self.parity_shards == 0
+    }
+}
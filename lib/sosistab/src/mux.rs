@@ -1,65 +1,347 @@
 use crate::*;
 use bytes::Bytes;
 use smol::channel::{Receiver, Sender};
+use smol::prelude::*;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 mod mempress;
 mod multiplex_actor;
 mod relconn;
 mod structs;
-pub use relconn::RelConn;
+pub use relconn::{RelConn, StreamInfo, StreamWindow};
+
+/// A request to open a stream: its additional info, its window, and where the opened stream, or why it couldn't be opened, goes.
+type OpenRequest = (
+    Option<String>,
+    StreamWindow,
+    Sender<std::io::Result<RelConn>>,
+);
 
 /// A multiplex session over a sosistab session, implementing both reliable "streams" and unreliable messages.
 #[derive(Clone)]
 pub struct Multiplex {
-    urel_send: Sender<Bytes>,
+    urel_send: Sender<(Option<u64>, Bytes)>,
     urel_recv: Receiver<Bytes>,
-    conn_open: Sender<(Option<String>, Sender<RelConn>)>,
+    flow_recv: Receiver<(u64, Bytes)>,
+    conn_open: Sender<OpenRequest>,
     conn_accept: Receiver<RelConn>,
+    stream_dump: Sender<Sender<Vec<StreamInfo>>>,
+    stats: Sender<Sender<MultiplexStats>>,
     sess_ref: Arc<Session>,
+    actor_liveness: Arc<ActorLiveness>,
+    last_pong: Arc<parking_lot::Mutex<Instant>>,
+    accepting: Arc<AtomicBool>,
 }
 
 fn to_ioerror<T: Into<Box<dyn std::error::Error + Send + Sync>>>(val: T) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::ConnectionReset, val)
 }
 
+fn not_accepting_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionRefused,
+        "multiplex is not accepting new streams",
+    )
+}
+
+fn actor_dead_error() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::ConnectionAborted,
+        "multiplex actor has exited",
+    )
+}
+
+/// Tracks whether the multiplex actor is still running, so that callers don't wait on a zombie.
+#[derive(Default)]
+struct ActorLiveness {
+    dead: AtomicBool,
+    event: event_listener::Event,
+}
+
+impl ActorLiveness {
+    fn mark_dead(&self) {
+        self.dead.store(true, Ordering::SeqCst);
+        self.event.notify(usize::MAX);
+    }
+
+    fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the actor has exited, then returns an error.
+    async fn wait_dead<T>(&self) -> std::io::Result<T> {
+        loop {
+            if self.is_dead() {
+                return Err(actor_dead_error());
+            }
+            let listener = self.event.listen();
+            if self.is_dead() {
+                return Err(actor_dead_error());
+            }
+            listener.await;
+        }
+    }
+}
+
+/// What to do with a stream the other side opens while the accept backlog is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptPolicy {
+    /// Wait until [Multiplex::accept_conn] makes room. Nothing else on the multiplex makes progress in the meantime.
+    Block,
+    /// Reset the oldest stream that is still waiting to be accepted, making room for the new one.
+    DropOldest,
+    /// Reset the new stream right away.
+    RejectWithReset,
+}
+
+/// What to do with an unreliable message from the other side while the receive queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrelPolicy {
+    /// Wait until [Multiplex::recv_urel] makes room. Nothing else on the multiplex makes progress in the meantime, so a slow reader stalls every stream.
+    Block,
+    /// Drop the oldest queued message, making room for the new one. Dropped messages are counted in [MultiplexStats].
+    DropOldest,
+}
+
+/// Options for a [Multiplex].
+#[derive(Debug, Clone)]
+pub struct MultiplexOptions {
+    /// How many streams opened by the other side may wait to be accepted.
+    pub accept_backlog: usize,
+    /// What to do once the backlog is full.
+    pub accept_policy: AcceptPolicy,
+    /// How many unreliable messages from the other side may wait to be received. Messages belonging to flows have a queue of the same size of their own, which always drops the oldest message once full, since nothing might ever read it.
+    pub urel_backlog: usize,
+    /// What to do once the queue of messages that don't belong to flows is full.
+    pub urel_policy: UrelPolicy,
+    /// If set, the multiplex gives up on a session that hasn't delivered anything for this long, resetting every stream. Heartbeats go out every 10 seconds, so this should be comfortably longer than that.
+    pub dead_session_timeout: Option<Duration>,
+    /// Fraction by which every heartbeat interval is randomly lengthened or shortened, so that the heartbeats of many multiplexes don't line up.
+    pub timer_jitter: f64,
+    /// Executor that the multiplex and its streams run their tasks on. None uses the one of the underlying session, if it has one, and otherwise the global one.
+    pub executor: Option<runtime::ExecutorHandle>,
+}
+
+impl Default for MultiplexOptions {
+    fn default() -> Self {
+        MultiplexOptions {
+            accept_backlog: 100,
+            accept_policy: AcceptPolicy::Block,
+            urel_backlog: 10,
+            urel_policy: UrelPolicy::Block,
+            dead_session_timeout: None,
+            timer_jitter: 0.1,
+            executor: None,
+        }
+    }
+}
+
+/// The queue of streams waiting to be accepted, as seen by the multiplex actor.
+struct AcceptBacklog {
+    send: Sender<RelConn>,
+    recv: Receiver<RelConn>,
+    policy: AcceptPolicy,
+}
+
+impl AcceptBacklog {
+    /// Whether a new stream should be turned away instead of being queued.
+    fn should_reject(&self) -> bool {
+        self.policy == AcceptPolicy::RejectWithReset && self.send.is_full()
+    }
+
+    /// Queues a stream to be accepted, following the policy if the backlog is full.
+    async fn push(&self, mut conn: RelConn) {
+        match self.policy {
+            AcceptPolicy::Block => drop(self.send.send(conn).await),
+            AcceptPolicy::RejectWithReset => {
+                if let Err(err) = self.send.try_send(conn) {
+                    err.into_inner().reset()
+                }
+            }
+            AcceptPolicy::DropOldest => loop {
+                match self.send.try_send(conn) {
+                    Err(smol::channel::TrySendError::Full(rejected)) => {
+                        if let Ok(oldest) = self.recv.try_recv() {
+                            oldest.reset()
+                        }
+                        conn = rejected;
+                    }
+                    _ => return,
+                }
+            },
+        }
+    }
+}
+
+/// The queue of unreliable messages waiting to be received, as seen by the multiplex actor.
+struct UrelBacklog<T = Bytes> {
+    send: Sender<T>,
+    recv: Receiver<T>,
+    policy: UrelPolicy,
+    dropped: AtomicU64,
+}
+
+impl<T> UrelBacklog<T> {
+    /// Queues a message to be received, following the policy if the queue is full.
+    async fn push(&self, mut msg: T) {
+        match self.policy {
+            UrelPolicy::Block => drop(self.send.send(msg).await),
+            UrelPolicy::DropOldest => loop {
+                match self.send.try_send(msg) {
+                    Err(smol::channel::TrySendError::Full(rejected)) => {
+                        if self.recv.try_recv().is_ok() {
+                            self.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        msg = rejected;
+                    }
+                    _ => return,
+                }
+            },
+        }
+    }
+
+    /// How many messages were dropped to make room for newer ones.
+    fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
 impl Multiplex {
     /// Creates a new multiplexed session
     pub fn new(session: Session) -> Self {
+        Self::with_options(session, MultiplexOptions::default())
+    }
+
+    /// Creates a new multiplexed session with the given options.
+    pub fn with_options(session: Session, opts: MultiplexOptions) -> Self {
+        Self::with_actor(session, opts, multiplex_actor::multiplex)
+    }
+
+    /// Creates a new multiplexed session driven by the given actor.
+    fn with_actor<F: Future<Output = anyhow::Result<()>> + Send + 'static>(
+        session: Session,
+        mut opts: MultiplexOptions,
+        actor: impl FnOnce(
+            Arc<Session>,
+            Receiver<(Option<u64>, Bytes)>,
+            UrelBacklog,
+            UrelBacklog<(u64, Bytes)>,
+            Receiver<OpenRequest>,
+            AcceptBacklog,
+            Receiver<Sender<Vec<StreamInfo>>>,
+            Receiver<Sender<MultiplexStats>>,
+            Arc<parking_lot::Mutex<Instant>>,
+            MultiplexOptions,
+            Arc<AtomicBool>,
+        ) -> F,
+    ) -> Self {
         let (urel_send, urel_send_recv) = smol::channel::bounded(10);
-        let (urel_recv_send, urel_recv) = smol::channel::bounded(10);
+        let (urel_recv_send, urel_recv) = smol::channel::bounded(opts.urel_backlog.max(1));
+        let (flow_recv_send, flow_recv) = smol::channel::bounded(opts.urel_backlog.max(1));
         let (conn_open, conn_open_recv) = smol::channel::unbounded();
-        let (conn_accept_send, conn_accept) = smol::channel::bounded(100);
+        let (conn_accept_send, conn_accept) = smol::channel::bounded(opts.accept_backlog.max(1));
+        let (stream_dump, stream_dump_recv) = smol::channel::unbounded();
+        let (stats, stats_recv) = smol::channel::unbounded();
+        if opts.executor.is_none() {
+            opts.executor = session.executor().cloned();
+        }
         let session = Arc::new(session);
-        let sess_cloned = session.clone();
-        runtime::spawn(async move {
-            let retval = multiplex_actor::multiplex(
-                sess_cloned,
-                urel_send_recv,
-                urel_recv_send,
-                conn_open_recv,
-                conn_accept_send,
-            )
-            .await;
+        let actor_liveness = Arc::new(ActorLiveness::default());
+        let last_pong = Arc::new(parking_lot::Mutex::new(Instant::now()));
+        let accepting = Arc::new(AtomicBool::new(true));
+        let actor = actor(
+            session.clone(),
+            urel_send_recv,
+            UrelBacklog {
+                send: urel_recv_send,
+                recv: urel_recv.clone(),
+                policy: opts.urel_policy,
+                dropped: AtomicU64::new(0),
+            },
+            UrelBacklog {
+                send: flow_recv_send,
+                recv: flow_recv.clone(),
+                policy: UrelPolicy::DropOldest,
+                dropped: AtomicU64::new(0),
+            },
+            conn_open_recv,
+            AcceptBacklog {
+                send: conn_accept_send,
+                recv: conn_accept.clone(),
+                policy: opts.accept_policy,
+            },
+            stream_dump_recv,
+            stats_recv,
+            last_pong.clone(),
+            opts.clone(),
+            accepting.clone(),
+        );
+        let liveness = actor_liveness.clone();
+        runtime::spawn_on(opts.executor.as_ref(), async move {
+            // mark the actor as dead even if it panics
+            let _guard = scopeguard::guard((), |_| liveness.mark_dead());
+            let retval = actor.await;
             log::debug!("multiplex actor returned {:?}", retval);
         })
         .detach();
         Multiplex {
             urel_send,
             urel_recv,
+            flow_recv,
             conn_open,
             conn_accept,
+            stream_dump,
+            stats,
             sess_ref: session,
+            actor_liveness,
+            last_pong,
+            accepting,
         }
     }
 
     /// Sends an unreliable message to the other side
     pub async fn send_urel(&self, msg: Bytes) -> std::io::Result<()> {
-        self.urel_send.send(msg).await.map_err(to_ioerror)
+        self.send_urel_inner(None, msg).await
+    }
+
+    /// Sends an unreliable message belonging to the given flow to the other side, which receives it through [Multiplex::recv_urel_flow] rather than [Multiplex::recv_urel].
+    pub async fn send_urel_to(&self, flow: u64, msg: Bytes) -> std::io::Result<()> {
+        self.send_urel_inner(Some(flow), msg).await
+    }
+
+    async fn send_urel_inner(&self, flow: Option<u64>, msg: Bytes) -> std::io::Result<()> {
+        if self.actor_liveness.is_dead() {
+            return Err(actor_dead_error());
+        }
+        async { self.urel_send.send((flow, msg)).await.map_err(to_ioerror) }
+            .or(self.actor_liveness.wait_dead())
+            .await
     }
 
     /// Receive an unreliable message
     pub async fn recv_urel(&self) -> std::io::Result<Bytes> {
-        self.urel_recv.recv().await.map_err(to_ioerror)
+        if self.actor_liveness.is_dead() {
+            return Err(actor_dead_error());
+        }
+        async { self.urel_recv.recv().await.map_err(to_ioerror) }
+            .or(self.actor_liveness.wait_dead())
+            .await
+    }
+
+    /// Receive an unreliable message sent with [Multiplex::send_urel_to], along with its flow
+    pub async fn recv_urel_flow(&self) -> std::io::Result<(u64, Bytes)> {
+        if self.actor_liveness.is_dead() {
+            return Err(actor_dead_error());
+        }
+        async { self.flow_recv.recv().await.map_err(to_ioerror) }
+            .or(self.actor_liveness.wait_dead())
+            .await
+    }
+
+    /// How long ago anything, such as the answer to a heartbeat, was last heard from the other side, or how long ago the multiplex was created if nothing ever was. Heartbeats are sent every 10 seconds, so a large value means that the other side is most likely gone. Peers that predate heartbeats don't answer them, though, and are only heard from when there's traffic.
+    pub fn last_pong_age(&self) -> Duration {
+        self.last_pong.lock().elapsed()
     }
 
     /// Gets a reference to the underlying Session
@@ -67,21 +349,904 @@ impl Multiplex {
         &self.sess_ref
     }
 
-    /// Open a reliable conn to the other end.
+    /// Sets whether new streams may be opened, in either direction. While not accepting, [Multiplex::open_conn] fails with ConnectionRefused and streams opened by the other side are reset, but existing streams carry on. This lets a session drain while a replacement takes over new traffic.
+    pub fn set_accepting(&self, accepting: bool) {
+        self.accepting.store(accepting, Ordering::SeqCst);
+    }
+
+    /// Open a reliable conn to the other end. Fails with ConnectionRefused if the other side turns the stream away.
     pub async fn open_conn(&self, additional: Option<String>) -> std::io::Result<RelConn> {
-        let (send, recv) = smol::channel::unbounded();
-        self.conn_open
-            .send((additional.clone(), send))
+        self.open_conn_with_window(additional, StreamWindow::Auto)
+            .await
+    }
+
+    /// Open a reliable conn to the other end, giving up with TimedOut if the other side hasn't answered within the given time. Until then, the SYN is resent with exponential backoff, so a lost SYN or SYN-ACK only delays the open. A stream that gets answered too late is reset.
+    pub async fn open_conn_with_timeout(
+        &self,
+        additional: Option<String>,
+        timeout: Duration,
+    ) -> std::io::Result<RelConn> {
+        self.open_conn_inner(additional, StreamWindow::Auto, Some(timeout))
             .await
-            .map_err(to_ioerror)?;
-        if let Ok(rc) = recv.recv().await {
-            return Ok(rc);
+    }
+
+    /// Open a reliable conn to the other end, with the given window for what it sends and receives.
+    pub async fn open_conn_with_window(
+        &self,
+        additional: Option<String>,
+        window: StreamWindow,
+    ) -> std::io::Result<RelConn> {
+        self.open_conn_inner(additional, window, None).await
+    }
+
+    async fn open_conn_inner(
+        &self,
+        additional: Option<String>,
+        window: StreamWindow,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<RelConn> {
+        if self.actor_liveness.is_dead() {
+            return Err(actor_dead_error());
+        }
+        if !self.accepting.load(Ordering::SeqCst) {
+            return Err(not_accepting_error());
+        }
+        async {
+            let (send, recv) = smol::channel::unbounded();
+            self.conn_open
+                .send((additional.clone(), window, send))
+                .await
+                .map_err(to_ioerror)?;
+            if let Ok(res) = recv.recv().await {
+                return res;
+            }
+            if !self.accepting.load(Ordering::SeqCst) {
+                return Err(not_accepting_error());
+            }
+            Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout"))
+        }
+        .or(self.actor_liveness.wait_dead())
+        .or(async {
+            match timeout {
+                Some(timeout) => {
+                    smol::Timer::after(timeout).await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "stream open timed out",
+                    ))
+                }
+                None => smol::future::pending().await,
+            }
+        })
+        .await
+    }
+
+    /// Lists the currently open reliable conns, for debugging.
+    pub async fn dump_streams(&self) -> std::io::Result<Vec<StreamInfo>> {
+        if self.actor_liveness.is_dead() {
+            return Err(actor_dead_error());
+        }
+        async {
+            let (send, recv) = smol::channel::bounded(1);
+            self.stream_dump.send(send).await.map_err(to_ioerror)?;
+            recv.recv().await.map_err(to_ioerror)
         }
-        Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "timeout"))
+        .or(self.actor_liveness.wait_dead())
+        .await
+    }
+
+    /// Gets counts of the reliable conns going through this multiplex.
+    pub async fn stats(&self) -> std::io::Result<MultiplexStats> {
+        if self.actor_liveness.is_dead() {
+            return Err(actor_dead_error());
+        }
+        async {
+            let (send, recv) = smol::channel::bounded(1);
+            self.stats.send(send).await.map_err(to_ioerror)?;
+            recv.recv().await.map_err(to_ioerror)
+        }
+        .or(self.actor_liveness.wait_dead())
+        .await
     }
 
     /// Accept a reliable conn from the other end.
     pub async fn accept_conn(&self) -> std::io::Result<RelConn> {
-        self.conn_accept.recv().await.map_err(to_ioerror)
+        if self.actor_liveness.is_dead() {
+            return Err(actor_dead_error());
+        }
+        async { self.conn_accept.recv().await.map_err(to_ioerror) }
+            .or(self.actor_liveness.wait_dead())
+            .await
+    }
+}
+
+/// Statistics of the streams and unreliable messages in a [Multiplex].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MultiplexStats {
+    /// Streams currently open, including those still shutting down.
+    pub open_streams: usize,
+    /// Streams opened by either side since the multiplex was created.
+    pub streams_opened_total: u64,
+    /// Streams that ended in a reset, from either side, rather than being closed.
+    pub streams_reset_total: u64,
+    /// Unreliable messages from the other side dropped because they weren't received in time. Only ever nonzero with [UrelPolicy::DropOldest].
+    pub urel_dropped_total: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::structs::{Message, RelKind};
+    use super::*;
+
+    fn session_with(
+        send_frame: Sender<msg::DataFrame>,
+        recv_frame: Receiver<msg::DataFrame>,
+    ) -> Session {
+        session_on(send_frame, recv_frame, None)
+    }
+
+    fn session_on(
+        send_frame: Sender<msg::DataFrame>,
+        recv_frame: Receiver<msg::DataFrame>,
+        executor: Option<runtime::ExecutorHandle>,
+    ) -> Session {
+        Session::new(SessionConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(10),
+            max_packet_age: None,
+            idle_timeout: None,
+            target_loss: 0.05,
+            min_loss_for_fec: 0.0,
+            fec_reorder_runs: 10,
+            fec_eviction: EvictionPolicy::Count,
+            shuffle_shards: false,
+            loss_percentile: 0.25,
+            loss_estimator: LossEstimator::Windowed,
+            fec_codec: Arc::new(crate::ReedSolomonCodec),
+            probed_datagram_size: None,
+            executor,
+            send_frame,
+            recv_frame,
+        })
+    }
+
+    fn dummy_session() -> Session {
+        let (send_frame, _) = smol::channel::unbounded();
+        let (_, recv_frame) = smol::channel::unbounded();
+        session_with(send_frame, recv_frame)
+    }
+
+    #[test]
+    fn label_shows_in_dump() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            let accepted = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move { mux_b.accept_conn().await })
+            };
+            let conn = mux_a.open_conn(Some("remote".into())).await.unwrap();
+            conn.set_label("user-42-download".into());
+            let dump = mux_a.dump_streams().await.unwrap();
+            assert_eq!(dump.len(), 1);
+            assert_eq!(dump[0].additional_info.as_deref(), Some("remote"));
+            assert_eq!(dump[0].label.as_deref(), Some("user-42-download"));
+            // labels are local only
+            let accepted = accepted.await.unwrap();
+            assert_eq!(accepted.label(), None);
+            assert_eq!(mux_b.dump_streams().await.unwrap()[0].label, None);
+        })
+    }
+
+    #[test]
+    fn accepted_conn_sees_additional_info() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            for &info in &[Some("example.com:443"), None] {
+                let accepted = {
+                    let mux_b = mux_b.clone();
+                    smol::spawn(async move { mux_b.accept_conn().await })
+                };
+                let conn = mux_a.open_conn(info.map(String::from)).await.unwrap();
+                assert_eq!(conn.additional_info(), info);
+                let accepted = accepted.await.unwrap();
+                assert_eq!(accepted.additional_info(), info);
+            }
+        })
+    }
+
+    #[test]
+    fn half_close_keeps_read_direction_open() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            let server = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move {
+                    let mut conn = mux_b.accept_conn().await.unwrap();
+                    let mut request = Vec::new();
+                    conn.read_to_end(&mut request).await.unwrap();
+                    assert_eq!(request, b"GET / HTTP/1.0\r\n\r\n");
+                    conn.write_all(b"HTTP/1.0 200 OK\r\n\r\nhello")
+                        .await
+                        .unwrap();
+                    conn.close().await.unwrap();
+                })
+            };
+            let mut conn = mux_a.open_conn(None).await.unwrap();
+            conn.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+            conn.close().await.unwrap();
+            let mut response = Vec::new();
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(10)).await;
+                panic!("half-closed exchange hung")
+            };
+            async {
+                conn.read_to_end(&mut response).await.unwrap();
+                server.await;
+            }
+            .or(timeout)
+            .await;
+            assert_eq!(response, b"HTTP/1.0 200 OK\r\n\r\nhello");
+        })
+    }
+
+    #[test]
+    fn stats_count_streams() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            let accepted = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move {
+                    (
+                        mux_b.accept_conn().await.unwrap(),
+                        mux_b.accept_conn().await.unwrap(),
+                    )
+                })
+            };
+            let first = mux_a.open_conn(None).await.unwrap();
+            let _second = mux_a.open_conn(None).await.unwrap();
+            let _remote = accepted.await;
+            for mux in &[&mux_a, &mux_b] {
+                let stats = mux.stats().await.unwrap();
+                assert_eq!(stats.open_streams, 2);
+                assert_eq!(stats.streams_opened_total, 2);
+                assert_eq!(stats.streams_reset_total, 0);
+            }
+            first.reset();
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(10)).await;
+                panic!("reset stream never went away")
+            };
+            async {
+                while mux_a.stats().await.unwrap().open_streams > 1 {
+                    smol::Timer::after(Duration::from_millis(10)).await;
+                }
+            }
+            .or(timeout)
+            .await;
+            let stats = mux_a.stats().await.unwrap();
+            assert_eq!(stats.streams_opened_total, 2);
+            assert_eq!(stats.streams_reset_total, 1);
+        })
+    }
+
+    /// Opens `count` streams from `mux_a` to a `mux_b` that never accepts, then expects exactly the `refused` ones to be turned away, either when opened or later with ConnectionReset.
+    async fn flood_backlog(policy: AcceptPolicy, count: usize, refused: &[usize]) -> Multiplex {
+        let (send_ab, recv_ab) = smol::channel::unbounded();
+        let (send_ba, recv_ba) = smol::channel::unbounded();
+        let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+        let mux_b = Multiplex::with_options(
+            session_with(send_ba, recv_ab),
+            MultiplexOptions {
+                accept_backlog: 2,
+                accept_policy: policy,
+                ..Default::default()
+            },
+        );
+        let mut conns = Vec::new();
+        for i in 0..count {
+            conns.push(mux_a.open_conn(Some(i.to_string())).await);
+        }
+        for (i, conn) in conns.iter_mut().enumerate() {
+            let conn = match conn {
+                Ok(conn) => conn,
+                Err(err) if refused.contains(&i) => {
+                    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+                    continue;
+                }
+                Err(err) => panic!("stream {} failed to open: {}", i, err),
+            };
+            let res = async { Some(conn.read(&mut [0u8; 1]).await) }
+                .or(async {
+                    smol::Timer::after(Duration::from_millis(500)).await;
+                    None
+                })
+                .await;
+            match res {
+                Some(Err(err)) if refused.contains(&i) => {
+                    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset)
+                }
+                None if !refused.contains(&i) => (),
+                other => panic!("stream {} ended up with {:?}", i, other),
+            }
+        }
+        drop(mux_a);
+        mux_b
+    }
+
+    #[test]
+    fn backlog_rejects_new_streams() {
+        smol::block_on(async {
+            let mux_b = flood_backlog(AcceptPolicy::RejectWithReset, 4, &[2, 3]).await;
+            for i in 0..2 {
+                let conn = mux_b.accept_conn().await.unwrap();
+                assert_eq!(conn.additional_info(), Some(i.to_string().as_str()));
+            }
+        })
+    }
+
+    #[test]
+    fn backlog_drops_oldest_streams() {
+        smol::block_on(async {
+            let mux_b = flood_backlog(AcceptPolicy::DropOldest, 4, &[0, 1]).await;
+            for i in 2..4 {
+                let conn = mux_b.accept_conn().await.unwrap();
+                assert_eq!(conn.additional_info(), Some(i.to_string().as_str()));
+            }
+        })
+    }
+
+    /// Sends `count` unreliable messages from `mux_a` to a `mux_b` that doesn't receive them until afterwards, then receives everything that made it.
+    async fn flood_urel(policy: UrelPolicy, count: u8) -> (Vec<Bytes>, MultiplexStats) {
+        let (send_ab, recv_ab) = smol::channel::unbounded();
+        let (send_ba, recv_ba) = smol::channel::unbounded();
+        let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+        let mux_b = Multiplex::with_options(
+            session_with(send_ba, recv_ab),
+            MultiplexOptions {
+                urel_backlog: 2,
+                urel_policy: policy,
+                ..Default::default()
+            },
+        );
+        for i in 0..count {
+            mux_a.send_urel(Bytes::from(vec![i])).await.unwrap();
+        }
+        smol::Timer::after(Duration::from_millis(300)).await;
+        let mut received = Vec::new();
+        loop {
+            let res = async { Some(mux_b.recv_urel().await.unwrap()) }
+                .or(async {
+                    smol::Timer::after(Duration::from_millis(300)).await;
+                    None
+                })
+                .await;
+            match res {
+                Some(msg) => received.push(msg),
+                None => break,
+            }
+        }
+        (received, mux_b.stats().await.unwrap())
+    }
+
+    #[test]
+    fn urel_backlog_blocks() {
+        smol::block_on(async {
+            let (received, stats) = flood_urel(UrelPolicy::Block, 6).await;
+            // the actor held on to the rest until there was room, so nothing got lost
+            let expected: Vec<_> = (0..6u8).map(|i| Bytes::from(vec![i])).collect();
+            assert_eq!(received, expected);
+            assert_eq!(stats.urel_dropped_total, 0);
+        })
+    }
+
+    #[test]
+    fn urel_backlog_drops_oldest() {
+        smol::block_on(async {
+            let (received, stats) = flood_urel(UrelPolicy::DropOldest, 6).await;
+            assert_eq!(received, vec![Bytes::from(vec![4]), Bytes::from(vec![5])]);
+            assert_eq!(stats.urel_dropped_total, 4);
+        })
+    }
+
+    #[test]
+    fn open_survives_lost_syns() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            // everything from a to b is lost until the blackout ends
+            let blackout = Arc::new(AtomicBool::new(true));
+            let (relay_ab, relayed_ab) = smol::channel::unbounded();
+            let _relay = {
+                let blackout = blackout.clone();
+                smol::spawn(async move {
+                    while let Ok(frame) = recv_ab.recv().await {
+                        if !blackout.load(Ordering::SeqCst) {
+                            drop(relay_ab.send(frame).await);
+                        }
+                    }
+                })
+            };
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, relayed_ab));
+            let opened = {
+                let mux_a = mux_a.clone();
+                smol::spawn(async move {
+                    mux_a
+                        .open_conn_with_timeout(Some("late".into()), Duration::from_secs(10))
+                        .await
+                })
+            };
+            // the first SYNs go out right away, and get lost
+            smol::Timer::after(Duration::from_millis(300)).await;
+            blackout.store(false, Ordering::SeqCst);
+            let accepted = mux_b.accept_conn().await.unwrap();
+            assert_eq!(accepted.additional_info(), Some("late"));
+            opened.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn open_times_out_without_answer() {
+        smol::block_on(async {
+            // nothing ever comes back on the other end
+            let (send_ab, _recv_ab) = smol::channel::unbounded();
+            let (_send_ba, recv_ba) = smol::channel::unbounded();
+            let mux = Multiplex::new(session_with(send_ab, recv_ba));
+            let start = Instant::now();
+            let err = mux
+                .open_conn_with_timeout(None, Duration::from_millis(200))
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+            assert!(start.elapsed() < Duration::from_secs(5));
+        })
+    }
+
+    #[test]
+    fn urel_flows_are_kept_apart() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            mux_a
+                .send_urel_to(7, Bytes::from_static(b"seven"))
+                .await
+                .unwrap();
+            mux_a.send_urel(Bytes::from_static(b"plain")).await.unwrap();
+            mux_a
+                .send_urel_to(9, Bytes::from_static(b"nine"))
+                .await
+                .unwrap();
+            assert_eq!(
+                mux_b.recv_urel().await.unwrap(),
+                Bytes::from_static(b"plain")
+            );
+            assert_eq!(
+                mux_b.recv_urel_flow().await.unwrap(),
+                (7, Bytes::from_static(b"seven"))
+            );
+            assert_eq!(
+                mux_b.recv_urel_flow().await.unwrap(),
+                (9, Bytes::from_static(b"nine"))
+            );
+        })
+    }
+
+    #[test]
+    fn unread_flows_stall_nothing() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::with_options(
+                session_with(send_ba, recv_ab),
+                MultiplexOptions {
+                    urel_backlog: 2,
+                    urel_policy: UrelPolicy::Block,
+                    ..Default::default()
+                },
+            );
+            // nobody on b ever reads these
+            for i in 0..6u8 {
+                mux_a.send_urel_to(1, Bytes::from(vec![i])).await.unwrap();
+            }
+            mux_a.send_urel(Bytes::from_static(b"plain")).await.unwrap();
+            let plain = async { Some(mux_b.recv_urel().await.unwrap()) }
+                .or(async {
+                    smol::Timer::after(Duration::from_secs(5)).await;
+                    None
+                })
+                .await
+                .expect("unread flows stalled the multiplex");
+            assert_eq!(plain, Bytes::from_static(b"plain"));
+            assert_eq!(
+                mux_b.recv_urel_flow().await.unwrap(),
+                (1, Bytes::from(vec![4]))
+            );
+            assert_eq!(mux_b.stats().await.unwrap().urel_dropped_total, 4);
+        })
+    }
+
+    #[test]
+    fn draining_refuses_new_streams() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            let accepted = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move { mux_b.accept_conn().await })
+            };
+            let mut conn = mux_a.open_conn(None).await.unwrap();
+            let mut remote = accepted.await.unwrap();
+            mux_b.set_accepting(false);
+            // neither side can open anything new
+            let err = mux_b.open_conn(None).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+            let err = mux_a.open_conn(None).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+            // while the existing stream keeps working both ways
+            let mut buf = [0u8; 5];
+            conn.write_all(b"hello").await.unwrap();
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            remote.write_all(b"world").await.unwrap();
+            conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+            // and accepting again works as before
+            mux_b.set_accepting(true);
+            let accepted = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move { mux_b.accept_conn().await })
+            };
+            let _conn = mux_a.open_conn(None).await.unwrap();
+            accepted.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn reset_aborts_the_peer() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            let accepted = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move { mux_b.accept_conn().await })
+            };
+            let mut conn = mux_a.open_conn(None).await.unwrap();
+            conn.write_all(b"hello").await.unwrap();
+            let mut remote = accepted.await.unwrap();
+            let mut buf = [0u8; 5];
+            remote.read_exact(&mut buf).await.unwrap();
+            conn.reset();
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(10)).await;
+                panic!("reset never reached the peer")
+            };
+            let err = async { remote.read(&mut buf).await.unwrap_err() }
+                .or(timeout)
+                .await;
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+            let err = remote.write_all(b"anyone there?").await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        })
+    }
+
+    #[test]
+    fn reset_in_syn_sent_refuses_the_open() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            // a bare session on the other side, answering every SYN with an RST
+            let sess_b = session_with(send_ba, recv_ab);
+            let _responder = smol::spawn(async move {
+                loop {
+                    let bts = sess_b.recv_bytes().await.unwrap();
+                    if let Ok(Message::Rel {
+                        kind: RelKind::Syn,
+                        stream_id,
+                        ..
+                    }) = bincode::deserialize(&bts)
+                    {
+                        let rst = Message::Rel {
+                            kind: RelKind::Rst,
+                            stream_id,
+                            seqno: 0,
+                            payload: Bytes::new(),
+                        };
+                        sess_b
+                            .send_bytes(bincode::serialize(&rst).unwrap().into())
+                            .await;
+                    }
+                }
+            });
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(10)).await;
+                panic!("reset in SynSent never failed the open")
+            };
+            let err = async { mux_a.open_conn(None).await.unwrap_err() }
+                .or(timeout)
+                .await;
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+        })
+    }
+
+    #[test]
+    fn read_timeout_fires_on_inactivity() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            let accepted = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move { mux_b.accept_conn().await })
+            };
+            let mut conn = mux_a.open_conn(None).await.unwrap();
+            let mut remote = accepted.await.unwrap();
+            conn.set_read_timeout(Duration::from_millis(100));
+            remote.write_all(b"hello").await.unwrap();
+            remote.flush().await.unwrap();
+            let mut buf = [0u8; 5];
+            conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+            // nothing else is coming, so the next read times out
+            let start = std::time::Instant::now();
+            let err = conn.clone().read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+            assert!(start.elapsed() >= Duration::from_millis(100));
+            assert!(start.elapsed() < Duration::from_secs(5));
+        })
+    }
+
+    #[test]
+    fn heartbeat_tracks_peer() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_with(send_ab, recv_ba));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            let lonely = Multiplex::new(dummy_session());
+            smol::Timer::after(Duration::from_millis(500)).await;
+            // whatever the other side sends counts, not just the answers to heartbeats
+            mux_b.send_urel(Bytes::from_static(b"hello")).await.unwrap();
+            mux_a.recv_urel().await.unwrap();
+            assert!(mux_a.last_pong_age() < Duration::from_millis(400));
+            // nobody answers the lonely one
+            assert!(lonely.last_pong_age() >= Duration::from_millis(500));
+        })
+    }
+
+    /// Forwards frames from one channel to another, each arriving the given time after it was sent.
+    fn delay_line<T: Send + 'static>(
+        recv: Receiver<T>,
+        send: Sender<T>,
+        delay: Duration,
+    ) -> smol::Task<()> {
+        let (send_timed, recv_timed) = smol::channel::unbounded();
+        smol::spawn(async move {
+            let stamp = async {
+                while let Ok(frame) = recv.recv().await {
+                    drop(send_timed.send((Instant::now() + delay, frame)).await);
+                }
+            };
+            let deliver = async {
+                while let Ok((due, frame)) = recv_timed.recv().await {
+                    smol::Timer::at(due).await;
+                    drop(send.send(frame).await);
+                }
+            };
+            smol::future::zip(stamp, deliver).await;
+        })
+    }
+
+    #[test]
+    fn fixed_windows_work_on_long_paths() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let (send_to_a, recv_to_a) = smol::channel::unbounded();
+            let (send_to_b, recv_to_b) = smol::channel::unbounded();
+            let _ab = delay_line(recv_ab, send_to_b, Duration::from_millis(20));
+            let _ba = delay_line(recv_ba, send_to_a, Duration::from_millis(20));
+            let mux_a = Multiplex::new(session_with(send_ab, recv_to_a));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_to_b));
+            let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            for &window in &[
+                StreamWindow::Fixed(4 * relconn::MSS),
+                StreamWindow::Fixed(256 * relconn::MSS),
+            ] {
+                let accepted = {
+                    let mux_b = mux_b.clone();
+                    smol::spawn(async move { mux_b.accept_conn().await })
+                };
+                let mut conn = mux_a.open_conn_with_window(None, window).await.unwrap();
+                assert_eq!(conn.local_window(), window);
+                let mut remote = accepted.await.unwrap();
+                let reader = smol::spawn(async move {
+                    let mut received = vec![0u8; 100_000];
+                    remote.read_exact(&mut received).await.unwrap();
+                    received
+                });
+                conn.write_all(&data).await.unwrap();
+                conn.flush().await.unwrap();
+                assert_eq!(reader.await, data);
+            }
+        })
+    }
+
+    #[test]
+    fn window_can_be_widened_midstream() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let (send_to_a, recv_to_a) = smol::channel::unbounded();
+            let (send_to_b, recv_to_b) = smol::channel::unbounded();
+            let _ab = delay_line(recv_ab, send_to_b, Duration::from_millis(20));
+            let _ba = delay_line(recv_ba, send_to_a, Duration::from_millis(20));
+            let mux_a = Multiplex::new(session_with(send_ab, recv_to_a));
+            let mux_b = Multiplex::new(session_with(send_ba, recv_to_b));
+            let accepted = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move { mux_b.accept_conn().await })
+            };
+            let mut conn = mux_a
+                .open_conn_with_window(None, StreamWindow::Fixed(4 * relconn::MSS))
+                .await
+                .unwrap();
+            let mut remote = accepted.await.unwrap();
+            assert_eq!(remote.local_window(), StreamWindow::Auto);
+            let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+            for &window in &[StreamWindow::Fixed(256 * relconn::MSS), StreamWindow::Auto] {
+                // the stream keeps going across the change
+                conn.set_local_window(window);
+                assert_eq!(conn.local_window(), window);
+                let write = async {
+                    conn.write_all(&data).await.unwrap();
+                    conn.flush().await.unwrap();
+                };
+                let read = async {
+                    let mut received = vec![0u8; 100_000];
+                    remote.read_exact(&mut received).await.unwrap();
+                    received
+                };
+                let ((), received) = write.zip(read).await;
+                assert_eq!(received, data);
+            }
+        })
+    }
+
+    #[test]
+    fn silent_session_fails_streams() {
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            // frames to A go through here, so that they can be cut off
+            let (send_to_a, recv_to_a) = smol::channel::unbounded();
+            let starved = Arc::new(AtomicBool::new(false));
+            let _forwarder = {
+                let starved = starved.clone();
+                smol::spawn(async move {
+                    while let Ok(frame) = recv_ba.recv().await {
+                        if !starved.load(Ordering::SeqCst) {
+                            drop(send_to_a.send(frame).await);
+                        }
+                    }
+                })
+            };
+            let mux_a = Multiplex::with_options(
+                session_with(send_ab, recv_to_a),
+                MultiplexOptions {
+                    dead_session_timeout: Some(Duration::from_millis(300)),
+                    ..Default::default()
+                },
+            );
+            let mux_b = Multiplex::new(session_with(send_ba, recv_ab));
+            let accepted = {
+                let mux_b = mux_b.clone();
+                smol::spawn(async move { mux_b.accept_conn().await })
+            };
+            let mut conn = mux_a.open_conn(None).await.unwrap();
+            let _remote = accepted.await.unwrap();
+            starved.store(true, Ordering::SeqCst);
+            let start = Instant::now();
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                panic!("starved mux never failed its streams")
+            };
+            let err = async { conn.read(&mut [0u8; 1]).await.unwrap_err() }
+                .or(timeout)
+                .await;
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+            assert!(start.elapsed() < Duration::from_secs(2));
+            assert!(mux_a.open_conn(None).await.is_err());
+        })
+    }
+
+    #[test]
+    fn mux_runs_on_its_own_executor() {
+        let exec = Arc::new(smol::Executor::new());
+        let handle = runtime::ExecutorHandle::new(exec.clone());
+        smol::block_on(async {
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let mux_a = Multiplex::new(session_on(send_ab, recv_ba, Some(handle.clone())));
+            let mux_b = Multiplex::new(session_on(send_ba, recv_ab, Some(handle)));
+            let mut opened = {
+                let mux_a = mux_a.clone();
+                smol::spawn(async move { mux_a.open_conn(None).await })
+            };
+            // nobody runs the executor yet, so neither multiplex gets anywhere
+            let stalled = async { Some((&mut opened).await) }
+                .or(async {
+                    smol::Timer::after(Duration::from_millis(200)).await;
+                    None
+                })
+                .await;
+            assert!(stalled.is_none());
+            exec.run(async {
+                let mut conn = opened.await.unwrap();
+                let mut accepted = mux_b.accept_conn().await.unwrap();
+                conn.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                accepted.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            })
+            .or(async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                panic!("streams never opened")
+            })
+            .await;
+        })
+    }
+
+    #[test]
+    fn dead_actor_fails_fast() {
+        smol::block_on(async {
+            let (send_exit, recv_exit) = smol::channel::bounded::<()>(1);
+            let mux = Multiplex::with_actor(
+                dummy_session(),
+                MultiplexOptions::default(),
+                |_, _, _, _, conn_open_recv, _, _, _, _, _, _| async move {
+                    // hold on to the open requests so that they would hang forever
+                    let _pending = conn_open_recv;
+                    let _ = recv_exit.recv().await;
+                    Err::<(), _>(anyhow::anyhow!("forced exit"))
+                },
+            );
+            let pending = {
+                let mux = mux.clone();
+                smol::spawn(async move { mux.open_conn(None).await })
+            };
+            smol::Timer::after(Duration::from_millis(50)).await;
+            send_exit.send(()).await.unwrap();
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                panic!("mux operation hung after actor exit")
+            };
+            assert!(pending.or(timeout).await.is_err());
+            assert!(mux.open_conn(None).await.is_err());
+            assert!(mux.send_urel(Bytes::new()).await.is_err());
+            assert!(mux.recv_urel().await.is_err());
+            assert!(mux.recv_urel_flow().await.is_err());
+            assert!(mux.accept_conn().await.is_err());
+        })
     }
 }
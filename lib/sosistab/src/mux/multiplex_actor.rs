@@ -1,35 +1,74 @@
 use crate::*;
 use bytes::Bytes;
-use mux::relconn::{RelConn, RelConnBack, RelConnState};
+use mux::relconn::{RelConn, RelConnBack, RelConnState, StreamInfo, StreamWindow};
 use mux::structs::*;
+use mux::{AcceptBacklog, MultiplexOptions, MultiplexStats, OpenRequest, UrelBacklog};
 use rand::prelude::*;
 use smol::channel::{Receiver, Sender};
 use smol::lock::RwLock;
 use smol::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, sync::Arc};
 
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+
 pub async fn multiplex(
     session: Arc<Session>,
-    urel_send_recv: Receiver<Bytes>,
-    urel_recv_send: Sender<Bytes>,
-    conn_open_recv: Receiver<(Option<String>, Sender<RelConn>)>,
-    conn_accept_send: Sender<RelConn>,
+    urel_send_recv: Receiver<(Option<u64>, Bytes)>,
+    urel_backlog: UrelBacklog,
+    flow_backlog: UrelBacklog<(u64, Bytes)>,
+    conn_open_recv: Receiver<OpenRequest>,
+    accept_backlog: AcceptBacklog,
+    stream_dump_recv: Receiver<Sender<Vec<StreamInfo>>>,
+    stats_recv: Receiver<Sender<MultiplexStats>>,
+    last_pong: Arc<parking_lot::Mutex<Instant>>,
+    opts: MultiplexOptions,
+    accepting: Arc<AtomicBool>,
 ) -> anyhow::Result<()> {
     let conn_tab = Arc::new(RwLock::new(ConnTable::default()));
     let (glob_send, glob_recv) = smol::channel::bounded(1000);
     let (dead_send, dead_recv) = smol::channel::unbounded();
+    // heartbeat, starting right away so that a dead peer is noticed early
+    let _pinger = {
+        let glob_send = glob_send.clone();
+        let timer_jitter = opts.timer_jitter;
+        runtime::spawn_on(opts.executor.as_ref(), async move {
+            loop {
+                if glob_send.send(Message::Ping).await.is_err() {
+                    return;
+                }
+                smol::Timer::after(runtime::jitter(PING_INTERVAL, timer_jitter)).await;
+            }
+        })
+    };
+    let last_recv = parking_lot::Mutex::new(Instant::now());
     loop {
         // fires on receiving messages
         let recv_evt = async {
-            let msg = session.recv_bytes().await;
+            let msg = session.recv_bytes().await?;
+            *last_recv.lock() = Instant::now();
+            // anything at all shows that the other side is there, even if it predates heartbeats
+            *last_pong.lock() = Instant::now();
             let msg = bincode::deserialize::<Message>(&msg);
             if let Ok(msg) = msg {
                 match msg {
                     // unreliable
                     Message::Urel(bts) => {
                         log::trace!("urel recv {}B", bts.len());
-                        drop(urel_recv_send.send(bts).await);
+                        urel_backlog.push(bts).await;
+                    }
+                    Message::UrelFlow { flow, body } => {
+                        log::trace!("urel recv {}B on flow {}", body.len(), flow);
+                        flow_backlog.push((flow, body)).await;
+                    }
+                    // heartbeat
+                    Message::Ping => {
+                        session
+                            .send_bytes(bincode::serialize(&Message::Pong).unwrap().into())
+                            .await;
                     }
+                    Message::Pong => {}
                     // connection opening
                     Message::Rel {
                         kind: RelKind::Syn,
@@ -52,6 +91,22 @@ pub async fn multiplex(
                                     .into(),
                                 )
                                 .await;
+                        } else if !accepting.load(Ordering::SeqCst)
+                            || accept_backlog.should_reject()
+                        {
+                            log::debug!("syn recv {} REJECT", stream_id);
+                            session
+                                .send_bytes(
+                                    bincode::serialize(&Message::Rel {
+                                        kind: RelKind::Rst,
+                                        stream_id,
+                                        seqno: 0,
+                                        payload: Bytes::new(),
+                                    })
+                                    .unwrap()
+                                    .into(),
+                                )
+                                .await;
                         } else {
                             let dead_send = dead_send.clone();
                             log::trace!("syn recv {} ACCEPT", stream_id);
@@ -60,14 +115,16 @@ pub async fn multiplex(
                             let (new_conn, new_conn_back) = RelConn::new(
                                 RelConnState::SynReceived { stream_id },
                                 glob_send.clone(),
-                                move || {
-                                    let _ = dead_send.try_send(stream_id);
+                                move |reset| {
+                                    let _ = dead_send.try_send((stream_id, reset));
                                 },
                                 additional_info,
+                                StreamWindow::Auto,
+                                opts.executor.as_ref(),
                             );
                             // the RelConn itself is responsible for sending the SynAck. Here we just store the connection into the table, accept it, and be done with it.
                             conn_tab.set_stream(stream_id, new_conn_back);
-                            drop(conn_accept_send.send(new_conn).await);
+                            accept_backlog.push(new_conn).await;
                         }
                     }
                     // associated with existing connection
@@ -109,18 +166,30 @@ pub async fn multiplex(
         };
         // fires on a new unreliable sending request
         let urel_send_evt = async {
-            let to_send = urel_send_recv.recv().await?;
+            let (flow, to_send) = urel_send_recv.recv().await?;
             log::trace!("urel send {}B", to_send.len());
-            glob_send.send(Message::Urel(to_send)).await?;
+            let msg = match flow {
+                Some(flow) => Message::UrelFlow {
+                    flow,
+                    body: to_send,
+                },
+                None => Message::Urel(to_send),
+            };
+            glob_send.send(msg).await?;
             Ok::<(), anyhow::Error>(())
         };
         // fires on a new stream open request
         let conn_open_evt = async {
-            let (additional_data, result_chan) = conn_open_recv.recv().await?;
+            let (additional_data, window, result_chan) = conn_open_recv.recv().await?;
+            if !accepting.load(Ordering::SeqCst) {
+                // dropping the result channel fails the open
+                return Ok(());
+            }
             let conn_tab = conn_tab.clone();
             let glob_send = glob_send.clone();
             let dead_send = dead_send.clone();
-            runtime::spawn(async move {
+            let executor = opts.executor.clone();
+            runtime::spawn_on(opts.executor.as_ref(), async move {
                 let stream_id = {
                     let mut conn_tab = conn_tab.write().await;
                     let stream_id = conn_tab.find_id();
@@ -133,14 +202,25 @@ pub async fn multiplex(
                                 result: send_sig,
                             },
                             glob_send.clone(),
-                            move || {
-                                let _ = dead_send.try_send(stream_id);
+                            move |reset| {
+                                let _ = dead_send.try_send((stream_id, reset));
                             },
                             additional_data.clone(),
+                            window,
+                            executor.as_ref(),
                         );
-                        runtime::spawn(async move {
-                            let _ = recv_sig.recv().await;
-                            drop(result_chan.send(conn).await)
+                        runtime::spawn_on(executor.as_ref(), async move {
+                            // the stream only reports back if the open failed
+                            if let Ok(err) = recv_sig.recv().await {
+                                let _ = result_chan.send(Err(err)).await;
+                                return;
+                            }
+                            // nobody is waiting anymore, say because the open timed out
+                            if let Err(rejected) = result_chan.send(Ok(conn)).await {
+                                if let Ok(conn) = rejected.into_inner() {
+                                    conn.reset()
+                                }
+                            }
                         })
                         .detach();
                         conn_tab.set_stream(stream_id, conn_back);
@@ -168,16 +248,45 @@ pub async fn multiplex(
             .await;
             Ok::<(), anyhow::Error>(())
         };
+        // fires on a request to dump the open streams
+        let dump_evt = async {
+            let reply = stream_dump_recv.recv().await?;
+            let dump = conn_tab.read().await.dump();
+            drop(reply.send(dump).await);
+            Ok::<(), anyhow::Error>(())
+        };
+        // fires on a request for stats
+        let stats_evt = async {
+            let reply = stats_recv.recv().await?;
+            let mut stats = conn_tab.read().await.stats();
+            stats.urel_dropped_total = urel_backlog.dropped() + flow_backlog.dropped();
+            drop(reply.send(stats).await);
+            Ok::<(), anyhow::Error>(())
+        };
         // dead stuff
         let dead_evt = async {
-            let lala = dead_recv.recv().await?;
-            log::debug!("removing stream {} from table", lala);
-            conn_tab.write().await.del_stream(lala);
+            let (lala, reset) = dead_recv.recv().await?;
+            log::debug!("removing stream {} from table (reset: {})", lala, reset);
+            conn_tab.write().await.del_stream(lala, reset);
             Ok(())
         };
+        // fires when the session has been silent for too long. Pings guarantee traffic from a live peer.
+        let silence_evt = async {
+            if let Some(timeout) = opts.dead_session_timeout {
+                smol::Timer::at(*last_recv.lock() + timeout).await;
+                log::warn!("session silent for {:?}, failing all streams", timeout);
+                for handle in conn_tab.read().await.sid_to_stream.values() {
+                    handle.reset();
+                }
+                anyhow::bail!("session silent for {:?}", timeout)
+            } else {
+                smol::future::pending().await
+            }
+        };
         // await on them all
         recv_evt
-            .or(send_evt.or(urel_send_evt.or(conn_open_evt.or(dead_evt))))
+            .or(send_evt.or(urel_send_evt
+                .or(conn_open_evt.or(dump_evt.or(stats_evt.or(dead_evt.or(silence_evt)))))))
             .await?;
     }
 }
@@ -186,6 +295,8 @@ pub async fn multiplex(
 struct ConnTable {
     /// Maps IDs to RelConn back handles.
     sid_to_stream: HashMap<u16, RelConnBack>,
+    opened_total: u64,
+    reset_total: u64,
 }
 
 impl ConnTable {
@@ -194,11 +305,34 @@ impl ConnTable {
     }
 
     fn set_stream(&mut self, id: u16, handle: RelConnBack) {
-        self.sid_to_stream.insert(id, handle);
+        if self.sid_to_stream.insert(id, handle).is_none() {
+            self.opened_total += 1;
+        }
+    }
+
+    fn del_stream(&mut self, id: u16, reset: bool) {
+        if self.sid_to_stream.remove(&id).is_some() && reset {
+            self.reset_total += 1;
+        }
+    }
+
+    fn stats(&self) -> MultiplexStats {
+        MultiplexStats {
+            open_streams: self.sid_to_stream.len(),
+            streams_opened_total: self.opened_total,
+            streams_reset_total: self.reset_total,
+            ..Default::default()
+        }
     }
 
-    fn del_stream(&mut self, id: u16) {
-        self.sid_to_stream.remove(&id);
+    fn dump(&self) -> Vec<StreamInfo> {
+        let mut dump: Vec<StreamInfo> = self
+            .sid_to_stream
+            .iter()
+            .map(|(sid, handle)| handle.info(*sid))
+            .collect();
+        dump.sort_unstable_by_key(|info| info.stream_id);
+        dump
     }
 
     fn find_id(&mut self) -> Option<u16> {
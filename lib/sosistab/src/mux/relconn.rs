@@ -1,10 +1,11 @@
 use crate::*;
 use async_dup::Arc as DArc;
 use async_dup::Mutex as DMutex;
-use bipe::{BipeReader, BipeWriter};
+use bipe::{BipeCapacity, BipeReader, BipeWriter};
 use bytes::{Bytes, BytesMut};
 use connvars::ConnVars;
 use mux::structs::{Message, RelKind, Seqno, VarRateLimit};
+use parking_lot::RwLock;
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
 use std::{
@@ -24,51 +25,180 @@ mod inflight;
 
 pub const MSS: usize = 1100;
 const MAX_WAIT_SECS: u64 = 60;
+/// Size of the buffer holding what has arrived but hasn't been read yet, unless the window says otherwise. Automatic windows start out with it and grow it along with the rate data arrives at.
+const DEFAULT_READ_BUFFER: usize = 512 * 1024;
+
+/// How much a stream may have in flight, sent but not yet acknowledged, at once. The window also sizes the buffer of data arrived but not yet read. Bulk transfers over long, fat paths want large windows, while interactive traffic does fine with small ones.
+///
+/// A stream holds up to 64 KiB of unsent writes, a read buffer (between 512 KiB and 8 MiB for [StreamWindow::Auto], the window itself for [StreamWindow::Fixed]) and up to a window's worth of unacknowledged segments, so servers with many open streams can bound their memory by choosing fixed windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamWindow {
+    /// Tuned to the path: twice its measured bandwidth-delay product, capped at 8 MiB. The read buffer is sized the same way, from the rate data arrives at.
+    Auto,
+    /// At most this many bytes.
+    Fixed(usize),
+}
+
+impl Default for StreamWindow {
+    fn default() -> Self {
+        StreamWindow::Auto
+    }
+}
+
+impl StreamWindow {
+    fn read_buffer(self) -> usize {
+        match self {
+            StreamWindow::Auto => DEFAULT_READ_BUFFER,
+            StreamWindow::Fixed(bytes) => bytes.max(MSS),
+        }
+    }
+}
 
-#[derive(Clone)]
 pub struct RelConn {
     send_write: DArc<DMutex<BipeWriter>>,
     recv_read: DArc<DMutex<BipeReader>>,
+    read_capacity: BipeCapacity,
     additional_info: Option<String>,
+    label: Arc<RwLock<Option<String>>>,
+    window: Arc<RwLock<StreamWindow>>,
+    send_reset: Sender<()>,
+    read_timeout: Option<Duration>,
+    read_timer: Option<smol::Timer>,
+    write_timeout: Option<Duration>,
+    write_timer: Option<smol::Timer>,
+}
+
+impl Clone for RelConn {
+    fn clone(&self) -> Self {
+        RelConn {
+            send_write: self.send_write.clone(),
+            recv_read: self.recv_read.clone(),
+            read_capacity: self.read_capacity.clone(),
+            additional_info: self.additional_info.clone(),
+            label: self.label.clone(),
+            window: self.window.clone(),
+            send_reset: self.send_reset.clone(),
+            read_timeout: self.read_timeout,
+            read_timer: None,
+            write_timeout: self.write_timeout,
+            write_timer: None,
+        }
+    }
+}
+
+/// Description of an open stream, as shown in a stream dump.
+#[derive(Debug, Clone)]
+pub struct StreamInfo {
+    pub stream_id: u16,
+    /// The additional info given when opening the stream.
+    pub additional_info: Option<String>,
+    /// The local label set through [RelConn::set_label].
+    pub label: Option<String>,
 }
 
 impl RelConn {
     pub(crate) fn new(
         state: RelConnState,
         output: Sender<Message>,
-        dropper: impl FnOnce() + Send + 'static,
+        dropper: impl FnOnce(bool) + Send + 'static,
         additional_info: Option<String>,
+        window: StreamWindow,
+        executor: Option<&runtime::ExecutorHandle>,
     ) -> (Self, RelConnBack) {
         let (send_write, recv_write) = bipe::bipe(64 * 1024);
-        let (send_read, recv_read) = bipe::bipe(512 * 1024);
+        let (send_read, recv_read) = bipe::bipe(window.read_buffer());
+        let read_capacity = recv_read.capacity_handle();
         let (send_wire_read, recv_wire_read) = smol::channel::bounded(16);
-        runtime::spawn(relconn_actor(
-            state,
-            recv_write,
-            send_read,
-            recv_wire_read,
-            output,
-            additional_info.clone(),
-            dropper,
-        ))
+        let (send_reset, recv_reset) = smol::channel::bounded(1);
+        let label = Arc::new(RwLock::new(None));
+        let shared_window = Arc::new(RwLock::new(window));
+        runtime::spawn_on(
+            executor,
+            relconn_actor(
+                state,
+                recv_write,
+                send_read,
+                read_capacity.clone(),
+                recv_wire_read,
+                recv_reset,
+                output,
+                additional_info.clone(),
+                label.clone(),
+                shared_window.clone(),
+                dropper,
+            ),
+        )
         .detach();
         (
             RelConn {
                 send_write: DArc::new(DMutex::new(send_write)),
                 recv_read: DArc::new(DMutex::new(recv_read)),
+                read_capacity,
+                additional_info: additional_info.clone(),
+                label: label.clone(),
+                window: shared_window,
+                send_reset: send_reset.clone(),
+                read_timeout: None,
+                read_timer: None,
+                write_timeout: None,
+                write_timer: None,
+            },
+            RelConnBack {
+                send_wire_read,
+                send_reset,
                 additional_info,
+                label,
             },
-            RelConnBack { send_wire_read },
         )
     }
 
+    /// Gets the additional info given to [Multiplex::open_conn](crate::mux::Multiplex::open_conn). On the accepting side, it arrives along with the SYN, so no extra round trip is needed. An empty string counts as no info.
     pub fn additional_info(&self) -> Option<&str> {
         self.additional_info.as_deref()
     }
 
+    /// Attaches a local, purely informational label to the stream. It shows up in stream dumps and logs, but is never sent to the other side.
+    pub fn set_label(&self, label: String) {
+        *self.label.write() = Some(label)
+    }
+
+    /// Gets the label set through [RelConn::set_label].
+    pub fn label(&self) -> Option<String> {
+        self.label.read().clone()
+    }
+
+    /// Changes this side's stream window: how much it keeps in flight from then on, and how much arrived data it buffers until read. Nothing is advertised to the other side, which keeps sending with its own window, so a smaller read buffer only means that arriving segments are taken in more slowly once it fills up.
+    pub fn set_local_window(&self, window: StreamWindow) {
+        *self.window.write() = window;
+        self.read_capacity.set(window.read_buffer());
+    }
+
+    /// Gets this side's current stream window.
+    pub fn local_window(&self) -> StreamWindow {
+        *self.window.read()
+    }
+
+    /// Makes reads fail with TimedOut once they have waited this long without any data arriving. Clones made afterwards inherit the timeout.
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = Some(timeout);
+        self.read_timer = None;
+    }
+
+    /// Makes writes and flushes fail with TimedOut once they have waited this long without making any progress. Clones made afterwards inherit the timeout.
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.write_timeout = Some(timeout);
+        self.write_timer = None;
+    }
+
+    /// Closes the write direction, just like [AsyncWriteExt::close](smol::io::AsyncWriteExt::close). The other side reads EOF once it has read everything written before, while this side can still read until the other side closes as well.
     pub async fn shutdown(&mut self) {
         drop(self.send_write.close().await)
     }
+
+    /// Aborts the stream, throwing away anything not yet delivered. The other side's reads and writes fail with ConnectionReset right away rather than timing out, and so do those of any clones of this RelConn.
+    pub fn reset(self) {
+        let _ = self.send_reset.try_send(());
+    }
 }
 
 impl AsyncRead for RelConn {
@@ -77,9 +207,9 @@ impl AsyncRead for RelConn {
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<std::io::Result<usize>> {
-        let recv_read = &mut self.recv_read;
-        smol::pin!(recv_read);
-        recv_read.poll_read(cx, buf)
+        let this = &mut *self;
+        let res = Pin::new(&mut this.recv_read).poll_read(cx, buf);
+        poll_with_timeout(this.read_timeout, &mut this.read_timer, cx, res)
     }
 }
 
@@ -89,9 +219,9 @@ impl AsyncWrite for RelConn {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<std::io::Result<usize>> {
-        let send_write = &mut self.send_write;
-        smol::pin!(send_write);
-        send_write.poll_write(cx, buf)
+        let this = &mut *self;
+        let res = Pin::new(&mut this.send_write).poll_write(cx, buf);
+        poll_with_timeout(this.write_timeout, &mut this.write_timer, cx, res)
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
@@ -101,12 +231,48 @@ impl AsyncWrite for RelConn {
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        let send_write = &mut self.send_write;
-        smol::pin!(send_write);
-        send_write.poll_flush(cx)
+        let this = &mut *self;
+        let res = Pin::new(&mut this.send_write).poll_flush(cx);
+        poll_with_timeout(this.write_timeout, &mut this.write_timer, cx, res)
     }
 }
 
+/// Passes through the result of polling an I/O operation, but fails with TimedOut once the operation has been pending for longer than the timeout. The timer restarts whenever the operation completes.
+fn poll_with_timeout<T>(
+    timeout: Option<Duration>,
+    timer: &mut Option<smol::Timer>,
+    cx: &mut Context<'_>,
+    res: Poll<std::io::Result<T>>,
+) -> Poll<std::io::Result<T>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return res,
+    };
+    if res.is_ready() {
+        *timer = None;
+        return res;
+    }
+    let pending_timer = timer.get_or_insert_with(|| smol::Timer::after(timeout));
+    if Pin::new(pending_timer).poll(cx).is_pending() {
+        return Poll::Pending;
+    }
+    *timer = None;
+    Poll::Ready(Err(std::io::Error::new(
+        std::io::ErrorKind::TimedOut,
+        "stream operation timed out",
+    )))
+}
+
+/// States of a stream.
+///
+/// - `SynSent`: we opened the stream and are waiting for a SYN-ACK, resending the SYN with exponential backoff. An RST instead means that the other side refused the stream, so the open fails with ConnectionRefused rather than handing out a stream that is already dead.
+/// - `SynReceived`: the other side opened the stream; we send a SYN-ACK and move on.
+/// - `SteadyState`: data flows both ways. Each direction is closed separately:
+///   - Closing our write half sends a FIN, which takes up a seqno just like data, so it is retransmitted until acked and delivered only after everything written before it.
+///   - Once the other side's FIN is delivered, our readers see EOF, but we can keep writing.
+///   - Once our FIN is acked and theirs is delivered (or nobody is reading anymore), the stream is torn down.
+///   - A FIN that isn't acknowledged within a minute, as happens with peers that predate FINs, tears the stream down.
+/// - `Reset`: the stream is dead. We send RSTs until the other side sends one back, or until a timeout. Reads only return EOF if the other side's FIN came in first; a stream that was aborted (through [RelConn::reset] on either side), timed out, or lost its multiplex makes them fail with ConnectionReset instead, so truncated transfers never pass for complete ones.
 pub(crate) enum RelConnState {
     SynReceived {
         stream_id: u16,
@@ -114,7 +280,7 @@ pub(crate) enum RelConnState {
     SynSent {
         stream_id: u16,
         tries: usize,
-        result: Sender<()>,
+        result: Sender<std::io::Error>,
     },
     SteadyState {
         stream_id: u16,
@@ -131,12 +297,22 @@ async fn relconn_actor(
     mut state: RelConnState,
     mut recv_write: BipeReader,
     mut send_read: BipeWriter,
+    read_capacity: BipeCapacity,
     recv_wire_read: Receiver<Message>,
+    recv_reset: Receiver<()>,
     send_wire_write: Sender<Message>,
     additional_info: Option<String>,
-    dropper: impl FnOnce(),
+    label: Arc<RwLock<Option<String>>>,
+    window: Arc<RwLock<StreamWindow>>,
+    dropper: impl FnOnce(bool),
 ) -> anyhow::Result<()> {
-    let _guard = scopeguard::guard((), |_| dropper());
+    // tells the dropper whether the stream ended in a reset rather than a close
+    let mut aborted = scopeguard::guard(false, |aborted| dropper(aborted));
+    // how the stream is named in logs
+    let tag = |stream_id: u16| match label.read().as_ref() {
+        Some(label) => format!("{} ({})", stream_id, label),
+        None => stream_id.to_string(),
+    };
     // match on our current state repeatedly
     #[derive(Debug, Clone)]
     enum Evt {
@@ -145,6 +321,8 @@ async fn relconn_actor(
         NewWrite(Bytes),
         NewPkt(Message),
         Closing,
+        FinTimeout,
+        Abort,
     }
 
     let transmit = |msg| async {
@@ -157,7 +335,7 @@ async fn relconn_actor(
     loop {
         state = match state {
             SynReceived { stream_id } => {
-                log::trace!("C={} SynReceived, sending SYN-ACK", stream_id);
+                log::trace!("C={} SynReceived, sending SYN-ACK", tag(stream_id));
                 // send a synack
                 transmit(Message::Rel {
                     kind: RelKind::SynAck,
@@ -168,7 +346,7 @@ async fn relconn_actor(
                 .await;
                 SteadyState {
                     stream_id,
-                    conn_vars: Box::new(ConnVars::default()),
+                    conn_vars: Box::new(ConnVars::with_window(*window.read())),
                 }
             }
             SynSent {
@@ -177,13 +355,29 @@ async fn relconn_actor(
                 result,
             } => {
                 let wait_interval = 2u64.saturating_pow(tries as u32);
-                log::trace!("C={} SynSent, tried {} times", stream_id, tries);
+                log::trace!("C={} SynSent, tried {} times", tag(stream_id), tries);
                 if wait_interval > MAX_WAIT_SECS {
+                    let _ = result.try_send(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "stream open timed out",
+                    ));
                     anyhow::bail!("timeout in SynSent");
                 }
                 let synack_evt = async {
                     loop {
                         match recv_wire_read.recv().await? {
+                            Message::Rel {
+                                kind: RelKind::Rst, ..
+                            } => {
+                                // the other side turned us away
+                                let _ = result.try_send(std::io::Error::new(
+                                    std::io::ErrorKind::ConnectionRefused,
+                                    "stream refused by the other side",
+                                ));
+                                send_read.reset();
+                                *aborted = true;
+                                anyhow::bail!("refused in SynSent")
+                            }
                             Message::Rel { .. } => return Ok::<_, anyhow::Error>(true),
                             _ => continue,
                         }
@@ -196,13 +390,13 @@ async fn relconn_actor(
                     })
                     .await?;
                 if success {
-                    log::trace!("C={} SynSent got SYN-ACK", stream_id);
+                    log::trace!("C={} SynSent got SYN-ACK", tag(stream_id));
                     SteadyState {
                         stream_id,
-                        conn_vars: Box::new(ConnVars::default()),
+                        conn_vars: Box::new(ConnVars::with_window(*window.read())),
                     }
                 } else {
-                    log::trace!("C={} SynSent timed out", stream_id);
+                    log::trace!("C={} SynSent timed out", tag(stream_id));
                     transmit(Message::Rel {
                         kind: RelKind::Syn,
                         stream_id,
@@ -226,8 +420,9 @@ async fn relconn_actor(
                 stream_id,
                 mut conn_vars,
             } => {
+                conn_vars.set_window(*window.read());
                 let event = {
-                    let writeable = conn_vars.inflight.inflight() <= conn_vars.cwnd as usize
+                    let writeable = conn_vars.inflight.inflight() <= conn_vars.send_window()
                         && conn_vars.inflight.len() < 10000
                         && !conn_vars.closing;
                     let force_ack = conn_vars.ack_seqnos.len() >= 32;
@@ -252,12 +447,9 @@ async fn relconn_actor(
                                 let to_write = {
                                     let mut bts = BytesMut::with_capacity(MSS);
                                     bts.extend_from_slice(&[0; MSS]);
-                                    let n = recv_write.read(&mut bts).await;
-                                    if let Ok(n) = n {
-                                        let bts = bts.freeze();
-                                        Some(bts.slice(0..n))
-                                    } else {
-                                        None
+                                    match recv_write.read(&mut bts).await {
+                                        Ok(n) if n > 0 => Some(bts.freeze().slice(0..n)),
+                                        _ => None,
                                     }
                                 };
                                 if let Some(to_write) = to_write {
@@ -280,21 +472,68 @@ async fn relconn_actor(
                     let new_pkt = async {
                         Ok::<Evt, anyhow::Error>(Evt::NewPkt(recv_wire_read.recv().await?))
                     };
-                    ack_timer.or(rto_timeout.or(new_write.or(new_pkt))).await
+                    let fin_deadline = conn_vars
+                        .fin_deadline
+                        .filter(|_| conn_vars.inflight.len() > 0);
+                    let fin_timeout = async {
+                        match fin_deadline {
+                            Some(deadline) => {
+                                smol::Timer::at(deadline).await;
+                                Ok::<Evt, anyhow::Error>(Evt::FinTimeout)
+                            }
+                            None => smol::future::pending().await,
+                        }
+                    };
+                    let abort = async {
+                        if recv_reset.recv().await.is_ok() {
+                            Ok::<Evt, anyhow::Error>(Evt::Abort)
+                        } else {
+                            // every handle is gone, which is not a reset
+                            smol::future::pending().await
+                        }
+                    };
+                    abort
+                        .or(fin_timeout)
+                        .or(ack_timer.or(rto_timeout.or(new_write.or(new_pkt))))
+                        .await
                 };
                 match event {
+                    Ok(Evt::Abort) => {
+                        log::trace!("C={} aborted locally", tag(stream_id));
+                        recv_write.close();
+                        send_read.reset();
+                        *aborted = true;
+                        Reset {
+                            stream_id,
+                            death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
+                        }
+                    }
                     Ok(Evt::Closing) => {
+                        // our write direction is done; the FIN goes out reliably, after everything we wrote
+                        log::trace!("C={} write direction closed, sending FIN", tag(stream_id));
                         conn_vars.closing = true;
-                        if conn_vars.inflight.len() > 0 {
-                            SteadyState {
-                                stream_id,
-                                conn_vars,
-                            }
-                        } else {
-                            Reset {
-                                stream_id,
-                                death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
-                            }
+                        conn_vars.fin_deadline =
+                            Some(Instant::now() + Duration::from_secs(MAX_WAIT_SECS));
+                        let seqno = conn_vars.next_free_seqno;
+                        conn_vars.next_free_seqno += 1;
+                        let msg = Message::Rel {
+                            kind: RelKind::Fin,
+                            stream_id,
+                            seqno,
+                            payload: Bytes::new(),
+                        };
+                        conn_vars.inflight.insert(seqno, msg.clone());
+                        transmit(msg).await;
+                        SteadyState {
+                            stream_id,
+                            conn_vars,
+                        }
+                    }
+                    Ok(Evt::FinTimeout) => {
+                        log::debug!("C={} FIN never acknowledged, giving up", tag(stream_id));
+                        Reset {
+                            stream_id,
+                            death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
                         }
                     }
                     Ok(Evt::Rto(Some((seqno, _is_timeout)))) => {
@@ -336,10 +575,16 @@ async fn relconn_actor(
                         kind: RelKind::Rst,
                         stream_id,
                         ..
-                    })) => Reset {
-                        stream_id,
-                        death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
-                    },
+                    })) => {
+                        log::trace!("C={} reset by the other side", tag(stream_id));
+                        recv_write.close();
+                        send_read.reset();
+                        *aborted = true;
+                        Reset {
+                            stream_id,
+                            death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
+                        }
+                    }
                     Ok(Evt::NewPkt(Message::Rel {
                         kind: RelKind::DataAck,
                         payload,
@@ -356,7 +601,10 @@ async fn relconn_actor(
                         }
                         conn_vars.inflight.mark_acked_lt(seqno);
                         implied_rate.store(conn_vars.pacing_rate() as u32, Ordering::Relaxed);
-                        if conn_vars.inflight.len() == 0 && conn_vars.closing {
+                        if conn_vars.inflight.len() == 0
+                            && conn_vars.closing
+                            && (conn_vars.read_finished() || send_read.is_closed())
+                        {
                             Reset {
                                 stream_id,
                                 death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
@@ -369,12 +617,15 @@ async fn relconn_actor(
                         }
                     }
                     Ok(Evt::NewPkt(Message::Rel {
-                        kind: RelKind::Data,
+                        kind,
                         seqno,
                         payload,
                         stream_id,
-                    })) => {
-                        log::trace!("new data pkt with seqno={}", seqno);
+                    })) if kind == RelKind::Data || kind == RelKind::Fin => {
+                        log::trace!("new {:?} pkt with seqno={}", kind, seqno);
+                        if kind == RelKind::Fin {
+                            conn_vars.fin_seqno = Some(seqno);
+                        }
                         // if conn_vars.delayed_ack_timer.is_none() {
                         conn_vars.delayed_ack_timer =
                             Instant::now().checked_add(Duration::from_millis(5));
@@ -386,9 +637,20 @@ async fn relconn_actor(
                         conn_vars.lowest_unseen += times.len() as u64;
                         let mut success = true;
                         for pkt in times {
-                            success |= send_read.write(&pkt).await.is_ok();
+                            success &= send_read.write(&pkt).await.is_ok();
+                            conn_vars.record_delivery(pkt.len());
+                        }
+                        if *window.read() == StreamWindow::Auto {
+                            read_capacity.set(conn_vars.read_buffer());
+                        }
+                        if conn_vars.read_finished() {
+                            // the other side's write direction is done, so readers see EOF once they've drained the pipe
+                            drop(send_read.close().await);
                         }
-                        if success {
+                        let both_finished = conn_vars.read_finished()
+                            && conn_vars.closing
+                            && conn_vars.inflight.len() == 0;
+                        if success && !both_finished {
                             SteadyState {
                                 stream_id,
                                 conn_vars,
@@ -442,7 +704,7 @@ async fn relconn_actor(
                         }
                     }
                     err => {
-                        log::trace!("forced to RESET due to {:?}", err);
+                        log::trace!("C={} forced to RESET due to {:?}", tag(stream_id), err);
                         Reset {
                             stream_id,
                             death: smol::Timer::after(Duration::from_secs(MAX_WAIT_SECS)),
@@ -454,8 +716,11 @@ async fn relconn_actor(
                 stream_id,
                 mut death,
             } => {
-                drop(send_read.close().await);
-                log::trace!("C={} RESET", stream_id);
+                // unless the other side's FIN already finished it, the read direction ends in an error rather than EOF
+                send_read.abort();
+                // writes fail from now on
+                recv_write.close();
+                log::trace!("C={} RESET", tag(stream_id));
                 transmit(Message::Rel {
                     kind: RelKind::Rst,
                     stream_id,
@@ -488,10 +753,26 @@ async fn relconn_actor(
 
 pub(crate) struct RelConnBack {
     send_wire_read: Sender<Message>,
+    send_reset: Sender<()>,
+    additional_info: Option<String>,
+    label: Arc<RwLock<Option<String>>>,
 }
 
 impl RelConnBack {
     pub async fn process(&self, input: Message) {
         drop(self.send_wire_read.send(input).await)
     }
+
+    /// Aborts the connection, just like [RelConn::reset].
+    pub fn reset(&self) {
+        let _ = self.send_reset.try_send(());
+    }
+
+    pub fn info(&self, stream_id: u16) -> StreamInfo {
+        StreamInfo {
+            stream_id,
+            additional_info: self.additional_info.clone(),
+            label: self.label.read().clone(),
+        }
+    }
 }
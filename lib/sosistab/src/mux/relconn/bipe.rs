@@ -6,12 +6,14 @@ use std::{pin::Pin, sync::Arc, task::Context, task::Poll};
 
 /// Create a "bipe". Use async_dup's methods if you want something cloneable/shareable
 pub fn bipe(capacity: usize) -> (BipeWriter, BipeReader) {
-    let info = Arc::new(Mutex::new((false, BytesMut::new())));
+    let info = Arc::new(Mutex::new(PipeState {
+        capacity,
+        ..Default::default()
+    }));
     let event = Arc::new(event_listener::Event::new());
     (
         BipeWriter {
             queue: info.clone(),
-            capacity,
             signal: event.clone(),
             listener: event.listen(),
         },
@@ -23,17 +25,26 @@ pub fn bipe(capacity: usize) -> (BipeWriter, BipeReader) {
     )
 }
 
+#[derive(Default)]
+struct PipeState {
+    closed: bool,
+    /// Whether the writer closed the pipe on purpose, so that reads end with EOF rather than an error.
+    finished: bool,
+    reset: bool,
+    capacity: usize,
+    buffer: BytesMut,
+}
+
 /// Writing end of a byte pipe.
 pub struct BipeWriter {
-    queue: Arc<Mutex<(bool, BytesMut)>>,
-    capacity: usize,
+    queue: Arc<Mutex<PipeState>>,
     signal: Arc<event_listener::Event>,
     listener: event_listener::EventListener,
 }
 
 impl Drop for BipeWriter {
     fn drop(&mut self) {
-        self.queue.lock().0 = true;
+        self.queue.lock().closed = true;
         self.signal.notify(usize::MAX);
     }
 }
@@ -53,11 +64,12 @@ impl AsyncWrite for BipeWriter {
             {
                 let boo = &self.queue;
                 let mut boo = boo.lock();
-                if boo.0 {
+                if boo.closed {
                     return Poll::Ready(Err(broken_pipe()));
                 }
-                let queue = &mut boo.1;
-                if queue.len() < self.capacity + buf.len() {
+                let capacity = boo.capacity;
+                let queue = &mut boo.buffer;
+                if queue.len() < capacity + buf.len() {
                     if queue.is_empty() {
                         self.signal.notify(usize::MAX);
                     }
@@ -78,19 +90,87 @@ impl AsyncWrite for BipeWriter {
     }
 
     fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
-        self.queue.lock().0 = true;
+        let mut state = self.queue.lock();
+        if !state.closed {
+            state.closed = true;
+            state.finished = true;
+        }
+        drop(state);
         self.signal.notify(usize::MAX);
         Poll::Ready(Ok(()))
     }
 }
 
-/// Read end of a byte pipe.
+impl BipeWriter {
+    /// Whether the pipe was closed, either from this end or by dropping the reader.
+    pub fn is_closed(&self) -> bool {
+        self.queue.lock().closed
+    }
+
+    /// Closes the pipe without finishing it, as dropping the writer does: readers still get what's buffered, but then fail with ConnectionReset instead of reading EOF. Does nothing once the pipe is closed.
+    pub fn abort(&mut self) {
+        self.queue.lock().closed = true;
+        self.signal.notify(usize::MAX);
+    }
+
+    /// Closes the pipe abruptly: whatever is still buffered is thrown away, and reads fail with ConnectionReset instead of returning EOF.
+    pub fn reset(&mut self) {
+        let mut state = self.queue.lock();
+        state.closed = true;
+        state.reset = true;
+        state.buffer = BytesMut::new();
+        drop(state);
+        self.signal.notify(usize::MAX);
+    }
+}
+
+/// Read end of a byte pipe. Reads return 0 bytes once the writer is closed and everything it wrote has been read. If the writer was dropped or aborted rather than closed, they fail with ConnectionReset at that point instead, and right away once the writer resets the pipe.
 pub struct BipeReader {
-    queue: Arc<Mutex<(bool, BytesMut)>>,
+    queue: Arc<Mutex<PipeState>>,
     signal: Arc<event_listener::Event>,
     listener: event_listener::EventListener,
 }
 
+impl Drop for BipeReader {
+    fn drop(&mut self) {
+        self.close()
+    }
+}
+
+impl BipeReader {
+    /// Gets a handle for resizing the pipe, which stays usable once the reader is shared or moved away.
+    pub fn capacity_handle(&self) -> BipeCapacity {
+        BipeCapacity {
+            queue: self.queue.clone(),
+            signal: self.signal.clone(),
+        }
+    }
+
+    /// Closes the pipe from the reading end, so that further writes fail.
+    pub fn close(&mut self) {
+        self.queue.lock().closed = true;
+        self.signal.notify(usize::MAX);
+    }
+}
+
+/// Handle for changing how much a pipe buffers, obtained through [BipeReader::capacity_handle].
+#[derive(Clone)]
+pub struct BipeCapacity {
+    queue: Arc<Mutex<PipeState>>,
+    signal: Arc<event_listener::Event>,
+}
+
+impl BipeCapacity {
+    /// Changes the capacity. Shrinking never throws away what is already buffered; writes just wait until the buffer drains below the new capacity.
+    pub fn set(&self, capacity: usize) {
+        let mut state = self.queue.lock();
+        if state.capacity != capacity {
+            state.capacity = capacity;
+            self.signal.notify(usize::MAX);
+        }
+    }
+}
+
 impl AsyncRead for BipeReader {
     fn poll_read(
         mut self: Pin<&mut Self>,
@@ -101,7 +181,13 @@ impl AsyncRead for BipeReader {
             {
                 let boo = &self.queue;
                 let mut boo = boo.lock();
-                let queue = &mut boo.1;
+                if boo.reset {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "stream reset",
+                    )));
+                }
+                let queue = &mut boo.buffer;
                 if !queue.is_empty() {
                     let to_copy_len = queue.len().min(buf.len());
                     (&mut buf[..to_copy_len]).copy_from_slice(&queue[..to_copy_len]);
@@ -112,8 +198,14 @@ impl AsyncRead for BipeReader {
                     self.signal.notify(usize::MAX);
                     return Poll::Ready(Ok(to_copy_len));
                 }
-                if boo.0 {
-                    return Poll::Ready(Err(broken_pipe()));
+                if boo.finished {
+                    return Poll::Ready(Ok(0));
+                }
+                if boo.closed {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "stream died without being closed",
+                    )));
                 }
             }
             let listen_new_data = &mut self.listener;
@@ -123,3 +215,33 @@ impl AsyncRead for BipeReader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_closing_gives_eof() {
+        smol::block_on(async {
+            let (mut writer, mut reader) = bipe(1024);
+            writer.write_all(b"hello").await.unwrap();
+            writer.close().await.unwrap();
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"hello");
+            // a writer that just goes away truncated the stream, which must not look like EOF
+            let (mut writer, mut reader) = bipe(1024);
+            writer.write_all(b"hello").await.unwrap();
+            drop(writer);
+            let mut buf = [0u8; 5];
+            reader.read_exact(&mut buf).await.unwrap();
+            let err = reader.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+            // and so does aborting, even after the fact
+            let (mut writer, mut reader) = bipe(1024);
+            writer.abort();
+            writer.close().await.unwrap();
+            assert!(reader.read(&mut buf).await.is_err());
+        })
+    }
+}
@@ -1,10 +1,34 @@
-use std::{collections::BTreeSet, collections::VecDeque, time::Instant};
+use std::{
+    collections::BTreeSet,
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 
 use crate::mux::{mempress, structs::*};
 
-use super::inflight::Inflight;
+use super::{inflight::Inflight, StreamWindow, DEFAULT_READ_BUFFER, MSS};
+
+/// Fewest segments an automatic window allows in flight, so that streams can ramp up before the path has been measured.
+const MIN_AUTO_WINDOW: usize = 64;
+
+/// Most bytes an automatic window allows in flight, and most that an automatic read buffer holds.
+const MAX_AUTO_WINDOW_BYTES: usize = 8 * 1024 * 1024;
+
+/// The automatic window, in segments, for a path with the given bandwidth-delay product, also in segments. It's twice the BDP, so that the delivery rate the BDP is measured from still has room to grow.
+fn auto_window(bdp: f64) -> usize {
+    ((bdp * 2.0) as usize)
+        .max(MIN_AUTO_WINDOW)
+        .min(MAX_AUTO_WINDOW_BYTES / MSS)
+}
+
+/// The automatic read buffer, in bytes, for data arriving at the given rate in bytes per second over a path with the given round-trip time. Just like the automatic window, it's twice the BDP, but never smaller than the default.
+fn auto_read_buffer(rate: f64, rtt: Duration) -> usize {
+    ((rate * rtt.as_secs_f64() * 2.0) as usize)
+        .max(DEFAULT_READ_BUFFER)
+        .min(MAX_AUTO_WINDOW_BYTES)
+}
 
 pub(crate) struct ConnVars {
     pub pre_inflight: VecDeque<Message>,
@@ -22,13 +46,23 @@ pub(crate) struct ConnVars {
     ssthresh: f64,
     pub cwnd: f64,
     last_loss: Instant,
+    window: StreamWindow,
 
     flights: u64,
     last_flight: Instant,
 
     loss_rate: f64,
 
+    recv_start: Instant,
+    recv_bytes: usize,
+    /// Rate that data is delivered to the reader at, in bytes per second.
+    recv_rate: f64,
+
     pub closing: bool,
+    /// When we give up on the other side acknowledging our FIN. Peers that predate FINs ignore them, so half-closed streams to them would otherwise linger.
+    pub fin_deadline: Option<Instant>,
+    /// Seqno of the FIN closing the other side's write direction, once it has arrived.
+    pub fin_seqno: Option<Seqno>,
 }
 
 impl Default for ConnVars {
@@ -49,18 +83,73 @@ impl Default for ConnVars {
             cwnd: 64.0,
             ssthresh: 10000.0,
             last_loss: Instant::now(),
+            window: StreamWindow::Auto,
 
             flights: 0,
             last_flight: Instant::now(),
 
             loss_rate: 0.0,
 
+            recv_start: Instant::now(),
+            recv_bytes: 0,
+            recv_rate: 0.0,
+
             closing: false,
+            fin_deadline: None,
+            fin_seqno: None,
         }
     }
 }
 
 impl ConnVars {
+    pub fn with_window(window: StreamWindow) -> Self {
+        ConnVars {
+            window,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_window(&mut self, window: StreamWindow) {
+        self.window = window
+    }
+
+    /// How many segments may be in flight, as allowed by both the congestion window and the stream window. An automatic window follows the measured bandwidth-delay product of the path.
+    pub fn send_window(&self) -> usize {
+        let window = match self.window {
+            StreamWindow::Auto => auto_window(self.inflight.bdp()),
+            StreamWindow::Fixed(bytes) => (bytes / MSS).max(1),
+        };
+        self.cwnd.min(window as f64) as usize
+    }
+
+    /// How many bytes that arrived may wait to be read. An automatic window follows the rate that data is delivered at.
+    pub fn read_buffer(&self) -> usize {
+        match self.window {
+            StreamWindow::Auto => auto_read_buffer(self.recv_rate, self.inflight.srtt()),
+            window => window.read_buffer(),
+        }
+    }
+
+    /// Counts data delivered to the reader, measuring the rate it arrives at once every round trip.
+    pub fn record_delivery(&mut self, bytes: usize) {
+        self.recv_bytes += bytes;
+        let elapsed = self.recv_start.elapsed();
+        if elapsed >= self.inflight.srtt().max(Duration::from_millis(10)) {
+            let sample = self.recv_bytes as f64 / elapsed.as_secs_f64();
+            // the highest recent sample counts, so that pauses in the transfer don't shrink the buffer right away
+            self.recv_rate = sample.max(self.recv_rate * 0.9);
+            self.recv_bytes = 0;
+            self.recv_start = Instant::now();
+        }
+    }
+
+    /// Whether everything the other side wrote, up to and including its FIN, has been delivered.
+    pub fn read_finished(&self) -> bool {
+        self.fin_seqno
+            .map(|fin| self.lowest_unseen > fin)
+            .unwrap_or(false)
+    }
+
     pub fn pacing_rate(&self) -> f64 {
         // calculate implicit rate
         self.cwnd / self.inflight.min_rtt().as_secs_f64()
@@ -103,3 +192,45 @@ impl ConnVars {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_window_follows_bdp() {
+        // nothing measured yet
+        assert_eq!(auto_window(0.0), MIN_AUTO_WINDOW);
+        assert_eq!(auto_window(100.0), 200);
+        assert_eq!(auto_window(1e9), MAX_AUTO_WINDOW_BYTES / MSS);
+        // 1 MB/s over a 100ms round trip
+        assert_eq!(
+            auto_read_buffer(1e6, Duration::from_millis(100)),
+            DEFAULT_READ_BUFFER
+        );
+        // 4 MB/s over a 250ms round trip
+        assert_eq!(auto_read_buffer(4e6, Duration::from_millis(250)), 2_000_000);
+        assert_eq!(
+            auto_read_buffer(1e9, Duration::from_millis(100)),
+            MAX_AUTO_WINDOW_BYTES
+        );
+    }
+
+    #[test]
+    fn stream_window_caps_send_window() {
+        let mut vars = ConnVars::with_window(StreamWindow::Fixed(4 * MSS));
+        vars.cwnd = 1000.0;
+        assert_eq!(vars.send_window(), 4);
+        assert_eq!(vars.read_buffer(), 4 * MSS);
+        vars.set_window(StreamWindow::Fixed(256 * MSS));
+        assert_eq!(vars.send_window(), 256);
+        assert_eq!(vars.read_buffer(), 256 * MSS);
+        // the congestion window still has the last word
+        vars.cwnd = 10.0;
+        assert_eq!(vars.send_window(), 10);
+        vars.cwnd = 1000.0;
+        vars.set_window(StreamWindow::Auto);
+        assert_eq!(vars.send_window(), auto_window(vars.inflight.bdp()));
+        assert_eq!(vars.read_buffer(), DEFAULT_READ_BUFFER);
+    }
+}
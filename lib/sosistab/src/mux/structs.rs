@@ -17,6 +17,15 @@ pub enum Message {
         seqno: Seqno,
         payload: Bytes,
     },
+    /// Heartbeat, answered by a Pong.
+    Ping,
+    /// Answer to a Ping.
+    Pong,
+    /// An unreliable message belonging to a flow, so that the other side can tell apart messages meant for different things.
+    UrelFlow {
+        flow: u64,
+        body: Bytes,
+    },
 }
 
 impl Message {
@@ -24,7 +33,9 @@ impl Message {
     pub fn clear_payload(&mut self) {
         match self {
             Message::Urel(b) => *b = Bytes::new(),
+            Message::UrelFlow { body, .. } => *body = Bytes::new(),
             Message::Rel { payload, .. } => *payload = Bytes::new(),
+            Message::Ping | Message::Pong => {}
         }
     }
 }
@@ -1,12 +1,25 @@
 use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use rand::Rng;
 use smol::net::AsyncToSocketAddrs;
 use smol::prelude::*;
 use smol::Executor;
 use socket2::{Domain, Socket, Type};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::{convert::TryInto, net::SocketAddr};
 
 static USER_EXEC: OnceCell<&'static Executor> = OnceCell::new();
 
+/// Receive and send buffer sizes of UDP sockets, if not left to the OS.
+static UDP_BUFFERS: RwLock<Option<(usize, usize)>> = parking_lot::const_rwlock(None);
+
+static SOCKET_FACTORY: RwLock<Option<SocketFactory>> = parking_lot::const_rwlock(None);
+
+static REUSE_ADDR: AtomicBool = AtomicBool::new(false);
+static REUSE_PORT: AtomicBool = AtomicBool::new(false);
+
 /// Sets the sosistab executor. If not set, smolscale will be used.
 pub fn set_smol_executor(exec: &'static Executor<'static>) {
     USER_EXEC.set(exec).expect("already initialized")
@@ -23,25 +36,442 @@ pub(crate) fn spawn<T: Send + 'static>(
     }
 }
 
-/// Create a new UDP socket that has a largeish buffer and isn't bound to anything.
-pub(crate) async fn new_udp_socket_bind(
-    addr: impl AsyncToSocketAddrs,
-) -> std::io::Result<smol::net::UdpSocket> {
-    let addr = smol::net::resolve(addr).await?[0];
+/// An executor that a particular session or multiplex runs its tasks on, in place of the one set through [set_smol_executor]. This lets independent sessions in the same process run on executors of their own. Whoever owns the executor must keep running it.
+#[derive(Clone)]
+pub struct ExecutorHandle(Arc<Executor<'static>>);
+
+impl ExecutorHandle {
+    /// Wraps an executor.
+    pub fn new(exec: Arc<Executor<'static>>) -> Self {
+        ExecutorHandle(exec)
+    }
+}
+
+impl std::fmt::Debug for ExecutorHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ExecutorHandle")
+    }
+}
+
+/// Spawns a future onto the given executor, or onto the sosistab worker if there is none.
+pub(crate) fn spawn_on<T: Send + 'static>(
+    exec: Option<&ExecutorHandle>,
+    future: impl Future<Output = T> + Send + 'static,
+) -> smol::Task<T> {
+    match exec {
+        Some(exec) => exec.0.spawn(future),
+        None => spawn(future),
+    }
+}
+
+/// Creates a UDP socket bound to the given address.
+pub type SocketFactory =
+    Box<dyn Fn(SocketAddr) -> std::io::Result<std::net::UdpSocket> + Send + Sync>;
+
+/// Sets the function that creates every UDP socket sosistab uses from now on, in place of binding one itself. This lets embedders hand over sockets they've set up themselves, for example ones exempted from the VPN route with `VpnService.protect` on Android. Address-reuse options don't apply to these sockets, while buffer sizes and [SocketBinding]s still do. Calling this again replaces the earlier factory.
+pub fn set_socket_factory(factory: SocketFactory) {
+    *SOCKET_FACTORY.write() = Some(factory)
+}
+
+/// Sets the receive and send buffer sizes, in bytes, of every UDP socket sosistab creates from now on. Bigger receive buffers keep the kernel from dropping packets when bursts arrive over links with a high bandwidth-delay product. Calling this again replaces the earlier sizes.
+pub fn set_udp_buffer_sizes(recv: usize, send: usize) {
+    *UDP_BUFFERS.write() = Some((recv, send))
+}
+
+/// Applies the UDP buffer sizes, if any were set. The OS may give us less than we ask for, in which case we warn and carry on.
+fn apply_udp_buffers(socket: &Socket, (recv, send): (usize, usize)) -> std::io::Result<()> {
+    socket.set_recv_buffer_size(recv)?;
+    socket.set_send_buffer_size(send)?;
+    let (actual_recv, actual_send) = (socket.recv_buffer_size()?, socket.send_buffer_size()?);
+    if actual_recv < recv {
+        log::warn!(
+            "asked for a {}-byte UDP receive buffer but got {}; raise the OS limit (net.core.rmem_max on Linux) for the full size",
+            recv,
+            actual_recv
+        );
+    }
+    if actual_send < send {
+        log::warn!(
+            "asked for a {}-byte UDP send buffer but got {}; raise the OS limit (net.core.wmem_max on Linux) for the full size",
+            send,
+            actual_send
+        );
+    }
+    Ok(())
+}
+
+/// Address-reuse options applied to sockets before they are bound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketReuse {
+    /// Set SO_REUSEADDR, so that restarts can rebind an address still held by a lingering socket.
+    pub reuse_addr: bool,
+    /// Set SO_REUSEPORT, so that several processes can share one address. Ignored on platforms without it.
+    pub reuse_port: bool,
+}
+
+impl SocketReuse {
+    /// Gets the process-wide options set by [set_socket_reuse].
+    pub fn global() -> Self {
+        SocketReuse {
+            reuse_addr: REUSE_ADDR.load(Ordering::Relaxed),
+            reuse_port: REUSE_PORT.load(Ordering::Relaxed),
+        }
+    }
+
+    fn apply(self, socket: &Socket) -> std::io::Result<()> {
+        if self.reuse_addr {
+            socket.set_reuse_address(true)?;
+        }
+        if self.reuse_port {
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+            log::warn!("SO_REUSEPORT is not supported on this platform, ignoring");
+        }
+        Ok(())
+    }
+}
+
+/// Settings for the UDP sockets of a session. A network interface or routing mark lets a client running inside a VPN-style routing setup keep its own traffic out of the tunnel; both only work on Linux, and usually need CAP_NET_ADMIN. A DSCP class lets QoS along the path prioritize the traffic.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketBinding {
+    /// Set SO_MARK to this firewall mark, so that policy routing can match the traffic.
+    pub mark: Option<u32>,
+    /// Set SO_BINDTODEVICE to this interface, such as "eth0", so that traffic leaves through it whatever the routing table says.
+    pub device: Option<String>,
+    /// Mark outgoing packets with this DSCP class, from 0 to 63, through IP_TOS or IPV6_TCLASS. 46 (expedited forwarding) suits interactive traffic.
+    pub dscp: Option<u8>,
+}
+
+impl SocketBinding {
+    fn apply(&self, socket: &Socket, addr: SocketAddr) -> std::io::Result<()> {
+        if self.mark.is_some() || self.device.is_some() {
+            self.apply_linux(socket)?;
+        }
+        if let Some(dscp) = self.dscp {
+            set_dscp(socket, addr, dscp)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_linux(&self, socket: &Socket) -> std::io::Result<()> {
+        if let Some(mark) = self.mark {
+            setsockopt(socket, libc::SOL_SOCKET, libc::SO_MARK, &mark.to_ne_bytes())?;
+        }
+        if let Some(device) = &self.device {
+            setsockopt(
+                socket,
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                device.as_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply_linux(&self, _socket: &Socket) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            "binding sockets to a mark or device is only supported on Linux",
+        ))
+    }
+}
+
+#[cfg(unix)]
+fn set_dscp(socket: &Socket, addr: SocketAddr, dscp: u8) -> std::io::Result<()> {
+    if dscp > 63 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "DSCP classes go from 0 to 63",
+        ));
+    }
+    // the DSCP takes up the upper six bits of the old ToS byte
+    let tos = (libc::c_int::from(dscp) << 2).to_ne_bytes();
+    match addr {
+        SocketAddr::V4(_) => setsockopt(socket, libc::IPPROTO_IP, libc::IP_TOS, &tos),
+        SocketAddr::V6(_) => {
+            setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, &tos)?;
+            // dual-stack sockets send to IPv4 servers with the IPv4 option, which not every OS allows setting
+            drop(setsockopt(socket, libc::IPPROTO_IP, libc::IP_TOS, &tos));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn set_dscp(_socket: &Socket, _addr: SocketAddr, _dscp: u8) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "setting the DSCP class is only supported on Unix",
+    ))
+}
+
+#[cfg(unix)]
+fn setsockopt(
+    socket: &Socket,
+    level: libc::c_int,
+    option: libc::c_int,
+    value: &[u8],
+) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            value.as_ptr() as *const libc::c_void,
+            value.len() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Sets the address-reuse options used for every socket sosistab binds from now on.
+pub fn set_socket_reuse(reuse: SocketReuse) {
+    REUSE_ADDR.store(reuse.reuse_addr, Ordering::Relaxed);
+    REUSE_PORT.store(reuse.reuse_port, Ordering::Relaxed);
+}
+
+fn new_socket(addr: SocketAddr, kind: Type, reuse: SocketReuse) -> std::io::Result<Socket> {
     let socket = Socket::new(
         match addr {
             SocketAddr::V4(_) => Domain::ipv4(),
             SocketAddr::V6(_) => Domain::ipv6(),
         },
-        Type::dgram(),
+        kind,
         None,
-    )
-    .unwrap();
+    )?;
     drop(socket.set_only_v6(false));
+    reuse.apply(&socket)?;
     socket.bind(&addr.into())?;
-    Ok(socket.into_udp_socket().try_into().unwrap())
+    Ok(socket)
+}
+
+/// Create a new UDP socket that has a largeish buffer and isn't bound to anything.
+pub(crate) async fn new_udp_socket_bind(
+    addr: impl AsyncToSocketAddrs,
+) -> std::io::Result<smol::net::UdpSocket> {
+    new_udp_socket_bind_with(addr, SocketReuse::global()).await
+}
+
+async fn new_udp_socket_bind_with(
+    addr: impl AsyncToSocketAddrs,
+    reuse: SocketReuse,
+) -> std::io::Result<smol::net::UdpSocket> {
+    let addr = smol::net::resolve(addr).await?[0];
+    new_udp_socket_with(addr, reuse, &SocketBinding::default())
+}
+
+/// Create a new UDP socket bound to an already-resolved address, without blocking. The socket is also tied to the given interface or mark, if any.
+pub(crate) fn new_udp_socket(
+    addr: SocketAddr,
+    binding: &SocketBinding,
+) -> std::io::Result<smol::net::UdpSocket> {
+    new_udp_socket_with(addr, SocketReuse::global(), binding)
+}
+
+fn new_udp_socket_with(
+    addr: SocketAddr,
+    reuse: SocketReuse,
+    binding: &SocketBinding,
+) -> std::io::Result<smol::net::UdpSocket> {
+    let socket = make_udp_socket(addr, reuse, SOCKET_FACTORY.read().as_ref())?;
+    binding.apply(&socket, addr)?;
+    let buffers = *UDP_BUFFERS.read();
+    if let Some(buffers) = buffers {
+        apply_udp_buffers(&socket, buffers)?;
+    }
+    socket.into_udp_socket().try_into()
+}
+
+fn make_udp_socket(
+    addr: SocketAddr,
+    reuse: SocketReuse,
+    factory: Option<&SocketFactory>,
+) -> std::io::Result<Socket> {
+    match factory {
+        Some(factory) => Ok(factory(addr)?.into()),
+        None => new_socket(addr, Type::dgram(), reuse),
+    }
+}
+
+/// Create a new TCP listener, honoring the options set by [set_socket_reuse]. On Unix, SO_REUSEADDR is always set, so that a restarted server can listen again while connections from before linger in TIME_WAIT.
+pub async fn new_tcp_listener_bind(
+    addr: impl AsyncToSocketAddrs,
+) -> std::io::Result<smol::net::TcpListener> {
+    new_tcp_listener_bind_with(addr, SocketReuse::global()).await
+}
+
+async fn new_tcp_listener_bind_with(
+    addr: impl AsyncToSocketAddrs,
+    reuse: SocketReuse,
+) -> std::io::Result<smol::net::TcpListener> {
+    let addr = smol::net::resolve(addr).await?[0];
+    // on Windows, SO_REUSEADDR would let others steal the address instead
+    let reuse = SocketReuse {
+        reuse_addr: reuse.reuse_addr || cfg!(unix),
+        ..reuse
+    };
+    let socket = new_socket(addr, Type::stream(), reuse)?;
+    socket.listen(1024)?;
+    socket.into_tcp_listener().try_into()
+}
+
+/// Randomly lengthens or shortens a timer period by up to the given fraction of it, so that the timers of many clients drift apart instead of firing in lockstep.
+pub fn jitter(period: Duration, fraction: f64) -> Duration {
+    let fraction = fraction.max(0.0).min(1.0);
+    if fraction == 0.0 {
+        return period;
+    }
+    period.mul_f64(1.0 + rand::thread_rng().gen_range(-fraction, fraction))
 }
 
 // fn anything_socket_addr() -> SocketAddr {
 //     "0.0.0.0:0".parse::<SocketAddr>().unwrap()
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_stays_in_range() {
+        let period = Duration::from_secs(10);
+        assert_eq!(jitter(period, 0.0), period);
+        let jittered: Vec<_> = (0..100).map(|_| jitter(period, 0.2)).collect();
+        assert!(jittered
+            .iter()
+            .all(|&d| d >= Duration::from_secs(8) && d <= Duration::from_secs(12)));
+        assert!(jittered.iter().any(|&d| d != jittered[0]));
+    }
+
+    #[test]
+    fn udp_buffers_are_applied() {
+        let socket = new_socket(
+            "127.0.0.1:0".parse().unwrap(),
+            Type::dgram(),
+            SocketReuse::default(),
+        )
+        .unwrap();
+        // small enough that no OS limit gets in the way
+        apply_udp_buffers(&socket, (32768, 16384)).unwrap();
+        assert!(socket.recv_buffer_size().unwrap() >= 32768);
+        assert!(socket.send_buffer_size().unwrap() >= 16384);
+    }
+
+    #[test]
+    fn udp_buffer_sizes_can_be_set_again() {
+        // clients set them up again every time they restart after an error
+        set_udp_buffer_sizes(1 << 20, 1 << 20);
+        set_udp_buffer_sizes(1 << 21, 1 << 21);
+        assert_eq!(*UDP_BUFFERS.read(), Some((1 << 21, 1 << 21)));
+        *UDP_BUFFERS.write() = None;
+    }
+
+    #[test]
+    fn empty_binding_changes_nothing() {
+        let socket = new_udp_socket("127.0.0.1:0".parse().unwrap(), &SocketBinding::default());
+        assert!(socket.is_ok());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn binding_to_a_missing_device_fails() {
+        let binding = SocketBinding {
+            device: Some("geph-no-such-if".into()),
+            ..Default::default()
+        };
+        assert!(new_udp_socket("127.0.0.1:0".parse().unwrap(), &binding).is_err());
+    }
+
+    #[test]
+    fn factory_supplies_sockets() {
+        let supplied = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let factory: SocketFactory = {
+            let supplied = supplied.clone();
+            Box::new(move |addr| {
+                supplied.fetch_add(1, Ordering::SeqCst);
+                std::net::UdpSocket::bind(addr)
+            })
+        };
+        let socket = make_udp_socket(
+            "127.0.0.1:0".parse().unwrap(),
+            SocketReuse::default(),
+            Some(&factory),
+        )
+        .unwrap();
+        assert_eq!(supplied.load(Ordering::SeqCst), 1);
+        let socket: smol::net::UdpSocket = socket.into_udp_socket().try_into().unwrap();
+        assert!(socket.local_addr().unwrap().ip().is_loopback());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dscp_sets_tos() {
+        use std::os::unix::io::AsRawFd;
+        let binding = SocketBinding {
+            dscp: Some(46),
+            ..Default::default()
+        };
+        let socket = new_udp_socket("127.0.0.1:0".parse().unwrap(), &binding).unwrap();
+        let mut tos: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                libc::IP_TOS,
+                &mut tos as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(tos, 46 << 2);
+        let binding = SocketBinding {
+            dscp: Some(64),
+            ..Default::default()
+        };
+        assert!(new_udp_socket("127.0.0.1:0".parse().unwrap(), &binding).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tcp_listeners_always_reuse_addr() {
+        smol::block_on(async {
+            let listener = new_tcp_listener_bind_with("127.0.0.1:0", SocketReuse::default())
+                .await
+                .unwrap();
+            let listener: std::sync::Arc<smol::Async<std::net::TcpListener>> = listener.into();
+            let socket = Socket::from(listener.get_ref().try_clone().unwrap());
+            assert!(socket.reuse_address().unwrap());
+        })
+    }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[test]
+    fn reuse_port_allows_double_bind() {
+        smol::block_on(async {
+            let reuse = SocketReuse {
+                reuse_addr: true,
+                reuse_port: true,
+            };
+            let first = new_udp_socket_bind_with("127.0.0.1:0", reuse)
+                .await
+                .unwrap();
+            let addr = first.local_addr().unwrap();
+            let _second = new_udp_socket_bind_with(addr, reuse).await.unwrap();
+
+            let first = new_tcp_listener_bind_with("127.0.0.1:0", reuse)
+                .await
+                .unwrap();
+            let addr = first.local_addr().unwrap();
+            let _second = new_tcp_listener_bind_with(addr, reuse).await.unwrap();
+        })
+    }
+}
@@ -1,12 +1,14 @@
-use crate::fec::{FrameDecoder, FrameEncoder};
+use crate::fec::{FecCodec, FecDecoder, FrameEncoder};
 use crate::msg::DataFrame;
 use crate::runtime;
 use bytes::Bytes;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
 use smol::channel::{Receiver, Sender};
 use smol::prelude::*;
-use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{BTreeMap, HashSet, VecDeque},
     time::Instant,
 };
 use std::{sync::Arc, time::Duration};
@@ -23,17 +25,70 @@ async fn infal<T, E, F: Future<Output = std::result::Result<T, E>>>(fut: F) -> T
 
 #[derive(Debug, Clone)]
 pub struct SessionConfig {
-    pub latency: Duration,
+    /// Batching window used when the send queue is shallow (interactive traffic).
+    pub min_latency: Duration,
+    /// Batching window used when the send queue is deep (bulk traffic).
+    pub max_latency: Duration,
+    /// Longest a packet may wait for its batch to fill up, counted from when it was handed to the session. Caps tail latency regardless of the batching window.
+    pub max_packet_age: Option<Duration>,
+    /// If set, the session closes once no valid frame has arrived from the peer for this long, and recv_bytes then fails with TimedOut. This lets servers reap sessions whose clients vanished without saying goodbye.
+    pub idle_timeout: Option<Duration>,
     pub target_loss: f64,
+    /// Percentile of recent loss samples taken as the measured loss that FEC is sized for. The default of 0.25 is optimistic and saves bandwidth; higher values spend more on parity to leave fewer losses unrecovered.
+    pub loss_percentile: f64,
+    /// How the measured loss is estimated from the peer's reports of what it received.
+    pub loss_estimator: LossEstimator,
+    /// Below this measured loss, runs are sent with data shards only. Zero means FEC is always on.
+    pub min_loss_for_fec: f64,
+    /// How many FEC runs behind the newest one are kept around for reordered shards.
+    pub fec_reorder_runs: u64,
+    /// How FEC runs that may still receive reordered shards are eventually given up on.
+    pub fec_eviction: EvictionPolicy,
+    /// Whether the shards of every run are sent in a random order instead of by index. Decoding doesn't care, since every frame carries its index within the run.
+    pub shuffle_shards: bool,
+    /// The FEC code runs are encoded and decoded with. The peer must use the same one.
+    pub fec_codec: Arc<dyn FecCodec>,
+    /// Largest datagram found to reach the peer while probing during the handshake, reported in [SessionStats]. None if the path wasn't probed.
+    pub probed_datagram_size: Option<usize>,
+    /// Executor that the tasks of the session, and by default those of a [Multiplex](crate::mux::Multiplex) over it, run on. None uses the one set through [set_smol_executor](crate::runtime::set_smol_executor), or smolscale.
+    pub executor: Option<runtime::ExecutorHandle>,
     pub send_frame: Sender<DataFrame>,
     pub recv_frame: Receiver<DataFrame>,
 }
 
+/// Policy for evicting incomplete FEC runs from the decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict runs more than `fec_reorder_runs` behind the newest one.
+    Count,
+    /// Evict runs whose first shard arrived longer ago than the given duration, no matter how many runs came after.
+    Time(Duration),
+}
+
+/// How a session estimates the loss that FEC is sized for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LossEstimator {
+    /// Takes the configured percentile of the last 64 samples, each covering at least two seconds and 100 frames. Steady, but slow to notice bursts of loss.
+    Windowed,
+    /// Exponentially-weighted moving average updated on every frame, giving the newest sample the given weight between 0 and 1. Follows sudden spikes in loss much faster.
+    Ewma(f64),
+}
+
+impl Default for LossEstimator {
+    fn default() -> Self {
+        LossEstimator::Windowed
+    }
+}
+
 /// Representation of an isolated session that deals only in DataFrames and abstracts away all I/O concerns. It's the user's responsibility to poll the session. Otherwise, it might not make progress and will drop packets.
 pub struct Session {
-    pub(crate) send_tosend: Sender<Bytes>,
+    pub(crate) send_tosend: Sender<(Bytes, Instant)>,
     recv_input: Receiver<Bytes>,
     get_stats: Sender<Sender<SessionStats>>,
+    fec_reorder_runs: Arc<AtomicU64>,
+    peer_closed: Arc<AtomicBool>,
+    idle_expired: Arc<AtomicBool>,
+    executor: Option<runtime::ExecutorHandle>,
     _dropper: Vec<Box<dyn FnOnce() + Send + Sync + 'static>>,
     _task: smol::Task<()>,
 }
@@ -44,11 +99,30 @@ impl Session {
         let (send_tosend, recv_tosend) = smol::channel::bounded(500);
         let (send_input, recv_input) = smol::channel::bounded(500);
         let (s, r) = smol::channel::unbounded();
-        let task = runtime::spawn(session_loop(cfg, recv_tosend, send_input, r));
+        let fec_reorder_runs = Arc::new(AtomicU64::new(cfg.fec_reorder_runs));
+        let peer_closed = Arc::new(AtomicBool::new(false));
+        let idle_expired = Arc::new(AtomicBool::new(false));
+        let executor = cfg.executor.clone();
+        let task = runtime::spawn_on(
+            executor.as_ref(),
+            session_loop(
+                cfg,
+                recv_tosend,
+                send_input,
+                r,
+                fec_reorder_runs.clone(),
+                peer_closed.clone(),
+                idle_expired.clone(),
+            ),
+        );
         Session {
             send_tosend,
             recv_input,
             get_stats: s,
+            fec_reorder_runs,
+            peer_closed,
+            idle_expired,
+            executor,
             _dropper: Vec::new(),
             _task: task,
         }
@@ -61,15 +135,43 @@ impl Session {
 
     /// Takes a Bytes to be sent and stuffs it into the session.
     pub async fn send_bytes(&self, to_send: Bytes) {
-        if self.send_tosend.try_send(to_send).is_err() {
+        if self
+            .send_tosend
+            .try_send((to_send, Instant::now()))
+            .is_err()
+        {
             log::trace!("overflowed send buffer at session!");
         }
         // drop(self.send_tosend.send(to_send).await)
     }
 
-    /// Waits until the next application input is decoded by the session.
-    pub async fn recv_bytes(&self) -> Bytes {
-        self.recv_input.recv().await.unwrap()
+    /// Waits until the next application input is decoded by the session. Once the peer closes the session and everything it sent has been received, fails with UnexpectedEof; once nothing has arrived for longer than the idle timeout, fails with TimedOut; fails with ConnectionReset if the session's internal task died for any other reason.
+    pub async fn recv_bytes(&self) -> std::io::Result<Bytes> {
+        self.recv_input.recv().await.map_err(|_| {
+            if self.peer_closed.load(Ordering::SeqCst) {
+                std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "session closed by peer")
+            } else if self.idle_expired.load(Ordering::SeqCst) {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "session idle for too long")
+            } else {
+                std::io::Error::new(std::io::ErrorKind::ConnectionReset, "session is dead")
+            }
+        })
+    }
+
+    /// Gracefully closes the session. Everything already passed to send_bytes is sent, followed by a goodbye that lets the peer tear down its end right away instead of timing out.
+    pub async fn close(self) {
+        self.send_tosend.close();
+        self._task.await;
+    }
+
+    /// Gets the executor that the session runs its tasks on, if it was given one.
+    pub fn executor(&self) -> Option<&runtime::ExecutorHandle> {
+        self.executor.as_ref()
+    }
+
+    /// Changes how many FEC runs are kept around for reordered shards.
+    pub fn set_fec_reorder_runs(&self, runs: u64) {
+        self.fec_reorder_runs.store(runs, Ordering::Relaxed)
     }
 
     /// Obtains current statistics.
@@ -87,44 +189,174 @@ pub struct SessionStats {
     pub down_loss: f64,
     pub down_recovered_loss: f64,
     pub down_redundant: f64,
-    pub recent_seqnos: Vec<(Instant, u64)>,
+    pub recent_seqnos: SeqnoTrace,
+    /// Smoothed round-trip time, or zero if there isn't enough data yet.
+    pub rtt: Duration,
+    /// Number of FEC runs still waiting for shards. A growing number means that reordering or loss is making reassembly struggle.
+    pub live_decoders: usize,
+    /// Bytes of shards buffered by the FEC runs still waiting for more.
+    pub buffered_shard_bytes: usize,
+    /// Number of frames dropped because they had already been received. A high count with low loss suggests more redundancy than needed.
+    pub down_duplicates: u64,
+    /// Number of frames dropped because they were too old for the replay filter to tell whether they had been received.
+    pub down_too_old: u64,
+    /// Frames sent per second, parity included, averaged over the last second.
+    pub up_frames_per_sec: f64,
+    /// Bytes of shards sent per second, before encryption and padding, averaged over the last second.
+    pub up_bytes_per_sec: f64,
+    /// Smoothed variation between the gaps of consecutive frame arrivals, leaving out gaps where the peer simply had nothing to send. High jitter with low loss is what makes a connection feel choppy.
+    pub down_jitter: Duration,
+    /// Fraction of received frames that arrived after a frame sent later than them.
+    pub down_reorder_rate: f64,
+    /// Largest datagram, in bytes of UDP payload, found to reach the peer while probing during the handshake. None if the path wasn't probed, which is always the case on the server side.
+    pub probed_datagram_size: Option<usize>,
+}
+
+/// A trace of received seqnos and their arrival times. Entries after the first are stored as compact deltas from the previous entry.
+#[derive(Debug, Clone, Default)]
+pub struct SeqnoTrace {
+    first: Option<(Instant, u64)>,
+    last: Option<(Instant, u64)>,
+    deltas: VecDeque<(u32, i32)>,
+}
+
+impl SeqnoTrace {
+    /// Appends an entry to the end of the trace.
+    pub fn push(&mut self, time: Instant, seqno: u64) {
+        match self.last {
+            None => {
+                self.first = Some((time, seqno));
+                self.last = self.first;
+            }
+            Some((last_time, last_seqno)) => {
+                let time_delta = time
+                    .saturating_duration_since(last_time)
+                    .as_micros()
+                    .min(u32::MAX as u128) as u32;
+                let seqno_delta = (seqno as i64 - last_seqno as i64)
+                    .max(i32::MIN as i64)
+                    .min(i32::MAX as i64) as i32;
+                self.deltas.push_back((time_delta, seqno_delta));
+                // track the reconstructed value, so that rounding errors don't accumulate
+                self.last = Some(apply_delta(
+                    (last_time, last_seqno),
+                    (time_delta, seqno_delta),
+                ));
+            }
+        }
+    }
+
+    /// Removes and returns the oldest entry.
+    pub fn pop_front(&mut self) -> Option<(Instant, u64)> {
+        let first = self.first?;
+        if let Some(delta) = self.deltas.pop_front() {
+            self.first = Some(apply_delta(first, delta));
+        } else {
+            self.first = None;
+            self.last = None;
+        }
+        Some(first)
+    }
+
+    /// Gets the oldest entry.
+    pub fn first(&self) -> Option<(Instant, u64)> {
+        self.first
+    }
+
+    /// Number of entries in the trace.
+    pub fn len(&self) -> usize {
+        if self.first.is_some() {
+            self.deltas.len() + 1
+        } else {
+            0
+        }
+    }
+
+    /// Whether the trace is empty.
+    pub fn is_empty(&self) -> bool {
+        self.first.is_none()
+    }
+
+    /// Iterates over the entries as absolute values, reconstructed from the deltas.
+    pub fn iter(&self) -> impl Iterator<Item = (Instant, u64)> + '_ {
+        self.first
+            .into_iter()
+            .chain(self.deltas.iter().scan(self.first, |curr, &delta| {
+                let next = apply_delta((*curr)?, delta);
+                *curr = Some(next);
+                Some(next)
+            }))
+    }
+}
+
+fn apply_delta(
+    (time, seqno): (Instant, u64),
+    (time_delta, seqno_delta): (u32, i32),
+) -> (Instant, u64) {
+    (
+        time + Duration::from_micros(time_delta as u64),
+        (seqno as i64 + seqno_delta as i64) as u64,
+    )
 }
 
 async fn session_loop(
     cfg: SessionConfig,
-    recv_tosend: Receiver<Bytes>,
+    recv_tosend: Receiver<(Bytes, Instant)>,
     send_input: Sender<Bytes>,
     recv_statreq: Receiver<Sender<SessionStats>>,
+    fec_reorder_runs: Arc<AtomicU64>,
+    peer_closed: Arc<AtomicBool>,
+    idle_expired: Arc<AtomicBool>,
 ) {
     let measured_loss = Arc::new(AtomicU8::new(0));
     let high_recv_frame_no = Arc::new(AtomicU64::new(0));
     let total_recv_frames = Arc::new(AtomicU64::new(0));
+    let rtt_calc = Arc::new(Mutex::new(RttCalculator::new()));
+    let send_rate = Arc::new(Mutex::new(SendRateMeter::default()));
 
     // sending loop
-    let send_task = runtime::spawn(session_send_loop(
-        cfg.clone(),
-        recv_tosend.clone(),
-        measured_loss.clone(),
-        high_recv_frame_no.clone(),
-        total_recv_frames.clone(),
-    ));
-    let recv_task = runtime::spawn(session_recv_loop(
-        cfg,
-        send_input,
-        recv_statreq,
-        measured_loss,
-        high_recv_frame_no,
-        total_recv_frames,
-    ));
+    let executor = cfg.executor.clone();
+    let send_task = runtime::spawn_on(
+        executor.as_ref(),
+        session_send_loop(
+            cfg.clone(),
+            recv_tosend.clone(),
+            measured_loss.clone(),
+            high_recv_frame_no.clone(),
+            total_recv_frames.clone(),
+            rtt_calc.clone(),
+            send_rate.clone(),
+        ),
+    );
+    let recv_task = runtime::spawn_on(
+        executor.as_ref(),
+        session_recv_loop(
+            cfg,
+            send_input,
+            recv_statreq,
+            measured_loss,
+            high_recv_frame_no,
+            total_recv_frames,
+            fec_reorder_runs,
+            rtt_calc,
+            send_rate,
+            peer_closed,
+            idle_expired,
+        ),
+    );
     smol::future::race(send_task, recv_task).await;
 }
 
+const GOODBYE_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
 async fn session_send_loop(
     cfg: SessionConfig,
-    recv_tosend: Receiver<Bytes>,
+    recv_tosend: Receiver<(Bytes, Instant)>,
     measured_loss: Arc<AtomicU8>,
     high_recv_frame_no: Arc<AtomicU64>,
     total_recv_frames: Arc<AtomicU64>,
+    rtt_calc: Arc<Mutex<RttCalculator>>,
+    send_rate: Arc<Mutex<SendRateMeter>>,
 ) {
     // let shaper = RateLimiter::direct_with_clock(
     //     Quota::per_second(NonZeroU32::new(10000u32).unwrap())
@@ -135,31 +367,36 @@ async fn session_send_loop(
     let mut run_no = 0u64;
     let mut to_send = Vec::new();
     loop {
-        // obtain a vector of bytes to send
-        let to_send = {
-            to_send.clear();
-            // get as much tosend as possible within the timeout
-            // this lets us do it at maximum efficiency
-            to_send.push(infal(recv_tosend.recv()).await);
-            let mut timeout = smol::Timer::after(cfg.latency);
-            loop {
-                let res = async {
-                    (&mut timeout).await;
-                    true
-                }
-                .or(async {
-                    to_send.push(infal(recv_tosend.recv()).await);
-                    false
-                });
-                if res.await || to_send.len() >= 16 {
-                    break &to_send;
-                }
+        // obtain a vector of bytes to send, or say goodbye once the session is closed and the queue is flushed
+        if !collect_batch(&cfg, &recv_tosend, &mut to_send).await {
+            log::debug!("send queue closed, sending goodbye at frame {}", frame_no);
+            drop(
+                cfg.send_frame
+                    .send(DataFrame::goodbye(frame_no, run_no))
+                    .await,
+            );
+            // give the backhaul a moment to actually put the remaining frames on the wire
+            let deadline = Instant::now() + GOODBYE_DRAIN_TIMEOUT;
+            while !cfg.send_frame.is_empty() && Instant::now() < deadline {
+                smol::Timer::after(Duration::from_millis(5)).await;
             }
+            return;
+        }
+        let to_send = &to_send;
+        // encode into raptor, unless the link is clean enough that parity is pure overhead
+        let current_loss = measured_loss.load(Ordering::Relaxed);
+        let encoded = if (current_loss as f64 / 256.0) < cfg.min_loss_for_fec {
+            FrameEncoder::encode_data_only(&to_send)
+        } else {
+            cfg.fec_codec
+                .encode(current_loss, loss_to_u8(cfg.target_loss), &to_send)
         };
-        // encode into raptor
-        let encoded = FrameEncoder::new(loss_to_u8(cfg.target_loss))
-            .encode(measured_loss.load(Ordering::Relaxed), &to_send);
-        for (idx, bts) in encoded.iter().enumerate() {
+        let mut order: Vec<usize> = (0..encoded.len()).collect();
+        if cfg.shuffle_shards {
+            order.shuffle(&mut rand::thread_rng());
+        }
+        for idx in order {
+            let bts = &encoded[idx];
             if frame_no % 1000 == 0 {
                 log::debug!(
                     "frame {}, measured loss {}",
@@ -167,20 +404,29 @@ async fn session_send_loop(
                     measured_loss.load(Ordering::Relaxed)
                 );
             }
-            drop(
-                cfg.send_frame
-                    .send(DataFrame {
-                        frame_no,
-                        run_no,
-                        run_idx: idx as u8,
-                        data_shards: to_send.len() as u8,
-                        parity_shards: (encoded.len() - to_send.len()) as u8,
-                        high_recv_frame_no: high_recv_frame_no.load(Ordering::Relaxed),
-                        total_recv_frames: total_recv_frames.load(Ordering::Relaxed),
-                        body: bts.clone(),
-                    })
-                    .await,
-            );
+            rtt_calc.lock().record_send(frame_no, Instant::now());
+            let sent = cfg
+                .send_frame
+                .send(DataFrame {
+                    frame_no,
+                    run_no,
+                    run_idx: idx as u8,
+                    data_shards: to_send.len() as u8,
+                    parity_shards: (encoded.len() - to_send.len()) as u8,
+                    high_recv_frame_no: high_recv_frame_no.load(Ordering::Relaxed),
+                    total_recv_frames: total_recv_frames.load(Ordering::Relaxed),
+                    body: bts.clone(),
+                })
+                .await;
+            // nobody is left to carry our frames, so encoding more of them is pointless
+            if sent.is_err() {
+                log::debug!(
+                    "send_frame closed at frame {}, stopping send loop",
+                    frame_no
+                );
+                return;
+            }
+            send_rate.lock().record(Instant::now(), bts.len());
             // every 10000 frames, we send 1000 frames slowly. this keeps the loss estimator accurate
             // let frame_cycle = frame_no % 10000;
             // if frame_cycle >= 9000 {
@@ -206,34 +452,63 @@ async fn session_recv_loop(
     measured_loss: Arc<AtomicU8>,
     high_recv_frame_no: Arc<AtomicU64>,
     total_recv_frames: Arc<AtomicU64>,
+    fec_reorder_runs: Arc<AtomicU64>,
+    rtt_calc: Arc<Mutex<RttCalculator>>,
+    send_rate: Arc<Mutex<SendRateMeter>>,
+    peer_closed: Arc<AtomicBool>,
+    idle_expired: Arc<AtomicBool>,
 ) {
-    let decoder = smol::lock::RwLock::new(RunDecoder::default());
-    let seqnos = smol::lock::RwLock::new(VecDeque::new());
+    let decoder = smol::lock::RwLock::new(RunDecoder::new(
+        cfg.fec_reorder_runs,
+        cfg.fec_eviction,
+        cfg.fec_codec.clone(),
+    ));
+    let seqnos = smol::lock::RwLock::new(SeqnoTrace::default());
+    let rp_filter = Mutex::new(ReplayFilter::new(0));
+    let arrivals = Mutex::new(ArrivalMeter::default());
+    let last_valid = Mutex::new(Instant::now());
     // receive loop
     let recv_loop = async {
-        let mut rp_filter = ReplayFilter::new(0);
-        let mut loss_calc = LossCalculator::new();
+        let mut loss_calc = LossCalculator::new(cfg.loss_estimator, cfg.loss_percentile);
         loop {
             let new_frame = infal(cfg.recv_frame.recv()).await;
-            if !rp_filter.add(new_frame.frame_no) {
+            if !rp_filter.lock().add(new_frame.frame_no) {
                 log::trace!(
                     "recv_loop: replay filter dropping frame {}",
                     new_frame.frame_no
                 );
                 continue;
             }
+            *last_valid.lock() = Instant::now();
+            if new_frame.is_goodbye() {
+                log::debug!(
+                    "recv_loop: peer said goodbye at frame {}",
+                    new_frame.frame_no
+                );
+                peer_closed.store(true, Ordering::SeqCst);
+                return;
+            }
+            arrivals.lock().record(new_frame.frame_no, Instant::now());
             {
                 let mut seqnos = seqnos.write().await;
-                seqnos.push_back((Instant::now(), new_frame.frame_no));
+                seqnos.push(Instant::now(), new_frame.frame_no);
                 if seqnos.len() > 100000 {
                     seqnos.pop_front();
                 }
             }
+            rtt_calc
+                .lock()
+                .record_echo(new_frame.high_recv_frame_no, Instant::now());
             loss_calc.update_params(new_frame.high_recv_frame_no, new_frame.total_recv_frames);
             measured_loss.store(loss_to_u8(loss_calc.median), Ordering::Relaxed);
             high_recv_frame_no.fetch_max(new_frame.frame_no, Ordering::Relaxed);
             total_recv_frames.fetch_add(1, Ordering::Relaxed);
-            if let Some(output) = decoder.write().await.input(
+            let mut decoder = decoder.write().await;
+            let reorder_runs = fec_reorder_runs.load(Ordering::Relaxed);
+            if reorder_runs != decoder.reorder_runs {
+                decoder.set_reorder_runs(reorder_runs);
+            }
+            if let Some(output) = decoder.input(
                 new_frame.run_no,
                 new_frame.run_idx,
                 new_frame.data_shards,
@@ -251,6 +526,16 @@ async fn session_recv_loop(
         loop {
             let req = infal(recv_statreq.recv()).await;
             let decoder = decoder.read().await;
+            let (live_decoders, buffered_shard_bytes) = decoder.occupancy();
+            let (down_duplicates, down_too_old) = {
+                let rp_filter = rp_filter.lock();
+                (rp_filter.duplicates, rp_filter.too_old)
+            };
+            let (up_frames_per_sec, up_bytes_per_sec) = send_rate.lock().rates(Instant::now());
+            let (down_jitter, down_reorder_rate) = {
+                let arrivals = arrivals.lock();
+                (arrivals.jitter(), arrivals.reorder_rate())
+            };
             let response = SessionStats {
                 down_total: high_recv_frame_no.load(Ordering::Relaxed),
                 down_loss: 1.0
@@ -261,19 +546,55 @@ async fn session_recv_loop(
                     - (decoder.correct_count as f64 / decoder.total_count as f64).min(1.0),
                 down_redundant: decoder.total_parity_shards as f64
                     / decoder.total_data_shards as f64,
-                recent_seqnos: seqnos.read().await.iter().cloned().collect(),
+                recent_seqnos: seqnos.read().await.clone(),
+                rtt: rtt_calc.lock().smoothed.unwrap_or_default(),
+                live_decoders,
+                buffered_shard_bytes,
+                down_duplicates,
+                down_too_old,
+                up_frames_per_sec,
+                up_bytes_per_sec,
+                down_jitter,
+                down_reorder_rate,
+                probed_datagram_size: cfg.probed_datagram_size,
             };
             infal(req.send(response)).await;
         }
     };
-    smol::future::race(stats_loop, recv_loop).await
+    // idle loop, which gives up on a peer that has gone quiet
+    let idle_loop = async {
+        let idle_timeout = match cfg.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => smol::future::pending().await,
+        };
+        loop {
+            let deadline = *last_valid.lock() + idle_timeout;
+            if Instant::now() >= deadline {
+                log::debug!(
+                    "recv_loop: nothing from peer in {:?}, closing",
+                    idle_timeout
+                );
+                idle_expired.store(true, Ordering::SeqCst);
+                return;
+            }
+            smol::Timer::at(deadline).await;
+        }
+    };
+    smol::future::race(stats_loop, recv_loop.race(idle_loop)).await
 }
 /// A reordering-resistant FEC reconstructor
-#[derive(Default)]
+/// Most runs that [EvictionPolicy::Time] keeps at once. Beyond that, the oldest ones are evicted early, so that a flood of runs can't grow the table without bound.
+const MAX_TIMED_RUNS: usize = 1000;
+
 struct RunDecoder {
     top_run: u64,
     bottom_run: u64,
-    decoders: HashMap<u64, FrameDecoder>,
+    reorder_runs: u64,
+    policy: EvictionPolicy,
+    codec: Arc<dyn FecCodec>,
+    decoders: BTreeMap<u64, Box<dyn FecDecoder>>,
+    /// Runs in the order they were created, along with when, for [EvictionPolicy::Time]. Runs already retired some other way are skipped over.
+    by_age: VecDeque<(u64, Instant)>,
     total_count: u64,
     correct_count: u64,
 
@@ -282,6 +603,84 @@ struct RunDecoder {
 }
 
 impl RunDecoder {
+    fn new(reorder_runs: u64, policy: EvictionPolicy, codec: Arc<dyn FecCodec>) -> Self {
+        RunDecoder {
+            top_run: 0,
+            bottom_run: 0,
+            reorder_runs,
+            policy,
+            codec,
+            decoders: BTreeMap::new(),
+            by_age: VecDeque::new(),
+            total_count: 0,
+            correct_count: 0,
+            total_data_shards: 0,
+            total_parity_shards: 0,
+        }
+    }
+
+    /// Changes the reordering depth. Runs that fall out of the new window are evicted and counted immediately.
+    fn set_reorder_runs(&mut self, reorder_runs: u64) {
+        self.reorder_runs = reorder_runs;
+        self.evict(Instant::now());
+    }
+
+    /// Evicts all decoders that the eviction policy gives up on, accumulating their statistics.
+    fn evict(&mut self, now: Instant) {
+        match self.policy {
+            EvictionPolicy::Count => {
+                while self.top_run.saturating_sub(self.bottom_run) > self.reorder_runs {
+                    self.retire(self.bottom_run);
+                    self.bottom_run += 1;
+                }
+            }
+            EvictionPolicy::Time(max_age) => {
+                while let Some(&(run_no, created)) = self.by_age.front() {
+                    let live = self.decoders.contains_key(&run_no);
+                    if live
+                        && now.saturating_duration_since(created) <= max_age
+                        && self.decoders.len() < MAX_TIMED_RUNS
+                    {
+                        break;
+                    }
+                    self.by_age.pop_front();
+                    if live {
+                        // never accept shards for an evicted run again, or we may output duplicates
+                        self.bottom_run = self.bottom_run.max(run_no + 1);
+                        self.retire_below_bottom();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Counts the runs that are still incomplete, and how many bytes they buffer.
+    fn occupancy(&self) -> (usize, usize) {
+        self.decoders
+            .values()
+            .filter(|dec| !dec.is_complete())
+            .fold((0, 0), |(count, bytes), dec| {
+                (count + 1, bytes + dec.buffered_bytes())
+            })
+    }
+
+    /// Retires every run below the bottom, including those that arrived out of order and haven't expired yet.
+    fn retire_below_bottom(&mut self) {
+        while let Some(&run_no) = self.decoders.keys().next() {
+            if run_no >= self.bottom_run {
+                break;
+            }
+            self.retire(run_no);
+        }
+    }
+
+    fn retire(&mut self, run_no: u64) {
+        if let Some(dec) = self.decoders.remove(&run_no) {
+            self.total_count += (dec.good_pkts() + dec.lost_pkts()) as u64;
+            self.correct_count += dec.good_pkts() as u64
+        }
+    }
+
     fn input(
         &mut self,
         run_no: u64,
@@ -290,22 +689,40 @@ impl RunDecoder {
         parity_shards: u8,
         bts: &[u8],
     ) -> Option<Vec<Bytes>> {
+        self.input_at(
+            Instant::now(),
+            run_no,
+            run_idx,
+            data_shards,
+            parity_shards,
+            bts,
+        )
+    }
+
+    fn input_at(
+        &mut self,
+        now: Instant,
+        run_no: u64,
+        run_idx: u8,
+        data_shards: u8,
+        parity_shards: u8,
+        bts: &[u8],
+    ) -> Option<Vec<Bytes>> {
+        if run_no > self.top_run {
+            self.top_run = run_no;
+        }
+        // advance bottom
+        self.evict(now);
         if run_no >= self.bottom_run {
-            if run_no > self.top_run {
-                self.top_run = run_no;
-                // advance bottom
-                while self.top_run - self.bottom_run > 10 {
-                    if let Some(dec) = self.decoders.remove(&self.bottom_run) {
-                        self.total_count += (dec.good_pkts() + dec.lost_pkts()) as u64;
-                        self.correct_count += dec.good_pkts() as u64
-                    }
-                    self.bottom_run += 1;
+            let codec = &self.codec;
+            let by_age = &mut self.by_age;
+            let timed = matches!(self.policy, EvictionPolicy::Time(_));
+            let decoder = self.decoders.entry(run_no).or_insert_with(|| {
+                if timed {
+                    by_age.push_back((run_no, now));
                 }
-            }
-            let decoder = self
-                .decoders
-                .entry(run_no)
-                .or_insert_with(|| FrameDecoder::new(data_shards as usize, parity_shards as usize));
+                codec.decoder(data_shards as usize, parity_shards as usize)
+            });
             if run_idx < data_shards {
                 self.total_data_shards += 1
             } else {
@@ -322,12 +739,14 @@ impl RunDecoder {
     }
 }
 
-/// A filter for replays. Records recently seen seqnos and rejects either repeats or really old seqnos.
+/// A filter for replays. Records recently seen seqnos and rejects either repeats or really old seqnos, counting each kind of rejection separately.
 #[derive(Debug)]
 struct ReplayFilter {
     top_seqno: u64,
     bottom_seqno: u64,
     seen_seqno: HashSet<u64>,
+    duplicates: u64,
+    too_old: u64,
 }
 
 impl ReplayFilter {
@@ -336,19 +755,23 @@ impl ReplayFilter {
             top_seqno: start,
             bottom_seqno: start,
             seen_seqno: HashSet::new(),
+            duplicates: 0,
+            too_old: 0,
         }
     }
 
     fn add(&mut self, seqno: u64) -> bool {
         if seqno < self.bottom_seqno {
             // out of range. we can't know, so we just say no
+            self.too_old += 1;
             return false;
         }
         // check the seen
-        if self.seen_seqno.contains(&seqno) {
+        if !self.seen_seqno.insert(seqno) {
+            self.duplicates += 1;
             return false;
         }
-        self.top_seqno = seqno;
+        self.top_seqno = self.top_seqno.max(seqno);
         while self.top_seqno - self.bottom_seqno > 10000 {
             self.seen_seqno.remove(&self.bottom_seqno);
             self.bottom_seqno += 1;
@@ -357,6 +780,57 @@ impl ReplayFilter {
     }
 }
 
+/// Collects a batch of packets to send. Waits for as many packets as possible within the batching window, since that lets us do FEC at maximum efficiency, but never lets the oldest packet in the batch go past its maximum age. Returns false once the queue is closed and empty.
+async fn collect_batch(
+    cfg: &SessionConfig,
+    recv_tosend: &Receiver<(Bytes, Instant)>,
+    to_send: &mut Vec<Bytes>,
+) -> bool {
+    to_send.clear();
+    let (first, first_time) = match recv_tosend.recv().await {
+        Ok(first) => first,
+        Err(_) => return false,
+    };
+    to_send.push(first);
+    let window = adaptive_latency(
+        cfg.min_latency,
+        cfg.max_latency,
+        recv_tosend.len(),
+        recv_tosend.capacity().unwrap_or(1),
+    );
+    let mut deadline = Instant::now() + window;
+    if let Some(max_age) = cfg.max_packet_age {
+        deadline = deadline.min(first_time + max_age);
+    }
+    let mut timeout = smol::Timer::at(deadline);
+    loop {
+        let res = async {
+            (&mut timeout).await;
+            true
+        }
+        .or(async {
+            to_send.push(infal(recv_tosend.recv()).await.0);
+            false
+        });
+        if res.await || to_send.len() >= 16 {
+            break;
+        }
+    }
+    true
+}
+
+/// Picks a batching window between the bounds, proportional to how full the send queue is.
+fn adaptive_latency(
+    min_latency: Duration,
+    max_latency: Duration,
+    queue_depth: usize,
+    queue_capacity: usize,
+) -> Duration {
+    let max_latency = max_latency.max(min_latency);
+    let fullness = (queue_depth as f64 / queue_capacity.max(1) as f64).min(1.0);
+    min_latency + (max_latency - min_latency).mul_f64(fullness)
+}
+
 fn loss_to_u8(loss: f64) -> u8 {
     let loss = loss * 256.0;
     if loss > 254.0 {
@@ -365,27 +839,184 @@ fn loss_to_u8(loss: f64) -> u8 {
     loss as u8
 }
 
+/// A round-trip time estimator. The peer echoes back the highest frame number it has received, so the time since we sent that frame is a round-trip sample.
+///
+/// The echo only goes out with whatever frame the peer sends next, so an idle peer may hold on to it for a long time. It can't have held it for longer than since its previous frame, which didn't echo it yet, so only echoes that closely follow the peer's previous frame are sampled.
+struct RttCalculator {
+    sent: VecDeque<(u64, Instant)>,
+    last_echo: u64,
+    last_arrival: Option<Instant>,
+    smoothed: Option<Duration>,
+}
+
+impl RttCalculator {
+    fn new() -> Self {
+        RttCalculator {
+            sent: VecDeque::new(),
+            last_echo: 0,
+            last_arrival: None,
+            smoothed: None,
+        }
+    }
+
+    fn record_send(&mut self, frame_no: u64, now: Instant) {
+        self.sent.push_back((frame_no, now));
+        if self.sent.len() > 10000 {
+            self.sent.pop_front();
+        }
+    }
+
+    /// Records a frame from the peer, which echoes the given frame number.
+    fn record_echo(&mut self, echoed: u64, now: Instant) {
+        let max_hold = self
+            .last_arrival
+            .replace(now)
+            .map(|last| now.saturating_duration_since(last));
+        // only a newly-echoed frame tells us anything
+        if echoed <= self.last_echo {
+            return;
+        }
+        self.last_echo = echoed;
+        while let Some(&(frame_no, sent_time)) = self.sent.front() {
+            if frame_no > echoed {
+                break;
+            }
+            self.sent.pop_front();
+            if frame_no == echoed {
+                let sample = now.saturating_duration_since(sent_time);
+                // the hold time may be at most a quarter of the sample, or the sample is too inflated to be of use
+                if !max_hold.map(|hold| hold * 4 <= sample).unwrap_or(false) {
+                    break;
+                }
+                // same smoothing factor as TCP
+                self.smoothed = Some(match self.smoothed {
+                    None => sample,
+                    Some(old) => old * 7 / 8 + sample / 8,
+                });
+                break;
+            }
+        }
+    }
+}
+
+/// How far back the send rate is averaged.
+const SEND_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Measures the rate at which frames are sent, over a rolling window.
+#[derive(Default)]
+struct SendRateMeter {
+    sent: VecDeque<(Instant, usize)>,
+    window_bytes: usize,
+}
+
+impl SendRateMeter {
+    fn record(&mut self, now: Instant, bytes: usize) {
+        self.sent.push_back((now, bytes));
+        self.window_bytes += bytes;
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(time, bytes)) = self.sent.front() {
+            if now.saturating_duration_since(time) <= SEND_RATE_WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+            self.window_bytes -= bytes;
+        }
+    }
+
+    /// Frames and bytes sent per second over the window.
+    fn rates(&mut self, now: Instant) -> (f64, f64) {
+        self.expire(now);
+        let secs = SEND_RATE_WINDOW.as_secs_f64();
+        (
+            self.sent.len() as f64 / secs,
+            self.window_bytes as f64 / secs,
+        )
+    }
+}
+
+/// Arrival gaps longer than this are taken to be the peer going quiet rather than jitter, and start a fresh run of gaps.
+const JITTER_IDLE_GAP: Duration = Duration::from_millis(100);
+
+/// Measures jitter and reordering of received frames. Frames carry no send timestamps, so jitter is the variation between consecutive arrival gaps within bursts of traffic.
+#[derive(Default)]
+struct ArrivalMeter {
+    last_arrival: Option<Instant>,
+    last_gap: Option<Duration>,
+    /// Smoothed jitter in seconds.
+    jitter: f64,
+    highest: Option<u64>,
+    reordered: u64,
+    total: u64,
+}
+
+impl ArrivalMeter {
+    fn record(&mut self, frame_no: u64, now: Instant) {
+        self.total += 1;
+        match self.highest {
+            Some(highest) if frame_no < highest => self.reordered += 1,
+            _ => self.highest = Some(frame_no),
+        }
+        if let Some(last_arrival) = self.last_arrival {
+            let gap = now.saturating_duration_since(last_arrival);
+            if gap > JITTER_IDLE_GAP {
+                self.last_gap = None;
+            } else {
+                if let Some(last_gap) = self.last_gap {
+                    let variation = (gap.as_secs_f64() - last_gap.as_secs_f64()).abs();
+                    // smoothed with the same 1/16 gain as RTP's jitter, which compares transit times instead
+                    self.jitter += (variation - self.jitter) / 16.0;
+                }
+                self.last_gap = Some(gap);
+            }
+        }
+        self.last_arrival = Some(now);
+    }
+
+    fn jitter(&self) -> Duration {
+        Duration::from_secs_f64(self.jitter)
+    }
+
+    fn reorder_rate(&self) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.reordered as f64 / self.total as f64
+        }
+    }
+}
+
 /// A packet loss calculator.
 struct LossCalculator {
     last_top_seqno: u64,
     last_total_seqno: u64,
     last_time: Instant,
     loss_samples: VecDeque<f64>,
+    estimator: LossEstimator,
+    percentile: f64,
     median: f64,
 }
 
 impl LossCalculator {
-    fn new() -> LossCalculator {
+    fn new(estimator: LossEstimator, percentile: f64) -> LossCalculator {
         LossCalculator {
             last_top_seqno: 0,
             last_total_seqno: 0,
             last_time: Instant::now(),
             loss_samples: VecDeque::new(),
+            estimator,
+            percentile,
             median: 0.0,
         }
     }
 
     fn update_params(&mut self, top_seqno: u64, total_seqno: u64) {
+        if let LossEstimator::Ewma(alpha) = self.estimator {
+            self.update_ewma(alpha, top_seqno, total_seqno);
+            return;
+        }
         let now = Instant::now();
         if total_seqno > self.last_total_seqno + 100
             && top_seqno > self.last_top_seqno + 100
@@ -408,11 +1039,725 @@ impl LossCalculator {
             let median = {
                 let mut lala: Vec<f64> = self.loss_samples.iter().cloned().collect();
                 lala.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
-                lala[lala.len() / 4]
+                lala[percentile_index(lala.len(), self.percentile)]
             };
             self.median = median;
             self.last_time = now;
         }
         // self.median = (1.0 - total_seqno as f64 / top_seqno as f64).max(0.0);
     }
+
+    fn update_ewma(&mut self, alpha: f64, top_seqno: u64, total_seqno: u64) {
+        // reordered reports say nothing new
+        if top_seqno <= self.last_top_seqno {
+            return;
+        }
+        let delta_top = (top_seqno - self.last_top_seqno) as f64;
+        let delta_total = total_seqno.saturating_sub(self.last_total_seqno) as f64;
+        self.last_top_seqno = top_seqno;
+        self.last_total_seqno = self.last_total_seqno.max(total_seqno);
+        let loss_sample = 1.0 - delta_total / delta_top.max(delta_total);
+        let alpha = alpha.max(0.0).min(1.0);
+        self.median = alpha * loss_sample + (1.0 - alpha) * self.median;
+    }
+}
+
+/// Index of the given percentile in a sorted, non-empty list of the given length.
+fn percentile_index(len: usize, percentile: f64) -> usize {
+    let percentile = if percentile.is_nan() {
+        0.0
+    } else {
+        percentile.max(0.0).min(1.0)
+    };
+    ((len as f64 * percentile) as usize).min(len - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fec::ReedSolomonCodec;
+
+    #[test]
+    fn ewma_follows_loss_spikes() {
+        let mut windowed = LossCalculator::new(LossEstimator::Windowed, 0.25);
+        let mut ewma = LossCalculator::new(LossEstimator::Ewma(0.1), 0.25);
+        // a clean start, then half of all frames lost
+        let mut total = 0;
+        for top in 1..=1000u64 {
+            if top <= 500 || top % 2 == 0 {
+                total += 1;
+            }
+            windowed.update_params(top, total);
+            ewma.update_params(top, total);
+        }
+        assert!(windowed.median.abs() < 1e-9);
+        assert!(ewma.median > 0.3 && ewma.median < 0.7);
+        // a stale, reordered report changes nothing
+        let before = ewma.median;
+        ewma.update_params(10, 10);
+        assert!((ewma.median - before).abs() < 1e-9);
+        // and once the loss stops, the estimate falls back down
+        for top in 1001..=1200u64 {
+            total += 1;
+            ewma.update_params(top, total);
+        }
+        assert!(ewma.median < 0.01);
+    }
+
+    #[test]
+    fn loss_percentile_is_clamped() {
+        // the default matches the old len / 4
+        for len in 1..100 {
+            assert_eq!(percentile_index(len, 0.25), len / 4);
+        }
+        assert_eq!(percentile_index(64, 0.5), 32);
+        assert_eq!(percentile_index(64, 1.0), 63);
+        assert_eq!(percentile_index(64, 7.0), 63);
+        assert_eq!(percentile_index(64, -1.0), 0);
+        assert_eq!(percentile_index(64, f64::NAN), 0);
+        assert_eq!(percentile_index(1, 0.75), 0);
+    }
+
+    #[test]
+    fn adaptive_latency_tracks_depth() {
+        let min = Duration::from_millis(1);
+        let max = Duration::from_millis(11);
+        assert_eq!(adaptive_latency(min, max, 0, 500), min);
+        assert_eq!(adaptive_latency(min, max, 500, 500), max);
+        assert_eq!(adaptive_latency(min, max, 1000, 500), max);
+        assert_eq!(
+            adaptive_latency(min, max, 250, 500),
+            Duration::from_millis(6)
+        );
+        let mut last = min;
+        for depth in 0..=500 {
+            let window = adaptive_latency(min, max, depth, 500);
+            assert!(window >= last && window >= min && window <= max);
+            last = window;
+        }
+    }
+
+    #[test]
+    fn run_decoder_depth_change_keeps_stats() {
+        let mut decoder = RunDecoder::new(10, EvictionPolicy::Count, Arc::new(ReedSolomonCodec));
+        for run_no in 0..20u64 {
+            decoder.input(run_no, 0, 1, 1, b"\x05\x00hello");
+        }
+        assert_eq!(decoder.total_count, 9);
+        assert_eq!(decoder.correct_count, 9);
+        decoder.set_reorder_runs(2);
+        assert_eq!(decoder.bottom_run, 17);
+        assert_eq!(decoder.total_count, 17);
+        assert_eq!(decoder.correct_count, 17);
+        decoder.set_reorder_runs(50);
+        assert_eq!(decoder.bottom_run, 17);
+        assert_eq!(decoder.total_count, 17);
+    }
+
+    #[test]
+    fn rtt_calculator_smooths_echoes() {
+        let start = Instant::now();
+        let mut calc = RttCalculator::new();
+        for frame_no in 0..100 {
+            calc.record_send(frame_no, start + Duration::from_millis(frame_no));
+        }
+        assert_eq!(calc.smoothed, None);
+        // nothing says how long the peer held on to the echo in its first frame
+        calc.record_echo(5, start + Duration::from_millis(88));
+        assert_eq!(calc.smoothed, None);
+        calc.record_echo(10, start + Duration::from_millis(90));
+        assert_eq!(calc.smoothed, Some(Duration::from_millis(80)));
+        // stale echoes are ignored
+        calc.record_echo(5, start + Duration::from_millis(91));
+        assert_eq!(calc.smoothed, Some(Duration::from_millis(80)));
+        // an idle peer may have held this one for most of the time
+        calc.record_echo(20, start + Duration::from_millis(400));
+        assert_eq!(calc.smoothed, Some(Duration::from_millis(80)));
+        calc.record_echo(30, start + Duration::from_millis(402));
+        assert_eq!(calc.smoothed, Some(Duration::from_micros(116_500)));
+        assert_eq!(calc.sent.front().unwrap().0, 31);
+    }
+
+    #[test]
+    fn arrivals_measure_jitter_and_reordering() {
+        let start = Instant::now();
+        let mut meter = ArrivalMeter::default();
+        // perfectly paced frames have no jitter
+        for frame_no in 0..10 {
+            meter.record(frame_no, start + Duration::from_millis(10 * frame_no));
+        }
+        assert_eq!(meter.jitter(), Duration::from_secs(0));
+        assert!(meter.reorder_rate().abs() < 1e-9);
+        // a frame 16ms late, then one overtaken by it
+        meter.record(11, start + Duration::from_millis(116));
+        meter.record(10, start + Duration::from_millis(120));
+        assert!((meter.reorder_rate() - 1.0 / 12.0).abs() < 1e-9);
+        // the gaps went 10ms, 26ms, 4ms: variations of 16ms and 22ms
+        let expected = 0.016 / 16.0;
+        let expected = expected + (0.022 - expected) / 16.0;
+        assert!((meter.jitter().as_secs_f64() - expected).abs() < 1e-6);
+        // going quiet for a while and resuming at the same pace isn't jitter
+        meter.record(12, start + Duration::from_millis(5120));
+        meter.record(13, start + Duration::from_millis(5124));
+        meter.record(14, start + Duration::from_millis(5128));
+        let expected = expected - expected / 16.0;
+        assert!((meter.jitter().as_secs_f64() - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn batches_respect_max_packet_age() {
+        smol::block_on(async {
+            let (send_frame, _) = smol::channel::unbounded();
+            let (_, recv_frame) = smol::channel::unbounded();
+            let cfg = SessionConfig {
+                min_latency: Duration::from_millis(500),
+                max_latency: Duration::from_millis(500),
+                max_packet_age: Some(Duration::from_millis(50)),
+                idle_timeout: None,
+                target_loss: 0.05,
+                min_loss_for_fec: 0.0,
+                fec_reorder_runs: 10,
+                fec_eviction: EvictionPolicy::Count,
+                shuffle_shards: false,
+                loss_percentile: 0.25,
+                loss_estimator: LossEstimator::Windowed,
+                fec_codec: Arc::new(ReedSolomonCodec),
+                probed_datagram_size: None,
+                executor: None,
+                send_frame,
+                recv_frame,
+            };
+            let (send_tosend, recv_tosend) = smol::channel::bounded(500);
+            let mut batch = Vec::new();
+            for round in 0..3 {
+                // the first packet has already waited a while before batching even starts
+                let enqueued = Instant::now();
+                send_tosend
+                    .send((Bytes::from_static(b"first"), enqueued))
+                    .await
+                    .unwrap();
+                smol::Timer::after(Duration::from_millis(20)).await;
+                send_tosend
+                    .send((Bytes::from_static(b"second"), Instant::now()))
+                    .await
+                    .unwrap();
+                collect_batch(&cfg, &recv_tosend, &mut batch).await;
+                let age = enqueued.elapsed();
+                assert_eq!(batch.len(), 2, "round {}", round);
+                assert!(age >= Duration::from_millis(50));
+                assert!(age < Duration::from_millis(200), "waited {:?}", age);
+            }
+        })
+    }
+
+    #[test]
+    fn send_loop_stops_when_frames_closed() {
+        smol::block_on(async {
+            let (send_frame, recv_frame_out) = smol::channel::unbounded();
+            let (_, recv_frame) = smol::channel::unbounded();
+            let cfg = SessionConfig {
+                min_latency: Duration::from_millis(1),
+                max_latency: Duration::from_millis(1),
+                max_packet_age: None,
+                idle_timeout: None,
+                target_loss: 0.05,
+                min_loss_for_fec: 0.0,
+                fec_reorder_runs: 10,
+                fec_eviction: EvictionPolicy::Count,
+                shuffle_shards: false,
+                loss_percentile: 0.25,
+                loss_estimator: LossEstimator::Windowed,
+                fec_codec: Arc::new(ReedSolomonCodec),
+                probed_datagram_size: None,
+                executor: None,
+                send_frame,
+                recv_frame,
+            };
+            drop(recv_frame_out);
+            let (send_tosend, recv_tosend) = smol::channel::bounded(500);
+            let send_loop = runtime::spawn(session_send_loop(
+                cfg,
+                recv_tosend,
+                Arc::new(AtomicU8::new(0)),
+                Arc::new(AtomicU64::new(0)),
+                Arc::new(AtomicU64::new(0)),
+                Arc::new(Mutex::new(RttCalculator::new())),
+                Arc::new(Mutex::new(SendRateMeter::default())),
+            ));
+            send_tosend
+                .send((Bytes::from_static(b"hello"), Instant::now()))
+                .await
+                .unwrap();
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                panic!("send loop kept spinning after send_frame closed")
+            };
+            send_loop.or(timeout).await;
+            // the loop dropped its end of the queue on the way out
+            assert!(send_tosend
+                .send((Bytes::from_static(b"hello"), Instant::now()))
+                .await
+                .is_err());
+        })
+    }
+
+    #[test]
+    fn send_rate_matches_emission() {
+        smol::block_on(async {
+            let (send_frame, recv_frame_out) = smol::channel::unbounded();
+            let (_, recv_frame) = smol::channel::unbounded();
+            let cfg = SessionConfig {
+                min_latency: Duration::from_millis(1),
+                max_latency: Duration::from_millis(1),
+                max_packet_age: None,
+                idle_timeout: None,
+                target_loss: 0.05,
+                min_loss_for_fec: 0.0,
+                fec_reorder_runs: 10,
+                fec_eviction: EvictionPolicy::Count,
+                shuffle_shards: false,
+                loss_percentile: 0.25,
+                loss_estimator: LossEstimator::Windowed,
+                fec_codec: Arc::new(ReedSolomonCodec),
+                probed_datagram_size: None,
+                executor: None,
+                send_frame,
+                recv_frame,
+            };
+            let (send_tosend, recv_tosend) = smol::channel::bounded(500);
+            let send_rate = Arc::new(Mutex::new(SendRateMeter::default()));
+            let _send_loop = runtime::spawn(session_send_loop(
+                cfg,
+                recv_tosend,
+                Arc::new(AtomicU8::new(0)),
+                Arc::new(AtomicU64::new(0)),
+                Arc::new(AtomicU64::new(0)),
+                Arc::new(Mutex::new(RttCalculator::new())),
+                send_rate.clone(),
+            ));
+            // note down when every frame actually comes out
+            let emitted = Arc::new(Mutex::new(Vec::new()));
+            let _collector = {
+                let emitted = emitted.clone();
+                smol::spawn(async move {
+                    while let Ok(frame) = recv_frame_out.recv().await {
+                        emitted.lock().push((Instant::now(), frame.body.len()));
+                    }
+                })
+            };
+            // sustained sends for a while longer than the window
+            let start = Instant::now();
+            while start.elapsed() < SEND_RATE_WINDOW * 3 / 2 {
+                send_tosend
+                    .send((Bytes::from(vec![0u8; 100]), Instant::now()))
+                    .await
+                    .unwrap();
+                smol::Timer::after(Duration::from_millis(5)).await;
+            }
+            let now = Instant::now();
+            let (frames_per_sec, bytes_per_sec) = send_rate.lock().rates(now);
+            let (actual_frames, actual_bytes) = emitted
+                .lock()
+                .iter()
+                .filter(|(time, _)| now.saturating_duration_since(*time) <= SEND_RATE_WINDOW)
+                .fold((0.0, 0.0), |(frames, bytes), (_, len)| {
+                    (frames + 1.0, bytes + *len as f64)
+                });
+            assert!(actual_frames > 50.0, "only {} frames", actual_frames);
+            let close = |reported: f64, actual: f64| (reported - actual).abs() <= actual * 0.1;
+            assert!(
+                close(frames_per_sec, actual_frames),
+                "reported {} frames/s, actually {}",
+                frames_per_sec,
+                actual_frames
+            );
+            assert!(
+                close(bytes_per_sec, actual_bytes),
+                "reported {} bytes/s, actually {}",
+                bytes_per_sec,
+                actual_bytes
+            );
+        })
+    }
+
+    #[test]
+    fn shuffled_shards_still_decode() {
+        smol::block_on(async {
+            let (send_frame, recv_frame_out) = smol::channel::unbounded();
+            let (_, recv_frame) = smol::channel::unbounded();
+            let cfg = SessionConfig {
+                min_latency: Duration::from_millis(1),
+                max_latency: Duration::from_millis(1),
+                max_packet_age: None,
+                idle_timeout: None,
+                target_loss: 0.05,
+                min_loss_for_fec: 0.0,
+                fec_reorder_runs: 10,
+                fec_eviction: EvictionPolicy::Count,
+                shuffle_shards: true,
+                loss_percentile: 0.25,
+                loss_estimator: LossEstimator::Windowed,
+                fec_codec: Arc::new(ReedSolomonCodec),
+                probed_datagram_size: None,
+                executor: None,
+                send_frame,
+                recv_frame,
+            };
+            // queue everything up front, so that every run is full
+            let (send_tosend, recv_tosend) = smol::channel::bounded(500);
+            let pkts: Vec<Bytes> = (0..160)
+                .map(|i| Bytes::from(format!("packet {}", i)))
+                .collect();
+            for pkt in pkts.iter() {
+                send_tosend
+                    .send((pkt.clone(), Instant::now()))
+                    .await
+                    .unwrap();
+            }
+            drop(send_tosend);
+            let _send_loop = runtime::spawn(session_send_loop(
+                cfg,
+                recv_tosend,
+                Arc::new(AtomicU8::new(64)),
+                Arc::new(AtomicU64::new(0)),
+                Arc::new(AtomicU64::new(0)),
+                Arc::new(Mutex::new(RttCalculator::new())),
+                Arc::new(Mutex::new(SendRateMeter::default())),
+            ));
+            let mut runs: std::collections::BTreeMap<u64, Vec<DataFrame>> = Default::default();
+            let mut last_frame_no = None;
+            loop {
+                let frame = recv_frame_out.recv().await.unwrap();
+                if frame.is_goodbye() {
+                    break;
+                }
+                // frame numbers still count up in the order frames are sent
+                assert!(last_frame_no < Some(frame.frame_no));
+                last_frame_no = Some(frame.frame_no);
+                runs.entry(frame.run_no).or_default().push(frame);
+            }
+            let shuffled = runs
+                .values()
+                .filter(|run| run.windows(2).any(|w| w[0].run_idx > w[1].run_idx))
+                .count();
+            assert!(shuffled > 0, "no run was sent out of order");
+            // the decoder puts everything back together, even with the first frame of every run lost
+            let mut decoder =
+                RunDecoder::new(100, EvictionPolicy::Count, Arc::new(ReedSolomonCodec));
+            let mut decoded = HashSet::new();
+            for (run_no, run) in runs.iter() {
+                assert!(run[0].parity_shards > 0);
+                for frame in run.iter().skip(1) {
+                    if let Some(out) = decoder.input(
+                        *run_no,
+                        frame.run_idx,
+                        frame.data_shards,
+                        frame.parity_shards,
+                        &frame.body,
+                    ) {
+                        decoded.extend(out);
+                    }
+                }
+            }
+            for pkt in pkts.iter() {
+                assert!(decoded.contains(pkt), "lost {:?}", pkt);
+            }
+        })
+    }
+
+    #[test]
+    fn close_says_goodbye_after_flushing() {
+        smol::block_on(async {
+            let new_session = |send_frame, recv_frame| {
+                Session::new(SessionConfig {
+                    min_latency: Duration::from_millis(1),
+                    max_latency: Duration::from_millis(10),
+                    max_packet_age: None,
+                    idle_timeout: None,
+                    target_loss: 0.05,
+                    min_loss_for_fec: 0.0,
+                    fec_reorder_runs: 10,
+                    fec_eviction: EvictionPolicy::Count,
+                    shuffle_shards: false,
+                    loss_percentile: 0.25,
+                    loss_estimator: LossEstimator::Windowed,
+                    fec_codec: Arc::new(ReedSolomonCodec),
+                    probed_datagram_size: None,
+                    executor: None,
+                    send_frame,
+                    recv_frame,
+                })
+            };
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let sess_a = new_session(send_ab, recv_ba);
+            let sess_b = new_session(send_ba, recv_ab);
+            for i in 0..10u8 {
+                sess_a.send_bytes(Bytes::from(vec![i; 10])).await;
+            }
+            sess_a.close().await;
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                panic!("peer never noticed the goodbye")
+            };
+            async {
+                for i in 0..10u8 {
+                    assert_eq!(sess_b.recv_bytes().await.unwrap(), vec![i; 10]);
+                }
+                let err = sess_b.recv_bytes().await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+            .or(timeout)
+            .await;
+        })
+    }
+
+    #[test]
+    fn idle_sessions_time_out() {
+        smol::block_on(async {
+            let new_session = |send_frame, recv_frame, idle_timeout| {
+                Session::new(SessionConfig {
+                    min_latency: Duration::from_millis(1),
+                    max_latency: Duration::from_millis(10),
+                    max_packet_age: None,
+                    idle_timeout,
+                    target_loss: 0.05,
+                    min_loss_for_fec: 0.0,
+                    fec_reorder_runs: 10,
+                    fec_eviction: EvictionPolicy::Count,
+                    shuffle_shards: false,
+                    loss_percentile: 0.25,
+                    loss_estimator: LossEstimator::Windowed,
+                    fec_codec: Arc::new(ReedSolomonCodec),
+                    probed_datagram_size: None,
+                    executor: None,
+                    send_frame,
+                    recv_frame,
+                })
+            };
+            let (send_ab, recv_ab) = smol::channel::unbounded();
+            let (send_ba, recv_ba) = smol::channel::unbounded();
+            let sess_a = new_session(send_ab, recv_ba, None);
+            let sess_b = new_session(send_ba, recv_ab, Some(Duration::from_millis(200)));
+            let timeout = async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                panic!("idle session never closed")
+            };
+            async {
+                // steady traffic keeps the session open well past the timeout
+                for i in 0..10u8 {
+                    sess_a.send_bytes(Bytes::from(vec![i; 10])).await;
+                    assert_eq!(sess_b.recv_bytes().await.unwrap(), vec![i; 10]);
+                    smol::Timer::after(Duration::from_millis(50)).await;
+                }
+                // then the peer goes quiet without saying goodbye
+                let start = Instant::now();
+                let err = sess_b.recv_bytes().await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+                assert!(start.elapsed() >= Duration::from_millis(150));
+            }
+            .or(timeout)
+            .await;
+        })
+    }
+
+    #[test]
+    fn replay_filter_counts_drops() {
+        let mut filter = ReplayFilter::new(0);
+        for seqno in 0..100 {
+            assert!(filter.add(seqno));
+        }
+        // the same frames again, say through a redundant path
+        for seqno in 50..60 {
+            assert!(!filter.add(seqno));
+        }
+        assert_eq!((filter.duplicates, filter.too_old), (10, 0));
+        // jump far enough ahead that the start of the window moves past the old frames
+        assert!(filter.add(20000));
+        for seqno in 100..105 {
+            assert!(!filter.add(seqno));
+        }
+        assert_eq!((filter.duplicates, filter.too_old), (10, 5));
+        // reordered frames within the window still get through, but only once
+        assert!(filter.add(19999));
+        assert!(!filter.add(19999));
+        assert_eq!((filter.duplicates, filter.too_old), (11, 5));
+    }
+
+    #[test]
+    fn run_decoder_occupancy_tracks_reordering() {
+        let pkts = vec![
+            Bytes::from_static(b"hello"),
+            Bytes::from_static(b"world"),
+            Bytes::from_static(b"there"),
+        ];
+        let encoded = FrameEncoder::new(1).encode(64, &pkts);
+        let parity_shards = (encoded.len() - pkts.len()) as u8;
+        let mut decoder = RunDecoder::new(10, EvictionPolicy::Count, Arc::new(ReedSolomonCodec));
+        assert_eq!(decoder.occupancy(), (0, 0));
+        // the first shard of every run arrives, but the rest are held up
+        for run_no in 0..5 {
+            decoder.input(run_no, 0, 3, parity_shards, &encoded[0]);
+        }
+        let (live, bytes) = decoder.occupancy();
+        assert_eq!(live, 5);
+        assert_eq!(bytes, 5 * encoded.len() * encoded[0].len());
+        // the stragglers show up and the runs complete
+        for run_no in 0..5 {
+            for idx in 1..3 {
+                decoder.input(run_no, idx as u8, 3, parity_shards, &encoded[idx]);
+            }
+            assert_eq!(decoder.occupancy().0, 4 - run_no as usize);
+        }
+        assert_eq!(decoder.occupancy(), (0, 0));
+        // late parity doesn't bring a completed run back to life
+        decoder.input(0, 3, 3, parity_shards, &encoded[3]);
+        assert_eq!(decoder.occupancy(), (0, 0));
+    }
+
+    #[test]
+    fn seqno_trace_reconstructs() {
+        let start = Instant::now();
+        let original: Vec<(Instant, u64)> = (0..1000u64)
+            .map(|i| {
+                let time = start + Duration::from_nanos(i * i * 1234 + i * 7);
+                // mostly increasing, with some reordering
+                let seqno = if i % 7 == 3 { i * 3 - 5 } else { i * 3 };
+                (time, seqno)
+            })
+            .collect();
+        let mut trace = SeqnoTrace::default();
+        for &(time, seqno) in original.iter() {
+            trace.push(time, seqno);
+        }
+        for _ in 0..10 {
+            trace.pop_front();
+        }
+        assert_eq!(trace.len(), 990);
+        assert_eq!(trace.first().unwrap().1, original[10].1);
+        let reconstructed: Vec<_> = trace.iter().collect();
+        assert_eq!(reconstructed.len(), 990);
+        for (&(orig_time, orig_seqno), &(time, seqno)) in
+            original[10..].iter().zip(reconstructed.iter())
+        {
+            assert_eq!(orig_seqno, seqno);
+            let error = if time > orig_time {
+                time - orig_time
+            } else {
+                orig_time - time
+            };
+            assert!(error < Duration::from_micros(1));
+        }
+    }
+
+    #[test]
+    fn run_decoder_time_eviction_waits_for_late_parity() {
+        let pkts = vec![Bytes::from_static(b"hello"), Bytes::from_static(b"world")];
+        let encoded = FrameEncoder::new(1).encode(64, &pkts);
+        let parity_shards = (encoded.len() - pkts.len()) as u8;
+        let run_late_parity = |mut decoder: RunDecoder| {
+            let start = Instant::now();
+            // the first data shard is lost, and the parity is badly reordered
+            decoder.input_at(start, 0, 1, 2, parity_shards, &encoded[1]);
+            for run_no in 1..50 {
+                let now = start + Duration::from_millis(run_no);
+                decoder.input_at(now, run_no, 0, 2, parity_shards, &encoded[0]);
+            }
+            decoder.input_at(
+                start + Duration::from_millis(100),
+                0,
+                2,
+                2,
+                parity_shards,
+                &encoded[2],
+            )
+        };
+        let by_time = RunDecoder::new(
+            10,
+            EvictionPolicy::Time(Duration::from_secs(1)),
+            Arc::new(ReedSolomonCodec),
+        );
+        assert_eq!(run_late_parity(by_time), Some(vec![pkts[0].clone()]));
+        let by_count = RunDecoder::new(10, EvictionPolicy::Count, Arc::new(ReedSolomonCodec));
+        assert_eq!(run_late_parity(by_count), None);
+
+        // once the time is up, the run is gone for good
+        let mut decoder = RunDecoder::new(
+            10,
+            EvictionPolicy::Time(Duration::from_secs(1)),
+            Arc::new(ReedSolomonCodec),
+        );
+        let start = Instant::now();
+        decoder.input_at(start, 0, 1, 2, parity_shards, &encoded[1]);
+        decoder.input_at(
+            start + Duration::from_secs(2),
+            1,
+            0,
+            2,
+            parity_shards,
+            &encoded[0],
+        );
+        assert_eq!(decoder.bottom_run, 1);
+        assert_eq!(decoder.total_count, 2);
+        assert_eq!(decoder.correct_count, 1);
+        assert_eq!(
+            decoder.input_at(
+                start + Duration::from_secs(2),
+                0,
+                2,
+                2,
+                parity_shards,
+                &encoded[2]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn run_decoder_caps_timed_runs() {
+        let mut decoder = RunDecoder::new(
+            10,
+            EvictionPolicy::Time(Duration::from_secs(60)),
+            Arc::new(ReedSolomonCodec),
+        );
+        let now = Instant::now();
+        // runs that never complete, all well within the time limit
+        for run_no in 0..(MAX_TIMED_RUNS as u64 * 3) {
+            decoder.input_at(now, run_no, 0, 2, 1, b"\x05\x00hello");
+        }
+        assert_eq!(decoder.decoders.len(), MAX_TIMED_RUNS);
+        assert!(decoder.by_age.len() <= MAX_TIMED_RUNS);
+        assert_eq!(decoder.bottom_run, MAX_TIMED_RUNS as u64 * 2);
+        assert_eq!(decoder.total_count, MAX_TIMED_RUNS as u64 * 2 * 2);
+    }
+
+    #[test]
+    fn run_decoder_mixes_fec_and_data_only_runs() {
+        let pkts = vec![Bytes::from_static(b"hello"), Bytes::from_static(b"world")];
+        let mut decoder = RunDecoder::new(10, EvictionPolicy::Count, Arc::new(ReedSolomonCodec));
+        // data-only runs pass straight through
+        for (idx, shard) in FrameEncoder::encode_data_only(&pkts).iter().enumerate() {
+            let out = decoder.input(0, idx as u8, 2, 0, shard).unwrap();
+            assert_eq!(out, vec![pkts[idx].clone()]);
+        }
+        // parity reappears mid-stream and recovers a lost data shard
+        let encoded = FrameEncoder::new(1).encode(64, &pkts);
+        let parity_shards = (encoded.len() - pkts.len()) as u8;
+        assert!(parity_shards > 0);
+        let mut recovered = Vec::new();
+        for (idx, shard) in encoded.iter().enumerate().skip(1) {
+            if let Some(out) = decoder.input(1, idx as u8, 2, parity_shards, shard) {
+                recovered.extend(out);
+            }
+        }
+        assert!(recovered.contains(&pkts[0]));
+        assert!(recovered.contains(&pkts[1]));
+        // back to data-only, with one shard lost
+        let shards = FrameEncoder::encode_data_only(&pkts);
+        decoder.input(2, 1, 2, 0, &shards[1]).unwrap();
+        decoder.input(3, 0, 2, 0, &shards[0]).unwrap();
+        decoder.set_reorder_runs(0);
+        assert_eq!(decoder.total_count, 6);
+        assert_eq!(decoder.correct_count, 5);
+    }
 }